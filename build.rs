@@ -0,0 +1,144 @@
+//! Generates the tables of event type and code names in `codes.rs` from the
+//! kernel's `input-event-codes.h`, so that they know every name the kernel
+//! does. The header is looked for where `INPUT_EVENT_CODES_H` says, or else
+//! in `/usr/include/linux`. If it isn't there (when cross-compiling, say),
+//! `src/code_names.rs`, a copy of the tables generated from a recent kernel,
+//! is used instead.
+
+use std::{env, fs, path::Path};
+
+/// The prefixes of the names in each table, the table's name, its doc
+/// comment, and the event type its codes are for (if it's a table of codes).
+const TABLES: &[(&[&str], &str, &str, Option<&str>)] = &[
+    (&["EV_"], "EV_NAMES", "Event types.", None),
+    (&["SYN_"], "SYN_NAMES", "`EV_SYN` codes.", Some("EV_SYN")),
+    (&["KEY_", "BTN_"], "KEY_NAMES", "`EV_KEY` codes.", Some("EV_KEY")),
+    (&["REL_"], "REL_NAMES", "`EV_REL` codes.", Some("EV_REL")),
+    (&["ABS_"], "ABS_NAMES", "`EV_ABS` codes.", Some("EV_ABS")),
+    (&["MSC_"], "MSC_NAMES", "`EV_MSC` codes.", Some("EV_MSC")),
+    (&["LED_"], "LED_NAMES", "`EV_LED` codes.", Some("EV_LED")),
+];
+
+/// Names that only mark the start of a range of codes. They're never the
+/// name shown for a code, as long as the code has another.
+const RANGE_STARTS: &[&str] = &[
+    "BTN_MISC", "BTN_MOUSE", "BTN_JOYSTICK", "BTN_GAMEPAD", "BTN_DIGI",
+    "BTN_WHEEL", "BTN_TRIGGER_HAPPY",
+];
+
+/// Returns true if a name from the header isn't a name for any one type or
+/// code, but a limit (like `KEY_MAX` or `ABS_CNT`).
+fn is_limit(name: &str) -> bool {
+    name.ends_with("_MAX") || name.ends_with("_CNT")
+        || name == "KEY_MIN_INTERESTING"
+}
+
+/// Parses a number the way C would (only decimal and hexadecimal are used).
+fn parse_number(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Returns the `#define`s in the header that give a number (or the name of
+/// another `#define` that does), as names and numbers, in order.
+fn defines(header: &str) -> Vec<(String, u16)> {
+    let mut ret: Vec<(String, u16)> = Vec::new();
+    for line in header.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("#define") { continue }
+        let (name, value) = match (words.next(), words.next()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => continue,
+        };
+        let value = parse_number(value).or_else(|| {
+            ret.iter().find(|x| x.0 == value).map(|x| x.1)
+        });
+        if let Some(value) = value { ret.push((name.to_owned(), value)) }
+    }
+    ret
+}
+
+/// Writes the Rust source of a table: each code, and the name to show for
+/// it, sorted by code, wrapped to fit in 79 columns.
+fn write_table(out: &mut String, name: &str, doc: &str,
+               entries: &[(u16, &str)]) {
+    out.push_str(&format!("/// {}\nconst {}: &[(u16, &str)] = &[\n",
+                          doc, name));
+    let mut line = String::from("   ");
+    for (value, name) in entries.iter() {
+        let entry = format!(" ({:#04x}, \"{}\"),", value, name);
+        if line.len() + entry.len() > 79 {
+            out.push_str(&line);
+            out.push('\n');
+            line = String::from("   ");
+        }
+        line.push_str(&entry);
+    }
+    out.push_str(&line);
+    out.push_str("\n];\n");
+}
+
+/// Generates the tables from the contents of the header.
+fn generate(header: &str) -> String {
+    let defines = defines(header);
+    let mut out = String::from("\
+// Generated by `build.rs` from `input-event-codes.h`. Where several names
+// share a value, the first one listed there is the one shown, except for the
+// names that only mark the start of a range (like `BTN_GAMEPAD`). The rest
+// are in `ALIASES`, so that they can still be used in configuration files.
+
+");
+    let mut aliases = Vec::new();
+    for (prefixes, table, doc, type_) in TABLES.iter() {
+        let names: Vec<(u16, &str)> = defines.iter()
+            .filter(|(name, _)| {
+                prefixes.iter().any(|x| name.starts_with(x))
+                    && !is_limit(name)
+            })
+            .map(|(name, value)| (*value, name.as_str()))
+            .collect();
+        let mut entries: Vec<(u16, &str)> = Vec::new();
+        for &(value, name) in names.iter() {
+            let preferred = names.iter().find(|x| {
+                x.0 == value && !RANGE_STARTS.contains(&x.1)
+            }).unwrap_or(&(value, name)).1;
+            if name == preferred { entries.push((value, name)) }
+            else if let Some(type_) = type_ {
+                aliases.push((*type_, value, name))
+            }
+        }
+        entries.sort_unstable();
+        entries.dedup_by_key(|x| x.0);
+        write_table(&mut out, table, doc, &entries);
+    }
+    out.push_str("/// Other names for codes, with their event types.\n\
+                  const ALIASES: &[(u16, u16, &str)] = &[\n");
+    for (type_, value, name) in aliases {
+        out.push_str(&format!("    ({}, {:#04x}, \"{}\"),\n", type_, value,
+                              name));
+    }
+    out.push_str("];\n");
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/code_names.rs");
+    println!("cargo:rerun-if-env-changed=INPUT_EVENT_CODES_H");
+    let header_path = env::var("INPUT_EVENT_CODES_H").unwrap_or_else(|_| {
+        "/usr/include/linux/input-event-codes.h".to_owned()
+    });
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap())
+        .join("code_names.rs");
+    let tables = match fs::read_to_string(&header_path) {
+        Ok(header) => {
+            println!("cargo:rerun-if-changed={}", header_path);
+            generate(&header)
+        },
+        Err(_) => fs::read_to_string("src/code_names.rs")
+            .expect("couldn't read src/code_names.rs"),
+    };
+    fs::write(out_path, tables).expect("couldn't write the code tables");
+}
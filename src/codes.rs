@@ -0,0 +1,159 @@
+//! Numeric constants from the kernel's `input-event-codes.h`, for the event
+//! types and codes that input2cmds itself cares about, and the names of
+//! event types and codes, for showing to humans.
+
+/// Synchronization events, which separate groups of other events.
+pub const EV_SYN: u16 = 0x00;
+/// Key and button events.
+pub const EV_KEY: u16 = 0x01;
+/// Relative axis events.
+pub const EV_REL: u16 = 0x02;
+/// Absolute axis events.
+pub const EV_ABS: u16 = 0x03;
+/// Miscellaneous events.
+pub const EV_MSC: u16 = 0x04;
+/// LED events, which say (or, written to a device, change) whether an LED is
+/// lit.
+pub const EV_LED: u16 = 0x11;
+
+/// Marks the end of a group of events that happened at the same time.
+pub const SYN_REPORT: u16 = 0x00;
+/// Means the kernel's event buffer overflowed, and some events were lost.
+pub const SYN_DROPPED: u16 = 0x03;
+
+/// The absolute axis code that selects which multitouch slot the following
+/// `ABS_MT_*` events apply to.
+pub const ABS_MT_SLOT: u16 = 0x2f;
+/// The absolute axis code that gives the ID of the contact in the current
+/// multitouch slot, or -1 when the contact lifts.
+pub const ABS_MT_TRACKING_ID: u16 = 0x39;
+
+/// The key code that single-touch devices use to say they're being touched.
+pub const BTN_TOUCH: u16 = 0x14a;
+
+/// The A key, which keyboards have (and other devices with keys usually
+/// don't).
+pub const KEY_A: u16 = 0x1e;
+/// The Z key.
+pub const KEY_Z: u16 = 0x2c;
+/// The space bar.
+pub const KEY_SPACE: u16 = 0x39;
+/// The button that mice have.
+pub const BTN_LEFT: u16 = 0x110;
+/// The first of the buttons that joysticks have (`BTN_TRIGGER`).
+pub const BTN_JOYSTICK: u16 = 0x120;
+/// The first of the buttons that gamepads have (`BTN_SOUTH`).
+pub const BTN_GAMEPAD: u16 = 0x130;
+/// The relative axis that mice move left and right along.
+pub const REL_X: u16 = 0x00;
+/// The relative axis that mice move up and down along.
+pub const REL_Y: u16 = 0x01;
+/// The first absolute axis, which joysticks have.
+pub const ABS_X: u16 = 0x00;
+
+/// One more than the highest event type.
+pub const EV_CNT: u16 = 0x20;
+/// One more than the highest absolute axis code.
+pub const ABS_CNT: u16 = 0x40;
+/// One more than the highest LED code.
+pub const LED_CNT: u16 = 0x10;
+
+/// The bus type of virtual devices, such as those made with `uinput`.
+pub const BUS_VIRTUAL: u16 = 0x06;
+
+/// Returns the name of an event type (like `"EV_KEY"`), if we know it.
+pub fn type_name(type_: u16) -> Option<&'static str> {
+    lookup(EV_NAMES, type_)
+}
+
+/// Returns the event type with the given name (like `"EV_KEY"`), if we know
+/// it.
+pub fn type_by_name(name: &str) -> Option<u16> {
+    EV_NAMES.iter().find(|x| x.1 == name).map(|x| x.0)
+}
+
+/// Returns the name of an event code (like `"KEY_A"`) for the given event
+/// type, if we know it.
+pub fn code_name(type_: u16, code: u16) -> Option<&'static str> {
+    lookup(code_table(type_)?, code)
+}
+
+/// Returns the event type and code with the given name (like `"KEY_A"`, or
+/// another name for the same code, like `"BTN_A"` for `"BTN_SOUTH"`), if we
+/// know it.
+pub fn code_by_name(name: &str) -> Option<(u16, u16)> {
+    const TYPES: [u16; 6] = [EV_SYN, EV_KEY, EV_REL, EV_ABS, EV_MSC, EV_LED];
+    TYPES.iter().find_map(|&type_| {
+        code_table(type_)?.iter().find(|x| x.1 == name)
+            .map(|x| (type_, x.0))
+    }).or_else(|| {
+        ALIASES.iter().find(|x| x.2 == name).map(|x| (x.0, x.1))
+    })
+}
+
+/// Returns the table of names for codes of the given event type, if we have
+/// one.
+fn code_table(type_: u16) -> Option<&'static [(u16, &'static str)]> {
+    Some(match type_ {
+        EV_SYN => SYN_NAMES,
+        EV_KEY => KEY_NAMES,
+        EV_REL => REL_NAMES,
+        EV_ABS => ABS_NAMES,
+        EV_MSC => MSC_NAMES,
+        EV_LED => LED_NAMES,
+        _ => return None,
+    })
+}
+
+/// Finds a value in one of the tables below, which are sorted by value.
+fn lookup(table: &[(u16, &'static str)], value: u16) -> Option<&'static str> {
+    table.binary_search_by_key(&value, |x| x.0).ok().map(|i| table[i].1)
+}
+
+// The tables of names are generated from `input-event-codes.h` by
+// `build.rs`.
+include!(concat!(env!("OUT_DIR"), "/code_names.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_are_sorted() {
+        for table in [EV_NAMES, SYN_NAMES, KEY_NAMES, REL_NAMES, ABS_NAMES,
+                      MSC_NAMES, LED_NAMES].iter() {
+            assert!(table.windows(2).all(|x| x[0].0 < x[1].0));
+        }
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(type_name(EV_KEY), Some("EV_KEY"));
+        assert_eq!(type_name(0x1e), None);
+        assert_eq!(type_by_name("EV_REL"), Some(EV_REL));
+        assert_eq!(type_by_name("EV_BOGUS"), None);
+        assert_eq!(code_name(EV_KEY, 30), Some("KEY_A"));
+        assert_eq!(code_name(EV_KEY, 0x130), Some("BTN_SOUTH"));
+        assert_eq!(code_name(EV_ABS, ABS_MT_SLOT), Some("ABS_MT_SLOT"));
+        assert_eq!(code_name(EV_SYN, SYN_DROPPED), Some("SYN_DROPPED"));
+        assert_eq!(code_name(0x1e, 0), None);
+        assert_eq!(code_by_name("BTN_SOUTH"), Some((EV_KEY, BTN_GAMEPAD)));
+        assert_eq!(code_by_name("REL_WHEEL"), Some((EV_REL, 8)));
+        assert_eq!(code_by_name("KEY_BOGUS"), None);
+        assert_eq!(code_by_name("LED_CAPSL"), Some((EV_LED, 1)));
+        assert_eq!(code_name(EV_LED, 8), Some("LED_MISC"));
+        // other names for the same code work too, but aren't shown
+        assert_eq!(code_by_name("BTN_A"), Some((EV_KEY, BTN_GAMEPAD)));
+        assert_eq!(code_by_name("BTN_GAMEPAD"), Some((EV_KEY, BTN_GAMEPAD)));
+        assert_eq!(code_by_name("KEY_MAX"), None);
+    }
+
+    #[test]
+    fn every_code_has_a_name() {
+        // the kernel has defined these for a long time, but the old tables
+        // didn't have them
+        assert_eq!(code_name(EV_KEY, 0x161), Some("KEY_SELECT"));
+        assert_eq!(code_name(EV_KEY, 0x220), Some("BTN_DPAD_UP"));
+        assert_eq!(code_by_name("KEY_BRIGHTNESSUP"), Some((EV_KEY, 0xe1)));
+    }
+}
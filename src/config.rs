@@ -0,0 +1,4159 @@
+//! Parsing of configuration files.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    time::Duration,
+};
+
+use crate::{
+    DBusCall, DeviceOptions, EventSender, LedSetting,
+    codes::*,
+    device::{find_devices, open_devices},
+    parse_json_lines,
+};
+
+/// Describes something wrong with a configuration file, in enough detail to
+/// point the user at the offending line (if there is one).
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ConfigError {
+    /// The path to the configuration file, as given on the command line.
+    pub file: String,
+    /// The line number within the file, counting from 1, if the problem is
+    /// with a particular line.
+    pub line: Option<usize>,
+    /// A human-readable description of the problem.
+    pub message: String,
+}
+
+impl ConfigError {
+    /// Formats the error as a single-line JSON object, for consumption by
+    /// other programs.
+    pub fn to_json(&self) -> String {
+        format!("{{\"file\":{},\"line\":{},\"message\":{}}}",
+                json_string(&self.file),
+                self.line.map(|x| x.to_string())
+                .unwrap_or_else(|| "null".to_owned()),
+                json_string(&self.message))
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line,
+                                 self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Returns a `ConfigError` for the given file and line from the current
+/// function. The remaining parameters are as for `format!`.
+macro_rules! config_bail {
+    ($path:expr, $line:expr, $($arg:tt)*) => {
+        return Err(ConfigError {
+            file: $path.to_owned(),
+            line: Some($line),
+            message: format!($($arg)*),
+        })
+    };
+}
+
+/// Quotes and escapes a string for inclusion in JSON output.
+pub fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                ret.push_str(&format!("\\u{:04x}", c as u32))
+            },
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// What to do when a match fires.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Action {
+    /// Run this command via `/bin/sh -c`. (`then:`)
+    Shell(String),
+    /// Run the script at this path via `/bin/sh`. If there's an event, its
+    /// type, code, and value are passed as arguments. (`then-file:`)
+    File(String),
+    /// Run the program named by the first element directly, with the rest as
+    /// its arguments. No shell is involved. (`exec:`)
+    Exec(Vec<String>),
+    /// Call a D-Bus method. No program is run. (`then-dbus:`)
+    DBus(DBusCall),
+    /// Change an LED on the device the event came from. No program is run.
+    /// (`then-led:`)
+    Led(LedSetting),
+}
+
+impl std::fmt::Display for Action {
+    /// Formats the action the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Action::Shell(x) => write!(f, "then: {}", x),
+            Action::File(x) => write!(f, "then-file: {}", x),
+            Action::Exec(x) => {
+                write!(f, "exec:")?;
+                for arg in x.iter() { write!(f, " {}", quote_word(arg))? }
+                Ok(())
+            },
+            Action::DBus(x) => {
+                write!(f, "then-dbus:")?;
+                for word in x.words().iter() {
+                    write!(f, " {}", quote_word(word))?
+                }
+                Ok(())
+            },
+            Action::Led(x) => write!(f, "then-led: {}", x.words().join(" ")),
+        }
+    }
+}
+
+/// Where in the configuration something came from.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct Location {
+    /// The path to the configuration file, as given on the command line.
+    pub file: String,
+    /// The line number within the file, counting from 1.
+    pub line: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Puts double quotes around a word if it wouldn't survive being written into
+/// a configuration file as-is, escaping any double quotes and backslashes in
+/// it (see `is_escape`).
+pub fn quote_word(word: &str) -> Cow<'_, str> {
+    if word.is_empty() || word.contains(|c: char| c.is_whitespace()
+                                        || c == ':' || c == '#' || c == '"') {
+        Cow::Owned(format!("\"{}\"", word.replace('\\', "\\\\")
+                           .replace('"', "\\\"")))
+    }
+    else {
+        Cow::Borrowed(word)
+    }
+}
+
+/// Options that affect how a command is run, rather than when. They can be
+/// given on the line with the command, or by a "defaults" directive.
+#[derive(Clone,Debug,Default,PartialEq,Eq,PartialOrd,Ord)]
+pub struct CommandOptions {
+    /// If not `None`, run the command with its niceness adjusted by this
+    /// amount, overriding the `--nice` option. (`nice=`)
+    pub nice: Option<i32>,
+    /// If not `None`, run the command in this directory instead of the one
+    /// input2cmds was run from. (`cwd=`)
+    pub cwd: Option<String>,
+    /// If not `None`, use this shell for `then:` and `then-file:` commands,
+    /// instead of `/bin/sh`. (`shell=`)
+    pub shell: Option<String>,
+    /// If not `None`, the exit statuses that mean the command worked, instead
+    /// of just 0. Any other status, or being killed by a signal, means it
+    /// failed. (`expect-exit=N,...`)
+    pub expect_exit: Option<Vec<i32>>,
+    /// Extra environment variables to give the command, as names and
+    /// values. (`env=NAME=VALUE`, which can be given more than once)
+    pub env: Vec<(String, String)>,
+}
+
+impl CommandOptions {
+    /// Returns these options, with any that weren't given taken from
+    /// `defaults` instead.
+    pub fn or(self, defaults: &CommandOptions) -> CommandOptions {
+        CommandOptions {
+            nice: self.nice.or(defaults.nice),
+            cwd: self.cwd.or_else(|| defaults.cwd.clone()),
+            shell: self.shell.or_else(|| defaults.shell.clone()),
+            expect_exit: self.expect_exit
+                .or_else(|| defaults.expect_exit.clone()),
+            env: {
+                let own = self.env;
+                defaults.env.iter()
+                    .filter(|(name, _)| own.iter().all(|x| &x.0 != name))
+                    .cloned().chain(own.iter().cloned()).collect()
+            },
+        }
+    }
+    /// If `el` is one of our options, parses it into `self` and returns
+    /// `true`. Otherwise, returns `false`.
+    fn parse(&mut self, path: &str, line_number: usize, el: &str)
+             -> Result<bool, ConfigError> {
+        if let Some(el) = el.strip_prefix("nice=") {
+            if self.nice.is_some() {
+                config_bail!(path, line_number, "multiple \"nice=\"s");
+            }
+            match el.parse() {
+                Err(_) => config_bail!(path, line_number, "invalid \"nice=\""),
+                Ok(x) => {
+                    if let Err(why) = check_nice(x) {
+                        config_bail!(path, line_number, "{}", why);
+                    }
+                    self.nice = Some(x);
+                },
+            }
+        }
+        else if let Some(el) = el.strip_prefix("cwd=") {
+            if self.cwd.is_some() {
+                config_bail!(path, line_number, "multiple \"cwd=\"s");
+            }
+            if !std::path::Path::new(el).is_dir() {
+                config_bail!(path, line_number, "{:?} is not a directory", el);
+            }
+            self.cwd = Some(el.to_owned());
+        }
+        else if let Some(el) = el.strip_prefix("shell=") {
+            if self.shell.is_some() {
+                config_bail!(path, line_number, "multiple \"shell=\"s");
+            }
+            if el.is_empty() {
+                config_bail!(path, line_number, "\"shell=\" needs a shell");
+            }
+            self.shell = Some(el.to_owned());
+        }
+        else if let Some(el) = el.strip_prefix("expect-exit=") {
+            if self.expect_exit.is_some() {
+                config_bail!(path, line_number, "multiple \"expect-exit=\"s");
+            }
+            match el.split(',').map(|x| x.parse().ok()).collect() {
+                Some(x) => self.expect_exit = Some(x),
+                None => config_bail!(path, line_number,
+                                     "\"expect-exit=\" wants exit statuses, \
+                                      separated by commas"),
+            }
+        }
+        else if let Some(el) = el.strip_prefix("env=") {
+            let (name, value) = match el.split_once('=') {
+                Some((name, value)) if is_env_name(name) => (name, value),
+                _ => config_bail!(path, line_number,
+                                  "\"env=\" wants a variable name, then \
+                                   \"=\", then its value"),
+            };
+            if self.env.iter().any(|x| x.0 == name) {
+                config_bail!(path, line_number, "multiple \"env={}=\"s",
+                             name);
+            }
+            self.env.push((name.to_owned(), value.to_owned()));
+        }
+        else {
+            return Ok(false)
+        }
+        Ok(true)
+    }
+}
+
+impl std::fmt::Display for CommandOptions {
+    /// Formats the options the way they would appear in a configuration file,
+    /// each with a space in front.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(x) = self.nice { write!(f, " nice={}", x)? }
+        if let Some(x) = self.cwd.as_ref() {
+            write!(f, " cwd={}", quote_word(x))?
+        }
+        if let Some(x) = self.shell.as_ref() {
+            write!(f, " shell={}", quote_word(x))?
+        }
+        if let Some(x) = self.expect_exit.as_ref() {
+            let statuses: Vec<String> = x.iter().map(i32::to_string)
+                .collect();
+            write!(f, " expect-exit={}", statuses.join(","))?
+        }
+        for (name, value) in self.env.iter() {
+            write!(f, " env={}", quote_word(&format!("{}={}", name, value)))?
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if `name` can be the name of an environment variable: some
+/// letters, digits, and underscores, not starting with a digit.
+pub(crate) fn is_env_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A condition on one of the numbers in an event (its type, code, or value),
+/// as given after `type=`, `code=`, or `value=`.
+#[derive(Clone,Debug,Default,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum FieldMatcher<T> {
+    /// Any number at all. (`*`, or leaving the condition out)
+    #[default]
+    Any,
+    /// Exactly this number. (`N`)
+    Is(T),
+    /// Anything but this number. (`!N` or `!=N`)
+    Not(T),
+    /// Any number from the first to the second, inclusive. (`LO-HI`)
+    Range(T, T),
+    /// Any number in any of these inclusive ranges. A lone number is a range
+    /// that starts and ends with it. (`A,B,LO-HI,...`)
+    List(Vec<(T, T)>),
+    /// This number, or anything bigger. (`>=N`)
+    AtLeast(T),
+    /// This number, or anything smaller. (`<=N`)
+    AtMost(T),
+}
+
+impl<T> From<Option<T>> for FieldMatcher<T> {
+    /// `None` is `Any`, and `Some(N)` is `Is(N)`.
+    fn from(x: Option<T>) -> FieldMatcher<T> {
+        match x {
+            None => FieldMatcher::Any,
+            Some(x) => FieldMatcher::Is(x),
+        }
+    }
+}
+
+/// Parses the text after `field=` in a `type=`, `code=`, or `value=`
+/// condition. On failure, returns a message saying what's wrong.
+pub(crate) fn parse_matcher<T>(field: &str, text: &str)
+                               -> Result<FieldMatcher<T>, String>
+where T: Bounded + Ord + Copy {
+    let number = |x: &str| x.parse().map_err(|_| bad_number::<T>(field, x));
+    // a '-' that isn't a minus sign, as in "-5--1"
+    let range = |x: &str| match x.char_indices().skip(1)
+        .find(|&(_, c)| c == '-') {
+            Some((i, _)) => {
+                let (lo, hi) = (number(&x[..i])?, number(&x[i+1..])?);
+                if lo > hi {
+                    return Err(format!("the range {:?} for \"{}=\" is \
+                                        backwards", x, field))
+                }
+                Ok((lo, hi))
+            },
+            None => number(x).map(|x| (x, x)),
+        };
+    if text == "*" { return Ok(FieldMatcher::Any) }
+    if let Some(x) = text.strip_prefix("!=").or(text.strip_prefix('!')) {
+        return number(x).map(FieldMatcher::Not)
+    }
+    if let Some(x) = text.strip_prefix(">=") {
+        return number(x).map(FieldMatcher::AtLeast)
+    }
+    if let Some(x) = text.strip_prefix("<=") {
+        return number(x).map(FieldMatcher::AtMost)
+    }
+    if text.contains(',') {
+        return text.split(',').map(range).collect::<Result<_, _>>()
+            .map(FieldMatcher::List)
+    }
+    match range(text)? {
+        (lo, hi) if lo == hi => Ok(FieldMatcher::Is(lo)),
+        (lo, hi) => Ok(FieldMatcher::Range(lo, hi)),
+    }
+}
+
+impl<T: PartialEq + Copy> FieldMatcher<T> {
+    /// The particular numbers this condition names, not counting any ranges,
+    /// like the `30` in `code=30`, or the `1` and `4` in `code=1,2-3,4`.
+    pub fn numbers(&self) -> Vec<T> {
+        match self {
+            FieldMatcher::Is(x) => vec![*x],
+            FieldMatcher::List(x) => x.iter()
+                .filter(|(lo, hi)| lo == hi).map(|(x, _)| *x).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<T: std::fmt::Display + PartialEq> std::fmt::Display for FieldMatcher<T> {
+    /// Formats the condition the way it would appear after `field=`.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FieldMatcher::Any => write!(f, "*"),
+            FieldMatcher::Is(x) => write!(f, "{}", x),
+            FieldMatcher::Not(x) => write!(f, "!{}", x),
+            FieldMatcher::Range(lo, hi) => write!(f, "{}-{}", lo, hi),
+            FieldMatcher::List(x) => {
+                for (i, (lo, hi)) in x.iter().enumerate() {
+                    if i != 0 { write!(f, ",")? }
+                    if lo == hi { write!(f, "{}", lo)? }
+                    else { write!(f, "{}-{}", lo, hi)? }
+                }
+                Ok(())
+            },
+            FieldMatcher::AtLeast(x) => write!(f, ">={}", x),
+            FieldMatcher::AtMost(x) => write!(f, "<={}", x),
+        }
+    }
+}
+
+/// Contains a parsed "if ... then ..." line, describing a command to execute
+/// if a certain event is seen. (Or an "unless ... then ..." line, describing a
+/// command to execute if anything *but* a certain event is seen.)
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct InputMatch {
+    /// If true, this is an "unless" line, and the conditions below (apart from
+    /// `when`) must *not* all match for the match to fire.
+    pub negated: bool,
+    /// If not `None`, run this command only if the event came from the device
+    /// with this label (or, if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// Run this command only if the event type meets this condition.
+    pub wants_type: FieldMatcher<u16>,
+    /// Run this command only if the event code meets this condition.
+    pub wants_code: FieldMatcher<u16>,
+    /// Run this command only if the event value meets this condition.
+    /// (`value=nonzero` is `!0`, `value=positive` is `>=1`,
+    /// `value=negative` is `<=-1`, and `value=repeat` is `2`, the value of a
+    /// key that's been held long enough to repeat.)
+    pub wants_value: FieldMatcher<i32>,
+    /// If not `None`, run this command only if this multitouch slot is the
+    /// currently selected one (see `ABS_MT_SLOT`).
+    pub wants_slot: Option<i32>,
+    /// If not `None`, the high and low thresholds: this match only fires when
+    /// the value rises to the high one or beyond, and then not again until
+    /// it has fallen to the low one or below. (`above=N below=M`, see
+    /// [`TriggerState`](struct.TriggerState.html))
+    pub hysteresis: Option<(i32, i32)>,
+    /// Run this command only if each of these keys is held down, on the same
+    /// device as the event. (`with-held=CODE`, which may be given more than
+    /// once)
+    pub with_held: Vec<u16>,
+    /// Run this command only if each of these latches is on. (See
+    /// [`Latch`](struct.Latch.html). `with-latch=NAME`, which may be given
+    /// more than once)
+    pub with_latch: Vec<String>,
+    /// If not `None`, this match only fires while this profile is the active
+    /// one. (`profile=NAME`, or being inside a `profile NAME { ... }` block)
+    pub profile: Option<String>,
+    /// If not `None`, this match fires at most this many times, and is
+    /// skipped after that, as if it didn't match. (`max=N`)
+    pub max: Option<u32>,
+    /// If not `None`, this match only looks at key presses, and instead of
+    /// firing right away, it fires once the key has been held down this
+    /// long, if it hasn't been released by then. Later matches get a chance
+    /// at the press in the meantime. (`held=MS`)
+    pub held: Option<Duration>,
+    /// If not `None`, this match's command runs this long after the event
+    /// that matched, instead of right away. (`delay=MS`)
+    pub delay: Option<Duration>,
+    /// If true, a delayed command is called off if, before it runs, the same
+    /// key is released (or, for other events, the same type and code goes
+    /// back to 0) on the same device. (`cancel-on-release`)
+    pub cancel_on_release: bool,
+    /// Matches with a higher priority are checked before ones with a lower
+    /// priority, wherever they were given. (`priority=N`, default 0)
+    pub priority: i32,
+    /// How to run the command.
+    pub options: CommandOptions,
+    /// If not `None`, this shell command is run when everything else matched,
+    /// and this match only fires if the command succeeds. If it fails, later
+    /// matches get a chance instead.
+    pub when: Option<String>,
+    /// If not `None`, this match only fires during this time of day, by the
+    /// local clock. At other times, later matches get a chance instead.
+    /// (`between=HH:MM-HH:MM`)
+    pub between: Option<TimeWindow>,
+    /// If true (`observe`), later matches still get a chance after this one
+    /// fires. If false (`consume`, the default), this one is the last.
+    pub observe: bool,
+    /// If all of the above fields matched (or were `None`), do this.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+/// Contains a parsed "map ... to=LO-HI then ..." line, describing a command to
+/// execute whenever an absolute axis moves far enough to change its value,
+/// scaled to a given range.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct AxisMap {
+    /// If not `None`, only follow the axis on the device with this label (or,
+    /// if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The code of the absolute axis to follow.
+    pub wants_code: u16,
+    /// The range to scale the axis's value into. The device's minimum maps to
+    /// the first value, and its maximum to the second.
+    pub to: (i32, i32),
+    /// How to run the command.
+    pub options: CommandOptions,
+    /// What to do when the scaled value changes.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for AxisMap {
+    /// Formats the map the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "map")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " type=3 code={} to={}-{}", self.wants_code, self.to.0,
+               self.to.1)?;
+        write!(f, "{} {}", self.options, self.action)
+    }
+}
+
+/// Contains a parsed "axis ... threshold=N then-pos: ... then-neg: ..."
+/// line, describing a command to execute when an absolute axis (like one
+/// direction of a stick) is pushed far enough one way, and another for the
+/// other way. Neither fires again until the axis has come most of the way
+/// back to the center.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct AxisMatch {
+    /// If not `None`, only follow the axis on the device with this label (or,
+    /// if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The code of the absolute axis to follow.
+    pub wants_code: u16,
+    /// The value the axis rests at. (`center=N`, default 0)
+    pub center: i32,
+    /// How far the axis must move from the center, either way, to fire.
+    /// Once it has, it must come back to within half of this before it can
+    /// fire again.
+    pub threshold: i32,
+    /// How to run the commands.
+    pub options: CommandOptions,
+    /// The shell command to run when the axis moves far enough above the
+    /// center. (`then-pos:`)
+    pub positive: Option<String>,
+    /// The shell command to run when the axis moves far enough below the
+    /// center. (`then-neg:`)
+    pub negative: Option<String>,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for AxisMatch {
+    /// Formats the axis the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "axis")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " type=3 code={}", self.wants_code)?;
+        if self.center != 0 { write!(f, " center={}", self.center)? }
+        write!(f, " threshold={}{}", self.threshold, self.options)?;
+        if let Some(x) = self.positive.as_ref() {
+            write!(f, " then-pos: {}", x)?
+        }
+        if let Some(x) = self.negative.as_ref() {
+            write!(f, " then-neg: {}", x)?
+        }
+        Ok(())
+    }
+}
+
+/// Contains a parsed "buckets ... [COND: ...] ... [else: ...]" line,
+/// describing a command for each range of values an absolute axis can be in
+/// (like the positions of a three-way switch), to execute when the axis
+/// moves from one range to another.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct BucketMatch {
+    /// If not `None`, only follow the axis on the device with this label (or,
+    /// if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The code of the absolute axis to follow.
+    pub wants_code: u16,
+    /// How far into another bucket the axis has to move before it counts as
+    /// being in that one, so that an axis resting on the edge between two
+    /// buckets doesn't flick back and forth. (`hysteresis=N`, default 0)
+    pub hysteresis: i32,
+    /// How to run the commands.
+    pub options: CommandOptions,
+    /// The conditions on the value, each with the shell command to run when
+    /// the value moves into it. The first one the value meets is the bucket
+    /// it's in.
+    pub buckets: Vec<(FieldMatcher<i32>, String)>,
+    /// The shell command to run when the value moves to somewhere none of the
+    /// conditions cover. (`[else: ...]`)
+    pub otherwise: Option<String>,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for BucketMatch {
+    /// Formats the line the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "buckets")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " type=3 code={}", self.wants_code)?;
+        if self.hysteresis != 0 {
+            write!(f, " hysteresis={}", self.hysteresis)?
+        }
+        write!(f, "{}", self.options)?;
+        for (condition, command) in self.buckets.iter() {
+            write!(f, " [{}: {}]", condition, command)?
+        }
+        if let Some(x) = self.otherwise.as_ref() {
+            write!(f, " [else: {}]", x)?
+        }
+        Ok(())
+    }
+}
+
+/// Parses the condition at the start of a bucket, like the `<-16000` in
+/// `[<-16000: ...]`. Besides everything `value=` takes, `<N` and `>N` work.
+/// `else` is `None`.
+pub(crate) fn parse_bucket_condition(text: &str)
+    -> Result<Option<FieldMatcher<i32>>, String> {
+    if text == "else" { return Ok(None) }
+    let strict = |x: &str, step: i32| {
+        x.parse::<i32>().ok().and_then(|x| x.checked_add(step))
+            .ok_or_else(|| format!("invalid bucket {:?}", text))
+    };
+    if !text.starts_with("<=") && !text.starts_with(">=") {
+        if let Some(x) = text.strip_prefix('<') {
+            return strict(x, -1).map(|x| Some(FieldMatcher::AtMost(x)))
+        }
+        if let Some(x) = text.strip_prefix('>') {
+            return strict(x, 1).map(|x| Some(FieldMatcher::AtLeast(x)))
+        }
+    }
+    parse_matcher("value", text).map(Some)
+        .map_err(|_| format!("invalid bucket {:?}", text))
+}
+
+/// Finds where each bucket starts in the text of a "buckets" line: every `[`
+/// at the start of a word that's followed by a condition and a colon.
+fn bucket_starts(line: &str) -> Vec<usize> {
+    line.char_indices().filter(|&(i, c)| {
+        c == '[' && line[..i].chars().next_back()
+            .map(char::is_whitespace).unwrap_or(true)
+            && line[i+1..].find(':').map(|colon| {
+                let condition = &line[i+1..i+1+colon];
+                !condition.contains(char::is_whitespace)
+                    && parse_bucket_condition(condition).is_ok()
+            }).unwrap_or(false)
+    }).map(|(i, _)| i).collect()
+}
+
+/// The directions a hat (a d-pad that reports itself as two absolute axes)
+/// can point in, with the X and Y values that mean each of them. Negative Y
+/// is up.
+pub(crate) const HAT_DIRECTIONS: [(&str, (i32, i32)); 9] = [
+    ("center", (0, 0)),
+    ("up", (0, -1)),
+    ("down", (0, 1)),
+    ("left", (-1, 0)),
+    ("right", (1, 0)),
+    ("up-left", (-1, -1)),
+    ("up-right", (1, -1)),
+    ("down-left", (-1, 1)),
+    ("down-right", (1, 1)),
+];
+
+/// Contains a parsed "hat ... DIRECTION then ..." line, describing a command
+/// to execute when a hat (a d-pad that reports itself as a pair of absolute
+/// axes, like `ABS_HAT0X` and `ABS_HAT0Y`) starts pointing in a direction.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct HatMatch {
+    /// If not `None`, only follow the hat on the device with this label (or,
+    /// if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The code of the hat's X axis.
+    pub code_x: u16,
+    /// The code of the hat's Y axis.
+    pub code_y: u16,
+    /// The direction to wait for, as X and Y values (each -1, 0, or 1).
+    /// Negative Y is up.
+    pub direction: (i32, i32),
+    /// How to run the command.
+    pub options: CommandOptions,
+    /// What to do when the hat starts pointing that way.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl HatMatch {
+    /// Returns the name of the direction this line waits for, like
+    /// `"up-left"`.
+    pub fn direction_name(&self) -> &'static str {
+        HAT_DIRECTIONS.iter().find(|x| x.1 == self.direction)
+            .map(|x| x.0).unwrap_or("?")
+    }
+}
+
+impl std::fmt::Display for HatMatch {
+    /// Formats the hat the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "hat")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " type=3 codeX={} codeY={} {}", self.code_x, self.code_y,
+               self.direction_name())?;
+        write!(f, "{} {}", self.options, self.action)
+    }
+}
+
+/// Contains a parsed "zone ... x=LO-HI y=LO-HI then ..." line, describing a
+/// command to execute when a touch lands in a certain part of a touch
+/// surface.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct ZoneMatch {
+    /// If not `None`, only follow touches on the device with this label (or,
+    /// if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The code of the absolute axis that gives a touch's X position.
+    pub code_x: u16,
+    /// The code of the absolute axis that gives a touch's Y position.
+    pub code_y: u16,
+    /// The part of the X axis the zone covers, in percent of the axis's
+    /// range.
+    pub x: (i32, i32),
+    /// The part of the Y axis the zone covers, in percent of the axis's
+    /// range.
+    pub y: (i32, i32),
+    /// How to run the command.
+    pub options: CommandOptions,
+    /// What to do when a touch lands in the zone.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for ZoneMatch {
+    /// Formats the zone the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "zone")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " type=3 codeX={} codeY={} x={}-{} y={}-{}", self.code_x,
+               self.code_y, self.x.0, self.x.1, self.y.0, self.y.1)?;
+        write!(f, "{} {}", self.options, self.action)
+    }
+}
+
+/// Contains a parsed "region ... x>=N y>=M then ..." line, describing a
+/// command to execute when a pair of absolute axes (like the two axes of a
+/// stick) moves into a certain region together.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct RegionMatch {
+    /// If not `None`, only follow the axes on the device with this label
+    /// (or, if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The code of the absolute axis that gives the X position.
+    pub code_x: u16,
+    /// The code of the absolute axis that gives the Y position.
+    pub code_y: u16,
+    /// The lowest and highest X values in the region (`x>=` and `x<=`), if
+    /// it's limited in that direction.
+    pub x: (Option<i32>, Option<i32>),
+    /// The lowest and highest Y values in the region (`y>=` and `y<=`), if
+    /// it's limited in that direction.
+    pub y: (Option<i32>, Option<i32>),
+    /// How to run the command.
+    pub options: CommandOptions,
+    /// What to do when the axes move into the region.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for RegionMatch {
+    /// Formats the region the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "region")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " type=3 codeX={} codeY={}", self.code_x, self.code_y)?;
+        for (axis, (lo, hi)) in [("x", self.x), ("y", self.y)].iter() {
+            if let Some(lo) = lo { write!(f, " {}>={}", axis, lo)? }
+            if let Some(hi) = hi { write!(f, " {}<={}", axis, hi)? }
+        }
+        write!(f, "{} {}", self.options, self.action)
+    }
+}
+
+/// Contains a parsed "sequence ... within=MS then ..." line, describing a
+/// command to execute when certain keys are pressed one after another.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct SequenceMatch {
+    /// If not `None`, only count presses on the device with this label (or,
+    /// if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The codes of the keys to press, in order.
+    pub codes: Vec<u16>,
+    /// How long, from the first press, there is to finish the sequence.
+    pub within: Duration,
+    /// How to run the command.
+    pub options: CommandOptions,
+    /// What to do when the sequence is finished.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for SequenceMatch {
+    /// Formats the sequence the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "sequence")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        for code in self.codes.iter() {
+            write!(f, " code={}", code)?;
+        }
+        write!(f, " within={}", self.within.as_millis())?;
+        write!(f, "{} {}", self.options, self.action)
+    }
+}
+
+/// Contains a parsed "combo ... then ..." line, describing a command to
+/// execute when several keys, possibly on different devices, are held down
+/// at once.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct ComboMatch {
+    /// The keys that must all be down, each with the label (or, if it has no
+    /// label, the path) of the device it must be down on, or `None` if any
+    /// device will do.
+    pub keys: Vec<(Option<String>, u16)>,
+    /// How to run the command.
+    pub options: CommandOptions,
+    /// What to do when the last of the keys is pressed.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for ComboMatch {
+    /// Formats the combo the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "combo")?;
+        let mut last_device = None;
+        for (device, code) in self.keys.iter() {
+            if device.as_ref() != last_device {
+                match device.as_ref() {
+                    Some(x) => write!(f, " dev={}", quote_word(x))?,
+                    None => write!(f, " dev=*")?,
+                }
+                last_device = device.as_ref();
+            }
+            write!(f, " code={}", code)?;
+        }
+        write!(f, "{} {}", self.options, self.action)
+    }
+}
+
+/// Contains a parsed "while-held ... taps ... count=N then ..." line,
+/// describing a command to execute when one key is tapped a number of times
+/// while another is held down.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct TapMatch {
+    /// If not `None`, only count keys on the device with this label (or, if
+    /// it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The code of the key to hold down.
+    pub held_code: u16,
+    /// The code of the key to tap.
+    pub tap_code: u16,
+    /// How many taps it takes.
+    pub count: u32,
+    /// How to run the command.
+    pub options: CommandOptions,
+    /// What to do on the last tap.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for TapMatch {
+    /// Formats the line the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "while-held")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " code={} taps code={} count={}", self.held_code,
+               self.tap_code, self.count)?;
+        write!(f, "{} {}", self.options, self.action)
+    }
+}
+
+/// Contains a parsed "calibrate ..." line, correcting the values of an
+/// absolute axis whose hardware doesn't center where it should, or reports a
+/// different range than it really has.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Calibration {
+    /// If not `None`, only correct the axis on the device with this label (or,
+    /// if it has no label, this path).
+    pub wants_device: Option<String>,
+    /// The code of the absolute axis to correct.
+    pub wants_code: u16,
+    /// The value the axis really rests at.
+    pub center: i32,
+    /// The smallest value the axis really reaches.
+    pub min: i32,
+    /// The largest value the axis really reaches.
+    pub max: i32,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for Calibration {
+    /// Formats the calibration the way it would appear in a configuration
+    /// file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "calibrate")?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " type=3 code={} center={} min={} max={}", self.wants_code,
+               self.center, self.min, self.max)
+    }
+}
+
+/// Contains a parsed "latch ..." line: a key that, instead of having to be
+/// held down, turns a latch on when it's pressed, and off when it's pressed
+/// again. "if" lines with `with-latch=NAME` only match while the latch is
+/// on.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Latch {
+    /// The name that `with-latch=` refers to. Several "latch" lines may share
+    /// a name, in which case any of their keys turns it on or off.
+    pub name: String,
+    /// If not `None`, only presses on the device with this label (or, if it
+    /// has no label, this path) count.
+    pub wants_device: Option<String>,
+    /// The code of the key.
+    pub wants_code: u16,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for Latch {
+    /// Formats the latch the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "latch {}", quote_word(&self.name))?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " code={}", self.wants_code)
+    }
+}
+
+/// Contains a parsed "switch-profile ..." line: a key that, when it's
+/// pressed, makes the named profile the active one, so that only the "if"
+/// lines in that profile (and those in no profile) can fire.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct ProfileSwitch {
+    /// The name of the profile to switch to.
+    pub name: String,
+    /// If not `None`, only presses on the device with this label (or, if it
+    /// has no label, this path) count.
+    pub wants_device: Option<String>,
+    /// The code of the key.
+    pub wants_code: u16,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for ProfileSwitch {
+    /// Formats the switch the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "switch-profile {}", quote_word(&self.name))?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        write!(f, " code={}", self.wants_code)
+    }
+}
+
+/// A time of day, from one time until another, which may be on the next day
+/// (as in `21:00-06:00`). Includes the first minute, but not the last.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct TimeWindow {
+    /// When the window starts, in minutes since midnight.
+    pub start: u32,
+    /// When the window ends, in minutes since midnight.
+    pub end: u32,
+}
+
+impl TimeWindow {
+    /// Returns true if the given time, in minutes since midnight, falls in
+    /// the window.
+    pub fn contains(&self, minutes: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minutes && minutes < self.end
+        }
+        else {
+            // past midnight
+            minutes >= self.start || minutes < self.end
+        }
+    }
+}
+
+impl std::str::FromStr for TimeWindow {
+    type Err = ();
+    /// Parses a window like `21:00-06:00`.
+    fn from_str(s: &str) -> Result<TimeWindow, ()> {
+        let time = |text: &str| {
+            let (hours, minutes) = text.split_once(':')?;
+            let hours: u32 = hours.parse().ok()?;
+            let minutes: u32 = minutes.parse().ok()?;
+            if hours >= 24 || minutes >= 60 || !text.is_ascii() { return None }
+            Some(hours * 60 + minutes)
+        };
+        let (start, end) = s.split_once('-').ok_or(())?;
+        match (time(start), time(end)) {
+            (Some(start), Some(end)) if start != end => {
+                Ok(TimeWindow { start, end })
+            },
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    /// Formats the window the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60,
+               self.end / 60, self.end % 60)
+    }
+}
+
+/// Contains a parsed "min-interval ..." line, keeping commands from running
+/// too close together, whichever rules they're for.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct MinInterval {
+    /// How long after one command finishes the next one may start.
+    pub interval: Duration,
+    /// If true (`drop`), a command that comes too soon is skipped. If false
+    /// (`delay`, the default), we wait until it's allowed.
+    pub drop: bool,
+}
+
+impl std::fmt::Display for MinInterval {
+    /// Formats the directive the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "min-interval {} {}", self.interval.as_millis(),
+               if self.drop { "drop" } else { "delay" })
+    }
+}
+
+/// Contains a parsed "idle ... then ..." line, describing a command to execute
+/// once no events have been seen for a certain amount of time.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct IdleMatch {
+    /// How long there must be no events before this command runs.
+    pub after: Duration,
+    /// What to do once that much time has passed.
+    pub action: Action,
+    /// Where in the configuration the line came from.
+    pub location: Location,
+}
+
+impl std::fmt::Display for IdleMatch {
+    /// Formats the match the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "idle {} {}", self.after.as_secs_f64(), self.action)
+    }
+}
+
+/// The longest an "idle" line can wait, in seconds: a year.
+pub(crate) const MAX_IDLE_SECS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+/// Converts the seconds an "idle" line waits into a `Duration`, or returns
+/// `None` if it isn't a number from 0 to `MAX_IDLE_SECS`.
+pub(crate) fn idle_after(secs: f64) -> Option<Duration> {
+    // (NaN isn't in any range)
+    if !(0.0 ..= MAX_IDLE_SECS).contains(&secs) { return None }
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Whether to read events from virtual devices (such as those made with
+/// `uinput`), which may be carrying events that our own commands produced.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum VirtualPolicy {
+    /// Read from devices whether they're virtual or not.
+    #[default]
+    Include,
+    /// Don't read from virtual devices.
+    Exclude,
+    /// Read only from virtual devices.
+    Only,
+}
+
+impl std::str::FromStr for VirtualPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<VirtualPolicy, ()> {
+        match s {
+            "include" => Ok(VirtualPolicy::Include),
+            "exclude" => Ok(VirtualPolicy::Exclude),
+            "only" => Ok(VirtualPolicy::Only),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for VirtualPolicy {
+    /// Formats the policy the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            VirtualPolicy::Include => "include",
+            VirtualPolicy::Exclude => "exclude",
+            VirtualPolicy::Only => "only",
+        })
+    }
+}
+
+/// What to do with an event from a device that already has as many events
+/// waiting in the queue as its `queue=` allows.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum QueuePolicy {
+    /// Drop the new event.
+    #[default]
+    DropNewest,
+    /// Drop the device's oldest waiting event, to make room for the new one.
+    DropOldest,
+    /// Stop reading from the device until there's room. (The kernel keeps
+    /// a small buffer of its own, and reports when that overflows.)
+    Block,
+}
+
+impl std::str::FromStr for QueuePolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<QueuePolicy, ()> {
+        match s {
+            "drop-newest" => Ok(QueuePolicy::DropNewest),
+            "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            "block" => Ok(QueuePolicy::Block),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for QueuePolicy {
+    /// Formats the policy the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            QueuePolicy::DropNewest => "drop-newest",
+            QueuePolicy::DropOldest => "drop-oldest",
+            QueuePolicy::Block => "block",
+        })
+    }
+}
+
+/// A kind of device that a "dev type=" line can ask for.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum DeviceKind {
+    /// Any event device at all.
+    Any,
+    /// Has the A, Z, and space keys.
+    Keyboard,
+    /// Has `REL_X`, `REL_Y`, and `BTN_LEFT`.
+    Mouse,
+    /// Has `BTN_GAMEPAD` (also known as `BTN_SOUTH`).
+    Gamepad,
+    /// Has `BTN_JOYSTICK` (also known as `BTN_TRIGGER`) and `ABS_X`.
+    Joystick,
+}
+
+impl DeviceKind {
+    /// The event types and codes a device has to be able to send to be this
+    /// kind of device.
+    pub fn wants(self) -> &'static [(u16, u16)] {
+        match self {
+            DeviceKind::Any => &[],
+            DeviceKind::Keyboard => {
+                &[(EV_KEY, KEY_A), (EV_KEY, KEY_Z), (EV_KEY, KEY_SPACE)]
+            },
+            DeviceKind::Mouse => {
+                &[(EV_REL, REL_X), (EV_REL, REL_Y), (EV_KEY, BTN_LEFT)]
+            },
+            DeviceKind::Gamepad => &[(EV_KEY, BTN_GAMEPAD)],
+            DeviceKind::Joystick => {
+                &[(EV_KEY, BTN_JOYSTICK), (EV_ABS, ABS_X)]
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for DeviceKind {
+    type Err = ();
+    fn from_str(s: &str) -> Result<DeviceKind, ()> {
+        match s {
+            "any" => Ok(DeviceKind::Any),
+            "keyboard" => Ok(DeviceKind::Keyboard),
+            "mouse" => Ok(DeviceKind::Mouse),
+            "gamepad" => Ok(DeviceKind::Gamepad),
+            "joystick" => Ok(DeviceKind::Joystick),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceKind {
+    /// Formats the kind the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            DeviceKind::Any => "any",
+            DeviceKind::Keyboard => "keyboard",
+            DeviceKind::Mouse => "mouse",
+            DeviceKind::Gamepad => "gamepad",
+            DeviceKind::Joystick => "joystick",
+        })
+    }
+}
+
+/// Which devices a "dev" line without a path wants: every device in
+/// `/dev/input` that can send all of the events its kind wants, and all of
+/// the ones in `has`.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct DeviceQuery {
+    /// The kind of device, from `type=`.
+    pub kind: DeviceKind,
+    /// The event types and codes from `has=`.
+    pub has: Vec<(u16, u16)>,
+}
+
+impl DeviceQuery {
+    /// Returns true if a device that can send the events `can_send` says it
+    /// can is one this query wants.
+    pub fn wants(&self, can_send: impl Fn(u16, u16) -> bool) -> bool {
+        self.kind.wants().iter().chain(self.has.iter())
+            .all(|&(type_, code)| can_send(type_, code))
+    }
+}
+
+/// Formats an event type and code for `has=`: by name, if it has one, and
+/// otherwise as `TYPE/CODE`.
+pub(crate) fn format_capability(type_: u16, code: u16) -> String {
+    match code_name(type_, code) {
+        Some(x) => x.to_owned(),
+        None => format!("{}/{}", type_, code),
+    }
+}
+
+/// Parses one element of `has=`: a code's name, like `KEY_A`, or a type and
+/// code, like `1/30`. (Not `1:30`, since a colon would end the line's
+/// options.)
+pub(crate) fn parse_capability(text: &str) -> Option<(u16, u16)> {
+    if let Some(x) = code_by_name(text) { return Some(x) }
+    let (type_, code) = text.split_once('/')?;
+    Some((type_by_name(type_).or_else(|| type_.parse().ok())?,
+          code.parse().ok()?))
+}
+
+/// Contains a parsed "dev ..." line, naming a device to read events from.
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct DeviceSpec {
+    /// The label given to the device, if any.
+    pub label: Option<String>,
+    /// The path to the device. Empty for a line with a `query`, until the
+    /// devices it wants are found.
+    pub path: String,
+    /// If not `None`, this line picks devices by what they can do, instead of
+    /// by path. Once they're found, there's a copy of the line for each one,
+    /// with its `path` filled in.
+    pub query: Option<DeviceQuery>,
+    /// If not `None`, overrides the `--virtual` option for this device.
+    pub virtual_policy: Option<VirtualPolicy>,
+    /// Whether to grab the device, so that no other program sees its events
+    /// while we're reading from it.
+    pub grab: bool,
+    /// If not `None`, at most this many of the device's events can be
+    /// waiting in the queue at once, whatever `--queue` says.
+    pub queue: Option<usize>,
+    /// What to do with the device's events once `queue` of them are
+    /// waiting.
+    pub queue_policy: QueuePolicy,
+    /// If not `None`, the device's motion is passed on at most this many
+    /// times per second: relative axes' movements are added up in between,
+    /// and absolute axes only keep their latest values. Other events, like
+    /// key presses, are passed on right away.
+    pub max_rate: Option<u32>,
+    /// Where the device was named.
+    pub location: Location,
+}
+
+impl std::fmt::Display for DeviceSpec {
+    /// Formats the device the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "dev")?;
+        if self.label.is_none() && self.virtual_policy.is_none() && !self.grab
+        && self.query.is_none() && self.queue.is_none()
+        && self.max_rate.is_none()
+        && !self.path.starts_with('"')
+        && !self.path.contains(char::is_whitespace) {
+            return write!(f, " {}", self.path)
+        }
+        if let Some(x) = self.label.as_ref() {
+            write!(f, " {}", quote_word(x))?
+        }
+        if let Some(x) = self.virtual_policy {
+            write!(f, " virtual={}", x)?
+        }
+        if self.grab { write!(f, " grab=yes")? }
+        if let Some(x) = self.queue {
+            write!(f, " queue={}", x)?;
+            if self.queue_policy != QueuePolicy::default() {
+                write!(f, " policy={}", self.queue_policy)?
+            }
+        }
+        if let Some(x) = self.max_rate { write!(f, " maxrate={}", x)? }
+        if let Some(query) = self.query.as_ref() {
+            write!(f, " type={}", query.kind)?;
+            if !query.has.is_empty() {
+                let has: Vec<String> = query.has.iter()
+                    .map(|&(type_, code)| format_capability(type_, code))
+                    .collect();
+                write!(f, " has={}", has.join(","))?;
+            }
+            return Ok(())
+        }
+        write!(f, ": {}", self.path)
+    }
+}
+
+impl DeviceSpec {
+    /// Returns the name that `dev=` selectors match against: the label, if
+    /// there is one, or else the path.
+    pub fn name(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.path)
+    }
+    /// Returns true if this and `other` describe the same device, read the
+    /// same way. Where they were named doesn't matter.
+    pub fn same_device(&self, other: &DeviceSpec) -> bool {
+        self.label == other.label && self.path == other.path
+            && self.query == other.query
+            && self.virtual_policy == other.virtual_policy
+            && self.grab == other.grab
+            && self.queue == other.queue
+            && self.queue_policy == other.queue_policy
+            && self.max_rate == other.max_rate
+    }
+    /// Checks that this device's name doesn't collide with the name of any of
+    /// the given devices. Only unlabeled devices may share a name (which means
+    /// their paths are the same).
+    fn check_unique(&self, others: &[DeviceSpec]) -> Result<(), ConfigError> {
+        for other in others.iter() {
+            if other.name() == self.name()
+            && (self.label.is_some() || other.label.is_some()) {
+                return Err(ConfigError {
+                    file: self.location.file.clone(),
+                    line: Some(self.location.line),
+                    message: format!("device label {:?} is already used at \
+                                      {}", self.name(), other.location),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Everything that the configuration files told us to read events from, and
+/// what to do in response to those events (or the lack of them).
+#[derive(Clone,Debug,Default)]
+pub struct Config {
+    /// The "dev" directives, in the order they were given.
+    pub devices: Vec<DeviceSpec>,
+    /// The "if" directives, highest `priority` first, and otherwise in the
+    /// order they were given.
+    pub matches: Vec<InputMatch>,
+    /// The "map" directives, in the order they were given.
+    pub maps: Vec<AxisMap>,
+    /// The "axis" directives, in the order they were given.
+    pub axes: Vec<AxisMatch>,
+    /// The "buckets" directives, in the order they were given.
+    pub buckets: Vec<BucketMatch>,
+    /// The "sequence" directives, in the order they were given.
+    pub sequences: Vec<SequenceMatch>,
+    /// The "combo" directives, in the order they were given.
+    pub combos: Vec<ComboMatch>,
+    /// The "while-held" directives, in the order they were given.
+    pub taps: Vec<TapMatch>,
+    /// The "hat" directives, in the order they were given.
+    pub hats: Vec<HatMatch>,
+    /// The "zone" directives, in the order they were given.
+    pub zones: Vec<ZoneMatch>,
+    /// The "region" directives, in the order they were given.
+    pub regions: Vec<RegionMatch>,
+    /// The "calibrate" directives, in the order they were given.
+    pub calibrations: Vec<Calibration>,
+    /// The "latch" directives, in the order they were given.
+    pub latches: Vec<Latch>,
+    /// The names of the profiles, in the order they were first used. The
+    /// first one is active when we start.
+    pub profiles: Vec<String>,
+    /// The "switch-profile" directives, in the order they were given.
+    pub profile_switches: Vec<ProfileSwitch>,
+    /// The last "min-interval" directive, if any.
+    pub min_interval: Option<MinInterval>,
+    /// The "idle" directives, in the order they were given.
+    pub idle: Vec<IdleMatch>,
+    /// The actions from "on-activity" directives, done when an event arrives
+    /// after at least one "idle" action has been done.
+    pub on_activity: Vec<Action>,
+    /// The actions from "on-start" directives, done once, before the first
+    /// event is read.
+    pub on_start: Vec<Action>,
+    /// The actions from "on-stop" directives, done once, when we're about to
+    /// exit.
+    pub on_stop: Vec<Action>,
+}
+
+impl Config {
+    /// Adds everything from another `Config` to the end of this one, as if
+    /// it had all come later in the same file. Fails if the other `Config`
+    /// reuses one of our device labels.
+    pub fn append(&mut self, mut other: Config) -> Result<(), ConfigError> {
+        for device in other.devices.iter() {
+            device.check_unique(&self.devices)?;
+        }
+        self.devices.append(&mut other.devices);
+        self.matches.append(&mut other.matches);
+        self.maps.append(&mut other.maps);
+        self.axes.append(&mut other.axes);
+        self.buckets.append(&mut other.buckets);
+        self.sequences.append(&mut other.sequences);
+        self.combos.append(&mut other.combos);
+        self.taps.append(&mut other.taps);
+        self.hats.append(&mut other.hats);
+        self.zones.append(&mut other.zones);
+        self.regions.append(&mut other.regions);
+        self.calibrations.append(&mut other.calibrations);
+        self.latches.append(&mut other.latches);
+        for name in other.profiles {
+            if !self.profiles.contains(&name) { self.profiles.push(name) }
+        }
+        self.profile_switches.append(&mut other.profile_switches);
+        if other.min_interval.is_some() {
+            self.min_interval = other.min_interval;
+        }
+        self.idle.append(&mut other.idle);
+        self.on_activity.append(&mut other.on_activity);
+        self.on_start.append(&mut other.on_start);
+        self.on_stop.append(&mut other.on_stop);
+        self.sort_matches();
+        Ok(())
+    }
+    /// Returns how many of our devices `dev=name` selects, or `None` if we
+    /// can't tell, because a line without a label that picks devices by what
+    /// they can do hasn't had its devices found yet (and they could be
+    /// selected by path).
+    pub fn devices_named(&self, name: &str) -> Option<usize> {
+        if self.devices.iter().any(|x| {
+            x.label.is_none() && x.query.is_some() && x.path.is_empty()
+        }) {
+            return None
+        }
+        Some(self.devices.iter().filter(|x| x.name() == name).count())
+    }
+    /// Returns each line whose `dev=` (or, in a combo, one of whose
+    /// `dev=`s) selects none of our devices, as it would appear in a
+    /// configuration file, along with the name it wants. Such a line can
+    /// never fire, which usually means the name has a typo in it.
+    pub fn unknown_devices(&self) -> Vec<(String, String)> {
+        let mut wanted: Vec<(String, &Option<String>)> = Vec::new();
+        wanted.extend(self.matches.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.maps.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.axes.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.buckets.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.sequences.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        for combo in self.combos.iter() {
+            wanted.extend(combo.keys.iter()
+                          .map(|x| (combo.to_string(), &x.0)));
+        }
+        wanted.extend(self.taps.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.hats.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.zones.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.regions.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.calibrations.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.latches.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        wanted.extend(self.profile_switches.iter()
+                      .map(|x| (x.to_string(), &x.wants_device)));
+        let mut ret: Vec<(String, String)> = Vec::new();
+        for (line, name) in wanted {
+            let name = match name { Some(x) => x, None => continue };
+            if self.devices_named(name) == Some(0)
+            && !ret.iter().any(|x| x.0 == line && &x.1 == name) {
+                ret.push((line, name.clone()));
+            }
+        }
+        ret
+    }
+    /// Puts the "if" directives in the order they're checked in: highest
+    /// priority first, and otherwise in the order they were given.
+    fn sort_matches(&mut self) {
+        // a stable sort, so ties stay in order
+        self.matches.sort_by_key(|x| std::cmp::Reverse(x.priority));
+    }
+}
+
+/// Checks that a niceness adjustment is one we're allowed to make. Only root
+/// may lower niceness (raise priority).
+pub fn check_nice(nice: i32) -> Result<(), &'static str> {
+    if nice < 0 && unsafe { libc::geteuid() } != 0 {
+        Err("only root can use a negative niceness")
+    }
+    else {
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for InputMatch {
+    /// Formats the match the way it would appear in a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", if self.negated { "unless" } else { "if" })?;
+        if let Some(x) = self.wants_device.as_ref() {
+            write!(f, " dev={}", quote_word(x))?
+        }
+        if self.wants_type != FieldMatcher::Any {
+            write!(f, " type={}", self.wants_type)?
+        }
+        if self.wants_code != FieldMatcher::Any {
+            write!(f, " code={}", self.wants_code)?
+        }
+        match self.wants_value {
+            FieldMatcher::Any => (),
+            FieldMatcher::Not(0) => write!(f, " value=nonzero")?,
+            FieldMatcher::AtLeast(1) => write!(f, " value=positive")?,
+            FieldMatcher::AtMost(-1) => write!(f, " value=negative")?,
+            FieldMatcher::Is(2) if self.wants_type == FieldMatcher::Is(EV_KEY)
+                => write!(f, " value=repeat")?,
+            ref x => write!(f, " value={}", x)?,
+        }
+        if let Some(x) = self.wants_slot { write!(f, " slot={}", x)? }
+        if let Some((above, below)) = self.hysteresis {
+            write!(f, " above={} below={}", above, below)?
+        }
+        for x in self.with_held.iter() { write!(f, " with-held={}", x)? }
+        for x in self.with_latch.iter() {
+            write!(f, " with-latch={}", quote_word(x))?
+        }
+        if let Some(x) = self.profile.as_ref() {
+            write!(f, " profile={}", quote_word(x))?
+        }
+        if let Some(x) = self.max { write!(f, " max={}", x)? }
+        if let Some(x) = self.held { write!(f, " held={}", x.as_millis())? }
+        if let Some(x) = self.delay { write!(f, " delay={}", x.as_millis())? }
+        if self.cancel_on_release { write!(f, " cancel-on-release")? }
+        if self.priority != 0 { write!(f, " priority={}", self.priority)? }
+        write!(f, "{}", self.options)?;
+        if let Some(x) = self.when.as_ref() {
+            write!(f, " when={}", quote_word(x))?
+        }
+        if let Some(x) = self.between { write!(f, " between={}", x)? }
+        if self.observe { write!(f, " observe")? }
+        write!(f, " {}", self.action)
+    }
+}
+
+/// Parses the `LO-HI` part of a `to=` option. Either number may be negative.
+fn parse_range(text: &str) -> Option<(i32, i32)> {
+    // skip the first character, so that a leading minus sign isn't taken for
+    // the separator
+    let dash = text.char_indices().skip(1).find(|(_, c)| *c == '-')?.0;
+    Some((text[..dash].parse().ok()?, text[dash+1..].parse().ok()?))
+}
+
+/// A kind of number that events are made of, and the range it can hold.
+pub(crate) trait Bounded: std::str::FromStr {
+    /// The smallest value.
+    const MIN: i64;
+    /// The largest value.
+    const MAX: i64;
+}
+
+impl Bounded for u16 {
+    const MIN: i64 = u16::MIN as i64;
+    const MAX: i64 = u16::MAX as i64;
+}
+
+impl Bounded for i32 {
+    const MIN: i64 = i32::MIN as i64;
+    const MAX: i64 = i32::MAX as i64;
+}
+
+/// Explains why `value`, given for `field=`, couldn't be parsed as a `T`:
+/// either it's a number that's too big or too small, or it isn't a number at
+/// all.
+fn bad_number<T: Bounded>(field: &str, value: &str) -> String {
+    if value.parse::<i128>().is_ok() {
+        format!("value {:?} out of range for {} ({}..{})", value, field,
+                T::MIN, T::MAX)
+    }
+    else {
+        format!("invalid \"{}=\"", field)
+    }
+}
+
+/// Parses the value of a `type=`, `code=`, or `value=` condition into
+/// `wanted`, which is `Some` if the condition was already given (even as
+/// `*`).
+fn parse_field<T>(path: &str, line_number: usize, field: &str, value: &str,
+                  wanted: &mut Option<FieldMatcher<T>>)
+                  -> Result<(), ConfigError>
+where T: Bounded + std::str::FromStr + Ord + Copy {
+    if wanted.is_some() {
+        config_bail!(path, line_number, "multiple \"{}=\"s", field);
+    }
+    match parse_matcher(field, value) {
+        Ok(x) => *wanted = Some(x),
+        Err(x) => config_bail!(path, line_number, "{}", x),
+    }
+    Ok(())
+}
+
+/// Parses the value of a `code=` condition that names a group, like
+/// `code=@face`, into `wanted`. `name` is the part after the `@`.
+fn parse_group_ref(path: &str, line_number: usize, name: &str,
+                   groups: &HashMap<String, Vec<(u16, u16)>>,
+                   wanted: &mut Option<FieldMatcher<u16>>)
+                   -> Result<(), ConfigError> {
+    if wanted.is_some() {
+        config_bail!(path, line_number, "multiple \"code=\"s");
+    }
+    let codes = match groups.get(name) {
+        Some(x) => x,
+        None => config_bail!(path, line_number, "undefined group {:?}", name),
+    };
+    *wanted = Some(match codes[..] {
+        [(lo, hi)] if lo == hi => FieldMatcher::Is(lo),
+        [(lo, hi)] => FieldMatcher::Range(lo, hi),
+        _ => FieldMatcher::List(codes.clone()),
+    });
+    Ok(())
+}
+
+/// Parses the value of an `if` condition like `slot=`, into `wanted`. `*`
+/// means "anything", the same as leaving the condition out. The outer `Option`
+/// of `wanted` says whether the condition was given at all, even as `*`.
+fn parse_wanted<T: Bounded>(path: &str, line_number: usize,
+                                      field: &str, value: &str,
+                                      wanted: &mut Option<Option<T>>)
+                                      -> Result<(), ConfigError> {
+    if wanted.is_some() {
+        config_bail!(path, line_number, "multiple \"{}=\"s", field);
+    }
+    if value == "*" {
+        *wanted = Some(None);
+        return Ok(())
+    }
+    match value.parse() {
+        Ok(x) => *wanted = Some(Some(x)),
+        Err(_) => config_bail!(path, line_number, "{}",
+                               bad_number::<T>(field, value)),
+    }
+    Ok(())
+}
+
+/// The words that can introduce the action at the end of a directive.
+/// (`then-pos` and `then-neg` are only for "axis" lines.)
+const ACTION_KEYWORDS: &[&str] = &[
+    "then", "then-file", "exec", "then-dbus", "then-led", "then-pos",
+    "then-neg",
+];
+
+/// Parses the action at the end of a directive. `rest` starts with one of the
+/// `ACTION_KEYWORDS`, and should contain only one other element: the text
+/// after the colon.
+fn parse_action(path: &str, line_number: usize, directive: &str,
+                rest: &[&str]) -> Result<Action, ConfigError> {
+    if rest.is_empty() {
+        config_bail!(path, line_number,
+                     "\"{}\" needs a \"then\"", directive);
+    }
+    else if rest.len() != 2 {
+        config_bail!(path, line_number,
+                     "put a colon after \"{}\"", rest[0]);
+    }
+    match rest[0] {
+        "then" => Ok(Action::Shell(rest[1].to_owned())),
+        "then-file" => {
+            if !std::path::Path::new(rest[1]).is_file() {
+                config_bail!(path, line_number,
+                             "then-file: {:?} is not a file", rest[1]);
+            }
+            Ok(Action::File(rest[1].to_owned()))
+        },
+        "exec" => {
+            let argv = match split_words(rest[1], false) {
+                Ok((argv, _)) => argv,
+                Err(x) => config_bail!(path, line_number, "{}", x),
+            };
+            if argv.is_empty() {
+                config_bail!(path, line_number, "exec: needs a command");
+            }
+            Ok(Action::Exec(argv))
+        },
+        "then-dbus" => {
+            let words = match split_words(rest[1], false) {
+                Ok((words, _)) => words,
+                Err(x) => config_bail!(path, line_number, "{}", x),
+            };
+            match DBusCall::from_words(&words) {
+                Ok(x) => Ok(Action::DBus(x)),
+                Err(x) => config_bail!(path, line_number, "{}", x),
+            }
+        },
+        "then-led" => {
+            let words = match split_words(rest[1], false) {
+                Ok((words, _)) => words,
+                Err(x) => config_bail!(path, line_number, "{}", x),
+            };
+            match LedSetting::from_words(&words) {
+                Ok(x) => Ok(Action::Led(x)),
+                Err(x) => config_bail!(path, line_number, "{}", x),
+            }
+        },
+        x => config_bail!(path, line_number,
+                          "wanted \"then\", \"then-file\", \"exec\", \
+                           \"then-dbus\", or \"then-led\", saw {:?}", x),
+    }
+}
+
+/// The words that can introduce the action at the end of most directives:
+/// all of `ACTION_KEYWORDS` but `then-pos` and `then-neg`.
+const COMMAND_KEYWORDS: &[&str] = &[
+    "then", "then-file", "exec", "then-dbus", "then-led",
+];
+
+/// The command options (see [`CommandOptions`](struct.CommandOptions.html)),
+/// as they're named in errors.
+const OPTION_KEYS: &[&str] = &["nice=", "cwd=", "shell=", "expect-exit=",
+                               "env="];
+
+/// What can come between a directive and its action, for
+/// [`parse_keys`](fn.parse_keys.html).
+struct Grammar {
+    /// The directive, like "map".
+    directive: &'static str,
+    /// The words that take a value, each ending in what comes between it and
+    /// the value, like `"code="` or `"x>="`.
+    keys: &'static [&'static str],
+    /// Whether a word is one that stands on its own, like "taps".
+    flag: fn(&str) -> bool,
+    /// Anything else to mention in errors, after the keys, like "a
+    /// direction".
+    mentions: &'static [&'static str],
+    /// The words that start the action, and so end the keys. With none,
+    /// every word is one of the keys.
+    ends: &'static [&'static str],
+}
+
+/// The words of a directive that [`parse_keys`](fn.parse_keys.html) found.
+struct KeyWords<'a> {
+    /// The file the line is in, for errors.
+    path: &'a str,
+    /// The line's number, for errors.
+    line_number: usize,
+    /// What the directive can have.
+    grammar: &'static Grammar,
+    /// Whether the command options were allowed, for errors.
+    options: bool,
+    /// Each word, as its key and its value, like `("code=", "30")`, in the
+    /// order they were given. A flag's value is empty.
+    words: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> KeyWords<'a> {
+    /// Returns the values given for `key`, in order.
+    fn all(&self, key: &str) -> Vec<&'a str> {
+        self.words.iter().filter(|x| x.0 == key).map(|x| x.1).collect()
+    }
+    /// Returns the value given for `key`, if it was given. Giving it more
+    /// than once is an error.
+    fn one(&self, key: &str) -> Result<Option<&'a str>, ConfigError> {
+        match self.all(key)[..] {
+            [] => Ok(None),
+            [x] => Ok(Some(x)),
+            _ => config_bail!(self.path, self.line_number, "multiple \"{}\"s",
+                              key),
+        }
+    }
+    /// Like [`one`](#method.one), but the value is parsed by `parse`. If
+    /// that returns `None`, the error is the one `error` makes from the
+    /// value.
+    fn value<T>(&self, key: &str, parse: impl FnOnce(&str) -> Option<T>,
+                error: impl FnOnce(&str) -> String)
+                -> Result<Option<T>, ConfigError> {
+        match self.one(key)? {
+            None => Ok(None),
+            Some(x) => match parse(x) {
+                Some(x) => Ok(Some(x)),
+                None => config_bail!(self.path, self.line_number, "{}",
+                                     error(x)),
+            },
+        }
+    }
+    /// Parses `value`, which was given for `key`, as a number.
+    fn to_number<T: Bounded>(&self, key: &str, value: &str)
+                             -> Result<T, ConfigError> {
+        match value.parse() {
+            Ok(x) => Ok(x),
+            Err(_) => config_bail!(self.path, self.line_number, "{}",
+                                   bad_number::<T>(&key[..key.len()-1],
+                                                   value)),
+        }
+    }
+    /// Like [`one`](#method.one), for a number, like `code=`.
+    fn number<T: Bounded>(&self, key: &str) -> Result<Option<T>, ConfigError> {
+        self.one(key)?.map(|x| self.to_number(key, x)).transpose()
+    }
+    /// Like [`all`](#method.all), for numbers.
+    fn numbers<T: Bounded>(&self, key: &str) -> Result<Vec<T>, ConfigError> {
+        self.all(key).into_iter().map(|x| self.to_number(key, x)).collect()
+    }
+    /// Returns the `dev=`, if there is one.
+    fn device(&self) -> Result<Option<String>, ConfigError> {
+        Ok(self.one("dev=")?.map(str::to_owned))
+    }
+    /// Checks the `type=`s, for a directive that only works with absolute
+    /// axes, where the only type there is is 3.
+    fn absolute(&self) -> Result<(), ConfigError> {
+        if self.all("type=").iter().any(|x| *x != "3") {
+            config_bail!(self.path, self.line_number,
+                         "{} only works with absolute axes (\"type=3\")",
+                         self.grammar.directive);
+        }
+        Ok(())
+    }
+    /// Splits the words at the first `flag`, into those before it, and
+    /// those after it (or `None` if it wasn't given).
+    fn split(&self, flag: &str) -> (KeyWords<'a>, Option<KeyWords<'a>>) {
+        let part = |words: &[(&'a str, &'a str)]| KeyWords {
+            path: self.path, line_number: self.line_number,
+            grammar: self.grammar, options: self.options,
+            words: words.to_vec(),
+        };
+        match self.words.iter().position(|x| x.0 == flag) {
+            Some(i) => (part(&self.words[..i]),
+                        Some(part(&self.words[i+1..]))),
+            None => (part(&self.words), None),
+        }
+    }
+    /// Fails with an error saying that `word` isn't something the directive
+    /// can have, and what it can.
+    fn unexpected<T>(&self, word: &str) -> Result<T, ConfigError> {
+        let grammar = self.grammar;
+        let quote = |x: &&str| format!("\"{}\"", x);
+        let mut wanted: Vec<String> = grammar.keys.iter().map(quote)
+            .collect();
+        wanted.extend(grammar.mentions.iter().map(|x| x.to_string()));
+        if self.options { wanted.extend(OPTION_KEYS.iter().map(quote)) }
+        wanted.extend(grammar.ends.iter().map(quote));
+        let wanted = match wanted.split_last() {
+            Some((last, [])) => last.clone(),
+            Some((last, [first])) => format!("{} or {}", first, last),
+            Some((last, others)) => {
+                format!("{}, or {}", others.join(", "), last)
+            },
+            None => "nothing".to_owned(),
+        };
+        config_bail!(self.path, self.line_number,
+                     "wanted {} after \"{}\", saw {:?}", wanted,
+                     grammar.directive, word)
+    }
+}
+
+/// Sorts out the words after a directive, up to the first of
+/// `grammar.ends`, which is returned with the words after it. If `options`
+/// is given, the command options are parsed into it. Any other word that
+/// `grammar` doesn't have is an error, which says what it does have. Each key
+/// is only looked at, and checked, once the directive asks for it.
+fn parse_keys<'a, 'w>(path: &'a str, line_number: usize,
+                      grammar: &'static Grammar, words: &'w [&'a str],
+                      mut options: Option<&mut CommandOptions>)
+                      -> Result<(KeyWords<'a>, &'w [&'a str]), ConfigError> {
+    let mut found = KeyWords {
+        path, line_number, grammar, options: options.is_some(),
+        words: Vec::new(),
+    };
+    let mut rest = words;
+    while let Some((&el, after)) = rest.split_first() {
+        if grammar.ends.contains(&el) { break }
+        rest = after;
+        if let Some(options) = options.as_mut() {
+            if options.parse(path, line_number, el)? { continue }
+        }
+        if (grammar.flag)(el) {
+            found.words.push((el, ""));
+            continue
+        }
+        match grammar.keys.iter()
+        .find_map(|key| Some((*key, el.strip_prefix(key)?))) {
+            Some(x) => found.words.push(x),
+            None => return found.unexpected(el),
+        }
+    }
+    Ok((found, rest))
+}
+
+/// A `Grammar::flag` for directives without any flags.
+fn no_flags(_: &str) -> bool { false }
+
+/// What can come after "map".
+const MAP: Grammar = Grammar {
+    directive: "map", keys: &["dev=", "type=", "code=", "to="],
+    flag: no_flags, mentions: &[], ends: COMMAND_KEYWORDS,
+};
+
+/// What can come after "axis".
+const AXIS: Grammar = Grammar {
+    directive: "axis",
+    keys: &["dev=", "type=", "code=", "center=", "threshold="],
+    flag: no_flags, mentions: &[], ends: &["then-pos", "then-neg"],
+};
+
+/// What can come after "buckets", before the buckets themselves.
+const BUCKETS: Grammar = Grammar {
+    directive: "buckets", keys: &["dev=", "type=", "code=", "hysteresis="],
+    flag: no_flags, mentions: &["a bucket"], ends: &[],
+};
+
+/// What can come after "sequence".
+const SEQUENCE: Grammar = Grammar {
+    directive: "sequence", keys: &["dev=", "code=", "within="],
+    flag: no_flags, mentions: &[], ends: COMMAND_KEYWORDS,
+};
+
+/// What can come after "combo".
+const COMBO: Grammar = Grammar {
+    directive: "combo", keys: &["dev=", "code="],
+    flag: no_flags, mentions: &[], ends: COMMAND_KEYWORDS,
+};
+
+/// What can come after "while-held".
+const WHILE_HELD: Grammar = Grammar {
+    directive: "while-held", keys: &["dev=", "code=", "count="],
+    flag: |x| x == "taps", mentions: &["\"taps\""], ends: COMMAND_KEYWORDS,
+};
+
+/// What can come after "hat".
+const HAT: Grammar = Grammar {
+    directive: "hat", keys: &["dev=", "type=", "codeX=", "codeY="],
+    flag: |x| HAT_DIRECTIONS.iter().any(|y| y.0 == x),
+    mentions: &["a direction"], ends: COMMAND_KEYWORDS,
+};
+
+/// What can come after "zone".
+const ZONE: Grammar = Grammar {
+    directive: "zone",
+    keys: &["dev=", "type=", "codeX=", "codeY=", "x=", "y="],
+    flag: no_flags, mentions: &[], ends: COMMAND_KEYWORDS,
+};
+
+/// What can come after "region".
+const REGION: Grammar = Grammar {
+    directive: "region",
+    keys: &["dev=", "type=", "codeX=", "codeY=", "x>=", "x<=", "y>=",
+            "y<="],
+    flag: no_flags, mentions: &[], ends: COMMAND_KEYWORDS,
+};
+
+/// What can come after "calibrate".
+const CALIBRATE: Grammar = Grammar {
+    directive: "calibrate",
+    keys: &["dev=", "type=", "code=", "center=", "min=", "max="],
+    flag: no_flags, mentions: &[], ends: &[],
+};
+
+/// What can come after "latch" and its name.
+const LATCH: Grammar = Grammar {
+    directive: "latch", keys: &["dev=", "code="],
+    flag: no_flags, mentions: &[], ends: &[],
+};
+
+/// What can come after "switch-profile" and its name.
+const SWITCH_PROFILE: Grammar = Grammar {
+    directive: "switch-profile", keys: &["dev=", "code="],
+    flag: no_flags, mentions: &[], ends: &[],
+};
+
+/// Returns true if the colon at byte `i` of `text` is part of a time in an
+/// option (like `between=21:00-06:00`), rather than the end of the options:
+/// if it's between two digits, in a word with a `=` in it.
+fn is_time_colon(text: &str, i: usize) -> bool {
+    let before = &text[..i];
+    let word = before.rsplit(char::is_whitespace).next().unwrap_or("");
+    word.contains('=')
+        && before.ends_with(|c: char| c.is_ascii_digit())
+        && text[i+1..].starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Returns true if the backslash at byte `i` of `text`, which is inside
+/// double quotes, escapes the character after it: inside double quotes, `\"`
+/// is a double quote and `\\` a backslash. Any other backslash is just a
+/// backslash.
+fn is_escape(text: &str, i: usize) -> bool {
+    text[i+1..].starts_with(['"', '\\'])
+}
+
+/// Splits text into words. Whitespace separates words, except inside double
+/// quotes, which are removed (see `is_escape` for putting one in a word). If
+/// `stop_at_colon` is true, stops at the first colon that isn't inside double
+/// quotes (or part of a time, see `is_time_colon`), and also returns the text
+/// after it.
+fn split_words(text: &str, stop_at_colon: bool)
+               -> Result<(Vec<String>, Option<&str>), &'static str> {
+    let mut words = Vec::new();
+    // `Some` as soon as a word starts, even if it's an empty `""`
+    let mut word: Option<String> = None;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            word.get_or_insert_with(String::new).push(c);
+            escaped = false;
+        }
+        else if in_quotes {
+            if c == '\\' && is_escape(text, i) { escaped = true }
+            else if c == '"' { in_quotes = false }
+            else { word.get_or_insert_with(String::new).push(c) }
+        }
+        else if c == '"' {
+            in_quotes = true;
+            word.get_or_insert_with(String::new);
+        }
+        else if c == ':' && stop_at_colon && !is_time_colon(text, i) {
+            words.extend(word.take());
+            return Ok((words, Some(&text[i+1..])))
+        }
+        else if c.is_whitespace() {
+            words.extend(word.take());
+        }
+        else {
+            word.get_or_insert_with(String::new).push(c)
+        }
+    }
+    if in_quotes {
+        return Err("unterminated quote")
+    }
+    words.extend(word.take());
+    Ok((words, None))
+}
+
+/// Removes the comment, if there is one, from a line. A `#` starts a comment,
+/// except inside double quotes, and except after the colon of an action
+/// (`then:`, `exec:`, and so on): the command is always taken literally, all
+/// the way to the end of the line, so that `then: echo hi # there` echoes
+/// "hi # there".
+fn strip_comment(line: &str) -> &str {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped { escaped = false }
+        else if in_quotes && c == '\\' && is_escape(line, i) { escaped = true }
+        else if c == '"' { in_quotes = !in_quotes }
+        else if in_quotes {}
+        else if c == '#' { return &line[..i] }
+        else if c == ':' && !is_time_colon(line, i) {
+            let words = split_words(&line[..i], false).ok()
+                .map(|(words, _)| words).unwrap_or_default();
+            let literal = match (words.first(), words.last()) {
+                (_, Some(x)) if ACTION_KEYWORDS.contains(&x.as_str()) => true,
+                // so is a bucket's command, brackets and all
+                (Some(x), Some(y)) => x == "buckets" && y.starts_with('['),
+                _ => false,
+            };
+            if literal { return line }
+            // anything else after a colon (like a device's path) can still
+            // be followed by a comment
+            return match line[i..].find('#') {
+                Some(j) => &line[..i+j],
+                None => line,
+            }
+        }
+    }
+    line
+}
+
+/// Splits a line (with any comment already removed) into the words before the
+/// first colon, and the text after that colon (minus leading whitespace), if
+/// there is a colon. See `split_words`.
+fn split_line<'a>(path: &str, line_number: usize, line: &'a str)
+                  -> Result<(Vec<String>, Option<&'a str>), ConfigError> {
+    match split_words(line, true) {
+        Ok((words, colon)) => Ok((words, colon.map(str::trim_start))),
+        Err(x) => config_bail!(path, line_number, "{}", x),
+    }
+}
+
+/// Reads a configuration file. For every "dev" directive, opens the given
+/// device, or the devices it picks (see
+/// [`find_devices`](fn.find_devices.html)), with the default
+/// [`DeviceOptions`](struct.DeviceOptions.html), and spawns a reader thread
+/// that sends events via `event_sender`. Returns everything the file said,
+/// including the devices it named or found.
+pub fn load_config(path: &str, event_sender: &EventSender)
+                   -> Result<Config, ConfigError> {
+    let mut config = read_config(path)?;
+    config.devices = find_devices(&config.devices);
+    open_devices(&config.devices, event_sender, &DeviceOptions::default())?;
+    Ok(config)
+}
+
+/// Reads and parses a configuration file, without opening any devices. A
+/// file whose name ends in `.jsonl` is parsed with
+/// [`parse_json_lines`](fn.parse_json_lines.html) instead of the usual way.
+pub fn read_config(path: &str) -> Result<Config, ConfigError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|x| ConfigError {
+            file: path.to_owned(), line: None,
+            message: format!("reading the file: {}", x),
+        })?;
+    if path.ends_with(".jsonl") { parse_json_lines(path, &text) }
+    else { parse_config(path, &text) }
+}
+
+/// Parses the text of a configuration file, without opening any devices. (The
+/// only thing it looks at outside of `text` is whether `then-file` scripts
+/// exist.) `path` is only used in error messages.
+pub fn parse_config(path: &str, text: &str) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    // from "defaults" directives, for the lines after them
+    let mut defaults = CommandOptions::default();
+    // from "group" directives, for the lines after them
+    let mut groups: HashMap<String, Vec<(u16, u16)>> = HashMap::new();
+    // the "profile" block we're in, and the line it started on
+    let mut block: Option<(String, usize)> = None;
+    for (line_number, line) in text.lines().enumerate() {
+        let line_number = line_number + 1;
+        // trailing whitespace is never meaningful, and trailing carriage
+        // returns (from CRLF line endings) are downright confusing
+        let line = strip_comment(line).trim_end();
+        let (words, colon) = split_line(path, line_number, line)?;
+        let mut splat: Vec<&str> = words.iter().map(String::as_str).collect();
+        if let Some(colon) = colon { splat.push(colon) }
+        if splat.is_empty() { continue }
+        let location = Location { file: path.to_owned(), line: line_number };
+        if block.is_some() && !["if", "unless", "profile", "}"]
+                              .contains(&splat[0]) {
+            config_bail!(path, line_number,
+                         "only \"if\" and \"unless\" lines can go in a \
+                          \"profile\" block, saw {:?}", splat[0]);
+        }
+        match splat[0] {
+            "profile" => {
+                let name = match &splat[1..] {
+                    [name, "{"] if !name.contains('=') => name.to_string(),
+                    _ => config_bail!(path, line_number,
+                                      "profile wants a name, then \"{{\""),
+                };
+                if let Some((_, line)) = block.as_ref() {
+                    config_bail!(path, line_number,
+                                 "the \"profile\" block from line {} isn't \
+                                  closed yet, and they can't be nested",
+                                 line);
+                }
+                if !config.profiles.contains(&name) {
+                    config.profiles.push(name.clone());
+                }
+                block = Some((name, line_number));
+            },
+            "}" => {
+                if splat.len() > 1 || block.take().is_none() {
+                    config_bail!(path, line_number,
+                                 "\"}}\" without a \"profile\" block to \
+                                  close");
+                }
+            },
+            "dev" => {
+                let rest = line.trim_start()[3..].trim();
+                let mut label = None;
+                let mut virtual_policy = None;
+                let mut grab = None;
+                let mut kind = None;
+                let mut has = None;
+                let mut queue = None;
+                let mut queue_policy = None;
+                let mut max_rate = None;
+                // a line that picks devices by what they can do has no path
+                let finds = colon.is_none() && words[1..].iter().any(|x| {
+                    x.starts_with("type=") || x.starts_with("has=")
+                });
+                let dev_path = if !finds && !rest.is_empty()
+                    && !rest.starts_with('"')
+                    && !rest.contains(char::is_whitespace) {
+                        // a lone path, which may contain colons of its own
+                        // (e.g. /dev/input/by-path/...)
+                        rest
+                    }
+                else if colon.is_some() || finds {
+                    for word in words[1..].iter() {
+                        if let Some(el) = word.strip_prefix("virtual=") {
+                            if virtual_policy.is_some() {
+                                config_bail!(path, line_number,
+                                             "multiple \"virtual=\"s");
+                            }
+                            match el.parse() {
+                                Ok(x) => virtual_policy = Some(x),
+                                Err(_) => {
+                                    config_bail!(path, line_number,
+                                                 "\"virtual=\" wants \
+                                                  \"include\", \
+                                                  \"exclude\", or \"only\"");
+                                },
+                            }
+                        }
+                        else if let Some(el) = word.strip_prefix("grab=") {
+                            let x = match el {
+                                "yes" => true,
+                                "no" => false,
+                                _ => config_bail!(path, line_number,
+                                                  "\"grab=\" wants \"yes\" \
+                                                   or \"no\""),
+                            };
+                            if grab.replace(x).is_some() {
+                                config_bail!(path, line_number,
+                                             "multiple \"grab=\"s");
+                            }
+                        }
+                        else if let Some(el) = word.strip_prefix("queue=") {
+                            let x = match el.parse() {
+                                Ok(x) if x > 0 => x,
+                                _ => config_bail!(path, line_number,
+                                                  "\"queue=\" wants a \
+                                                   number of events, more \
+                                                   than zero"),
+                            };
+                            if queue.replace(x).is_some() {
+                                config_bail!(path, line_number,
+                                             "multiple \"queue=\"s");
+                            }
+                        }
+                        else if let Some(el) = word.strip_prefix("policy=") {
+                            let x = match el.parse() {
+                                Ok(x) => x,
+                                Err(_) => config_bail!(path, line_number,
+                                                       "\"policy=\" wants \
+                                                        \"drop-newest\", \
+                                                        \"drop-oldest\", or \
+                                                        \"block\""),
+                            };
+                            if queue_policy.replace(x).is_some() {
+                                config_bail!(path, line_number,
+                                             "multiple \"policy=\"s");
+                            }
+                        }
+                        else if let Some(el) = word.strip_prefix("maxrate=")
+                        {
+                            let x = match el.parse() {
+                                Ok(x) if x > 0 => x,
+                                _ => config_bail!(path, line_number,
+                                                  "\"maxrate=\" wants a \
+                                                   number of times per \
+                                                   second, more than \
+                                                   zero"),
+                            };
+                            if max_rate.replace(x).is_some() {
+                                config_bail!(path, line_number,
+                                             "multiple \"maxrate=\"s");
+                            }
+                        }
+                        else if let Some(el) = word.strip_prefix("type=") {
+                            let x = match el.parse() {
+                                Ok(x) => x,
+                                Err(_) => config_bail!(path, line_number,
+                                                       "\"type=\" wants \
+                                                        \"keyboard\", \
+                                                        \"mouse\", \
+                                                        \"gamepad\", \
+                                                        \"joystick\", or \
+                                                        \"any\""),
+                            };
+                            if kind.replace(x).is_some() {
+                                config_bail!(path, line_number,
+                                             "multiple \"type=\"s");
+                            }
+                        }
+                        else if let Some(el) = word.strip_prefix("has=") {
+                            let x = match el.split(',')
+                                .map(parse_capability).collect() {
+                                    Some(x) => x,
+                                    None => config_bail!(path, line_number,
+                                                         "\"has=\" wants \
+                                                          codes like KEY_A \
+                                                          or 1/30, \
+                                                          separated by \
+                                                          commas"),
+                                };
+                            if has.replace(x).is_some() {
+                                config_bail!(path, line_number,
+                                             "multiple \"has=\"s");
+                            }
+                        }
+                        else if word.contains('=') {
+                            config_bail!(path, line_number,
+                                         "unknown dev option {:?}", word);
+                        }
+                        else if label.is_some() {
+                            config_bail!(path, line_number,
+                                         "dev can only have one label");
+                        }
+                        else {
+                            label = Some(word.clone());
+                        }
+                    }
+                    match colon {
+                        Some(colon) => colon.trim_end(),
+                        None => "",
+                    }
+                }
+                else {
+                    config_bail!(path, line_number,
+                                 "dev wants a path, or a label, a colon, \
+                                  and a path");
+                };
+                let query = if finds {
+                    Some(DeviceQuery {
+                        kind: kind.unwrap_or(DeviceKind::Any),
+                        has: has.unwrap_or_default(),
+                    })
+                }
+                else if kind.is_some() || has.is_some() {
+                    config_bail!(path, line_number,
+                                 "a dev line with a path can't have \
+                                  \"type=\" or \"has=\"");
+                }
+                else { None };
+                if queue_policy.is_some() && queue.is_none() {
+                    config_bail!(path, line_number,
+                                 "\"policy=\" needs a \"queue=\"");
+                }
+                if label.as_deref() == Some("")
+                || (dev_path.is_empty() && query.is_none()) {
+                    config_bail!(path, line_number,
+                                 "dev wants a path, or a label, a colon, \
+                                  and a path");
+                }
+                let device = DeviceSpec {
+                    label, path: dev_path.to_owned(), query, virtual_policy,
+                    grab: grab.unwrap_or(false),
+                    queue, queue_policy: queue_policy.unwrap_or_default(),
+                    max_rate, location,
+                };
+                device.check_unique(&config.devices)?;
+                config.devices.push(device);
+            },
+            "if" | "unless" => {
+                let negated = splat[0] == "unless";
+                let mut rest = &splat[1..];
+                let mut wants_device = None;
+                let mut wants_type = None;
+                let mut wants_code = None;
+                let mut wants_value = None;
+                let mut wants_slot = None;
+                let mut above = None;
+                let mut below = None;
+                let mut with_held = Vec::new();
+                let mut with_latch = Vec::new();
+                let mut profile = block.as_ref().map(|x| x.0.clone());
+                let mut max = None;
+                let mut held = None;
+                let mut delay = None;
+                let mut cancel_on_release = false;
+                let mut priority = None;
+                let mut options = CommandOptions::default();
+                let mut when = None;
+                let mut between = None;
+                let mut observe = None;
+                while !rest.is_empty() && !ACTION_KEYWORDS.contains(&rest[0]) {
+                    let el = rest[0];
+                    rest = &rest[1..];
+                    if let Some(el) = el.strip_prefix("dev=") {
+                        if wants_device.is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"dev=\"s");
+                        }
+                        wants_device = Some(el.to_owned());
+                    }
+                    else if let Some(el) = el.strip_prefix("type=") {
+                        parse_field(path, line_number, "type", el,
+                                    &mut wants_type)?;
+                    }
+                    else if let Some(el) = el.strip_prefix("code=@") {
+                        parse_group_ref(path, line_number, el, &groups,
+                                        &mut wants_code)?;
+                    }
+                    else if let Some(el) = el.strip_prefix("code=") {
+                        parse_field(path, line_number, "code", el,
+                                    &mut wants_code)?;
+                    }
+                    else if let Some(el) = el.strip_prefix("value=") {
+                        let el = match el {
+                            "nonzero" => "!0",
+                            "positive" => ">=1",
+                            "negative" => "<=-1",
+                            "repeat" => "2",
+                            x => x,
+                        };
+                        parse_field(path, line_number, "value", el,
+                                    &mut wants_value)?;
+                    }
+                    else if let Some(el) = el.strip_prefix("slot=") {
+                        parse_wanted(path, line_number, "slot", el,
+                                     &mut wants_slot)?;
+                    }
+                    else if let Some(el) = el.strip_prefix("above=") {
+                        let x = match el.parse() {
+                            Ok(x) => x,
+                            Err(_) => config_bail!(path, line_number,
+                                                   "\"above=\" wants a \
+                                                    value"),
+                        };
+                        if above.replace(x).is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"above=\"s");
+                        }
+                    }
+                    else if let Some(el) = el.strip_prefix("below=") {
+                        let x = match el.parse() {
+                            Ok(x) => x,
+                            Err(_) => config_bail!(path, line_number,
+                                                   "\"below=\" wants a \
+                                                    value"),
+                        };
+                        if below.replace(x).is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"below=\"s");
+                        }
+                    }
+                    else if let Some(el) = el.strip_prefix("max=") {
+                        let x = match el.parse() {
+                            Ok(x) if x > 0 => x,
+                            _ => config_bail!(path, line_number,
+                                              "\"max=\" wants a positive \
+                                               number of times"),
+                        };
+                        if max.replace(x).is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"max=\"s");
+                        }
+                    }
+                    else if let Some(el) = el.strip_prefix("held=") {
+                        let x = match el.parse() {
+                            Ok(x) if x > 0 => Duration::from_millis(x),
+                            _ => config_bail!(path, line_number,
+                                              "\"held=\" wants a positive \
+                                               number of milliseconds"),
+                        };
+                        if held.replace(x).is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"held=\"s");
+                        }
+                    }
+                    else if let Some(el) = el.strip_prefix("delay=") {
+                        let x = match el.parse() {
+                            Ok(x) => Duration::from_millis(x),
+                            _ => config_bail!(path, line_number,
+                                              "\"delay=\" wants a number of \
+                                               milliseconds"),
+                        };
+                        if delay.replace(x).is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"delay=\"s");
+                        }
+                    }
+                    else if el == "cancel-on-release" {
+                        cancel_on_release = true;
+                    }
+                    else if let Some(el) = el.strip_prefix("priority=") {
+                        let x = match el.parse() {
+                            Ok(x) => x,
+                            Err(_) => config_bail!(path, line_number, "{}",
+                                                   bad_number::<i32>(
+                                                       "priority", el)),
+                        };
+                        if priority.replace(x).is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"priority=\"s");
+                        }
+                    }
+                    else if let Some(el) = el.strip_prefix("with-held=") {
+                        match el.parse() {
+                            Ok(x) => with_held.push(x),
+                            Err(_) => config_bail!(path, line_number, "{}",
+                                                   bad_number::<u16>(
+                                                       "with-held", el)),
+                        }
+                    }
+                    else if let Some(el) = el.strip_prefix("with-latch=") {
+                        if el.is_empty() {
+                            config_bail!(path, line_number,
+                                         "\"with-latch=\" needs the name of \
+                                          a latch");
+                        }
+                        with_latch.push(el.to_owned());
+                    }
+                    else if let Some(el) = el.strip_prefix("profile=") {
+                        if el.is_empty() {
+                            config_bail!(path, line_number,
+                                         "\"profile=\" needs the name of a \
+                                          profile");
+                        }
+                        if profile.replace(el.to_owned()).is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"profile=\"s (or one \
+                                          inside a \"profile\" block)");
+                        }
+                        if !config.profiles.iter().any(|x| x == el) {
+                            config.profiles.push(el.to_owned());
+                        }
+                    }
+                    else if options.parse(path, line_number, el)? {}
+                    else if let Some(el) = el.strip_prefix("when=") {
+                        if when.is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"when=\"s");
+                        }
+                        if el.trim().is_empty() {
+                            config_bail!(path, line_number,
+                                         "\"when=\" needs a command");
+                        }
+                        when = Some(el.to_owned());
+                    }
+                    else if let Some(el) = el.strip_prefix("between=") {
+                        let x = match el.parse() {
+                            Ok(x) => x,
+                            Err(()) => config_bail!(path, line_number,
+                                                    "\"between=\" wants two \
+                                                     different times, like \
+                                                     \"21:00-06:00\""),
+                        };
+                        if between.replace(x).is_some() {
+                            config_bail!(path, line_number,
+                                         "multiple \"between=\"s");
+                        }
+                    }
+                    else if el == "observe" || el == "consume" {
+                        if observe.replace(el == "observe").is_some() {
+                            config_bail!(path, line_number,
+                                         "\"observe\" and \"consume\" can \
+                                          only be given once");
+                        }
+                    }
+                    else {
+                        config_bail!(path, line_number,
+                                     "wanted \"dev=\", \"type=\", \
+                                      \"code=\", \"value=\", \"slot=\", \
+                                      \"above=\", \"below=\", \
+                                      \"with-held=\", \"with-latch=\", \
+                                      \"profile=\", \"max=\", \"held=\", \
+                                      \"delay=\", \"cancel-on-release\", \
+                                      \"priority=\", \
+                                      \"nice=\", \"cwd=\", \"shell=\", \
+                                      \"expect-exit=\", \"env=\", \"when=\", \
+                                      \"between=\", \
+                                      \"observe\", \"consume\", \"then\", \
+                                      \"then-file\", \"exec\", \
+                                      \"then-dbus\", or \"then-led\" \
+                                      after {:?}, saw {:?}",
+                                     splat[0], el);
+                    }
+                }
+                if held.is_some() && delay.is_some() {
+                    config_bail!(path, line_number,
+                                 "\"held=\" and \"delay=\" can't go \
+                                  together");
+                }
+                let hysteresis = match (above, below) {
+                    (None, None) => None,
+                    (Some(above), Some(below)) if below < above => {
+                        Some((above, below))
+                    },
+                    (Some(_), Some(_)) => {
+                        config_bail!(path, line_number,
+                                     "\"below=\" must be less than \
+                                      \"above=\"")
+                    },
+                    _ => config_bail!(path, line_number,
+                                      "\"above=\" and \"below=\" must be \
+                                       given together"),
+                };
+                if hysteresis.is_some() && (held.is_some() || negated) {
+                    config_bail!(path, line_number,
+                                 "\"above=\" can't go with \"held=\" or \
+                                  \"unless\"");
+                }
+                if cancel_on_release && delay.is_none() {
+                    config_bail!(path, line_number,
+                                 "\"cancel-on-release\" needs a \
+                                  \"delay=\"");
+                }
+                let action = parse_action(path, line_number, splat[0],
+                                          rest)?;
+                config.matches.push(InputMatch {
+                    negated, wants_device,
+                    wants_type: wants_type.unwrap_or_default(),
+                    wants_code: wants_code.unwrap_or_default(),
+                    wants_value: wants_value.unwrap_or_default(),
+                    wants_slot: wants_slot.flatten(), hysteresis, with_held,
+                    with_latch, profile,
+                    max, held, delay, cancel_on_release,
+                    priority: priority.unwrap_or(0),
+                    options: options.or(&defaults), when, between,
+                    observe: observe.unwrap_or(false), action,
+                    location,
+                })
+            },
+            "map" => {
+                let mut options = CommandOptions::default();
+                let (given, rest) = parse_keys(path, line_number, &MAP,
+                                               &splat[1..],
+                                               Some(&mut options))?;
+                given.absolute()?;
+                let wants_code = match given.number("code=")? {
+                    Some(x) => x,
+                    None => config_bail!(path, line_number,
+                                         "map needs a \"code=\""),
+                };
+                let to = given.value("to=", parse_range, |_| {
+                    "\"to=\" wants two numbers with a dash between, like \
+                     \"to=0-100\"".to_owned()
+                })?;
+                let to = match to {
+                    Some(x) => x,
+                    None => config_bail!(path, line_number,
+                                         "map needs a \"to=\""),
+                };
+                let action = parse_action(path, line_number, "map", rest)?;
+                config.maps.push(AxisMap {
+                    wants_device: given.device()?, wants_code, to,
+                    options: options.or(&defaults), action,
+                    location,
+                })
+            },
+            "axis" => {
+                let mut options = CommandOptions::default();
+                let (given, rest) = parse_keys(path, line_number, &AXIS,
+                                               &splat[1..],
+                                               Some(&mut options))?;
+                given.absolute()?;
+                let threshold = given.value("threshold=", |x| {
+                    x.parse().ok().filter(|x| *x > 0)
+                }, |_| "\"threshold=\" wants a positive number".to_owned())?;
+                let (wants_code, threshold)
+                = match (given.number("code=")?, threshold) {
+                    (Some(code), Some(threshold)) => (code, threshold),
+                    _ => config_bail!(path, line_number,
+                                      "axis needs a \"code=\" and a \
+                                       \"threshold=\""),
+                };
+                let (positive, negative) = match rest {
+                    [] => config_bail!(path, line_number,
+                                       "axis needs a \"then-pos:\", a \
+                                        \"then-neg:\", or both"),
+                    [keyword, text] => {
+                        // the positive command runs up to the negative one
+                        let (positive, negative) = match *keyword {
+                            "then-pos" => match text.find("then-neg:") {
+                                Some(i) => (Some(text[..i].trim_end()),
+                                            Some(text[i+9..].trim_start())),
+                                None => (Some(*text), None),
+                            },
+                            _ if text.contains("then-pos:") => {
+                                config_bail!(path, line_number,
+                                             "put \"then-pos:\" before \
+                                              \"then-neg:\"")
+                            },
+                            _ => (None, Some(*text)),
+                        };
+                        if positive == Some("") || negative == Some("") {
+                            config_bail!(path, line_number,
+                                         "\"then-pos:\" and \"then-neg:\" \
+                                          each need a command");
+                        }
+                        (positive.map(str::to_owned),
+                         negative.map(str::to_owned))
+                    },
+                    [keyword, ..] => config_bail!(path, line_number,
+                                                  "put a colon after \"{}\"",
+                                                  keyword),
+                };
+                config.axes.push(AxisMatch {
+                    wants_device: given.device()?, wants_code,
+                    center: given.number("center=")?.unwrap_or(0),
+                    threshold, options: options.or(&defaults), positive,
+                    negative,
+                    location,
+                })
+            },
+            "buckets" => {
+                // the buckets have colons of their own, so the words before
+                // them are split up again without them
+                let starts = bucket_starts(line);
+                let head = &line[..starts.first().cloned()
+                                 .unwrap_or(line.len())];
+                let (words, colon) = split_line(path, line_number, head)?;
+                if colon.is_some() || starts.is_empty() {
+                    config_bail!(path, line_number,
+                                 "buckets wants its commands in brackets, \
+                                  like \"[<0: ...] [>=0: ...]\"");
+                }
+                let mut options = CommandOptions::default();
+                let words: Vec<&str> = words.iter().skip(1)
+                    .map(String::as_str).collect();
+                let (given, _) = parse_keys(path, line_number, &BUCKETS,
+                                            &words, Some(&mut options))?;
+                given.absolute()?;
+                let wants_code = match given.number("code=")? {
+                    Some(x) => x,
+                    None => config_bail!(path, line_number,
+                                         "buckets needs a \"code=\""),
+                };
+                let hysteresis = given.value("hysteresis=", |x| {
+                    x.parse().ok().filter(|x| *x >= 0)
+                }, |_| "invalid \"hysteresis=\"".to_owned())?;
+                let mut buckets = Vec::new();
+                let mut otherwise = None;
+                let ends = starts.iter().skip(1).cloned()
+                    .chain(std::iter::once(line.len()));
+                for (&start, end) in starts.iter().zip(ends) {
+                    let bucket = line[start..end].trim_end();
+                    let colon = bucket.find(':').unwrap_or(0);
+                    let command = match bucket.strip_suffix(']') {
+                        Some(x) => x[colon+1..].trim(),
+                        None => config_bail!(path, line_number,
+                                             "{:?} is missing its \"]\"",
+                                             bucket),
+                    };
+                    if command.is_empty() {
+                        config_bail!(path, line_number,
+                                     "{:?} needs a command", bucket);
+                    }
+                    let command = command.to_owned();
+                    match parse_bucket_condition(&bucket[1..colon]) {
+                        Ok(Some(x)) if otherwise.is_none() => {
+                            buckets.push((x, command))
+                        },
+                        Ok(None) if otherwise.is_none() => {
+                            otherwise = Some(command)
+                        },
+                        _ => config_bail!(path, line_number,
+                                          "\"[else: ...]\" has to be the \
+                                           last bucket"),
+                    }
+                }
+                config.buckets.push(BucketMatch {
+                    wants_device: given.device()?, wants_code,
+                    hysteresis: hysteresis.unwrap_or(0),
+                    options: options.or(&defaults), buckets, otherwise,
+                    location,
+                })
+            },
+            "sequence" => {
+                let mut options = CommandOptions::default();
+                let (given, rest) = parse_keys(path, line_number, &SEQUENCE,
+                                               &splat[1..],
+                                               Some(&mut options))?;
+                let codes = given.numbers("code=")?;
+                if codes.len() < 2 {
+                    config_bail!(path, line_number,
+                                 "a sequence needs at least two \"code=\"s");
+                }
+                let within = given.value("within=", |x| {
+                    x.parse().ok().map(Duration::from_millis)
+                }, |_| {
+                    "\"within=\" wants a number of milliseconds".to_owned()
+                })?;
+                let within = match within {
+                    Some(x) => x,
+                    None => config_bail!(path, line_number,
+                                         "sequence needs a \"within=\""),
+                };
+                let action = parse_action(path, line_number, "sequence",
+                                          rest)?;
+                config.sequences.push(SequenceMatch {
+                    wants_device: given.device()?, codes, within,
+                    options: options.or(&defaults), action,
+                    location,
+                })
+            },
+            "combo" => {
+                let mut options = CommandOptions::default();
+                let (given, rest) = parse_keys(path, line_number, &COMBO,
+                                               &splat[1..],
+                                               Some(&mut options))?;
+                // each "dev=" is for the "code="s after it
+                let mut device = None;
+                // true if the last "dev=" hasn't been followed by a "code="
+                let mut dangling_device = false;
+                let mut keys = Vec::new();
+                for &(key, value) in given.words.iter() {
+                    if key == "dev=" {
+                        device = if value == "*" { None }
+                        else { Some(value.to_owned()) };
+                        dangling_device = true;
+                    }
+                    else {
+                        keys.push((device.clone(),
+                                   given.to_number(key, value)?));
+                        dangling_device = false;
+                    }
+                }
+                if dangling_device {
+                    config_bail!(path, line_number,
+                                 "each \"dev=\" in a combo applies to the \
+                                  \"code=\"s after it, but the last one has \
+                                  none");
+                }
+                if keys.len() < 2 {
+                    config_bail!(path, line_number,
+                                 "a combo needs at least two \"code=\"s");
+                }
+                let action = parse_action(path, line_number, "combo", rest)?;
+                config.combos.push(ComboMatch {
+                    keys, options: options.or(&defaults), action,
+                    location,
+                })
+            },
+            "while-held" => {
+                let mut options = CommandOptions::default();
+                let (given, rest) = parse_keys(path, line_number, &WHILE_HELD,
+                                               &splat[1..],
+                                               Some(&mut options))?;
+                // the "code=" before "taps" is for the key to hold, and the
+                // one after it for the key to tap
+                let (holding, tapping) = given.split("taps");
+                let code = |words: &KeyWords, side| {
+                    match words.all("code=")[..] {
+                        [] => Ok(None),
+                        [x] => words.to_number("code=", x).map(Some),
+                        _ => config_bail!(path, line_number,
+                                          "multiple \"code=\"s {} \"taps\"",
+                                          side),
+                    }
+                };
+                let held_code = code(&holding, "before")?;
+                let tap_code = match tapping.as_ref() {
+                    Some(_) if held_code.is_none() => {
+                        config_bail!(path, line_number,
+                                     "wanted the \"code=\" of the key to \
+                                      hold before \"taps\"")
+                    },
+                    Some(x) if x.one("taps")?.is_some() => {
+                        return x.unexpected("taps")
+                    },
+                    Some(x) => code(x, "after")?,
+                    None => None,
+                };
+                let (held_code, tap_code) = match (held_code, tap_code) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => config_bail!(path, line_number,
+                                      "while-held needs the \"code=\" of the \
+                                       key to hold, then \"taps\", then the \
+                                       \"code=\" of the key to tap"),
+                };
+                if held_code == tap_code {
+                    config_bail!(path, line_number,
+                                 "the key to hold and the key to tap must \
+                                  be different");
+                }
+                let count = given.value("count=", |x| {
+                    x.parse().ok().filter(|x| *x > 0)
+                }, |_| {
+                    "\"count=\" wants a positive number of taps".to_owned()
+                })?;
+                let count = match count {
+                    Some(x) => x,
+                    None => config_bail!(path, line_number,
+                                         "while-held needs a \"count=\""),
+                };
+                let action = parse_action(path, line_number, "while-held",
+                                          rest)?;
+                config.taps.push(TapMatch {
+                    wants_device: given.device()?, held_code, tap_code,
+                    count, options: options.or(&defaults), action,
+                    location,
+                })
+            },
+            "hat" => {
+                let mut options = CommandOptions::default();
+                let (given, rest) = parse_keys(path, line_number, &HAT,
+                                               &splat[1..],
+                                               Some(&mut options))?;
+                given.absolute()?;
+                let (code_x, code_y) = match (given.number("codeX=")?,
+                                              given.number("codeY=")?) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => config_bail!(path, line_number,
+                                      "hat needs a \"codeX=\" and a \
+                                       \"codeY=\""),
+                };
+                let directions: Vec<(i32, i32)> = given.words.iter()
+                    .filter_map(|(word, _)| {
+                        HAT_DIRECTIONS.iter().find(|x| x.0 == *word)
+                    }).map(|x| x.1).collect();
+                let direction = match directions[..] {
+                    [x] => x,
+                    [] => config_bail!(path, line_number,
+                                       "hat needs a direction: \
+                                        \"center\", \"up\", \"down\", \
+                                        \"left\", \"right\", \
+                                        \"up-left\", \"up-right\", \
+                                        \"down-left\", or \"down-right\""),
+                    _ => config_bail!(path, line_number,
+                                      "a hat line can only have one \
+                                       direction"),
+                };
+                let action = parse_action(path, line_number, "hat", rest)?;
+                config.hats.push(HatMatch {
+                    wants_device: given.device()?, code_x, code_y, direction,
+                    options: options.or(&defaults), action,
+                    location,
+                })
+            },
+            "zone" => {
+                let mut options = CommandOptions::default();
+                let (given, rest) = parse_keys(path, line_number, &ZONE,
+                                               &splat[1..],
+                                               Some(&mut options))?;
+                given.absolute()?;
+                let (code_x, code_y) = match (given.number("codeX=")?,
+                                              given.number("codeY=")?) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => config_bail!(path, line_number,
+                                      "zone needs a \"codeX=\" and a \
+                                       \"codeY=\""),
+                };
+                let percentages = |key| given.value(key, |x| {
+                    parse_range(x)
+                        .filter(|&(lo, hi)| 0 <= lo && lo <= hi && hi <= 100)
+                }, |_| {
+                    format!("\"{}\" wants two percentages with a dash \
+                             between, like \"{}0-50\"", key, key)
+                });
+                let (x, y) = (percentages("x=")?, percentages("y=")?);
+                let action = parse_action(path, line_number, "zone", rest)?;
+                config.zones.push(ZoneMatch {
+                    wants_device: given.device()?, code_x, code_y,
+                    x: x.unwrap_or((0, 100)), y: y.unwrap_or((0, 100)),
+                    options: options.or(&defaults), action,
+                    location,
+                })
+            },
+            "region" => {
+                let mut options = CommandOptions::default();
+                let (given, rest) = parse_keys(path, line_number, &REGION,
+                                               &splat[1..],
+                                               Some(&mut options))?;
+                given.absolute()?;
+                let (code_x, code_y) = match (given.number("codeX=")?,
+                                              given.number("codeY=")?) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => config_bail!(path, line_number,
+                                      "region needs a \"codeX=\" and a \
+                                       \"codeY=\""),
+                };
+                let x = (given.number("x>=")?, given.number("x<=")?);
+                let y = (given.number("y>=")?, given.number("y<=")?);
+                if x == (None, None) && y == (None, None) {
+                    config_bail!(path, line_number,
+                                 "region needs at least one of \"x>=\", \
+                                  \"x<=\", \"y>=\", and \"y<=\"");
+                }
+                for (lo, hi) in [x, y].iter() {
+                    if let (Some(lo), Some(hi)) = (lo, hi) {
+                        if lo > hi {
+                            config_bail!(path, line_number,
+                                         "this region is empty ({} is more \
+                                          than {})", lo, hi);
+                        }
+                    }
+                }
+                let action = parse_action(path, line_number, "region",
+                                          rest)?;
+                config.regions.push(RegionMatch {
+                    wants_device: given.device()?, code_x, code_y, x, y,
+                    options: options.or(&defaults), action,
+                    location,
+                })
+            },
+            "calibrate" => {
+                let (given, _) = parse_keys(path, line_number, &CALIBRATE,
+                                            &splat[1..], None)?;
+                given.absolute()?;
+                let (wants_code, min, max) = match (given.number("code=")?,
+                                                    given.number("min=")?,
+                                                    given.number("max=")?) {
+                    (Some(code), Some(min), Some(max)) => (code, min, max),
+                    _ => config_bail!(path, line_number,
+                                      "calibrate needs a \"code=\", a \
+                                       \"min=\", and a \"max=\""),
+                };
+                let center = given.number("center=")?
+                    .unwrap_or(min + (max - min) / 2);
+                if !(min < center && center < max) {
+                    config_bail!(path, line_number,
+                                 "calibrate wants \"min=\" < \"center=\" < \
+                                  \"max=\"");
+                }
+                config.calibrations.push(Calibration {
+                    wants_device: given.device()?, wants_code, center, min,
+                    max,
+                    location,
+                })
+            },
+            "latch" | "switch-profile" => {
+                let grammar = if splat[0] == "latch" { &LATCH }
+                else { &SWITCH_PROFILE };
+                let name = match splat.get(1) {
+                    Some(x) if !x.contains('=') => x.to_string(),
+                    _ => config_bail!(path, line_number,
+                                      "{} wants a name, then \"code=\"",
+                                      splat[0]),
+                };
+                let (given, _) = parse_keys(path, line_number, grammar,
+                                            &splat[2..], None)?;
+                let wants_code = match given.number("code=")? {
+                    Some(x) => x,
+                    None => config_bail!(path, line_number,
+                                         "{} wants a name, then \"code=\"",
+                                         splat[0]),
+                };
+                let wants_device = given.device()?;
+                if splat[0] == "latch" {
+                    config.latches.push(Latch {
+                        name, wants_device, wants_code, location,
+                    })
+                }
+                else {
+                    config.profile_switches.push(ProfileSwitch {
+                        name, wants_device, wants_code,
+                        location,
+                    })
+                }
+            },
+            "defaults" => {
+                let mut options = CommandOptions::default();
+                for el in splat[1..].iter() {
+                    if !options.parse(path, line_number, el)? {
+                        config_bail!(path, line_number,
+                                     "wanted \"nice=\", \"cwd=\", \
+                                      \"shell=\", \"expect-exit=\", or \
+                                      \"env=\" after \"defaults\", saw \
+                                      {:?}", el);
+                    }
+                }
+                defaults = options.or(&defaults);
+            },
+            "group" => {
+                let name = match splat.get(1..3) {
+                    Some([name, "="])
+                    if !name.is_empty() && !name.contains(['=', '@']) => name,
+                    _ => config_bail!(path, line_number,
+                                      "group wants a name, \"=\", and some \
+                                       codes"),
+                };
+                if groups.contains_key(*name) {
+                    config_bail!(path, line_number,
+                                 "group {:?} is already defined", name);
+                }
+                let mut codes = Vec::new();
+                for el in splat[3..].iter() {
+                    if let Some(other) = el.strip_prefix('@') {
+                        match groups.get(other) {
+                            Some(x) => codes.extend_from_slice(x),
+                            None => config_bail!(path, line_number,
+                                                 "undefined group {:?}",
+                                                 other),
+                        }
+                        continue
+                    }
+                    match parse_matcher::<u16>("group", el) {
+                        Ok(FieldMatcher::Is(x)) => codes.push((x, x)),
+                        Ok(FieldMatcher::Range(lo, hi)) => {
+                            codes.push((lo, hi))
+                        },
+                        Ok(FieldMatcher::List(x)) => codes.extend(x),
+                        Ok(_) => config_bail!(path, line_number,
+                                              "a group can only hold codes \
+                                               and ranges of codes, not {:?}",
+                                              el),
+                        Err(_) => config_bail!(path, line_number,
+                                               "invalid code {:?} in group",
+                                               el),
+                    }
+                }
+                if codes.is_empty() {
+                    config_bail!(path, line_number,
+                                 "group wants a name, \"=\", and some \
+                                  codes");
+                }
+                groups.insert(name.to_string(), codes);
+            },
+            "min-interval" => {
+                let interval = match splat.get(1).map(|x| x.parse()) {
+                    Some(Ok(x)) => Duration::from_millis(x),
+                    _ => config_bail!(path, line_number,
+                                      "min-interval wants a number of \
+                                       milliseconds"),
+                };
+                let drop = match splat.get(2).copied() {
+                    None | Some("delay") => false,
+                    Some("drop") => true,
+                    Some(x) => config_bail!(path, line_number,
+                                            "wanted \"delay\" or \"drop\" \
+                                             after the milliseconds, saw \
+                                             {:?}", x),
+                };
+                if splat.len() > 3 {
+                    config_bail!(path, line_number,
+                                 "too much after \"min-interval\"");
+                }
+                config.min_interval = Some(MinInterval { interval, drop });
+            },
+            "idle" => {
+                if splat.len() < 2 {
+                    config_bail!(path, line_number,
+                                 "idle wants a number of seconds");
+                }
+                let after = match splat[1].parse().ok().and_then(idle_after) {
+                    Some(x) => x,
+                    None => {
+                        config_bail!(path, line_number,
+                                     "invalid number of seconds for idle \
+                                      (wanted 0 to {})", MAX_IDLE_SECS);
+                    },
+                };
+                let action = parse_action(path, line_number, "idle",
+                                          &splat[2..])?;
+                config.idle.push(IdleMatch { after, action, location })
+            },
+            "on-activity" => {
+                let action = parse_action(path, line_number, "on-activity",
+                                          &splat[1..])?;
+                config.on_activity.push(action)
+            },
+            "on-start" | "on-stop" => {
+                let action = parse_action(path, line_number, splat[0],
+                                          &splat[1..])?;
+                if splat[0] == "on-start" { config.on_start.push(action) }
+                else { config.on_stop.push(action) }
+            },
+            x => {
+                config_bail!(path, line_number,
+                             "Unknown config directive {:?}", x);
+            },
+        }
+    }
+    if let Some((name, line)) = block {
+        config_bail!(path, line, "the \"profile\" block for {:?} is never \
+                                  closed", name);
+    }
+    config.sort_matches();
+    // All done!
+    Ok(config)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        parse_config("test.conf", text).unwrap()
+    }
+
+    /// Asserts that parsing fails on the given line, with a message
+    /// containing the given text.
+    fn parse_err(text: &str, line: usize, message: &str) {
+        let err = parse_config("test.conf", text).unwrap_err();
+        assert_eq!(err.file, "test.conf");
+        assert_eq!(err.line, Some(line));
+        assert!(err.message.contains(message),
+                "{:?} doesn't contain {:?}", err.message, message);
+    }
+
+    /// The location of a line of "test.conf".
+    fn at(line: usize) -> Location {
+        Location { file: "test.conf".to_owned(), line }
+    }
+
+    fn shell_match(line: usize, wants_type: Option<u16>,
+                   wants_code: Option<u16>, wants_value: Option<i32>,
+                   command: &str) -> InputMatch {
+        InputMatch {
+            negated: false, wants_device: None,
+            wants_type: wants_type.into(), wants_code: wants_code.into(),
+            wants_value: wants_value.into(), wants_slot: None,
+            hysteresis: None,
+            with_held: Vec::new(), with_latch: Vec::new(), profile: None,
+            max: None, held: None, delay: None,
+            cancel_on_release: false, priority: 0,
+            options: CommandOptions::default(), when: None, between: None,
+            observe: false, action: Action::Shell(command.to_owned()),
+            location: at(line),
+        }
+    }
+
+    fn device(label: Option<&str>, path: &str, line: usize) -> DeviceSpec {
+        DeviceSpec {
+            label: label.map(str::to_owned), path: path.to_owned(),
+            query: None, virtual_policy: None, grab: false, queue: None,
+            queue_policy: QueuePolicy::default(), max_rate: None,
+            location: at(line),
+        }
+    }
+
+    #[test]
+    fn dev() {
+        let config = parse("dev /dev/input/event0\n\ndev /dev/input/event1");
+        assert_eq!(config.devices, vec![
+            device(None, "/dev/input/event0", 1),
+            device(None, "/dev/input/event1", 3),
+        ]);
+        parse_err("dev", 1, "wants a path");
+        parse_err("dev a b", 1, "wants a path");
+    }
+
+    #[test]
+    fn dev_labels() {
+        let config = parse("dev /dev/input/by-path/pci-0000:00:14.0-event\n\
+                            dev pad: /dev/input/event1\n\
+                            dev \"Left Pad\": /dev/input/event2 \n\
+                            dev \"a:b\":/dev/input/event3\n");
+        assert_eq!(config.devices, vec![
+            device(None, "/dev/input/by-path/pci-0000:00:14.0-event", 1),
+            device(Some("pad"), "/dev/input/event1", 2),
+            device(Some("Left Pad"), "/dev/input/event2", 3),
+            device(Some("a:b"), "/dev/input/event3", 4),
+        ]);
+        assert_eq!(config.devices[0].name(),
+                   "/dev/input/by-path/pci-0000:00:14.0-event");
+        assert_eq!(config.devices[2].name(), "Left Pad");
+        assert_eq!(config.devices[0].to_string(),
+                   "dev /dev/input/by-path/pci-0000:00:14.0-event");
+        assert_eq!(config.devices[3].to_string(),
+                   "dev \"a:b\": /dev/input/event3");
+        let config = parse("dev : /dev/input/Left Pad");
+        assert_eq!(config.devices[0].path, "/dev/input/Left Pad");
+        assert_eq!(config.devices[0].to_string(),
+                   "dev: /dev/input/Left Pad");
+        assert_eq!(parse(&config.devices[0].to_string()).devices,
+                   config.devices);
+        parse_err("dev \"Left Pad\"", 1, "wants a path");
+        parse_err("dev a b: /a", 1, "only have one label");
+        parse_err("dev \"\": /a", 1, "wants a path");
+        parse_err("dev \"Left Pad: /a", 1, "unterminated quote");
+        parse_err("dev a: /a\ndev a: /b", 2,
+                  "\"a\" is already used at test.conf:1");
+        parse_err("dev /a\ndev /a: /b", 2, "already used");
+        // unlabeled devices can share a path, though it's not a good idea
+        parse("dev /a\ndev /a");
+        let config = parse("if dev=\"Left Pad\" type=1 then: a");
+        assert_eq!(config.matches[0].wants_device.as_deref(),
+                   Some("Left Pad"));
+        assert_eq!(config.matches[0].to_string(),
+                   "if dev=\"Left Pad\" type=1 then: a");
+        let mut config = parse("dev a: /a");
+        let other = parse_config("other.conf", "dev a: /b").unwrap();
+        assert_eq!(config.append(other).unwrap_err().to_string(),
+                   "other.conf:1: device label \"a\" is already used at \
+                    test.conf:1");
+    }
+
+    #[test]
+    fn dev_options() {
+        let config = parse("dev virtual=exclude: /a\n\
+                            dev \"Left Pad\" virtual=only grab=yes: /b\n");
+        assert_eq!(config.devices[0].label, None);
+        assert_eq!(config.devices[0].virtual_policy,
+                   Some(VirtualPolicy::Exclude));
+        assert_eq!(config.devices[1].label.as_deref(), Some("Left Pad"));
+        assert_eq!(config.devices[1].virtual_policy,
+                   Some(VirtualPolicy::Only));
+        assert!(!config.devices[0].grab);
+        assert!(config.devices[1].grab);
+        assert_eq!(config.devices[0].to_string(), "dev virtual=exclude: /a");
+        assert_eq!(config.devices[1].to_string(),
+                   "dev \"Left Pad\" virtual=only grab=yes: /b");
+        parse_err("dev grab=maybe: /a", 1, "wants \"yes\"");
+        parse_err("dev virtual=maybe: /a", 1, "wants \"include\"");
+        parse_err("dev virtual=only virtual=only: /a", 1, "multiple");
+        parse_err("dev bogus=1: /a", 1, "unknown dev option \"bogus=1\"");
+    }
+
+    #[test]
+    fn dev_queues() {
+        let config = parse("dev queue=64 policy=drop-oldest: /a\n\
+                            dev mouse queue=8 type=mouse\n\
+                            dev queue=4 policy=drop-newest: /b\n");
+        assert_eq!(config.devices[0].queue, Some(64));
+        assert_eq!(config.devices[0].queue_policy, QueuePolicy::DropOldest);
+        assert_eq!(config.devices[1].queue, Some(8));
+        assert_eq!(config.devices[1].queue_policy, QueuePolicy::DropNewest);
+        assert_eq!(config.devices[0].to_string(),
+                   "dev queue=64 policy=drop-oldest: /a");
+        assert_eq!(config.devices[1].to_string(),
+                   "dev mouse queue=8 type=mouse");
+        assert_eq!(config.devices[2].to_string(), "dev queue=4: /b");
+        assert!(!config.devices[0].same_device(&DeviceSpec {
+            queue_policy: QueuePolicy::Block, ..config.devices[0].clone()
+        }));
+        parse_err("dev queue=0: /a", 1, "wants a number of events");
+        parse_err("dev queue=lots: /a", 1, "wants a number of events");
+        parse_err("dev policy=block: /a", 1, "needs a \"queue=\"");
+        parse_err("dev queue=1 policy=wait: /a", 1, "wants \"drop-newest\"");
+        parse_err("dev queue=1 queue=2: /a", 1, "multiple");
+    }
+
+    #[test]
+    fn dev_max_rate() {
+        let config = parse("dev maxrate=125: /a\n\
+                            dev mouse type=mouse maxrate=250\n");
+        assert_eq!(config.devices[0].max_rate, Some(125));
+        assert_eq!(config.devices[0].to_string(), "dev maxrate=125: /a");
+        assert_eq!(config.devices[1].to_string(),
+                   "dev mouse maxrate=250 type=mouse");
+        parse_err("dev maxrate=0: /a", 1, "wants a number of times");
+        parse_err("dev maxrate=fast: /a", 1, "wants a number of times");
+        parse_err("dev maxrate=1 maxrate=2: /a", 1, "multiple");
+    }
+
+    #[test]
+    fn dev_queries() {
+        let config = parse("dev type=gamepad\n\
+                            dev pad grab=yes type=any has=BTN_SOUTH,5/2\n\
+                            dev has=1/30\n");
+        assert_eq!(config.devices[0].path, "");
+        assert_eq!(config.devices[0].query, Some(DeviceQuery {
+            kind: DeviceKind::Gamepad, has: Vec::new(),
+        }));
+        assert_eq!(config.devices[1].query.as_ref().unwrap().has,
+                   vec![(EV_KEY, BTN_GAMEPAD), (5, 2)]);
+        assert_eq!(config.devices[0].to_string(), "dev type=gamepad");
+        assert_eq!(config.devices[1].to_string(),
+                   "dev pad grab=yes type=any has=BTN_SOUTH,5/2");
+        assert_eq!(config.devices[2].to_string(), "dev type=any has=KEY_A");
+        let mouse = DeviceQuery { kind: DeviceKind::Mouse, has: Vec::new() };
+        assert!(mouse.wants(|type_, code| {
+            [(EV_REL, REL_X), (EV_REL, REL_Y), (EV_KEY, BTN_LEFT),
+             (EV_KEY, KEY_A)].contains(&(type_, code))
+        }));
+        assert!(!mouse.wants(|type_, _| type_ == EV_REL));
+        parse_err("dev type=toaster", 1, "\"type=\" wants");
+        parse_err("dev has=KEY_BOGUS", 1, "\"has=\" wants");
+        parse_err("dev type=mouse type=any", 1, "multiple");
+        parse_err("dev type=mouse: /a", 1, "can't have \"type=\"");
+    }
+
+    #[test]
+    fn if_then() {
+        let config = parse("if type=1 code=304 value=1 then: echo hi\n\
+                            if code=2 then: a\n\
+                            if then: b\n");
+        assert_eq!(config.matches, vec![
+            shell_match(1, Some(1), Some(304), Some(1), "echo hi"),
+            shell_match(2, None, Some(2), None, "a"),
+            shell_match(3, None, None, None, "b"),
+        ]);
+        let config = parse("if type=1 value=nonzero then: a\n\
+                            if value=!-1 then: b");
+        assert_eq!(config.matches[0].wants_value, FieldMatcher::Not(0));
+        assert_eq!(config.matches[1].wants_value, FieldMatcher::Not(-1));
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 value=nonzero then: a");
+        assert_eq!(config.matches[1].to_string(), "if value=!-1 then: b");
+        let config = parse("if type=2 value=positive then: up\n\
+                            if value=negative then: down");
+        assert_eq!(config.matches[0].wants_value, FieldMatcher::AtLeast(1));
+        assert_eq!(config.matches[1].wants_value, FieldMatcher::AtMost(-1));
+        assert_eq!(config.matches[1].to_string(),
+                   "if value=negative then: down");
+        parse_err("if value=positive value=1 then: a", 1, "multiple");
+        let config = parse("if type=1 code=30 value=repeat then: a\n\
+                            if type=3 value=2 then: b");
+        assert_eq!(config.matches[0].wants_value, FieldMatcher::Is(2));
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 code=30 value=repeat then: a");
+        // only keys repeat
+        assert_eq!(config.matches[1].to_string(), "if type=3 value=2 then: b");
+        parse_err("if value=1 value=negative then: a", 1, "multiple");
+        let config = parse("if type=* code=* value=* slot=* then: a");
+        assert_eq!(config.matches, vec![shell_match(1, None, None, None,
+                                                    "a")]);
+        let config = parse("if code=30 with-held=42 with-held=29 then: c");
+        assert_eq!(config.matches[0].with_held, [42, 29]);
+        assert_eq!(config.matches[0].to_string(),
+                   "if code=30 with-held=42 with-held=29 then: c");
+        parse_err("if with-held=shift then: a", 1, "invalid \"with-held=\"");
+        let config = parse("if code=30 max=1 then: setup");
+        assert_eq!(config.matches[0].max, Some(1));
+        assert_eq!(config.matches[0].to_string(),
+                   "if code=30 max=1 then: setup");
+        parse_err("if max=0 then: a", 1, "positive number");
+        parse_err("if max=1 max=2 then: a", 1, "multiple \"max=\"s");
+        let config = parse("if type=1 code=30 held=800 then: long");
+        assert_eq!(config.matches[0].held, Some(Duration::from_millis(800)));
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 code=30 held=800 then: long");
+        parse_err("if held=0 then: a", 1, "positive number of milliseconds");
+        parse_err("if held=1 held=2 then: a", 1, "multiple \"held=\"s");
+        let config = parse("if code=30 value=1 delay=3000 cancel-on-release \
+                            then: boom");
+        assert_eq!(config.matches[0].delay, Some(Duration::from_secs(3)));
+        assert!(config.matches[0].cancel_on_release);
+        assert_eq!(config.matches[0].to_string(),
+                   "if code=30 value=1 delay=3000 cancel-on-release \
+                    then: boom");
+        parse_err("if delay=soon then: a", 1, "number of milliseconds");
+        parse_err("if delay=1 delay=2 then: a", 1, "multiple \"delay=\"s");
+        parse_err("if held=1 delay=2 then: a", 1, "can't go together");
+        parse_err("if cancel-on-release then: a", 1, "needs a \"delay=\"");
+        let config = parse("if code=30 priority=-1 then: late\n\
+                            if code=30 then: a\n\
+                            if code=30 priority=5 then: early\n\
+                            if code=30 then: b");
+        let order: Vec<String> = config.matches.iter()
+            .map(|x| x.action.to_string()).collect();
+        assert_eq!(order, ["then: early", "then: a", "then: b", "then: late"]);
+        assert_eq!(config.matches[0].to_string(),
+                   "if code=30 priority=5 then: early");
+        let mut config = parse("if code=1 then: first\n\
+                                if code=1 priority=1 then: second");
+        let other = parse_config("other.conf", "if code=1 priority=1 \
+                                                then: third").unwrap();
+        config.append(other).unwrap();
+        let order: Vec<String> = config.matches.iter()
+            .map(|x| x.action.to_string()).collect();
+        assert_eq!(order, ["then: second", "then: third", "then: first"]);
+        parse_err("if priority=high then: a", 1, "invalid \"priority=\"");
+        let config = parse("if slot=2 nice=5 then: c");
+        assert_eq!(config.matches[0].wants_slot, Some(2));
+        assert_eq!(config.matches[0].options.nice, Some(5));
+        let config = parse("if type=1 when=\"pgrep mpd\" then: mpc pause");
+        assert_eq!(config.matches[0].when.as_deref(), Some("pgrep mpd"));
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 when=\"pgrep mpd\" then: mpc pause");
+        let config = parse("if type=1 observe then: a\n\
+                            if type=1 consume then: b\n");
+        assert!(config.matches[0].observe);
+        assert!(!config.matches[1].observe);
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 observe then: a");
+        parse_err("if observe consume then: a", 1, "only be given once");
+    }
+
+    #[test]
+    fn field_matchers() {
+        let round_trip = |text: &str, shown: &str| {
+            let matcher = parse_matcher::<i32>("value", text).unwrap();
+            assert_eq!(matcher.to_string(), shown);
+            assert_eq!(parse_matcher::<i32>("value", shown).unwrap(),
+                       matcher);
+            matcher
+        };
+        assert_eq!(round_trip("*", "*"), FieldMatcher::Any);
+        assert_eq!(round_trip("30", "30"), FieldMatcher::Is(30));
+        assert_eq!(round_trip("-3", "-3"), FieldMatcher::Is(-3));
+        assert_eq!(round_trip("!5", "!5"), FieldMatcher::Not(5));
+        assert_eq!(round_trip("!=5", "!5"), FieldMatcher::Not(5));
+        assert_eq!(round_trip("30-39", "30-39"), FieldMatcher::Range(30, 39));
+        assert_eq!(round_trip("-5--1", "-5--1"), FieldMatcher::Range(-5, -1));
+        assert_eq!(round_trip("1,2-3,4", "1,2-3,4"),
+                   FieldMatcher::List(vec![(1, 1), (2, 3), (4, 4)]));
+        assert_eq!(round_trip(">=10", ">=10"), FieldMatcher::AtLeast(10));
+        assert_eq!(round_trip("<=-1", "<=-1"), FieldMatcher::AtMost(-1));
+        let err = |field: &str, text: &str, wanted: &str| {
+            let message = match field {
+                "code" => parse_matcher::<u16>(field, text).unwrap_err(),
+                _ => parse_matcher::<i32>(field, text).unwrap_err(),
+            };
+            assert!(message.contains(wanted), "{:?} gave {:?}", text,
+                    message);
+        };
+        err("value", "9-3", "backwards");
+        err("value", "1,,2", "invalid \"value=\"");
+        err("value", "5-", "invalid \"value=\"");
+        err("value", ">=x", "invalid \"value=\"");
+        err("code", "-1", "out of range");
+        err("code", "1-70000", "out of range");
+        let config = parse("if type=1 code=2-11,30 value=>=1 then: a\n\
+                            if type=3 code=!0 value=<=-100 then: b");
+        assert_eq!(config.matches[0].wants_code,
+                   FieldMatcher::List(vec![(2, 11), (30, 30)]));
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 code=2-11,30 value=positive then: a");
+        assert_eq!(config.matches[1].to_string(),
+                   "if type=3 code=!0 value=<=-100 then: b");
+        parse_err("if type=1-3 type=2 then: a", 1, "multiple");
+    }
+
+    #[test]
+    fn groups() {
+        let config = parse("group face = 304 305 307-308\n\
+                            group mode = 314\n\
+                            group all = @face @mode 316,317\n\
+                            if type=1 code=@face value=1 then: a\n\
+                            if code=@mode then: b\n\
+                            unless code=@all then: c");
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 code=304,305,307-308 value=1 then: a");
+        assert_eq!(config.matches[1].wants_code, FieldMatcher::Is(314));
+        assert_eq!(config.matches[2].to_string(),
+                   "unless code=304,305,307-308,314,316,317 then: c");
+        parse_err("if code=@face then: a", 1, "undefined group \"face\"");
+        parse_err("group face = 1\nif code=@face code=2 then: a", 2,
+                  "multiple");
+        parse_err("group face = 1\ngroup face = 2", 2, "already defined");
+        parse_err("group face = @nope", 1, "undefined group");
+        parse_err("group face 304", 1, "wants a name");
+        parse_err("group face =", 1, "wants a name");
+        parse_err("group face = !3", 1, "only hold codes");
+        parse_err("group face = x", 1, "invalid code \"x\"");
+    }
+
+    #[test]
+    fn unless() {
+        let config = parse("unless type=1 code=2 then: a");
+        assert!(config.matches[0].negated);
+        assert_eq!(config.matches[0].wants_code, FieldMatcher::Is(2));
+        assert_eq!(config.matches[0].to_string(),
+                   "unless type=1 code=2 then: a");
+        parse_err("unless type=1", 1, "\"unless\" needs a \"then\"");
+    }
+
+    #[test]
+    fn if_then_file() {
+        let config = parse("if type=1 then-file: /bin/sh");
+        assert_eq!(config.matches[0].action,
+                   Action::File("/bin/sh".to_owned()));
+        parse_err("if type=1 then-file: /nonexistent/script.sh", 1,
+                  "is not a file");
+    }
+
+    #[test]
+    fn if_exec() {
+        let config = parse("if type=1 exec: xdotool  key \"a b\" c:d");
+        assert_eq!(config.matches[0].action,
+                   Action::Exec(vec!["xdotool".to_owned(), "key".to_owned(),
+                                     "a b".to_owned(), "c:d".to_owned()]));
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 exec: xdotool key \"a b\" \"c:d\"");
+        parse_err("if type=1 exec:", 1, "needs a command");
+        parse_err("if type=1 exec: echo \"oops", 1, "unterminated quote");
+    }
+
+    #[test]
+    fn if_dbus() {
+        let config = parse("if type=1 then-dbus: system org.a.B /b \
+                            org.a.B.Do \"string:x y\" uint32:{value}");
+        let call = match &config.matches[0].action {
+            Action::DBus(x) => x,
+            x => panic!("{:?}", x),
+        };
+        assert_eq!(call.bus, crate::Bus::System);
+        assert_eq!(call.args, vec!["string:x y", "uint32:{value}"]);
+        assert_eq!(config.matches[0].to_string(),
+                   "if type=1 then-dbus: system org.a.B /b org.a.B.Do \
+                    \"string:x y\" \"uint32:{value}\"");
+        parse_err("if type=1 then-dbus: org.a.B /b", 1, "needs a destination");
+        parse_err("if type=1 then-dbus: org.a.B /b a.B byte:300", 1,
+                  "argument \"byte:300\": bad value");
+    }
+
+    #[test]
+    fn if_led() {
+        let config = parse("if type=1 code=30 then-led: LED_NUML on\n\
+                            if type=1 code=31 then-led: 9 off");
+        assert_eq!(config.matches[0].action, Action::Led(LedSetting {
+            code: 0, state: crate::LedState::On,
+        }));
+        assert_eq!(config.matches[1].to_string(),
+                   "if type=1 code=31 then-led: LED_MAIL off");
+        parse_err("if type=1 then-led: LED_NUML", 1, "needs an LED");
+        parse_err("if type=1 then-led: KEY_A on", 1,
+                  "\"KEY_A\" is not an LED");
+        parse_err("if type=1 then-led: LED_NUML blink", 1,
+                  "wanted \"on\", \"off\", or \"toggle\", saw \"blink\"");
+    }
+
+    #[test]
+    fn if_errors() {
+        parse_err("\nif type=1 type=2 then: a", 2, "multiple \"type=\"s");
+        parse_err("if code=1 code=2 then: a", 1, "multiple \"code=\"s");
+        parse_err("if value=1 value=2 then: a", 1, "multiple \"value=\"s");
+        parse_err("if type=x then: a", 1, "invalid \"type=\"");
+        parse_err("if code=x then: a", 1, "invalid \"code=\"");
+        parse_err("if code=70000 then: a", 1,
+                  "value \"70000\" out of range for code (0..65535)");
+        parse_err("if value=!-3000000000 then: a", 1,
+                  "out of range for value (-2147483648..2147483647)");
+        parse_err("if value=x then: a", 1, "invalid \"value=\"");
+        parse_err("if value=* value=1 then: a", 1, "multiple \"value=\"s");
+        parse_err("if value=1 value=nonzero then: a", 1,
+                  "multiple \"value=\"s");
+        parse_err("if value=!2 value=1 then: a", 1, "multiple \"value=\"s");
+        parse_err("if value=!x then: a", 1, "invalid \"value=\"");
+        parse_err("if type=1", 1, "needs a \"then\"");
+        parse_err("if type=1 then echo hi", 1, "put a colon after \"then\"");
+        parse_err("if type=1 bogus then: a", 1, "saw \"bogus\"");
+        parse_err("if when= then: a", 1, "\"when=\" needs a command");
+        parse_err("if when=a when=b then: a", 1, "multiple \"when=\"s");
+        parse_err("unless type=1 bogus then: a", 1,
+                  "after \"unless\", saw \"bogus\"");
+    }
+
+    #[test]
+    fn map() {
+        let config = parse("map type=3 code=1 to=0-100 then: echo a\n\
+                            map dev=knob code=2 to=-10--5 nice=3 exec: b");
+        assert_eq!(config.maps[0], AxisMap {
+            wants_device: None, wants_code: 1, to: (0, 100),
+            options: CommandOptions::default(),
+            action: Action::Shell("echo a".to_owned()),
+            location: at(1),
+        });
+        assert_eq!(config.maps[1].to, (-10, -5));
+        assert_eq!(config.maps[1].to_string(),
+                   "map dev=knob type=3 code=2 to=-10--5 nice=3 exec: b");
+        parse_err("map type=1 code=1 to=0-1 then: a", 1, "absolute axes");
+        parse_err("map to=0-1 then: a", 1, "needs a \"code=\"");
+        parse_err("map code=1 then: a", 1, "needs a \"to=\"");
+        parse_err("map code=1 to=5 then: a", 1, "two numbers");
+        parse_err("map code=1 code=2 to=0-1 then: a", 1,
+                  "multiple \"code=\"s");
+        parse_err("map code=1 to=0-1 value=2 then: a", 1, "saw \"value=2\"");
+    }
+
+    #[test]
+    fn axis() {
+        let config = parse("axis type=3 code=1 threshold=8000 then-pos: a b \
+                            then-neg: c\n\
+                            axis dev=pad code=2 center=128 threshold=64 \
+                            nice=1 then-neg:d");
+        assert_eq!(config.axes[0], AxisMatch {
+            wants_device: None, wants_code: 1, center: 0, threshold: 8000,
+            options: CommandOptions::default(),
+            positive: Some("a b".to_owned()),
+            negative: Some("c".to_owned()),
+            location: at(1),
+        });
+        assert_eq!(config.axes[1].to_string(),
+                   "axis dev=pad type=3 code=2 center=128 threshold=64 \
+                    nice=1 then-neg: d");
+        // the commands are taken literally, like any other
+        let config = parse("axis code=1 threshold=1 then-pos: echo up # x \
+                            then-neg: echo down # y");
+        assert_eq!(config.axes[0].positive.as_deref(), Some("echo up # x"));
+        assert_eq!(config.axes[0].negative.as_deref(), Some("echo down # y"));
+        parse_err("axis code=1 then-pos: a", 1, "needs a \"code=\" and");
+        parse_err("axis code=1 threshold=8000", 1, "needs a \"then-pos:\"");
+        parse_err("axis code=1 threshold=0 then-pos: a", 1,
+                  "positive number");
+        parse_err("axis type=1 code=1 threshold=1 then-pos: a", 1,
+                  "absolute axes");
+        parse_err("axis code=1 threshold=1 then-neg: a then-pos: b", 1,
+                  "before");
+        parse_err("axis code=1 threshold=1 then-pos: a then-neg:", 1,
+                  "each need a command");
+        parse_err("axis code=1 threshold=1 then: a", 1, "saw \"then\"");
+    }
+
+    #[test]
+    fn buckets() {
+        let config = parse("buckets type=3 code=5 [<-16000: low] \
+                            [>16000: [ -f x ] && high] [else: mid]\n\
+                            buckets dev=dial code=6 hysteresis=5 nice=1 \
+                            [0-9: a] [10,20: b]");
+        assert_eq!(config.buckets[0], BucketMatch {
+            wants_device: None, wants_code: 5, hysteresis: 0,
+            options: CommandOptions::default(),
+            buckets: vec![
+                (FieldMatcher::AtMost(-16001), "low".to_owned()),
+                (FieldMatcher::AtLeast(16001), "[ -f x ] && high".to_owned()),
+            ],
+            otherwise: Some("mid".to_owned()),
+            location: at(1),
+        });
+        assert_eq!(config.buckets[1].to_string(),
+                   "buckets dev=dial type=3 code=6 hysteresis=5 nice=1 \
+                    [0-9: a] [10,20: b]");
+        // the commands are taken literally, like any other
+        let config = parse("buckets type=3 code=5 [<-16000: echo low # x] \
+                            [else: echo mid]");
+        assert_eq!(config.buckets[0].buckets[0].1, "echo low # x");
+        assert_eq!(config.buckets[0].otherwise.as_deref(), Some("echo mid"));
+        parse_err("buckets code=5 then: a", 1, "in brackets");
+        parse_err("buckets code=5", 1, "in brackets");
+        parse_err("buckets type=3 [<0: a]", 1, "needs a \"code=\"");
+        parse_err("buckets code=5 [<0: a] [>=0: b", 1, "missing its");
+        parse_err("buckets code=5 [<0: ] [>=0: b]", 1, "needs a command");
+        parse_err("buckets code=5 [else: a] [<0: b]", 1, "last bucket");
+        parse_err("buckets code=5 [else: a] [else: b]", 1, "last bucket");
+        parse_err("buckets code=5 hysteresis=-1 [<0: a]", 1,
+                  "invalid \"hysteresis=\"");
+        parse_err("buckets code=5 value=1 [<0: a]", 1, "saw \"value=1\"");
+    }
+
+    #[test]
+    fn sequence() {
+        let config = parse("sequence code=30 code=48 within=400 then: a\n\
+                            sequence dev=kbd code=1 code=1 code=2 \
+                            within=1000 nice=2 exec: b");
+        assert_eq!(config.sequences[0], SequenceMatch {
+            wants_device: None, codes: vec![30, 48],
+            within: Duration::from_millis(400),
+            options: CommandOptions::default(),
+            action: Action::Shell("a".to_owned()),
+            location: at(1),
+        });
+        assert_eq!(config.sequences[1].to_string(),
+                   "sequence dev=kbd code=1 code=1 code=2 within=1000 \
+                    nice=2 exec: b");
+        parse_err("sequence code=1 within=5 then: a", 1, "at least two");
+        parse_err("sequence code=1 code=2 then: a", 1,
+                  "needs a \"within=\"");
+        parse_err("sequence code=1 code=2 within=x then: a", 1,
+                  "milliseconds");
+        parse_err("sequence code=1 code=2 within=5 within=6 then: a", 1,
+                  "multiple \"within=\"s");
+        parse_err("sequence code=1 code=2 within=5 value=1 then: a", 1,
+                  "saw \"value=1\"");
+    }
+
+    #[test]
+    fn combo() {
+        let config = parse("combo code=29 code=56 then: a\n\
+                            combo dev=pedalL code=1 dev=pedalR code=1 \
+                            dev=* code=2 nice=1 exec: b");
+        assert_eq!(config.combos[0].keys, [(None, 29), (None, 56)]);
+        assert_eq!(config.combos[1].keys, [
+            (Some("pedalL".to_owned()), 1),
+            (Some("pedalR".to_owned()), 1),
+            (None, 2),
+        ]);
+        assert_eq!(config.combos[1].to_string(),
+                   "combo dev=pedalL code=1 dev=pedalR code=1 dev=* code=2 \
+                    nice=1 exec: b");
+        parse_err("combo code=1 then: a", 1, "at least two");
+        parse_err("combo code=1 code=2 dev=x then: a", 1, "has none");
+        parse_err("combo code=1 code=x then: a", 1, "invalid \"code=\"");
+        parse_err("combo code=1 code=2 value=1 then: a", 1,
+                  "saw \"value=1\"");
+    }
+
+    #[test]
+    fn while_held() {
+        let config = parse("while-held code=314 taps code=304 count=2 \
+                            then: a\n\
+                            while-held dev=pad code=5 taps code=6 count=3 \
+                            nice=1 exec: b");
+        assert_eq!(config.taps[0], TapMatch {
+            wants_device: None, held_code: 314, tap_code: 304, count: 2,
+            options: CommandOptions::default(),
+            action: Action::Shell("a".to_owned()),
+            location: at(1),
+        });
+        assert_eq!(config.taps[1].to_string(),
+                   "while-held dev=pad code=5 taps code=6 count=3 nice=1 \
+                    exec: b");
+        parse_err("while-held taps code=1 count=2 then: a", 1,
+                  "before \"taps\"");
+        parse_err("while-held code=1 count=2 then: a", 1, "then \"taps\"");
+        parse_err("while-held code=1 taps code=1 count=2 then: a", 1,
+                  "must be different");
+        parse_err("while-held code=1 code=2 taps code=3 count=2 then: a", 1,
+                  "multiple \"code=\"s before \"taps\"");
+        parse_err("while-held code=1 taps code=2 then: a", 1,
+                  "needs a \"count=\"");
+        parse_err("while-held code=1 taps code=2 count=0 then: a", 1,
+                  "positive number of taps");
+        parse_err("while-held code=1 taps code=2 count=2 taps then: a", 1,
+                  "saw \"taps\"");
+    }
+
+    #[test]
+    fn hat() {
+        let config = parse("hat codeX=16 codeY=17 up-left then: a\n\
+                            hat dev=pad type=3 center codeY=17 codeX=16 \
+                            exec: b");
+        assert_eq!(config.hats[0], HatMatch {
+            wants_device: None, code_x: 16, code_y: 17, direction: (-1, -1),
+            options: CommandOptions::default(),
+            action: Action::Shell("a".to_owned()),
+            location: at(1),
+        });
+        assert_eq!(config.hats[1].to_string(),
+                   "hat dev=pad type=3 codeX=16 codeY=17 center exec: b");
+        parse_err("hat codeX=16 up then: a", 1, "needs a \"codeX=\"");
+        parse_err("hat codeX=16 codeY=17 then: a", 1, "needs a direction");
+        parse_err("hat codeX=16 codeY=17 up down then: a", 1,
+                  "only have one direction");
+        parse_err("hat type=1 codeX=16 codeY=17 up then: a", 1,
+                  "absolute axes");
+        parse_err("hat codeX=a codeY=17 up then: a", 1,
+                  "invalid \"codeX=\"");
+        parse_err("hat codeX=16 codeY=17 codeX=1 up then: a", 1,
+                  "multiple \"codeX=\"s");
+    }
+
+    #[test]
+    fn zone() {
+        let config = parse("zone codeX=53 codeY=54 x=0-20 y=80-100 then: a\n\
+                            zone dev=pad type=3 codeX=0 codeY=1 y=0-50 \
+                            exec: b");
+        assert_eq!(config.zones[0], ZoneMatch {
+            wants_device: None, code_x: 53, code_y: 54, x: (0, 20),
+            y: (80, 100), options: CommandOptions::default(),
+            action: Action::Shell("a".to_owned()),
+            location: at(1),
+        });
+        assert_eq!(config.zones[1].to_string(),
+                   "zone dev=pad type=3 codeX=0 codeY=1 x=0-100 y=0-50 \
+                    exec: b");
+        parse_err("zone codeX=53 then: a", 1, "needs a \"codeX=\"");
+        parse_err("zone codeX=53 codeY=54 x=50-150 then: a", 1,
+                  "two percentages");
+        parse_err("zone codeX=53 codeY=54 y=60-40 then: a", 1,
+                  "two percentages");
+        parse_err("zone codeX=53 codeY=54 x=1-2 x=3-4 then: a", 1,
+                  "multiple \"x=\"s");
+    }
+
+    #[test]
+    fn region() {
+        let config = parse("region type=3 codeX=0 codeY=1 x>=20000 y>=20000 \
+                            then: a\n\
+                            region dev=stick codeY=1 codeX=0 y<=100 \
+                            x<=-5 x>=-900 exec: b");
+        assert_eq!(config.regions[0], RegionMatch {
+            wants_device: None, code_x: 0, code_y: 1, x: (Some(20000), None),
+            y: (Some(20000), None), options: CommandOptions::default(),
+            action: Action::Shell("a".to_owned()),
+            location: at(1),
+        });
+        assert_eq!(config.regions[1].to_string(),
+                   "region dev=stick type=3 codeX=0 codeY=1 x>=-900 x<=-5 \
+                    y<=100 exec: b");
+        parse_err("region codeX=0 x>=1 then: a", 1, "needs a \"codeX=\"");
+        parse_err("region codeX=0 codeY=1 then: a", 1, "at least one");
+        parse_err("region codeX=0 codeY=1 x>=5 x<=4 then: a", 1,
+                  "region is empty");
+        parse_err("region codeX=0 codeY=1 x>=a then: a", 1, "invalid \"x>=\"");
+        parse_err("region codeX=0 codeY=1 x>=1 x>=2 then: a", 1,
+                  "multiple \"x>=\"s");
+        parse_err("region codeX=0 codeY=1 x=1-2 then: a", 1, "saw \"x=1-2\"");
+        parse_err("region type=1 codeX=0 codeY=1 x>=1 then: a", 1,
+                  "absolute axes");
+    }
+
+    #[test]
+    fn calibrate() {
+        let config = parse("calibrate type=3 code=0 center=140 min=10 \
+                            max=250\n\
+                            calibrate dev=pad code=1 min=0 max=255");
+        assert_eq!(config.calibrations[0], Calibration {
+            wants_device: None, wants_code: 0, center: 140, min: 10,
+            max: 250,
+            location: at(1),
+        });
+        assert_eq!(config.calibrations[1].to_string(),
+                   "calibrate dev=pad type=3 code=1 center=127 min=0 \
+                    max=255");
+        parse_err("calibrate code=0 center=5", 1, "needs a \"code=\"");
+        parse_err("calibrate code=0 center=300 min=0 max=255", 1,
+                  "wants \"min=\" < \"center=\"");
+        parse_err("calibrate code=0 min=0 max=1 then: a", 1, "saw \"then\"");
+    }
+
+    #[test]
+    fn between() {
+        let config = parse("if code=30 between=21:00-06:00 then: a\n\
+                            if code=31 between=9:30-17:00 then: b:c\n\
+                            if code=32 then:1:2\n");
+        let night = config.matches[0].between.unwrap();
+        assert_eq!(night, TimeWindow { start: 21 * 60, end: 6 * 60 });
+        assert!(night.contains(23 * 60) && night.contains(0));
+        assert!(night.contains(21 * 60) && !night.contains(6 * 60));
+        assert!(!night.contains(12 * 60));
+        let day = config.matches[1].between.unwrap();
+        assert!(day.contains(9 * 60 + 30) && !day.contains(17 * 60));
+        assert!(!day.contains(0));
+        assert_eq!(config.matches[1].to_string(),
+                   "if code=31 between=09:30-17:00 then: b:c");
+        // a colon between digits anywhere else still ends the options
+        assert_eq!(config.matches[2].action, Action::Shell("1:2".to_owned()));
+        parse_err("if between=9:00-9:00 then: a", 1, "two different times");
+        parse_err("if between=25:00-06:00 then: a", 1, "two different times");
+        parse_err("if between=21:00 then: a", 1, "two different times");
+        parse_err("if between=1:00-2:00 between=3:00-4:00 then: a", 1,
+                  "multiple");
+    }
+
+    #[test]
+    fn latch() {
+        let config = parse("latch shift code=42\n\
+                            latch \"pad shift\" dev=pad code=310\n\
+                            if code=30 with-latch=shift then: a\n");
+        assert_eq!(config.latches[0], Latch {
+            name: "shift".to_owned(), wants_device: None, wants_code: 42,
+            location: at(1),
+        });
+        assert_eq!(config.latches[1].to_string(),
+                   "latch \"pad shift\" dev=pad code=310");
+        assert_eq!(config.matches[0].with_latch, ["shift"]);
+        assert_eq!(config.matches[0].to_string(),
+                   "if code=30 with-latch=shift then: a");
+        parse_err("latch code=42", 1, "wants a name");
+        parse_err("latch shift", 1, "wants a name, then \"code=\"");
+        parse_err("latch shift code=42 code=54", 1, "multiple \"code=\"s");
+        parse_err("latch shift code=42 then: a", 1, "saw \"then\"");
+        parse_err("if with-latch= then: a", 1, "needs the name");
+    }
+
+    #[test]
+    fn min_interval() {
+        let config = parse("min-interval 100\nmin-interval 250 drop");
+        assert_eq!(config.min_interval, Some(MinInterval {
+            interval: Duration::from_millis(250), drop: true,
+        }));
+        assert_eq!(parse("min-interval 5").min_interval.unwrap().to_string(),
+                   "min-interval 5 delay");
+        parse_err("min-interval", 1, "wants a number of milliseconds");
+        parse_err("min-interval 1.5", 1, "wants a number of milliseconds");
+        parse_err("min-interval 10 later", 1, "saw \"later\"");
+        parse_err("min-interval 10 drop drop", 1, "too much");
+    }
+
+    #[test]
+    fn defaults() {
+        let config = parse("if then: a\n\
+                            defaults nice=3 shell=/bin/bash\n\
+                            if then: b\n\
+                            if nice=1 then: c\n\
+                            defaults cwd=/ nice=2\n\
+                            if shell=zsh then: d\n\
+                            map code=1 to=0-1 then: e\n");
+        assert_eq!(config.matches[0].options, CommandOptions::default());
+        assert_eq!(config.matches[1].to_string(),
+                   "if nice=3 shell=/bin/bash then: b");
+        // the line's own options win
+        assert_eq!(config.matches[2].to_string(),
+                   "if nice=1 shell=/bin/bash then: c");
+        // a later "defaults" only replaces the options it gives
+        assert_eq!(config.matches[3].to_string(),
+                   "if nice=2 cwd=/ shell=zsh then: d");
+        assert_eq!(config.maps[0].to_string(),
+                   "map type=3 code=1 to=0-1 nice=2 cwd=/ shell=/bin/bash \
+                    then: e");
+        parse_err("defaults type=1", 1, "saw \"type=1\"");
+        parse_err("defaults cwd=/nonexistent/directory", 1,
+                  "is not a directory");
+        parse_err("defaults nice=1 nice=2", 1, "multiple \"nice=\"s");
+    }
+
+    #[test]
+    fn expect_exit() {
+        let config = parse("if code=1 expect-exit=1 then: a\n\
+                            defaults expect-exit=0,3\n\
+                            if code=2 then: b\n\
+                            combo code=1 code=2 then: c\n");
+        assert_eq!(config.matches[0].options.expect_exit, Some(vec![1]));
+        assert_eq!(config.matches[1].to_string(),
+                   "if code=2 expect-exit=0,3 then: b");
+        assert_eq!(config.combos[0].options.expect_exit, Some(vec![0, 3]));
+        parse_err("if expect-exit=yes then: a", 1, "wants exit statuses");
+        parse_err("if expect-exit=1, then: a", 1, "wants exit statuses");
+        parse_err("if expect-exit=1 expect-exit=2 then: a", 1, "multiple");
+    }
+
+    #[test]
+    fn unknown_devices() {
+        let config = parse("dev pad: /dev/input/event3\n\
+                            dev /dev/input/event4\n\
+                            if dev=pad code=1 then: a\n\
+                            if dev=Pad code=2 then: b\n\
+                            if dev=/dev/input/event4 code=3 then: c\n\
+                            combo dev=pad code=4 dev=stick code=5 then: d\n\
+                            latch shift dev=kbd code=42\n");
+        assert_eq!(config.devices_named("pad"), Some(1));
+        assert_eq!(config.devices_named("/dev/input/event3"), Some(0));
+        assert_eq!(config.unknown_devices(), [
+            ("if dev=Pad code=2 then: b".to_owned(), "Pad".to_owned()),
+            ("combo dev=pad code=4 dev=stick code=5 then: d".to_owned(),
+             "stick".to_owned()),
+            ("latch shift dev=kbd code=42".to_owned(), "kbd".to_owned()),
+        ]);
+        // until they're found, any device could be one of these
+        let config = parse("dev type=gamepad\n\
+                            if dev=/dev/input/event9 then: a\n");
+        assert_eq!(config.devices_named("/dev/input/event9"), None);
+        assert!(config.unknown_devices().is_empty());
+    }
+
+    #[test]
+    fn hysteresis() {
+        let config = parse("if type=3 code=2 above=200 below=50 then: a\n\
+                            if code=5 below=-10 above=-5 then: b\n");
+        assert_eq!(config.matches[0].hysteresis, Some((200, 50)));
+        assert_eq!(config.matches[1].to_string(),
+                   "if code=5 above=-5 below=-10 then: b");
+        parse_err("if above=200 then: a", 1, "must be given together");
+        parse_err("if above=50 below=50 then: a", 1, "must be less than");
+        parse_err("if above=x below=1 then: a", 1, "\"above=\" wants a value");
+        parse_err("if above=2 above=3 below=1 then: a", 1, "multiple");
+        parse_err("unless above=2 below=1 then: a", 1, "can't go with");
+    }
+
+    #[test]
+    fn profiles() {
+        let config = parse("if code=1 profile=\"road trip\" then: a\n\
+                            profile racing { # for the racing games\n\
+                            \x20   if code=2 then: b\n\
+                            \n\
+                            \x20   unless code=3 then: c\n\
+                            }\n\
+                            if code=4 then: d\n\
+                            profile racing {\n\
+                            }\n\
+                            switch-profile racing dev=pad code=59\n");
+        assert_eq!(config.profiles, ["road trip", "racing"]);
+        let profiles: Vec<Option<&str>> = config.matches.iter()
+            .map(|x| x.profile.as_deref()).collect();
+        assert_eq!(profiles, [Some("road trip"), Some("racing"),
+                              Some("racing"), None]);
+        assert_eq!(config.matches[1].to_string(),
+                   "if code=2 profile=racing then: b");
+        assert_eq!(config.profile_switches[0].to_string(),
+                   "switch-profile racing dev=pad code=59");
+        parse_err("profile a {\nmap code=1 to=0-9 then: a\n}", 2,
+                  "only \"if\" and \"unless\" lines");
+        parse_err("profile a {\nprofile b {\n}", 2, "can't be nested");
+        parse_err("if code=1 then: a\nprofile a {\n", 2, "never closed");
+        parse_err("}", 1, "without a \"profile\" block");
+        parse_err("profile a", 1, "wants a name, then \"{\"");
+        parse_err("profile a {\nif profile=b then: a\n}", 2,
+                  "multiple \"profile=\"s");
+        parse_err("switch-profile a", 1, "wants a name, then \"code=\"");
+        parse_err("switch-profile a code=1 code=2", 1, "multiple \"code=\"s");
+        parse_err("switch-profile a code=1 nice=1", 1,
+                  "wanted \"dev=\" or \"code=\" after \"switch-profile\", \
+                   saw \"nice=1\"");
+    }
+
+    #[test]
+    fn env() {
+        let config = parse("defaults env=WHO=me env=ACTION=walk\n\
+                            if code=1 env=ACTION=jump then: a\n\
+                            if code=2 env=\"TEXT=a b\" env=EMPTY= then: b\n");
+        assert_eq!(config.matches[0].options.env,
+                   [("WHO".to_owned(), "me".to_owned()),
+                    ("ACTION".to_owned(), "jump".to_owned())]);
+        assert_eq!(config.matches[1].to_string(),
+                   "if code=2 env=WHO=me env=ACTION=walk \
+                    env=\"TEXT=a b\" env=EMPTY= then: b");
+        parse_err("if env=ACTION then: a", 1, "wants a variable name");
+        parse_err("if env=2X=y then: a", 1, "wants a variable name");
+        parse_err("if env=A=1 env=A=2 then: a", 1, "multiple \"env=A=\"s");
+    }
+
+    #[test]
+    fn idle() {
+        let config = parse("idle 1.5 then: dim\non-activity then: undim");
+        assert_eq!(config.idle, vec![IdleMatch {
+            after: Duration::from_millis(1500),
+            action: Action::Shell("dim".to_owned()),
+            location: at(1),
+        }]);
+        assert_eq!(config.on_activity,
+                   vec![Action::Shell("undim".to_owned())]);
+        parse_err("idle", 1, "wants a number of seconds");
+        parse_err("idle -1 then: a", 1, "invalid number of seconds");
+        parse_err("idle 1e30 then: x", 1, "invalid number of seconds");
+        parse_err("idle NaN then: x", 1, "invalid number of seconds");
+        parse_err("idle 1", 1, "needs a \"then\"");
+    }
+
+    #[test]
+    fn lifecycle_hooks() {
+        let config = parse("on-start then: led on\non-stop then: led off\n\
+                            on-start exec: logger started");
+        assert_eq!(config.on_start,
+                   vec![Action::Shell("led on".to_owned()),
+                        Action::Exec(vec!["logger".to_owned(),
+                                          "started".to_owned()])]);
+        assert_eq!(config.on_stop, vec![Action::Shell("led off".to_owned())]);
+        parse_err("on-stop", 1, "needs a \"then\"");
+    }
+
+    #[test]
+    fn unknown_directive() {
+        parse_err("# fine\nfi type=1 then: a", 2,
+                  "Unknown config directive \"fi\"");
+    }
+
+    #[test]
+    fn comments_and_colons() {
+        let config = parse("# a comment\n\
+                            \n\
+                            if type=1 then: echo a:b\n\
+                            if type=1 then:echo c\n\
+                            if type=1 then: echo hi # not a comment\n\
+                            if type=1 when=\"test -e #x\" exec: a\n\
+                            dev pad: /dev/input/event3 # a comment\n");
+        assert_eq!(config.matches[.. 3], [
+            shell_match(3, Some(1), None, None, "echo a:b"),
+            shell_match(4, Some(1), None, None, "echo c"),
+            shell_match(5, Some(1), None, None, "echo hi # not a comment"),
+        ]);
+        assert_eq!(config.matches[3].when.as_deref(), Some("test -e #x"));
+        assert_eq!(config.devices[0].path, "/dev/input/event3");
+        assert!(parse("#if type=1 then: a").matches.is_empty());
+        parse_err("if type=1 # then: a", 1, "needs a \"then\"");
+    }
+
+    #[test]
+    fn escapes() {
+        let config = parse(concat!(r#"if code=1 when="grep \"a b\" x" "#,
+                                   r#"exec: say "\"hi\"" "C:\x" "a\\" b"#));
+        assert_eq!(config.matches[0].when.as_deref(), Some(r#"grep "a b" x"#));
+        assert_eq!(config.matches[0].action,
+                   Action::Exec(vec!["say".to_owned(), r#""hi""#.to_owned(),
+                                     r"C:\x".to_owned(), r"a\".to_owned(),
+                                     "b".to_owned()]));
+        // words with quotes or backslashes write back out the same way
+        let again = parse(&config.matches[0].to_string());
+        assert_eq!(again.matches[0].when, config.matches[0].when);
+        assert_eq!(again.matches[0].action, config.matches[0].action);
+        assert_eq!(quote_word(r#"a"b"#), r#""a\"b""#);
+        assert_eq!(quote_word(r"a\b"), r"a\b");
+        assert_eq!(quote_word(r"a b\"), r#""a b\\""#);
+        parse_err(r#"if code=1 when="a\" then: b"#, 1, "unterminated");
+    }
+
+    #[test]
+    fn crlf() {
+        let config = parse("dev /dev/input/event3\r\n\
+                            if type=1 then: echo a \r\n\
+                            if type=1 then: echo b\r\r\n");
+        assert_eq!(config.devices[0].path, "/dev/input/event3");
+        assert_eq!(config.matches, vec![
+            shell_match(2, Some(1), None, None, "echo a"),
+            shell_match(3, Some(1), None, None, "echo b"),
+        ]);
+    }
+}
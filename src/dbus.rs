@@ -0,0 +1,601 @@
+//! Calling D-Bus methods directly, without running a program to do it. Only
+//! as much of the protocol as that takes is here: connecting to a bus,
+//! authenticating, sending a method call with simple arguments, and waiting
+//! for its reply.
+
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    os::unix::net::UnixStream,
+    time::Duration,
+};
+
+/// How long to wait for the reply to a call. (D-Bus's usual default.)
+const REPLY_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// The biggest message D-Bus allows.
+const MAX_MESSAGE: usize = 128 << 20;
+
+/// Which message bus to call a method on.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum Bus {
+    /// The bus for the current login session.
+    Session,
+    /// The bus for the whole system.
+    System,
+}
+
+impl std::fmt::Display for Bus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Bus::Session => "session",
+            Bus::System => "system",
+        })
+    }
+}
+
+/// A D-Bus method call to make when a match fires. (`then-dbus:`)
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct DBusCall {
+    /// The bus to make the call on.
+    pub bus: Bus,
+    /// The bus name of whoever should answer the call, like
+    /// `org.mpris.MediaPlayer2.vlc`.
+    pub destination: String,
+    /// The path of the object to call the method on.
+    pub path: String,
+    /// The interface the method belongs to.
+    pub interface: String,
+    /// The name of the method.
+    pub member: String,
+    /// The arguments, each written as `TYPE:VALUE` (like `int32:5`), the way
+    /// `dbus-send` takes them. Placeholders in them are filled in when the
+    /// call is made.
+    pub args: Vec<String>,
+}
+
+impl DBusCall {
+    /// Makes a call out of the words after `then-dbus:`: optionally `session`
+    /// or `system`, then the destination, the object path, the interface and
+    /// method (like `org.mpris.MediaPlayer2.Player.PlayPause`), and any
+    /// arguments.
+    pub fn from_words(words: &[String]) -> Result<DBusCall, String> {
+        let (bus, words) = match words.first().map(String::as_str) {
+            Some("session") => (Bus::Session, &words[1..]),
+            Some("system") => (Bus::System, &words[1..]),
+            _ => (Bus::Session, words),
+        };
+        if words.len() < 3 {
+            return Err("then-dbus: needs a destination, an object path, and \
+                        an interface and method".to_owned())
+        }
+        let destination = &words[0];
+        if !destination.contains('.') {
+            return Err(format!("then-dbus: {:?} is not a bus name",
+                               destination))
+        }
+        let path = &words[1];
+        if !path.starts_with('/') {
+            return Err(format!("then-dbus: {:?} is not an object path",
+                               path))
+        }
+        let (interface, member) = match words[2].rsplit_once('.') {
+            Some((a, b)) if !a.is_empty() && !b.is_empty() => (a, b),
+            _ => return Err(format!("then-dbus: {:?} is not an interface \
+                                     and method, like \
+                                     \"org.example.Thing.Method\"",
+                                    words[2])),
+        };
+        for arg in words[3..].iter() {
+            // placeholders can't be checked until they're filled in
+            let check = if arg.contains('{') {
+                arg.split_once(':').map(|(kind, _)| {
+                    if type_code(kind).is_some() { Ok(()) }
+                    else { Err(format!("unknown type {:?}", kind)) }
+                }).unwrap_or(Err("no type".to_owned()))
+            }
+            else {
+                put_arg(&mut Writer::default(), &mut String::new(), arg)
+            };
+            if let Err(x) = check {
+                return Err(format!("then-dbus: argument {:?}: {}", arg, x))
+            }
+        }
+        Ok(DBusCall {
+            bus,
+            destination: destination.clone(),
+            path: path.clone(),
+            interface: interface.to_owned(),
+            member: member.to_owned(),
+            args: words[3..].to_vec(),
+        })
+    }
+    /// The words [`from_words`](#method.from_words) would make this call out
+    /// of.
+    pub fn words(&self) -> Vec<String> {
+        let mut ret = vec![self.bus.to_string(), self.destination.clone(),
+                           self.path.clone(),
+                           format!("{}.{}", self.interface, self.member)];
+        ret.extend(self.args.iter().cloned());
+        ret
+    }
+}
+
+/// The type code for the given `dbus-send` type name.
+fn type_code(kind: &str) -> Option<char> {
+    Some(match kind {
+        "string" => 's',
+        "objpath" => 'o',
+        "boolean" => 'b',
+        "byte" => 'y',
+        "int16" => 'n',
+        "uint16" => 'q',
+        "int32" => 'i',
+        "uint32" => 'u',
+        "int64" => 'x',
+        "uint64" => 't',
+        "double" => 'd',
+        "variant" => 'v',
+        _ => return None,
+    })
+}
+
+/// Marshals one `TYPE:VALUE` argument onto the end of `body`, and adds its
+/// type to `signature`.
+fn put_arg(body: &mut Writer, signature: &mut String, arg: &str)
+           -> Result<(), String> {
+    let (kind, value) = arg.split_once(':')
+        .ok_or_else(|| "no type".to_owned())?;
+    let code = type_code(kind)
+        .ok_or_else(|| format!("unknown type {:?}", kind))?;
+    signature.push(code);
+    fn bad<T>(_: T) -> String { "bad value".to_owned() }
+    match code {
+        's' => body.string(value),
+        'o' => {
+            if !value.starts_with('/') {
+                return Err("bad object path".to_owned())
+            }
+            body.string(value)
+        },
+        'b' => body.u32(match value {
+            "true" => 1,
+            "false" => 0,
+            _ => return Err("wanted \"true\" or \"false\"".to_owned()),
+        }),
+        'y' => body.u8(value.parse().map_err(bad)?),
+        'n' => body.u16(value.parse::<i16>().map_err(bad)? as u16),
+        'q' => body.u16(value.parse().map_err(bad)?),
+        'i' => body.u32(value.parse::<i32>().map_err(bad)? as u32),
+        'u' => body.u32(value.parse().map_err(bad)?),
+        'x' => body.u64(value.parse::<i64>().map_err(bad)? as u64),
+        't' => body.u64(value.parse().map_err(bad)?),
+        'd' => body.u64(value.parse::<f64>().map_err(bad)?.to_bits()),
+        'v' => {
+            let inner = value.split_once(':')
+                .and_then(|(kind, _)| type_code(kind))
+                .ok_or_else(|| "bad value".to_owned())?;
+            body.signature(&inner.to_string());
+            put_arg(body, &mut String::new(), value)?;
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// A message being marshaled, in little-endian byte order.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn align(&mut self, n: usize) {
+        while !self.0.len().is_multiple_of(n) { self.0.push(0) }
+    }
+    fn u8(&mut self, x: u8) { self.0.push(x) }
+    fn u16(&mut self, x: u16) {
+        self.align(2);
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+    fn u32(&mut self, x: u32) {
+        self.align(4);
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+    fn u64(&mut self, x: u64) {
+        self.align(8);
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+    fn string(&mut self, x: &str) {
+        self.u32(x.len() as u32);
+        self.0.extend_from_slice(x.as_bytes());
+        self.0.push(0);
+    }
+    fn signature(&mut self, x: &str) {
+        self.u8(x.len() as u8);
+        self.0.extend_from_slice(x.as_bytes());
+        self.0.push(0);
+    }
+    /// Adds a header field whose value is a string of the given type.
+    fn field(&mut self, field: u8, code: &str, value: &str) {
+        self.align(8);
+        self.u8(field);
+        self.signature(code);
+        if code == "g" { self.signature(value) }
+        else { self.string(value) }
+    }
+}
+
+/// Marshals a method call, with the given serial number, and with a body
+/// already marshaled (starting at an 8-byte boundary) with the given
+/// signature.
+fn method_call(serial: u32, destination: &str, path: &str, interface: &str,
+               member: &str, signature: &str, body: &[u8]) -> Vec<u8> {
+    let mut ret = Writer::default();
+    // little-endian, method call, no flags, protocol version 1
+    ret.0.extend_from_slice(&[b'l', 1, 0, 1]);
+    ret.u32(body.len() as u32);
+    ret.u32(serial);
+    // the length of the header fields, filled in below
+    ret.u32(0);
+    ret.field(1, "o", path);
+    ret.field(2, "s", interface);
+    ret.field(3, "s", member);
+    ret.field(6, "s", destination);
+    if !signature.is_empty() { ret.field(8, "g", signature) }
+    let fields_len = (ret.0.len() - 16) as u32;
+    ret.0[12..16].copy_from_slice(&fields_len.to_le_bytes());
+    ret.align(8);
+    ret.0.extend_from_slice(body);
+    ret.0
+}
+
+/// Unmarshals a received message, in either byte order.
+struct Reader<'a> {
+    message: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, align: usize, n: usize) -> std::io::Result<&[u8]> {
+        self.pos = self.pos.next_multiple_of(align);
+        if self.pos + n > self.message.len() {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  "truncated message from the bus"))
+        }
+        self.pos += n;
+        Ok(&self.message[self.pos - n .. self.pos])
+    }
+    fn u8(&mut self) -> std::io::Result<u8> { Ok(self.take(1, 1)?[0]) }
+    fn u32(&mut self) -> std::io::Result<u32> {
+        let big_endian = self.big_endian;
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4, 4)?);
+        Ok(if big_endian { u32::from_be_bytes(bytes) }
+           else { u32::from_le_bytes(bytes) })
+    }
+    fn string(&mut self) -> std::io::Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(1, len + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+    fn signature(&mut self) -> std::io::Result<String> {
+        let len = self.u8()? as usize;
+        let bytes = self.take(1, len + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+}
+
+/// What we care about in a message we received.
+#[derive(Debug,PartialEq,Eq)]
+struct Incoming {
+    /// 2 for a method return, 3 for an error, 4 for a signal.
+    kind: u8,
+    /// The serial number of the call this is a reply to, if it's a reply.
+    reply_serial: Option<u32>,
+    /// The name of the error, and its message if it has one, if it's an error.
+    error: Option<(String, Option<String>)>,
+}
+
+/// Reads one message from the bus.
+fn read_message<R: Read>(stream: &mut R) -> std::io::Result<Incoming> {
+    let mut fixed = [0u8; 16];
+    stream.read_exact(&mut fixed)?;
+    let big_endian = match fixed[0] {
+        b'l' => false,
+        b'B' => true,
+        _ => return Err(Error::new(ErrorKind::InvalidData,
+                                   "garbled message from the bus")),
+    };
+    let mut reader = Reader { message: &fixed, pos: 4, big_endian };
+    let body_len = reader.u32()? as usize;
+    reader.u32()?;
+    let fields_len = reader.u32()? as usize;
+    let body_start = (16 + fields_len).next_multiple_of(8);
+    if body_start + body_len > MAX_MESSAGE {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              "oversized message from the bus"))
+    }
+    let mut message = vec![0; body_start + body_len];
+    message[..16].copy_from_slice(&fixed);
+    stream.read_exact(&mut message[16..])?;
+    let mut reader = Reader {
+        message: &message[..16 + fields_len], pos: 16, big_endian,
+    };
+    let mut ret = Incoming { kind: fixed[1], reply_serial: None, error: None };
+    let mut signature = String::new();
+    while reader.pos < reader.message.len() {
+        reader.take(8, 0)?;
+        let field = reader.u8()?;
+        match (field, reader.signature()?.as_str()) {
+            (4, "s") => ret.error = Some((reader.string()?, None)),
+            (5, "u") => ret.reply_serial = Some(reader.u32()?),
+            (8, "g") => signature = reader.signature()?,
+            (_, "s") | (_, "o") => { reader.string()?; },
+            (_, "u") => { reader.u32()?; },
+            (_, "g") => { reader.signature()?; },
+            _ => return Err(Error::new(ErrorKind::InvalidData,
+                                       "unexpected header field from the \
+                                        bus")),
+        }
+    }
+    if let Some((_, text)) = ret.error.as_mut() {
+        if signature.starts_with('s') {
+            let mut reader = Reader {
+                message: &message, pos: body_start, big_endian,
+            };
+            *text = Some(reader.string()?);
+        }
+    }
+    Ok(ret)
+}
+
+/// Finds the address of a bus, as the path of a socket (or, if it starts
+/// with `@`, a name in the abstract namespace).
+fn bus_socket(bus: Bus) -> std::io::Result<String> {
+    let (var, default) = match bus {
+        Bus::Session => ("DBUS_SESSION_BUS_ADDRESS", None),
+        Bus::System => ("DBUS_SYSTEM_BUS_ADDRESS",
+                        Some("unix:path=/var/run/dbus/system_bus_socket")),
+    };
+    let address = match (std::env::var(var), default) {
+        (Ok(x), _) => x,
+        (Err(_), Some(x)) => x.to_owned(),
+        (Err(_), None) => match std::env::var("XDG_RUNTIME_DIR") {
+            Ok(x) => format!("unix:path={}/bus", x),
+            Err(_) => return Err(Error::new(ErrorKind::NotFound,
+                                            format!("{} is not set", var))),
+        },
+    };
+    // several addresses may be given, to be tried in order; we only know how
+    // to use Unix sockets
+    for address in address.split(';') {
+        let params = match address.strip_prefix("unix:") {
+            Some(x) => x,
+            None => continue,
+        };
+        for param in params.split(',') {
+            if let Some(x) = param.strip_prefix("path=") {
+                return Ok(unescape(x))
+            }
+            if let Some(x) = param.strip_prefix("abstract=") {
+                return Ok(format!("@{}", unescape(x)))
+            }
+        }
+    }
+    Err(Error::new(ErrorKind::NotFound,
+                   format!("no usable address in {}", var)))
+}
+
+/// Undoes the `%XX` escapes in part of a bus address.
+fn unescape(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i+1..i+3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(x)) => { ret.push(x); i += 3 },
+            (x, _) => { ret.push(x); i += 1 },
+        }
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// A connection to a message bus, kept open so that each call doesn't have to
+/// set one up.
+struct Connection {
+    stream: UnixStream,
+    /// The serial number of the last message we sent.
+    serial: u32,
+}
+
+impl Connection {
+    /// Connects to a bus, and introduces ourselves.
+    fn open(bus: Bus) -> std::io::Result<Connection> {
+        use std::os::unix::net::SocketAddr;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+        let socket = bus_socket(bus)?;
+        let stream = match socket.strip_prefix('@') {
+            Some(name) => UnixStream::connect_addr(
+                &SocketAddr::from_abstract_name(name)?)?,
+            None => UnixStream::connect(&socket)?,
+        };
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        let mut ret = Connection { stream, serial: 0 };
+        ret.authenticate()?;
+        let serial = ret.send(&method_call(0, "org.freedesktop.DBus",
+                                           "/org/freedesktop/DBus",
+                                           "org.freedesktop.DBus", "Hello",
+                                           "", &[]))?;
+        ret.wait_for_reply(serial)?.map_err(Error::other)?;
+        Ok(ret)
+    }
+    /// Proves to the bus that we are who the kernel says we are.
+    fn authenticate(&mut self) -> std::io::Result<()> {
+        let uid = unsafe { libc::getuid() }.to_string();
+        let hex: String = uid.bytes().map(|x| format!("{:02x}", x)).collect();
+        self.stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", hex)
+                              .as_bytes())?;
+        // read the response a byte at a time, so as not to read past it
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while !line.ends_with(b"\r\n") {
+            self.stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+            if line.len() > 512 { break }
+        }
+        if !line.starts_with(b"OK ") {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                                  "the bus wouldn't let us in"))
+        }
+        self.stream.write_all(b"BEGIN\r\n")
+    }
+    /// Sends a message, giving it the next serial number. Returns that
+    /// number.
+    fn send(&mut self, message: &[u8]) -> std::io::Result<u32> {
+        self.serial = self.serial.checked_add(1).unwrap_or(1);
+        let mut message = message.to_vec();
+        message[8..12].copy_from_slice(&self.serial.to_le_bytes());
+        self.stream.write_all(&message)?;
+        Ok(self.serial)
+    }
+    /// Waits for the reply to the message with the given serial number,
+    /// ignoring anything else that arrives in the meantime. If the reply was
+    /// an error, returns a description of it.
+    fn wait_for_reply(&mut self, serial: u32)
+                      -> std::io::Result<Result<(), String>> {
+        loop {
+            let message = read_message(&mut self.stream)?;
+            if message.reply_serial != Some(serial) { continue }
+            return Ok(match message.error {
+                None => Ok(()),
+                Some((name, None)) => Err(name),
+                Some((name, Some(text))) => Err(format!("{}: {}", name, text)),
+            })
+        }
+    }
+}
+
+/// Connections to the message buses, opened when they're first needed.
+#[derive(Default)]
+pub struct DBusConnections {
+    session: Option<Connection>,
+    system: Option<Connection>,
+}
+
+impl DBusConnections {
+    /// Makes a call, with `args` (the call's arguments, with their
+    /// placeholders filled in), and waits for the reply. If the connection to
+    /// the bus turns out to have been lost, opens a new one and tries again.
+    /// Returns a description of what went wrong, if anything did. That
+    /// description is in `Err` if the call couldn't be made at all, or in
+    /// `Ok(Err(...))` if it was made but the reply was an error.
+    pub fn call(&mut self, call: &DBusCall, args: &[String])
+                -> Result<Result<(), String>, String> {
+        let mut signature = String::new();
+        let mut body = Writer::default();
+        for arg in args.iter() {
+            put_arg(&mut body, &mut signature, arg)
+                .map_err(|x| format!("argument {:?}: {}", arg, x))?;
+        }
+        let message = method_call(0, &call.destination, &call.path,
+                                  &call.interface, &call.member, &signature,
+                                  &body.0);
+        let slot = match call.bus {
+            Bus::Session => &mut self.session,
+            Bus::System => &mut self.system,
+        };
+        let sent = slot.as_mut().and_then(|x| x.send(&message).ok());
+        let serial = match sent {
+            Some(x) => x,
+            None => {
+                // never connected, or the connection went away
+                *slot = None;
+                let mut connection = Connection::open(call.bus)
+                    .map_err(|x| format!("connecting to the {} bus: {}",
+                                         call.bus, x))?;
+                let serial = connection.send(&message)
+                    .map_err(|x| x.to_string())?;
+                slot.get_or_insert(connection);
+                serial
+            },
+        };
+        let connection = slot.as_mut().unwrap();
+        let ret = connection.wait_for_reply(serial);
+        if ret.is_err() { *slot = None }
+        ret.map_err(|x| x.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn parsing() {
+        let call = DBusCall::from_words(&words(
+            "org.mpris.MediaPlayer2.vlc /org/mpris/MediaPlayer2 \
+             org.mpris.MediaPlayer2.Player.Seek int64:{value}")).unwrap();
+        assert_eq!(call.bus, Bus::Session);
+        assert_eq!(call.interface, "org.mpris.MediaPlayer2.Player");
+        assert_eq!(call.member, "Seek");
+        assert_eq!(call.words(), words(
+            "session org.mpris.MediaPlayer2.vlc /org/mpris/MediaPlayer2 \
+             org.mpris.MediaPlayer2.Player.Seek int64:{value}"));
+        assert_eq!(DBusCall::from_words(&call.words()).unwrap(), call);
+        for bad in &["org.a.B /x", "nodots /x a.B", "org.a.B x a.B",
+                     "org.a.B /x Method", "org.a.B /x a.B int32:x",
+                     "org.a.B /x a.B float:1", "org.a.B /x a.B {value}",
+                     "org.a.B /x a.B boolean:yes"] {
+            assert!(DBusCall::from_words(&words(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn marshaling() {
+        let mut body = Writer::default();
+        let mut signature = String::new();
+        for arg in &["byte:7", "string:hi", "boolean:true", "int16:-2",
+                     "variant:double:0.5"] {
+            put_arg(&mut body, &mut signature, arg).unwrap();
+        }
+        assert_eq!(signature, "ysbnv");
+        let mut expected = vec![7, 0, 0, 0, 2, 0, 0, 0, b'h', b'i', 0, 0,
+                                1, 0, 0, 0, 0xfe, 0xff, 1, b'd', 0, 0, 0, 0];
+        expected.extend_from_slice(&0.5f64.to_bits().to_le_bytes());
+        assert_eq!(body.0, expected);
+    }
+
+    #[test]
+    fn replies() {
+        // an error reply to serial 3, as a little-endian bus would send it
+        let mut message = Writer::default();
+        message.0.extend_from_slice(&[b'l', 3, 1, 1]);
+        message.u32(8);
+        message.u32(9);
+        message.u32(0);
+        message.field(4, "s", "org.example.Oops");
+        message.align(8);
+        message.u8(5);
+        message.signature("u");
+        message.u32(3);
+        message.field(8, "g", "s");
+        let fields_len = (message.0.len() - 16) as u32;
+        message.0[12..16].copy_from_slice(&fields_len.to_le_bytes());
+        message.align(8);
+        message.string("bad");
+        assert_eq!(read_message(&mut &message.0[..]).unwrap(), Incoming {
+            kind: 3, reply_serial: Some(3),
+            error: Some(("org.example.Oops".to_owned(),
+                         Some("bad".to_owned()))),
+        });
+        assert!(read_message(&mut &message.0[..20]).is_err());
+        assert_eq!(unescape("/tmp/a%2cb%"), "/tmp/a,b%");
+    }
+}
@@ -0,0 +1,91 @@
+//! Collapsing identical events that arrive from several devices at once.
+
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{DeviceEvent, InputEvent};
+
+/// Remembers recent events, so that an event that another device already
+/// reported can be ignored. Some hardware shows up as more than one device,
+/// and reports every event on each of them.
+pub struct Deduplicator {
+    /// How close together two identical events must be to count as the same.
+    window: Duration,
+    /// Every event seen within the last `window`, oldest first.
+    recent: VecDeque<DeviceEvent>,
+}
+
+/// Returns the event's timestamp as a `Duration` since the epoch (or since
+/// whenever the device's clock started).
+pub(crate) fn timestamp(event: &InputEvent) -> Duration {
+    Duration::from_secs(event.time.tv_sec.max(0) as u64)
+        + Duration::from_micros(event.time.tv_usec.max(0) as u64)
+}
+
+impl Deduplicator {
+    /// Makes a new `Deduplicator` that collapses identical events that are
+    /// at most `window` apart.
+    pub fn new(window: Duration) -> Deduplicator {
+        Deduplicator { window, recent: VecDeque::new() }
+    }
+    /// Returns `true` if a *different* device reported an event with the same
+    /// type, code, and value within the window. Either way, remembers the
+    /// event for next time. Uses the timestamps the kernel put on the events,
+    /// so time spent waiting in the queue doesn't matter.
+    pub fn is_duplicate(&mut self, event: &DeviceEvent) -> bool {
+        let now = timestamp(&event.event);
+        while let Some(old) = self.recent.front() {
+            if now.saturating_sub(timestamp(&old.event)) <= self.window {
+                break
+            }
+            self.recent.pop_front();
+        }
+        let ret = self.recent.iter().any(|old| {
+            !Arc::ptr_eq(&old.device, &event.device)
+                && old.event.type_ == event.event.type_
+                && old.event.code == event.event.code
+                && old.event.value == event.event.value
+        });
+        self.recent.push_back(event.clone());
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{device, event};
+
+    /// Makes an event at the given number of milliseconds.
+    fn event_at(device: &Arc<crate::DeviceSpec>, value: i32, millis: i64)
+                -> DeviceEvent {
+        let mut ret = event(device, 1, 30, value);
+        ret.event.time.tv_sec = millis / 1000;
+        ret.event.time.tv_usec = (millis % 1000) * 1000;
+        ret
+    }
+
+    #[test]
+    fn collapses_mirrored_events() {
+        let a = device(Some("a"));
+        let b = device(Some("b"));
+        let mut dedup = Deduplicator::new(Duration::from_millis(5));
+        assert!(!dedup.is_duplicate(&event_at(&a, 1, 1000)));
+        assert!(dedup.is_duplicate(&event_at(&b, 1, 1003)));
+        // different value
+        assert!(!dedup.is_duplicate(&event_at(&b, 0, 1004)));
+        // too late
+        assert!(!dedup.is_duplicate(&event_at(&b, 1, 1010)));
+    }
+
+    #[test]
+    fn same_device_is_never_a_duplicate() {
+        let a = device(None);
+        let mut dedup = Deduplicator::new(Duration::from_millis(5));
+        assert!(!dedup.is_duplicate(&event_at(&a, 1, 1000)));
+        assert!(!dedup.is_duplicate(&event_at(&a, 1, 1001)));
+    }
+}
@@ -0,0 +1,1608 @@
+//! Reading events from input devices.
+
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs::File,
+    io::{Read, BufReader, Seek, SeekFrom},
+    os::unix::{
+        fs::{FileExt, FileTypeExt},
+        io::{AsRawFd, FromRawFd},
+    },
+    sync::{Arc, Condvar, Mutex, atomic::{AtomicBool, AtomicU32, Ordering}},
+    thread::{sleep, spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ConfigError, InputEvent, DeviceSpec, EventSender, Message, VirtualPolicy,
+    codes::*,
+    config::{json_string, quote_word},
+    ioctl::{abs_info, device_id, event_bits, grab, key_state},
+};
+
+/// Settings that affect how devices are opened and read, as opposed to what
+/// happens to the events once they've been read.
+#[derive(Clone,Debug)]
+pub struct DeviceOptions {
+    /// Whether to read from virtual devices, for devices that don't say.
+    pub virtual_policy: VirtualPolicy,
+    /// How many times in a row to restart a device's reader thread if it
+    /// panics, before giving up on the device.
+    pub max_restarts: u32,
+    /// Whether to throw away any events that are already waiting when a
+    /// device is opened (or reopened), so that only new input counts.
+    pub drain_on_start: bool,
+    /// Whether to make sure each device is really an event device that can be
+    /// read from, right after opening it, and fail if not.
+    pub check_readable: bool,
+    /// Event types to throw away as soon as they're read, without sending
+    /// them on. `SYN_REPORT`s are always sent on anyway.
+    pub ignored_types: Vec<u16>,
+    /// If not `None`, whenever a device has been quiet for this long, check
+    /// whether another program has grabbed it, and warn if so. A device we
+    /// were meant to grab, but couldn't, is grabbed then if it's free.
+    pub grab_check: Option<Duration>,
+    /// How many times to read each regular file through, starting over from
+    /// the beginning each time it ends, or `None` to keep starting over until
+    /// we're told to stop. Pipes and real devices can't start over, so
+    /// they're only ever read once.
+    pub replay_passes: Option<u32>,
+    /// How the events in files and pipes are laid out, or `None` to work it
+    /// out for each regular file from what's in it. Real devices always use
+    /// `EventFormat::NATIVE`.
+    pub event_format: Option<EventFormat>,
+    /// Counts the times that reader threads for devices opened with these
+    /// options (or a clone of them) have been restarted after crashing.
+    pub restart_count: Arc<AtomicU32>,
+    /// Whether to ask each device, right after opening it, which of its keys
+    /// are already down and where its absolute axes already are. (See
+    /// `DeviceHandle::initial_state`.)
+    pub probe_initial_state: bool,
+    /// If not `None`, the `SCHED_FIFO` priority (1 to 99) to run reader
+    /// threads at, so that events are read as soon as they happen, however
+    /// busy the machine is.
+    pub realtime: Option<i32>,
+}
+
+impl Default for DeviceOptions {
+    fn default() -> DeviceOptions {
+        DeviceOptions {
+            virtual_policy: VirtualPolicy::default(),
+            max_restarts: 5,
+            drain_on_start: false,
+            check_readable: false,
+            ignored_types: Vec::new(),
+            grab_check: None,
+            replay_passes: Some(1),
+            event_format: None,
+            restart_count: Arc::new(AtomicU32::new(0)),
+            probe_initial_state: false,
+            realtime: None,
+        }
+    }
+}
+
+/// How an event is laid out in bytes. The kernel's `input_event` starts with
+/// a `timeval` made of two `long`s, so it's a different size on 32-bit and
+/// 64-bit machines, and a recording made on one doesn't make sense read as
+/// the other. Either way, the numbers are in this machine's byte order.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum EventFormat {
+    /// 24 bytes: 64-bit seconds and microseconds, then the type, code, and
+    /// value. What 64-bit machines use.
+    Bits64,
+    /// 16 bytes: 32-bit seconds and microseconds, then the type, code, and
+    /// value. What 32-bit machines use (even ones whose `time_t` is 64-bit,
+    /// since the kernel doesn't use `time_t` here).
+    Bits32,
+}
+
+/// The size of the biggest `EventFormat`.
+const MAX_EVENT_SIZE: usize = 24;
+
+impl EventFormat {
+    /// The format this machine's kernel uses.
+    pub const NATIVE: EventFormat =
+        if std::mem::size_of::<libc::c_long>() == 8 { EventFormat::Bits64 }
+        else { EventFormat::Bits32 };
+    /// Returns how many bytes an event takes up.
+    pub const fn size(self) -> usize {
+        match self {
+            EventFormat::Bits64 => 24,
+            EventFormat::Bits32 => 16,
+        }
+    }
+    /// Takes apart the event in `buf`, which holds exactly `self.size()`
+    /// bytes.
+    fn decode(self, buf: &[u8]) -> InputEvent {
+        let u16_at = |at: usize| u16::from_ne_bytes([buf[at], buf[at + 1]]);
+        let i32_at = |at: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&buf[at .. at + 4]);
+            i32::from_ne_bytes(bytes)
+        };
+        let i64_at = |at: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&buf[at .. at + 8]);
+            i64::from_ne_bytes(bytes)
+        };
+        let (sec, usec, rest) = match self {
+            EventFormat::Bits64 => (i64_at(0), i64_at(8), 16),
+            EventFormat::Bits32 => (i32_at(0) as i64, i32_at(4) as i64, 8),
+        };
+        InputEvent {
+            time: libc::timeval {
+                tv_sec: sec as libc::time_t,
+                tv_usec: usec as libc::suseconds_t,
+            },
+            type_: u16_at(rest),
+            code: u16_at(rest + 2),
+            value: i32_at(rest + 4),
+        }
+    }
+    /// Works out which format the events at the start of a file are in,
+    /// given the first few hundred bytes (`head`) and the file's total
+    /// length. A format fits if the file is a whole number of events in it,
+    /// and every event `head` holds has a sensible time and type. If both
+    /// fit (or neither does), guesses `NATIVE`.
+    fn detect(head: &[u8], length: u64) -> EventFormat {
+        let fits = |format: EventFormat| {
+            length.is_multiple_of(format.size() as u64)
+                && head.chunks_exact(format.size()).all(|x| {
+                    let event = format.decode(x);
+                    event.time.tv_sec >= 0 && event.time.tv_usec >= 0
+                        && event.time.tv_usec < 1_000_000
+                        && event.type_ < EV_CNT
+                })
+        };
+        let other = match EventFormat::NATIVE {
+            EventFormat::Bits64 => EventFormat::Bits32,
+            EventFormat::Bits32 => EventFormat::Bits64,
+        };
+        if !fits(EventFormat::NATIVE) && fits(other) { other }
+        else { EventFormat::NATIVE }
+    }
+}
+
+impl std::str::FromStr for EventFormat {
+    type Err = ();
+    fn from_str(s: &str) -> Result<EventFormat, ()> {
+        match s {
+            "64" => Ok(EventFormat::Bits64),
+            "32" => Ok(EventFormat::Bits32),
+            "native" => Ok(EventFormat::NATIVE),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A device that we're reading from.
+pub struct DeviceHandle {
+    /// Every "dev" line that named the device. There's more than one if
+    /// several lines gave the same path (with different labels, say), since
+    /// each device is only opened once.
+    devices: Vec<Arc<DeviceSpec>>,
+    /// Set to tell the reader to stop.
+    stop: Arc<AtomicBool>,
+    /// Set, and notified, once the reader has stopped and the device is
+    /// closed.
+    finished: Arc<(Mutex<bool>, Condvar)>,
+    /// What the device said about its state when it was opened.
+    initial_state: Vec<DeviceEvent>,
+}
+
+impl DeviceHandle {
+    /// Returns every "dev" line that named the device. They all have the same
+    /// path.
+    pub fn devices(&self) -> &[Arc<DeviceSpec>] { &self.devices }
+    /// If `DeviceOptions::probe_initial_state` was set, returns events that
+    /// describe the device as it was when it was opened: a press for each key
+    /// that was down, and the value of each absolute axis, for each of
+    /// `devices`. Feeding them to whatever tracks the device's state brings
+    /// it up to date, without waiting for the keys to be pressed again.
+    /// Otherwise, or if the device couldn't say, returns nothing.
+    pub fn initial_state(&self) -> &[DeviceEvent] { &self.initial_state }
+    /// Tells the reader to stop reading from the device. It doesn't send any
+    /// more events after this, and it notices within `STOP_CHECK` (or, if
+    /// it's waiting to restart after a crash, once it's done waiting). When
+    /// it does, it closes the device and sends a `Message::Finished`.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+    /// Waits up to `timeout` for the reader to finish, as it does after
+    /// [`stop`](#method.stop), so that the device is closed (and let go, if
+    /// we had it grabbed) before it's opened again. Returns `false` if the
+    /// reader is still going.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (finished, changed) = &*self.finished;
+        let finished = finished.lock().unwrap();
+        *changed.wait_timeout_while(finished, timeout, |x| !*x).unwrap().0
+    }
+}
+
+/// Opens each of the given devices and spawns a reader thread for it that
+/// sends events via `event_sender`. Devices that `options` (or the device's
+/// own settings) say to skip are skipped, with a note on stderr. Returns a
+/// handle for each device that wasn't skipped.
+///
+/// Each path is only opened once, however many devices give it; each event
+/// read from it is sent once for each of them. The device is grabbed if any
+/// of them asks for that, with a warning if they don't all agree. Likewise,
+/// the first of them with a `queue=` limits how many of its events can wait
+/// in the queue.
+///
+/// When a reader stops for good (because the device went away, or it crashed
+/// too often, or it was told to stop), it sends a `Message::Finished`.
+pub fn open_devices(devices: &[DeviceSpec], event_sender: &EventSender,
+                    options: &DeviceOptions)
+                    -> Result<Vec<DeviceHandle>, ConfigError> {
+    let mut paths: Vec<&str> = Vec::with_capacity(devices.len());
+    for device in devices.iter() {
+        if !paths.contains(&device.path.as_str()) { paths.push(&device.path) }
+    }
+    let mut handles = Vec::with_capacity(paths.len());
+    for path in paths.into_iter() {
+        let named: Vec<&DeviceSpec> = devices.iter()
+            .filter(|x| x.path == path).collect();
+        let first = named[0];
+        let dev_file = File::open(path)
+            .map_err(|x| device_error(first, format!(
+                "opening device {:?}: {}{}", path, x,
+                if x.kind() == std::io::ErrorKind::PermissionDenied {
+                    " (are you in the \"input\" group?)"
+                } else { "" })))?;
+        if options.check_readable { check_readable(first, &dev_file)? }
+        // if we can't ask, it's probably not a device at all, and certainly
+        // not a virtual one
+        let is_virtual = device_id(dev_file.as_raw_fd())
+            .map(|x| x.bustype == BUS_VIRTUAL).unwrap_or(false);
+        let named: Vec<Arc<DeviceSpec>> = named.into_iter().filter(|device| {
+            let skip = match device.virtual_policy
+                .unwrap_or(options.virtual_policy) {
+                    VirtualPolicy::Include => false,
+                    VirtualPolicy::Exclude => is_virtual,
+                    VirtualPolicy::Only => !is_virtual,
+                };
+            if skip {
+                eprintln!("Not reading from {:?} for {}, because it is {}a \
+                           virtual device", path, device.location,
+                          if is_virtual { "" } else { "not " });
+            }
+            !skip
+        }).map(|x| Arc::new(x.clone())).collect();
+        if named.is_empty() { continue }
+        let wants_grab = named.iter().find(|x| x.grab);
+        if let (Some(grabber), Some(watcher))
+        = (wants_grab, named.iter().find(|x| !x.grab)) {
+            eprintln!("Warning: grabbing {:?}, as {} asks, even though {} \
+                       doesn't. No other program will see its events.",
+                      path, grabber.location, watcher.location);
+        }
+        let limiter = named.iter().find(|x| x.queue.is_some());
+        let sender = match limiter {
+            Some(limiter) => {
+                if let Some(other) = named.iter().find(|x| {
+                    x.queue.is_some() && (x.queue, x.queue_policy)
+                        != (limiter.queue, limiter.queue_policy)
+                }) {
+                    eprintln!("Warning: {} and {} give {:?} different \
+                               queues. Using the first one.",
+                              limiter.location, other.location, path);
+                }
+                event_sender.limited(limiter.queue.unwrap(),
+                                     limiter.queue_policy)
+            },
+            None => event_sender.clone(),
+        };
+        let mut rates = named.iter().filter(|x| x.max_rate.is_some());
+        if let Some(first) = rates.next() {
+            if let Some(other) = rates.find(|x| x.max_rate != first.max_rate) {
+                eprintln!("Warning: {} and {} give {:?} different maxrates. \
+                           Using the lowest one.",
+                          first.location, other.location, path);
+            }
+        }
+        if options.drain_on_start { drain(path, &dev_file) }
+        let grab = if wants_grab.is_some() { grab_device(path, &dev_file) }
+        else { Grab::No };
+        let initial_state = if options.probe_initial_state {
+            probe_state(&named, &dev_file)
+        } else { Vec::new() };
+        let mut handle = spawn_reader(named, dev_file, sender, options, grab);
+        handle.initial_state = initial_state;
+        handles.push(handle);
+    }
+    Ok(handles)
+}
+
+/// Where [`find_devices`](fn.find_devices.html) looks for devices.
+const INPUT_DIR: &str = "/dev/input";
+
+/// What an event device says it can send.
+struct Capabilities {
+    /// For each event type, a bitmap of the codes of that type, or an empty
+    /// bitmap if the device doesn't send that type at all.
+    bits: Vec<Vec<u8>>,
+}
+
+impl Capabilities {
+    /// Asks the device what it can send. Fails if it isn't an event device.
+    fn probe(dev_file: &File) -> std::io::Result<Capabilities> {
+        let fd = dev_file.as_raw_fd();
+        let mut types = [0u8; (EV_CNT as usize).div_ceil(8)];
+        event_bits(fd, 0, &mut types)?;
+        let mut bits = vec![Vec::new(); EV_CNT as usize];
+        for (type_, codes) in bits.iter_mut().enumerate().skip(1) {
+            if types[type_ / 8] & (1 << (type_ % 8)) == 0 { continue }
+            // enough for every key, the type with the most codes
+            let mut buf = vec![0u8; 0x300 / 8];
+            let len = event_bits(fd, type_ as u16, &mut buf)?;
+            buf.truncate(len);
+            *codes = buf;
+        }
+        Ok(Capabilities { bits })
+    }
+    /// Returns true if the device can send the given type and code.
+    fn can_send(&self, type_: u16, code: u16) -> bool {
+        let codes = match self.bits.get(type_ as usize) {
+            Some(x) => x,
+            None => return false,
+        };
+        let code = code as usize;
+        codes.get(code / 8).map(|x| x & (1 << (code % 8)) != 0)
+            .unwrap_or(false)
+    }
+}
+
+/// Asks the device which of its keys are down and where its absolute axes
+/// are, and returns that as events from each of `devices` (which all name
+/// it). Multitouch axes are left out, since their values only mean anything
+/// slot by slot. Returns nothing if the device can't say (if it's really a
+/// file, say).
+fn probe_state(devices: &[Arc<DeviceSpec>], dev_file: &File)
+               -> Vec<DeviceEvent> {
+    let fd = dev_file.as_raw_fd();
+    let capabilities = match Capabilities::probe(dev_file) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+    let mut found = Vec::new();
+    let mut keys = vec![0u8; 0x300 / 8];
+    if let Ok(len) = key_state(fd, &mut keys) {
+        for code in 0 .. len * 8 {
+            if keys[code / 8] & (1 << (code % 8)) != 0 {
+                found.push((EV_KEY, code as u16, 1, None));
+            }
+        }
+    }
+    for code in (0 .. ABS_MT_SLOT).filter(|x| {
+        capabilities.can_send(EV_ABS, *x)
+    }) {
+        if let Ok(info) = abs_info(fd, code) {
+            found.push((EV_ABS, code, info.value,
+                        Some((info.minimum, info.maximum))));
+        }
+    }
+    devices.iter().flat_map(|device| {
+        found.iter().map(move |&(type_, code, value, range)| DeviceEvent {
+            device: device.clone(),
+            event: InputEvent {
+                time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+                type_, code, value,
+            },
+            range,
+        })
+    }).collect()
+}
+
+/// What we've seen of a device's keys and absolute axes, so that when the
+/// kernel drops events, we can tell which changes we missed.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+struct Seen {
+    /// A bitmap of the keys that are down, as `EVIOCGKEY` gives it, or empty
+    /// if the device can't say.
+    keys: Vec<u8>,
+    /// The value of each absolute axis other than the multitouch ones, by
+    /// code, if known.
+    axes: Vec<Option<i32>>,
+}
+
+impl Seen {
+    /// Asks the device what state it's in now. Whatever it can't tell us (or
+    /// doesn't have) is left unknown.
+    fn probe(fd: std::os::unix::io::RawFd) -> Seen {
+        let mut keys = vec![0u8; 0x300 / 8];
+        match key_state(fd, &mut keys) {
+            Ok(len) => keys.truncate(len),
+            Err(_) => keys.clear(),
+        }
+        let axes = (0 .. ABS_MT_SLOT).map(|code| {
+            abs_info(fd, code).ok().map(|x| x.value)
+        }).collect();
+        Seen { keys, axes }
+    }
+    /// Takes note of an event that's being passed on.
+    fn note(&mut self, event: &InputEvent) {
+        let code = event.code as usize;
+        let bit = 1 << (code % 8);
+        match event.type_ {
+            EV_KEY if code / 8 < self.keys.len() => {
+                if event.value == 0 { self.keys[code / 8] &= !bit }
+                else { self.keys[code / 8] |= bit }
+            },
+            EV_ABS if code < self.axes.len() => {
+                self.axes[code] = Some(event.value)
+            },
+            _ => (),
+        }
+    }
+    /// Takes note of the state the device is in `now` (as `probe` found
+    /// it), and returns an event for each change since what we'd seen: a
+    /// press or release for each key, and the new value of each axis, at
+    /// `time`. Whatever `now` doesn't know is left as it was.
+    fn catch_up(&mut self, now: &Seen, time: libc::timeval)
+                -> Vec<InputEvent> {
+        let event = |type_, code: usize, value| InputEvent {
+            time, type_, code: code as u16, value,
+        };
+        let mut ret = Vec::new();
+        if self.keys.len() < now.keys.len() {
+            self.keys.resize(now.keys.len(), 0);
+        }
+        for (i, (old, new)) in self.keys.iter_mut().zip(now.keys.iter())
+        .enumerate() {
+            for bit in (0 .. 8).filter(|x| (*old ^ *new) & (1 << x) != 0) {
+                ret.push(event(EV_KEY, i * 8 + bit,
+                               (*new >> bit & 1) as i32));
+            }
+            *old = *new;
+        }
+        if self.axes.len() < now.axes.len() {
+            self.axes.resize(now.axes.len(), None);
+        }
+        for (code, (old, new)) in self.axes.iter_mut().zip(now.axes.iter())
+        .enumerate() {
+            if new.is_some() && new != old {
+                ret.push(event(EV_ABS, code, new.unwrap()));
+                *old = *new;
+            }
+        }
+        ret
+    }
+}
+
+/// Returns the paths of the event devices in `dir`, in order of their
+/// numbers (so `event2` comes before `event10`).
+fn event_device_paths(dir: &str) -> std::io::Result<Vec<String>> {
+    let mut numbered: Vec<(u32, String)> = std::fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            let number = name.strip_prefix("event")?.parse().ok()?;
+            Some((number, format!("{}/{}", dir, name)))
+        }).collect();
+    numbered.sort();
+    Ok(numbered.into_iter().map(|x| x.1).collect())
+}
+
+/// Returns the paths of the event devices in `/dev/input` right now, in
+/// order of their numbers.
+pub fn present_devices() -> Vec<String> {
+    event_device_paths(INPUT_DIR).unwrap_or_default()
+}
+
+/// Returns the paths in `/dev/input/by-id` of the event devices there right
+/// now, sorted. These stay the same from boot to boot, unlike the numbered
+/// paths, so they're the ones to put in a configuration file.
+pub fn devices_by_id() -> Vec<String> {
+    let dir = format!("{}/by-id", INPUT_DIR);
+    let mut paths: Vec<String> = match std::fs::read_dir(&dir) {
+        Ok(x) => x.filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            if !name.contains("-event-") { return None }
+            Some(format!("{}/{}", dir, name))
+        }).collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+/// Replaces each device that picks devices by what they can do (that has a
+/// `query`) with a copy for each event device in `/dev/input` that it wants,
+/// with the copy's `path` filled in. Other devices are left as they are.
+/// Devices that can't be opened are passed over, and a line that doesn't
+/// find any devices gets a warning on stderr.
+pub fn find_devices(devices: &[DeviceSpec]) -> Vec<DeviceSpec> {
+    if devices.iter().all(|x| x.query.is_none()) { return devices.to_vec() }
+    // whether any devices were passed over because we aren't allowed to
+    // open them
+    let mut denied = false;
+    let paths = event_device_paths(INPUT_DIR).unwrap_or_else(|x| {
+        eprintln!("Couldn't look for devices in {:?}: {}", INPUT_DIR, x);
+        Vec::new()
+    });
+    let found: Vec<(String, Capabilities)> = paths.into_iter()
+        .filter_map(|path| {
+            let dev_file = match File::open(&path) {
+                Ok(x) => x,
+                Err(x) => {
+                    if x.kind() == std::io::ErrorKind::PermissionDenied {
+                        denied = true;
+                    }
+                    return None
+                },
+            };
+            Some((path, Capabilities::probe(&dev_file).ok()?))
+        }).collect();
+    let mut ret = Vec::with_capacity(devices.len());
+    for device in devices.iter() {
+        let query = match device.query.as_ref() {
+            Some(x) => x,
+            None => { ret.push(device.clone()); continue },
+        };
+        let count = ret.len();
+        for (path, capabilities) in found.iter() {
+            if !query.wants(|type_, code| capabilities.can_send(type_, code)) {
+                continue
+            }
+            ret.push(DeviceSpec { path: path.clone(), ..device.clone() });
+        }
+        if ret.len() == count {
+            eprintln!("Warning: no device in {:?} is what {} wants{}",
+                      INPUT_DIR, device.location,
+                      if denied { " (are you in the \"input\" group?)" }
+                      else { "" });
+        }
+    }
+    ret
+}
+
+/// Returns a copy of each of `devices` that wants the event device at
+/// `path`: each line with a `query` that wants what the device can do (with
+/// its `path` filled in), and each other line whose path leads to the same
+/// device (through a symlink in `/dev/input/by-id`, say). Returns nothing if
+/// the device can't be opened.
+pub fn devices_at(path: &str, devices: &[DeviceSpec]) -> Vec<DeviceSpec> {
+    let capabilities = match File::open(path)
+        .and_then(|x| Capabilities::probe(&x)) {
+            Ok(x) => x,
+            Err(_) => return Vec::new(),
+        };
+    let real_path = std::fs::canonicalize(path).ok();
+    devices.iter().filter_map(|device| match device.query.as_ref() {
+        Some(query) => {
+            let wanted = query.wants(|type_, code| {
+                capabilities.can_send(type_, code)
+            });
+            wanted.then(|| DeviceSpec {
+                path: path.to_owned(), ..device.clone()
+            })
+        },
+        None => {
+            (real_path.is_some()
+             && std::fs::canonicalize(&device.path).ok() == real_path)
+                .then(|| device.clone())
+        },
+    }).collect()
+}
+
+/// Watches `/dev/input` for event devices that appear, and sends a
+/// `Message::DeviceAppeared` with the path of each one, from a thread of its
+/// own. Devices whose permissions change are sent too, since a new device
+/// often can't be opened until udev has given it the right ones. Fails if
+/// the directory can't be watched.
+pub fn watch_devices(event_sender: EventSender) -> std::io::Result<()> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 { return Err(std::io::Error::last_os_error()) }
+    let mut inotify = unsafe { File::from_raw_fd(fd) };
+    let dir = CString::new(INPUT_DIR).unwrap();
+    if unsafe {
+        libc::inotify_add_watch(fd, dir.as_ptr(),
+                                libc::IN_CREATE | libc::IN_ATTRIB)
+    } < 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = match inotify.read(&mut buf) {
+                Ok(x) => x,
+                Err(x) if x.kind() == std::io::ErrorKind::Interrupted => {
+                    continue
+                },
+                Err(x) => {
+                    eprintln!("Stopped watching {:?} for new devices: {}",
+                              INPUT_DIR, x);
+                    return
+                },
+            };
+            for name in inotify_names(&buf[..len]) {
+                match name.strip_prefix("event").map(str::parse::<u32>) {
+                    Some(Ok(_)) => (),
+                    _ => continue,
+                }
+                let path = format!("{}/{}", INPUT_DIR, name);
+                if !event_sender.send_message(Message::DeviceAppeared(path)) {
+                    return
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Takes apart what was read from an inotify file descriptor (some whole
+/// `inotify_event`s, each followed by a name padded with NULs), returning
+/// the name from each one.
+fn inotify_names(buf: &[u8]) -> Vec<String> {
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut ret = Vec::new();
+    let mut at = 0;
+    while at + header <= buf.len() {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&buf[at + header - 4 .. at + header]);
+        let end = (at + header + u32::from_ne_bytes(len) as usize)
+            .min(buf.len());
+        let name = buf[at + header .. end].split(|x| *x == 0).next()
+            .unwrap_or(&[]);
+        ret.push(String::from_utf8_lossy(name).into_owned());
+        at = end;
+    }
+    ret
+}
+
+/// Makes a `ConfigError` pointing at the line that named `device`.
+fn device_error(device: &DeviceSpec, message: String) -> ConfigError {
+    ConfigError {
+        file: device.location.file.clone(),
+        line: Some(device.location.line),
+        message,
+    }
+}
+
+/// Makes sure that `dev_file` is an event device, and that reading from it
+/// isn't going to fail straight away, without actually reading anything.
+fn check_readable(device: &DeviceSpec, dev_file: &File)
+                  -> Result<(), ConfigError> {
+    let fd = dev_file.as_raw_fd();
+    if let Err(x) = device_id(fd) {
+        return Err(device_error(device, format!(
+            "opened {:?}, but it isn't an event device ({}). Make sure you \
+             use an \"event\" device, and not (for example) a \"joystick\" \
+             or \"mouse\" device.", device.path, x)))
+    }
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let result = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    if result < 0 {
+        return Err(device_error(device, format!(
+            "opened {:?}, but couldn't check it: {}", device.path,
+            std::io::Error::last_os_error())))
+    }
+    if pollfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+        return Err(device_error(device, format!(
+            "opened {:?}, but can't read from it (are you in the \"input\" \
+             group? has the device been taken away?)", device.path)))
+    }
+    Ok(())
+}
+
+/// Whether we have a device grabbed.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Grab {
+    /// We weren't asked to grab it.
+    No,
+    /// We were asked to, but couldn't.
+    Wanted,
+    /// We have.
+    Held,
+}
+
+/// Grabs `dev_file`, or complains on stderr if it can't. `path` is only used
+/// in messages. Returns `Grab::Held` or `Grab::Wanted`.
+fn grab_device(path: &str, dev_file: &File) -> Grab {
+    match grab(dev_file.as_raw_fd(), true) {
+        Ok(()) => Grab::Held,
+        Err(x) => {
+            eprintln!("Couldn't grab {:?}: {}", path, x);
+            Grab::Wanted
+        },
+    }
+}
+
+/// Waits up to `timeout` for `fd` to have something to read (or an error to
+/// report). Returns `false` if it doesn't.
+fn wait_readable(fd: libc::c_int, timeout: Duration)
+                 -> std::io::Result<bool> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let timeout = timeout.as_millis().min(libc::c_int::MAX as u128);
+    match unsafe { libc::poll(&mut pollfd, 1, timeout as libc::c_int) } {
+        x if x > 0 => Ok(true),
+        0 => Ok(false),
+        _ => {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted { Ok(false) }
+            else { Err(error) }
+        },
+    }
+}
+
+/// Checks on a device that has gone quiet, by trying to grab it. Returns
+/// `true` if someone else has grabbed it. Unless we were meant to have it
+/// grabbed ourselves, we let it go again straight away. If we were, we
+/// keep it, and `grab` is updated to match.
+fn check_grab(path: &str, fd: libc::c_int, grab: &mut Grab) -> bool {
+    match crate::ioctl::grab(fd, true) {
+        Ok(()) if *grab == Grab::Wanted => {
+            eprintln!("Grabbed {:?} at last.", path);
+            *grab = Grab::Held;
+            false
+        },
+        Ok(()) => {
+            // nobody else has it; it's just quiet
+            let _ = crate::ioctl::grab(fd, false);
+            false
+        },
+        Err(x) => x.raw_os_error() == Some(libc::EBUSY),
+    }
+}
+
+/// Reads and discards everything that can be read from `dev_file` without
+/// waiting. `path` is only used in messages.
+fn drain(path: &str, dev_file: &File) {
+    let fd = dev_file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1
+    || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) }
+    == -1 {
+        eprintln!("Couldn't drain {:?}: {}", path,
+                  std::io::Error::last_os_error());
+        return
+    }
+    let mut buf = [0u8; 4096];
+    let mut reader = dev_file;
+    loop {
+        match reader.read(&mut buf[..]) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(x) if x.kind() == std::io::ErrorKind::Interrupted => (),
+            // including `WouldBlock`, which means we're done
+            Err(_) => break,
+        }
+    }
+    unsafe { libc::fcntl(fd, libc::F_SETFL, flags) };
+}
+
+/// An event, along with the device it came from.
+#[derive(Clone)]
+pub struct DeviceEvent {
+    /// The device that the event came from.
+    pub device: Arc<DeviceSpec>,
+    /// The event itself.
+    pub event: InputEvent,
+    /// For absolute axis events, the smallest and largest values that the
+    /// device says the axis can have, if it told us.
+    pub range: Option<(i32, i32)>,
+}
+
+impl std::fmt::Display for DeviceEvent {
+    /// Formats the event as the conditions of an "if" line that would match
+    /// it. The device is only mentioned if it has a label.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(label) = self.device.label.as_ref() {
+            write!(f, "dev={} ", quote_word(label))?;
+        }
+        write!(f, "type={} code={} value={}", self.event.type_,
+               self.event.code, self.event.value)
+    }
+}
+
+impl DeviceEvent {
+    /// Formats the event as a single-line JSON object, with `type`, `code`,
+    /// `value`, and `device` (its label, or its path if it has no label), for
+    /// consumption by other programs.
+    pub fn to_json(&self) -> String {
+        format!("{{\"type\":{},\"code\":{},\"value\":{},\"device\":{}}}",
+                self.event.type_, self.event.code, self.event.value,
+                json_string(self.device.name()))
+    }
+}
+
+/// Reads one event's worth of bytes (as many as `buf` holds) into `buf`.
+/// Returns `false` if the end of the file was reached first, between events.
+/// Reaching the end of the file partway through an event is an error.
+fn read_event<R: Read>(reader: &mut R, buf: &mut [u8])
+                       -> std::io::Result<bool> {
+    let mut got = 0;
+    while got < buf.len() {
+        match reader.read(&mut buf[got..]) {
+            Ok(0) if got == 0 => return Ok(false),
+            Ok(0) => return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the last event was cut off")),
+            Ok(n) => got += n,
+            Err(x) if x.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(x) => return Err(x),
+        }
+    }
+    Ok(true)
+}
+
+/// How often a reader waiting for its device's next event checks whether it
+/// has been told to stop.
+const STOP_CHECK: Duration = Duration::from_millis(100);
+
+/// How many more times to try reading from a device that returned nothing,
+/// before deciding it's gone.
+const ZERO_READ_RETRIES: u32 = 5;
+/// How long to wait before the first retry of a read that returned nothing.
+/// Each retry after that waits twice as long as the last.
+const ZERO_READ_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Like [`read_event`](fn.read_event.html), but if nothing can be read, tries
+/// again up to `retries` times, waiting `backoff` (and then twice as long each
+/// time) in between. Some flaky USB and virtual devices return nothing now and
+/// then without being gone. If they keep returning nothing, that's an error,
+/// of kind `UnexpectedEof`. With no retries, this is just `read_event`.
+fn read_event_patiently<R: Read>(reader: &mut R, buf: &mut [u8],
+                                 retries: u32, backoff: Duration)
+                                 -> std::io::Result<bool> {
+    let mut backoff = backoff;
+    for _ in 0..retries {
+        if read_event(reader, buf)? { return Ok(true) }
+        sleep(backoff);
+        backoff *= 2;
+    }
+    if read_event(reader, buf)? { return Ok(true) }
+    if retries == 0 { return Ok(false) }
+    Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                            "the device keeps returning nothing"))
+}
+
+/// Holds back a device's motion so that it's passed on at most so many times
+/// per second (`maxrate=`). Between then, relative axes' movements are added
+/// up, so none is lost, and absolute axes only keep their latest values.
+/// Everything else (keys, buttons, multitouch) is passed on right away,
+/// after any motion that came before it.
+struct Coalescer {
+    /// The least time between two batches of motion.
+    interval: Duration,
+    /// When the last batch was passed on.
+    last_sent: Option<Instant>,
+    /// The motion waiting to be passed on, by type and code.
+    pending: BTreeMap<(u16, u16), InputEvent>,
+}
+
+impl Coalescer {
+    /// Makes a coalescer that passes motion on at most `max_rate` times per
+    /// second.
+    fn new(max_rate: u32) -> Coalescer {
+        Coalescer {
+            interval: Duration::from_secs(1) / max_rate,
+            last_sent: None, pending: BTreeMap::new(),
+        }
+    }
+    /// Takes an event from the device (anything but an `EV_SYN`), and adds
+    /// any events that should be passed on now to `out`.
+    fn push(&mut self, event: InputEvent, out: &mut Vec<InputEvent>) {
+        let key = (event.type_, event.code);
+        match key {
+            (EV_REL, _) => {
+                let held = self.pending.entry(key).or_insert(InputEvent {
+                    value: 0, ..event
+                });
+                held.value = held.value.saturating_add(event.value);
+                held.time = event.time;
+            },
+            (EV_ABS, code) if code < ABS_MT_SLOT => {
+                self.pending.insert(key, event);
+            },
+            _ => {
+                self.flush(out);
+                out.push(event);
+            },
+        }
+    }
+    /// Handles a `SYN_REPORT` that arrived at `now`, adding the waiting
+    /// motion to `out` if it's been long enough since the last batch.
+    /// Returns true if the report should be passed on too, because `out`
+    /// isn't empty.
+    fn report(&mut self, now: Instant, out: &mut Vec<InputEvent>) -> bool {
+        if self.last_sent.map(|x| now >= x + self.interval).unwrap_or(true) {
+            self.flush(out);
+            self.last_sent = Some(now);
+        }
+        !out.is_empty()
+    }
+    /// Returns when the waiting motion should be passed on, whether or not
+    /// the device sends anything else, or `None` if there's nothing waiting.
+    fn due(&self) -> Option<Instant> {
+        if self.pending.is_empty() { return None }
+        Some(self.last_sent.map(|x| x + self.interval)
+             .unwrap_or_else(Instant::now))
+    }
+    /// Adds the waiting motion to `out`, leaving nothing waiting.
+    fn flush(&mut self, out: &mut Vec<InputEvent>) {
+        out.extend(std::mem::take(&mut self.pending).into_values()
+                   .filter(|x| x.type_ != EV_REL || x.value != 0));
+    }
+}
+
+/// Reads events from an already-opened device and sends them via
+/// `event_sender`, once for each of `devices` (which all name the same path),
+/// tagged with that device. Events of the types in `options.ignored_types`
+/// aren't sent. Only returns if there's an error reading from the device, if
+/// nobody is listening anymore, or if `stop` is set. Reaching the end of the
+/// file counts as an error, of kind `UnexpectedEof`, but with no inner error.
+/// (A real device never ends, so if one seems to, we try again for a little
+/// while before treating it as having been lost.) A regular file starts over
+/// from the beginning when it ends, as many times as `options.replay_passes`
+/// says, unless a whole pass through it had no events in it.
+///
+/// If the kernel reports that events were lost (`SYN_DROPPED`), prints a
+/// warning, ignores the incomplete group of events that follows, and then
+/// asks the device which of its keys are down, where its absolute axes are,
+/// and which multitouch slot is selected. For each of those that differs
+/// from what we last saw, we send the event we missed (a press or release,
+/// or the new value), so that nothing seems stuck down. (The multitouch
+/// axes other than the slot aren't asked about, since their values only
+/// mean anything slot by slot.)
+///
+/// If `options.grab_check` isn't `None`, each time the device is quiet for
+/// that long, we check whether someone else has grabbed it (unless we have,
+/// as `grab` says), and warn if so.
+///
+/// If any of `devices` has a `max_rate`, the lowest one is enforced here, by
+/// a [`Coalescer`](struct.Coalescer.html).
+///
+/// Real devices' events are always in `EventFormat::NATIVE`. Other files'
+/// are in `options.event_format`, or whatever a regular file seems to use if
+/// that's `None`.
+fn read_events(devices: &[Arc<DeviceSpec>], dev_file: File,
+               event_sender: &EventSender, options: &DeviceOptions,
+               mut grab: Grab, stop: &AtomicBool) -> std::io::Result<()> {
+    let fd = dev_file.as_raw_fd();
+    let guess = |length| {
+        let mut head = [0u8; MAX_EVENT_SIZE * 16];
+        let got = dev_file.read_at(&mut head, 0).unwrap_or(0);
+        EventFormat::detect(&head[..got], length)
+    };
+    let (retries, mut passes, format) = match dev_file.metadata() {
+        Ok(x) if x.file_type().is_char_device() => {
+            (ZERO_READ_RETRIES, Some(1), EventFormat::NATIVE)
+        },
+        Ok(x) if x.file_type().is_file() => {
+            (0, options.replay_passes,
+             options.event_format.unwrap_or_else(|| guess(x.len())))
+        },
+        _ => (0, Some(1), options.event_format.unwrap_or(EventFormat::NATIVE)),
+    };
+    // whether anything was read since we last started over
+    let mut read_any = false;
+    let mut dev_file = BufReader::new(dev_file);
+    let mut buf = [0u8; MAX_EVENT_SIZE];
+    let buf = &mut buf[.. format.size()];
+    // true between a SYN_DROPPED and the next SYN_REPORT
+    let mut dropping = false;
+    // the multitouch slot most recently selected (devices that don't have
+    // slots behave as if slot 0 is always selected)
+    let mut last_slot = 0;
+    // the keys and axes as of the events we've passed on, for catching up
+    // after a SYN_DROPPED
+    let mut seen = Seen::probe(fd);
+    // the ranges of the absolute axes, looked up the first time we see each
+    let mut ranges = [None; ABS_CNT as usize];
+    // whether we've warned that someone else seems to have grabbed the device
+    let mut stolen = false;
+    let mut coalescer = devices.iter().filter_map(|x| x.max_rate).min()
+        .map(Coalescer::new);
+    // sends events (and then a SYN_REPORT, if `report` is set) for each
+    // device, returning false if nobody is listening anymore
+    let mut send = |events: Vec<InputEvent>, report: bool| {
+        for event in events.into_iter() {
+            let range = if event.type_ == EV_ABS && event.code < ABS_CNT {
+                *ranges[event.code as usize].get_or_insert_with(|| {
+                    abs_info(fd, event.code).ok()
+                        .map(|x| (x.minimum, x.maximum))
+                })
+            }
+            else { None };
+            for device in devices.iter() {
+                let event = DeviceEvent {
+                    device: device.clone(), event, range,
+                };
+                if !event_sender.send(event) { return false }
+            }
+        }
+        !report || devices.iter().all(|x| event_sender.send_sync(x.clone()))
+    };
+    loop {
+        // motion that's been held back long enough goes on its way, even if
+        // the device has gone quiet
+        if let Some(due) = coalescer.as_ref().and_then(Coalescer::due) {
+            let now = Instant::now();
+            if dev_file.buffer().is_empty()
+            && (now >= due || !wait_readable(fd, due - now)?) {
+                let mut events = Vec::new();
+                if coalescer.as_mut().unwrap().report(Instant::now(),
+                                                      &mut events)
+                && !send(events, true) {
+                    return Ok(())
+                }
+                continue
+            }
+        }
+        // only wait when there's nothing left in the buffer to read, and
+        // then only a little at a time, so that being told to stop doesn't
+        // have to wait for the device's next event
+        let mut quiet_since = Instant::now();
+        while dev_file.buffer().is_empty() && !wait_readable(fd, STOP_CHECK)? {
+            if stop.load(Ordering::Relaxed) { return Ok(()) }
+            let quiet = match options.grab_check {
+                Some(x) if grab != Grab::Held => x,
+                _ => continue,
+            };
+            if quiet_since.elapsed() < quiet { continue }
+            quiet_since = Instant::now();
+            if check_grab(&devices[0].path, fd, &mut grab) && !stolen {
+                eprintln!("Warning: {:?} has been quiet for {:?}, and \
+                           another program has grabbed it. Its events may \
+                           be going there instead of here.",
+                          devices[0].path, quiet);
+                stolen = true;
+            }
+        }
+        if !read_event_patiently(&mut dev_file, buf, retries,
+                                 ZERO_READ_BACKOFF)? {
+            // don't lose any motion that was held back
+            if let Some(coalescer) = coalescer.as_mut() {
+                let mut events = Vec::new();
+                coalescer.flush(&mut events);
+                if !events.is_empty() && !send(events, true) {
+                    return Ok(())
+                }
+            }
+            passes = passes.map(|x| x.saturating_sub(1));
+            if passes == Some(0) || !read_any {
+                return Err(std::io::ErrorKind::UnexpectedEof.into())
+            }
+            dev_file.seek(SeekFrom::Start(0))?;
+            read_any = false;
+            dropping = false;
+            continue
+        }
+        read_any = true;
+        if stop.load(Ordering::Relaxed) { return Ok(()) }
+        if stolen {
+            eprintln!("Getting events from {:?} again.", devices[0].path);
+            stolen = false;
+        }
+        let event = format.decode(buf);
+        let mut events = vec![event];
+        let report = (event.type_, event.code) == (EV_SYN, SYN_REPORT);
+        match (event.type_, event.code) {
+            (EV_SYN, SYN_DROPPED) => {
+                eprintln!("Warning: some events from {:?} were lost, because \
+                           they arrived faster than we could read them",
+                          devices[0].path);
+                dropping = true;
+                continue
+            },
+            (EV_SYN, SYN_REPORT) if dropping => {
+                dropping = false;
+                events.clear();
+                events = seen.catch_up(&Seen::probe(fd), event.time);
+                if let Ok(info) = abs_info(fd, ABS_MT_SLOT) {
+                    if info.value != last_slot {
+                        events.push(InputEvent {
+                            type_: EV_ABS, code: ABS_MT_SLOT,
+                            value: info.value, ..event
+                        });
+                    }
+                }
+            },
+            _ if dropping => continue,
+            (EV_SYN, SYN_REPORT) => events.clear(),
+            (EV_SYN, _) | (EV_MSC, _) => continue,
+            _ => (),
+        }
+        let mut out = Vec::with_capacity(events.len());
+        for event in events.into_iter() {
+            if event.type_ == EV_ABS && event.code == ABS_MT_SLOT {
+                last_slot = event.value;
+            }
+            seen.note(&event);
+            if options.ignored_types.contains(&event.type_) { continue }
+            match coalescer.as_mut() {
+                Some(coalescer) => coalescer.push(event, &mut out),
+                None => out.push(event),
+            }
+        }
+        let report = report && match coalescer.as_mut() {
+            Some(coalescer) => coalescer.report(Instant::now(), &mut out),
+            None => true,
+        };
+        if !send(out, report) {
+            // quietly end the thread, our parent thread is no longer
+            // listening :(
+            return Ok(())
+        }
+    }
+}
+
+/// Spawns a thread that reads events from an already-opened device and sends
+/// them via `event_sender`, and another thread that restarts the first one if
+/// it panics. `devices` all name the same path. `grab` says whether we have
+/// the device grabbed; unless it's `Grab::No`, the device is grabbed again
+/// whenever it's reopened.
+fn spawn_reader(devices: Vec<Arc<DeviceSpec>>, dev_file: File,
+                event_sender: EventSender, options: &DeviceOptions,
+                grab: Grab) -> DeviceHandle {
+    let max_restarts = options.max_restarts;
+    let drain_on_start = options.drain_on_start;
+    let options = options.clone();
+    let stop = Arc::new(AtomicBool::new(false));
+    let finished = Arc::new((Mutex::new(false), Condvar::new()));
+    let handle = DeviceHandle {
+        devices: devices.clone(), stop: stop.clone(),
+        finished: finished.clone(), initial_state: Vec::new(),
+    };
+    // set if the reader reached the end of the file
+    let eof = Arc::new(AtomicBool::new(false));
+    spawn(move || {
+        let path = devices[0].path.clone();
+        let mut dev_file = Some((dev_file, grab));
+        supervise(&path, max_restarts, RESTART_BACKOFF, || {
+            if stop.load(Ordering::Relaxed) { return None }
+            let (dev_file, grab) = match dev_file.take() {
+                Some(x) => x,
+                None => match File::open(&path) {
+                    Ok(x) => {
+                        options.restart_count.fetch_add(1, Ordering::Relaxed);
+                        if drain_on_start { drain(&path, &x) }
+                        let grab = if grab == Grab::No { Grab::No }
+                        else { grab_device(&path, &x) };
+                        (x, grab)
+                    },
+                    Err(x) => {
+                        eprintln!("Couldn't reopen {:?}: {}", path, x);
+                        return None
+                    },
+                },
+            };
+            let devices = devices.clone();
+            let event_sender = event_sender.clone();
+            let stop = stop.clone();
+            let eof = eof.clone();
+            let options = options.clone();
+            Some(spawn(move || {
+                if let Some(priority) = options.realtime {
+                    make_realtime(priority)
+                }
+                match read_events(&devices, dev_file, &event_sender,
+                                  &options, grab, &stop) {
+                    Ok(()) => (),
+                    Err(x) if x.kind() == std::io::ErrorKind::UnexpectedEof
+                        && x.get_ref().is_none() => {
+                            eof.store(true, Ordering::Relaxed);
+                        },
+                    Err(x) => eprintln!("Error reading from {:?}: {}",
+                                        devices[0].path, x),
+                }
+            }))
+        });
+        let eof = eof.load(Ordering::Relaxed);
+        *finished.0.lock().unwrap() = true;
+        finished.1.notify_all();
+        event_sender.send_message(Message::Finished { devices, eof });
+    });
+    handle
+}
+
+/// Set once we've warned that realtime scheduling isn't allowed, so that
+/// every reader thread doesn't warn about it again.
+static REALTIME_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Switches the calling thread to `SCHED_FIFO` scheduling at the given
+/// priority. (Commands are never started from a reader thread, so they don't
+/// inherit it.) If that isn't allowed, which takes `CAP_SYS_NICE` or a high
+/// enough `RLIMIT_RTPRIO`, warns and carries on with the usual scheduling.
+fn make_realtime(priority: i32) {
+    let param = libc::sched_param { sched_priority: priority };
+    // 0 is the calling thread, not the whole process
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } == 0 {
+        return
+    }
+    let error = std::io::Error::last_os_error();
+    if !REALTIME_WARNED.swap(true, Ordering::Relaxed) {
+        eprintln!("Warning: couldn't use realtime scheduling for reading \
+                   from devices ({}), so reading them as usual. It takes \
+                   CAP_SYS_NICE, or an RLIMIT_RTPRIO of at least {}.", error,
+                  priority);
+    }
+}
+
+/// How long to wait before restarting a reader thread the first time. Each
+/// restart after that waits twice as long as the last, up to
+/// `MAX_RESTART_BACKOFF`.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest we'll wait before restarting a reader thread.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+/// If a reader thread ran for at least this long before panicking, it doesn't
+/// count against the restart limit, and the wait starts over.
+const RESTART_FORGIVENESS: Duration = Duration::from_secs(60);
+
+/// Starts a thread with `start`, and waits for it to finish. If it panicked,
+/// waits a while and starts it again, up to `max_restarts` times. Gives up if
+/// `start` returns `None`. `name` is only used in messages.
+fn supervise<F>(name: &str, max_restarts: u32, backoff: Duration, mut start: F)
+where F: FnMut() -> Option<JoinHandle<()>> {
+    let mut restarts = 0;
+    let mut next_backoff = backoff;
+    loop {
+        let started_at = Instant::now();
+        let handle = match start() {
+            Some(x) => x,
+            None => return,
+        };
+        if handle.join().is_ok() { return }
+        if started_at.elapsed() >= RESTART_FORGIVENESS {
+            restarts = 0;
+            next_backoff = backoff;
+        }
+        if restarts >= max_restarts {
+            eprintln!("The reader for {:?} crashed too many times, giving up \
+                       on it", name);
+            return
+        }
+        restarts += 1;
+        eprintln!("The reader for {:?} crashed, restarting it in {}s \
+                   (restart {} of {})", name, next_backoff.as_secs_f64(),
+                  restarts, max_restarts);
+        sleep(next_backoff);
+        next_backoff = (next_backoff * 2).min(MAX_RESTART_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    const EVENT_SIZE: usize = EventFormat::NATIVE.size();
+
+    #[test]
+    fn reads_whole_events() {
+        let mut buf = [0u8; EVENT_SIZE];
+        let two = [7u8; EVENT_SIZE * 2];
+        let mut reader = &two[..];
+        assert!(read_event(&mut reader, &mut buf).unwrap());
+        assert!(read_event(&mut reader, &mut buf).unwrap());
+        assert!(!read_event(&mut reader, &mut buf).unwrap());
+        let short = [7u8; EVENT_SIZE + 1];
+        let mut reader = &short[..];
+        assert!(read_event(&mut reader, &mut buf).unwrap());
+        assert!(read_event(&mut reader, &mut buf).is_err());
+    }
+
+    #[test]
+    fn capabilities() {
+        let mut bits = vec![Vec::new(); EV_CNT as usize];
+        bits[EV_KEY as usize] = vec![0u8; 0x300 / 8];
+        bits[EV_KEY as usize][(BTN_GAMEPAD / 8) as usize] = 1;
+        bits[EV_ABS as usize] = vec![1u8];
+        let capabilities = Capabilities { bits };
+        assert!(capabilities.can_send(EV_KEY, BTN_GAMEPAD));
+        assert!(!capabilities.can_send(EV_KEY, BTN_GAMEPAD + 1));
+        assert!(capabilities.can_send(EV_ABS, ABS_X));
+        // past the end of the bitmap
+        assert!(!capabilities.can_send(EV_ABS, 40));
+        assert!(!capabilities.can_send(EV_REL, REL_X));
+        assert!(!capabilities.can_send(0x100, 0));
+    }
+
+    #[test]
+    fn inotify_events() {
+        let mut buf = Vec::new();
+        for (name, padded) in [("event3", 16), ("js0", 4)] {
+            // wd, mask, cookie, and len
+            for x in [1u32, libc::IN_CREATE, 0, padded] {
+                buf.extend_from_slice(&x.to_ne_bytes());
+            }
+            buf.extend_from_slice(name.as_bytes());
+            buf.resize(buf.len() + padded as usize - name.len(), 0);
+        }
+        assert_eq!(inotify_names(&buf), ["event3", "js0"]);
+        assert_eq!(inotify_names(&buf[.. 8]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn stops_without_another_event() {
+        // a pipe that nothing is ever written to, kept open so that reading
+        // from it waits instead of reaching the end
+        let path = std::env::temp_dir()
+            .join(format!("input2cmds-quiet-{}", std::process::id()));
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let _writer = std::fs::OpenOptions::new().read(true).write(true)
+            .open(&path).unwrap();
+        let config = crate::parse_config("test.conf", &format!(
+            "dev quiet: {}\n", path.display())).unwrap();
+        let (sender, receiver) = crate::event_queue(None);
+        let handles = open_devices(&config.devices, &sender,
+                                   &DeviceOptions::default()).unwrap();
+        assert!(!handles[0].wait(Duration::from_millis(10)));
+        handles[0].stop();
+        assert!(handles[0].wait(Duration::from_secs(5)));
+        assert!(matches!(receiver.recv_timeout(Duration::from_secs(5)),
+                         Ok(Message::Finished { eof: false, .. })));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_from_uinput() {
+        let keyboard = match test_util::VirtualDevice::keyboard(&[KEY_A]) {
+            Some(x) => x,
+            // there's no uinput here, so nothing to test against
+            None => return,
+        };
+        let config = crate::parse_config("test.conf", &format!(
+            "dev kbd: {}\n\
+             if dev=kbd type=1 code=30 value=1 then: a\n", keyboard.path))
+            .unwrap();
+        let (sender, receiver) = crate::event_queue(None);
+        let handles = open_devices(&config.devices, &sender,
+                                   &DeviceOptions::default()).unwrap();
+        keyboard.inject(EV_KEY, KEY_A, 1);
+        let event = loop {
+            match receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Message::Event(x) => break x,
+                _ => continue,
+            }
+        };
+        assert_eq!(event.device.name(), "kbd");
+        let mut state = crate::InputState::default();
+        state.update(&event);
+        let fired = crate::match_event(&event, &state, &config.matches);
+        assert_eq!(fired.map(|x| x.action.to_string()).as_deref(),
+                   Some("then: a"));
+        for handle in handles.iter() { handle.stop() }
+    }
+
+    #[test]
+    fn catches_up_after_drops() {
+        let time = libc::timeval { tv_sec: 5, tv_usec: 0 };
+        let key = |code: u16, value| InputEvent {
+            time, type_: EV_KEY, code, value,
+        };
+        let mut seen = Seen {
+            keys: vec![0u8; 0x300 / 8], axes: vec![None; 3],
+        };
+        seen.note(&key(KEY_A, 1));
+        seen.note(&key(KEY_Z, 1));
+        seen.note(&InputEvent { time, type_: EV_ABS, code: 1, value: 7 });
+        // KEY_A's release and KEY_SPACE's press were lost, and the axis moved
+        let mut now = seen.clone();
+        now.keys[(KEY_A / 8) as usize] &= !(1 << (KEY_A % 8));
+        now.keys[(KEY_SPACE / 8) as usize] |= 1 << (KEY_SPACE % 8);
+        now.axes = vec![None, Some(9), Some(-2)];
+        let values = |events: Vec<InputEvent>| -> Vec<(u16, u16, i32)> {
+            events.iter().map(|x| (x.type_, x.code, x.value)).collect()
+        };
+        assert_eq!(values(seen.catch_up(&now, time)),
+                   [(EV_KEY, KEY_A, 0), (EV_KEY, KEY_SPACE, 1),
+                    (EV_ABS, 1, 9), (EV_ABS, 2, -2)]);
+        assert_eq!(seen, now);
+        // nothing more to do the second time, and nothing the device
+        // couldn't tell us is forgotten
+        assert!(seen.catch_up(&Seen::default(), time).is_empty());
+        assert_eq!(seen, now);
+    }
+
+    #[test]
+    fn catches_up_with_uinput() {
+        let keyboard = match test_util::VirtualDevice::keyboard(&[KEY_A]) {
+            Some(x) => x,
+            None => return,
+        };
+        let file = File::open(&keyboard.path).unwrap();
+        let mut seen = Seen::probe(file.as_raw_fd());
+        // as if the press had been lost
+        keyboard.inject(EV_KEY, KEY_A, 1);
+        sleep(Duration::from_millis(100));
+        let events = seen.catch_up(&Seen::probe(file.as_raw_fd()),
+                                   libc::timeval { tv_sec: 0, tv_usec: 0 });
+        let values: Vec<(u16, u16, i32)> = events.iter()
+            .map(|x| (x.type_, x.code, x.value)).collect();
+        assert_eq!(values, [(EV_KEY, KEY_A, 1)]);
+    }
+
+    #[test]
+    fn files_have_no_initial_state() {
+        let file = File::open("Cargo.toml").unwrap();
+        assert!(probe_state(&[test_util::device(None)], &file).is_empty());
+    }
+
+    #[test]
+    fn event_formats() {
+        let mut wide = Vec::new();
+        let mut narrow = Vec::new();
+        for (sec, code) in [(1_700_000_000i64, 30u16), (1_700_000_001, 0)] {
+            wide.extend_from_slice(&sec.to_ne_bytes());
+            wide.extend_from_slice(&250_000i64.to_ne_bytes());
+            narrow.extend_from_slice(&(sec as i32).to_ne_bytes());
+            narrow.extend_from_slice(&250_000i32.to_ne_bytes());
+            for bytes in [&mut wide, &mut narrow] {
+                bytes.extend_from_slice(&EV_KEY.to_ne_bytes());
+                bytes.extend_from_slice(&code.to_ne_bytes());
+                bytes.extend_from_slice(&(-1i32).to_ne_bytes());
+            }
+        }
+        for (format, bytes) in [(EventFormat::Bits64, &wide),
+                                (EventFormat::Bits32, &narrow)] {
+            assert_eq!(bytes.len(), format.size() * 2);
+            let event = format.decode(&bytes[format.size() ..]);
+            assert_eq!((event.time.tv_sec, event.time.tv_usec),
+                       (1_700_000_001, 250_000));
+            assert_eq!((event.type_, event.code, event.value),
+                       (EV_KEY, 0, -1));
+            assert_eq!(EventFormat::detect(bytes, bytes.len() as u64), format);
+        }
+        // a file that's a whole number of events either way, with nothing
+        // to tell them apart, is taken to be native
+        assert_eq!(EventFormat::detect(&[0u8; 48], 48), EventFormat::NATIVE);
+        assert_eq!("32".parse(), Ok(EventFormat::Bits32));
+        assert_eq!("native".parse(), Ok(EventFormat::NATIVE));
+        assert_eq!("16".parse::<EventFormat>(), Err(()));
+    }
+
+    /// Returns nothing for the first few reads, and then the bytes it holds.
+    struct Hiccup {
+        nothing: u32,
+        bytes: &'static [u8],
+    }
+
+    impl Read for Hiccup {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.nothing > 0 {
+                self.nothing -= 1;
+                return Ok(0)
+            }
+            self.bytes.read(buf)
+        }
+    }
+
+    #[test]
+    fn retries_zero_reads() {
+        static BYTES: [u8; EVENT_SIZE] = [7u8; EVENT_SIZE];
+        let mut buf = [0u8; EVENT_SIZE];
+        let backoff = Duration::from_secs(0);
+        let mut reader = Hiccup { nothing: 2, bytes: &BYTES };
+        assert!(read_event_patiently(&mut reader, &mut buf, 2, backoff)
+                .unwrap());
+        let mut reader = Hiccup { nothing: 3, bytes: &BYTES };
+        let err = read_event_patiently(&mut reader, &mut buf, 2, backoff)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(err.get_ref().is_some());
+        let mut reader = Hiccup { nothing: 1, bytes: &BYTES };
+        assert!(!read_event_patiently(&mut reader, &mut buf, 0, backoff)
+                .unwrap());
+    }
+
+    #[test]
+    fn quiet_devices() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let short = Duration::from_millis(1);
+        assert!(!wait_readable(fds[0], short).unwrap());
+        assert_eq!(unsafe { libc::write(fds[1], b"x".as_ptr() as *const _,
+                                        1) }, 1);
+        assert!(wait_readable(fds[0], short).unwrap());
+        // a pipe can't be grabbed by anyone
+        let mut grab = Grab::Wanted;
+        assert!(!check_grab("pipe", fds[0], &mut grab));
+        assert_eq!(grab, Grab::Wanted);
+        unsafe { libc::close(fds[0]); libc::close(fds[1]); }
+    }
+
+    #[test]
+    fn coalesces_motion() {
+        let event = |type_, code, value| InputEvent {
+            time: libc::timeval { tv_sec: 0, tv_usec: 0 }, type_, code, value,
+        };
+        let values = |events: &[InputEvent]| -> Vec<(u16, u16, i32)> {
+            events.iter().map(|x| (x.type_, x.code, x.value)).collect()
+        };
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut coalescer = Coalescer::new(10);
+        let mut out = Vec::new();
+        // the first batch goes right away
+        coalescer.push(event(EV_REL, REL_X, 3), &mut out);
+        coalescer.push(event(EV_REL, REL_Y, 1), &mut out);
+        assert!(coalescer.report(start, &mut out));
+        assert_eq!(values(&out), [(EV_REL, REL_X, 3), (EV_REL, REL_Y, 1)]);
+        out.clear();
+        assert_eq!(coalescer.due(), None);
+        // then motion is added up until 100 ms have passed
+        coalescer.push(event(EV_REL, REL_X, 2), &mut out);
+        assert!(!coalescer.report(start + ms(10), &mut out));
+        assert_eq!(coalescer.due(), Some(start + ms(100)));
+        // but a key goes on right away, after the motion before it
+        coalescer.push(event(EV_REL, REL_X, 5), &mut out);
+        coalescer.push(event(EV_KEY, BTN_LEFT, 1), &mut out);
+        assert!(coalescer.report(start + ms(20), &mut out));
+        assert_eq!(values(&out), [(EV_REL, REL_X, 7), (EV_KEY, BTN_LEFT, 1)]);
+        out.clear();
+        coalescer.push(event(EV_ABS, ABS_X, 5), &mut out);
+        coalescer.push(event(EV_ABS, ABS_X, 9), &mut out);
+        assert!(!coalescer.report(start + ms(30), &mut out));
+        assert!(coalescer.report(start + ms(100), &mut out));
+        assert_eq!(values(&out), [(EV_ABS, ABS_X, 9)]);
+        out.clear();
+        // movements that cancel out are nothing to report
+        coalescer.push(event(EV_REL, REL_X, 1), &mut out);
+        coalescer.push(event(EV_REL, REL_X, -1), &mut out);
+        assert!(!coalescer.report(start + ms(200), &mut out));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn replays_files() {
+        let path = std::env::temp_dir()
+            .join(format!("input2cmds-replay-{}", std::process::id()));
+        let key = InputEvent {
+            time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_: EV_KEY, code: 30, value: 1,
+        };
+        let bytes: [u8; EVENT_SIZE] = unsafe { std::mem::transmute(key) };
+        std::fs::write(&path, &bytes[..]).unwrap();
+        let count = |passes| {
+            let (tx, rx) = crate::event_queue(None);
+            let options = DeviceOptions {
+                replay_passes: passes, ..DeviceOptions::default()
+            };
+            let stop = AtomicBool::new(false);
+            let err = read_events(&[crate::test_util::device(None)],
+                                  File::open(&path).unwrap(), &tx, &options,
+                                  Grab::No, &stop).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            drop(tx);
+            std::iter::from_fn(|| rx.recv().ok()).count()
+        };
+        assert_eq!(count(Some(1)), 1);
+        assert_eq!(count(Some(3)), 3);
+        // an empty file can't be replayed forever
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(count(None), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn supervisor_restarts_panics() {
+        let mut starts = 0;
+        supervise("test", 3, Duration::from_secs(0), || {
+            starts += 1;
+            Some(spawn(|| panic!("expected panic, please ignore")))
+        });
+        assert_eq!(starts, 4);
+    }
+
+    #[test]
+    fn supervisor_leaves_clean_exits_alone() {
+        let mut starts = 0;
+        supervise("test", 3, Duration::from_secs(0), || {
+            starts += 1;
+            Some(spawn(|| ()))
+        });
+        assert_eq!(starts, 1);
+        let mut starts = 0;
+        supervise("test", 3, Duration::from_secs(0), || {
+            starts += 1;
+            if starts > 1 { None }
+            else { Some(spawn(|| panic!("expected panic, please ignore"))) }
+        });
+        assert_eq!(starts, 2);
+    }
+}
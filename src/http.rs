@@ -0,0 +1,245 @@
+//! Fetching a configuration file over HTTP directly, without running a
+//! program to do it. Only as much of the protocol as that takes is here:
+//! sending a `GET`, following redirects, and reading back the body of a
+//! `200 OK`. There's no TLS, so only `http://` URLs can be fetched, and
+//! (unless told otherwise) only from this machine, since anybody between here
+//! and another one could change what comes back.
+
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// How long to wait to connect, and for each read and write after that.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most redirects to follow before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// The biggest response we'll read. (A configuration file this big is
+/// surely a mistake.)
+const MAX_RESPONSE: u64 = 16 << 20;
+
+/// Returns an error of kind `InvalidData` with the given message.
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Splits an `http://` URL into the host (with the port, if one was given)
+/// and the path to ask for.
+fn split_url(url: &str) -> Result<(&str, String), Error> {
+    let rest = match url.strip_prefix("http://") {
+        Some(x) => x,
+        None if url.starts_with("https://") => {
+            return Err(Error::new(ErrorKind::Unsupported,
+                                  "https:// URLs aren't supported (there's \
+                                   no TLS built in); fetch it through a \
+                                   proxy on this machine instead, as an \
+                                   http://localhost URL"))
+        },
+        None => return Err(invalid(format!("{:?} isn't an http:// URL",
+                                           url))),
+    };
+    // a fragment is never sent to the server
+    let rest = rest.split('#').next().unwrap_or(rest);
+    let (host, path) = match rest.find(&['/', '?'][..]) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    let path = if path.starts_with('/') { path.to_owned() }
+    else { format!("/{}", path) };
+    if host.is_empty() || host.contains('@') {
+        return Err(invalid(format!("{:?} doesn't name a host (and user \
+                                    names aren't supported)", url)))
+    }
+    Ok((host, path))
+}
+
+/// What came back from a request: a body, or somewhere else to ask.
+#[derive(Debug,PartialEq,Eq)]
+enum Response {
+    /// The server answered `200 OK` with this body.
+    Body(String),
+    /// The server said to ask this URL instead.
+    Redirect(String),
+}
+
+/// Parses a whole HTTP/1.0 response (the server closes the connection when
+/// it's done, so there's no chunked encoding to undo).
+fn parse_response(response: &[u8]) -> Result<Response, Error> {
+    let end = response.windows(4).position(|x| x == b"\r\n\r\n")
+        .ok_or_else(|| invalid("the response ended early".to_owned()))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head.split("\r\n").next().unwrap_or("");
+    let mut words = status.splitn(3, ' ');
+    let code = match (words.next(), words.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code,
+        _ => return Err(invalid(format!("bad status line {:?}", status))),
+    };
+    let header = |name: &str| {
+        head.split("\r\n").skip(1).find_map(|line| {
+            let colon = line.find(':')?;
+            if line[..colon].eq_ignore_ascii_case(name) {
+                Some(line[colon+1..].trim().to_owned())
+            }
+            else { None }
+        })
+    };
+    match code {
+        "200" => {
+            let body = response[end+4..].to_vec();
+            String::from_utf8(body).map(Response::Body)
+                .map_err(|_| invalid("the body isn't UTF-8".to_owned()))
+        },
+        "301" | "302" | "303" | "307" | "308" => match header("Location") {
+            Some(x) => Ok(Response::Redirect(x)),
+            None => Err(invalid(format!("{:?} without a Location",
+                                        status))),
+        },
+        _ => Err(Error::other(format!("the server said {:?}", status))),
+    }
+}
+
+/// Makes one `GET` request, and reads the whole response. Unless `remote` is
+/// true, fails with `ErrorKind::PermissionDenied` if the host isn't this
+/// machine.
+fn get(url: &str, remote: bool) -> Result<Response, Error> {
+    let (host, path) = split_url(url)?;
+    let mut addrs: Vec<_> = if host.contains(':') && !host.ends_with(']') {
+        host.to_socket_addrs()?.collect()
+    }
+    else {
+        (host.trim_start_matches('[').trim_end_matches(']'), 80)
+            .to_socket_addrs()?.collect()
+    };
+    if !remote {
+        addrs.retain(|x| x.ip().is_loopback());
+        if addrs.is_empty() {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                                  format!("{:?} isn't this machine, and \
+                                           anybody on the way could change \
+                                           what comes back over plain \
+                                           http://", host)))
+        }
+    }
+    let mut last_error = invalid(format!("{:?} has no addresses", host));
+    let mut stream = None;
+    for addr in addrs.iter() {
+        match TcpStream::connect_timeout(addr, TIMEOUT) {
+            Ok(x) => { stream = Some(x); break },
+            Err(x) => last_error = x,
+        }
+    }
+    let mut stream = stream.ok_or(last_error)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: \
+                           input2cmds/{}\r\nConnection: close\r\n\r\n",
+                          path, host, env!("CARGO_PKG_VERSION"));
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE + 1).read_to_end(&mut response)?;
+    if response.len() as u64 > MAX_RESPONSE {
+        return Err(invalid("the response is too big".to_owned()))
+    }
+    parse_response(&response)
+}
+
+/// Fetches the text at an `http://` URL, following redirects. Unless `remote`
+/// is true, only fetches from this machine (`localhost` and the like), even
+/// after a redirect, and fails with `ErrorKind::PermissionDenied` for any
+/// other host.
+pub fn fetch_url(url: &str, remote: bool) -> Result<String, Error> {
+    let mut url = url.to_owned();
+    for _ in 0 ..= MAX_REDIRECTS {
+        url = match get(&url, remote)? {
+            Response::Body(x) => return Ok(x),
+            // a path on the same server
+            Response::Redirect(x) if x.starts_with('/') => {
+                let (host, _) = split_url(&url)?;
+                format!("http://{}{}", host, x)
+            },
+            Response::Redirect(x) => x,
+        };
+    }
+    Err(invalid(format!("more than {} redirects", MAX_REDIRECTS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn urls() {
+        let split = |url| {
+            split_url(url).map(|(host, path)| (host.to_owned(), path))
+                .unwrap()
+        };
+        assert_eq!(split("http://example.com/a/b.conf?x=1#y"),
+                   ("example.com".to_owned(), "/a/b.conf?x=1".to_owned()));
+        assert_eq!(split("http://[::1]:8080"),
+                   ("[::1]:8080".to_owned(), "/".to_owned()));
+        assert_eq!(split("http://host?x"),
+                   ("host".to_owned(), "/?x".to_owned()));
+        assert_eq!(split_url("https://example.com/").unwrap_err().kind(),
+                   ErrorKind::Unsupported);
+        assert!(split_url("ftp://example.com/").is_err());
+        assert!(split_url("http:///a").is_err());
+        assert!(split_url("http://me@example.com/").is_err());
+    }
+
+    #[test]
+    fn responses() {
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\nA: b\r\n\r\nif x")
+                       .unwrap(), Response::Body("if x".to_owned()));
+        assert_eq!(parse_response(b"HTTP/1.0 302 Found\r\nlocation: /b\r\n\
+                                    \r\n").unwrap(),
+                   Response::Redirect("/b".to_owned()));
+        assert!(parse_response(b"HTTP/1.0 404 Not Found\r\n\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.0 301 Moved\r\n\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.0 200 OK\r\n").is_err());
+        assert!(parse_response(b"SSH-2.0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn fetches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            let replies = [
+                &b"HTTP/1.0 301 Moved\r\nLocation: /new\r\n\r\n"[..],
+                b"HTTP/1.0 200 OK\r\n\r\nif code=1 then: a\n",
+            ];
+            for reply in replies.iter() {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                requests.push(String::from_utf8_lossy(&request)
+                              .lines().next().unwrap().to_owned());
+                stream.write_all(reply).unwrap();
+            }
+            requests
+        });
+        let url = format!("http://127.0.0.1:{}/old.conf", port);
+        assert_eq!(fetch_url(&url, false).unwrap(), "if code=1 then: a\n");
+        assert_eq!(server.join().unwrap(),
+                   ["GET /old.conf HTTP/1.0", "GET /new HTTP/1.0"]);
+    }
+
+    #[test]
+    fn only_this_machine() {
+        // refused before anything is sent, so there's no need for a server
+        // (192.0.2.0/24 is only for documentation)
+        assert_eq!(fetch_url("http://192.0.2.1/a.conf", false).unwrap_err()
+                       .kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fetch_url("http://[2001:db8::1]/a.conf", false)
+                       .unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+}
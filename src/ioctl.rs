@@ -0,0 +1,97 @@
+//! Wrappers for the evdev ioctls that input2cmds uses.
+
+use std::{
+    io,
+    os::unix::io::RawFd,
+};
+
+/// The magic number shared by all evdev ioctls.
+const EVDEV_MAGIC: libc::c_ulong = b'E' as libc::c_ulong;
+
+/// Builds an ioctl request number that reads `size` bytes, like the kernel's
+/// `_IOR` macro.
+const fn ior(nr: libc::c_ulong, size: usize) -> libc::c_ulong {
+    (2 << 30) | ((size as libc::c_ulong) << 16) | (EVDEV_MAGIC << 8) | nr
+}
+
+/// Builds an ioctl request number that writes `size` bytes, like the kernel's
+/// `_IOW` macro.
+const fn iow(nr: libc::c_ulong, size: usize) -> libc::c_ulong {
+    (1 << 30) | ((size as libc::c_ulong) << 16) | (EVDEV_MAGIC << 8) | nr
+}
+
+/// Asks the device about one of its absolute axes (`EVIOCGABS`), including
+/// its current value. Fails if the device doesn't have that axis.
+pub(crate) fn abs_info(fd: RawFd, axis: u16)
+                       -> io::Result<libc::input_absinfo> {
+    let request = ior(0x40 + axis as libc::c_ulong,
+                      std::mem::size_of::<libc::input_absinfo>());
+    let mut ret = libc::input_absinfo {
+        value: 0, minimum: 0, maximum: 0, fuzz: 0, flat: 0, resolution: 0,
+    };
+    if unsafe { libc::ioctl(fd, request as _, &mut ret) } < 0 {
+        Err(io::Error::last_os_error())
+    }
+    else {
+        Ok(ret)
+    }
+}
+
+/// Asks the device for its bus type, vendor, product, and version
+/// (`EVIOCGID`).
+pub(crate) fn device_id(fd: RawFd) -> io::Result<libc::input_id> {
+    let request = ior(0x02, std::mem::size_of::<libc::input_id>());
+    let mut ret = libc::input_id {
+        bustype: 0, vendor: 0, product: 0, version: 0,
+    };
+    if unsafe { libc::ioctl(fd, request as _, &mut ret) } < 0 {
+        Err(io::Error::last_os_error())
+    }
+    else {
+        Ok(ret)
+    }
+}
+
+/// Asks the device which event types it can send (`EVIOCGBIT(0)`), or, if
+/// `type_` isn't 0, which codes of that type, as a bitmap in `bits`.
+/// Returns how many bytes of `bits` were filled in; the rest are left alone.
+pub(crate) fn event_bits(fd: RawFd, type_: u16, bits: &mut [u8])
+                         -> io::Result<usize> {
+    let request = ior(0x20 + type_ as libc::c_ulong, bits.len());
+    match unsafe { libc::ioctl(fd, request as _, bits.as_mut_ptr()) } {
+        x if x < 0 => Err(io::Error::last_os_error()),
+        x => Ok(x as usize),
+    }
+}
+
+/// Asks the device which of its keys are down right now (`EVIOCGKEY`), as a
+/// bitmap in `bits`. Returns how many bytes of `bits` were filled in.
+pub(crate) fn key_state(fd: RawFd, bits: &mut [u8]) -> io::Result<usize> {
+    let request = ior(0x18, bits.len());
+    match unsafe { libc::ioctl(fd, request as _, bits.as_mut_ptr()) } {
+        x if x < 0 => Err(io::Error::last_os_error()),
+        x => Ok(x as usize),
+    }
+}
+
+/// Asks the device which of its LEDs are lit right now (`EVIOCGLED`), as a
+/// bitmap in `bits`. Returns how many bytes of `bits` were filled in.
+pub(crate) fn led_state(fd: RawFd, bits: &mut [u8]) -> io::Result<usize> {
+    let request = ior(0x19, bits.len());
+    match unsafe { libc::ioctl(fd, request as _, bits.as_mut_ptr()) } {
+        x if x < 0 => Err(io::Error::last_os_error()),
+        x => Ok(x as usize),
+    }
+}
+
+/// Grabs the device (`EVIOCGRAB`), so that its events only come to us, or
+/// lets it go again. Fails with `EBUSY` if someone else already grabbed it.
+pub(crate) fn grab(fd: RawFd, grab: bool) -> io::Result<()> {
+    let request = iow(0x90, std::mem::size_of::<libc::c_int>());
+    if unsafe { libc::ioctl(fd, request as _, grab as libc::c_int) } < 0 {
+        Err(io::Error::last_os_error())
+    }
+    else {
+        Ok(())
+    }
+}
@@ -0,0 +1,1294 @@
+//! Reading and writing configuration as JSON Lines, for programs that edit
+//! configuration without wanting to parse the usual format themselves.
+
+use std::{convert::TryFrom, time::Duration};
+
+use crate::{
+    Action, AxisMap, AxisMatch, BucketMatch, Calibration, ComboMatch,
+    CommandOptions, Config, ConfigError, DBusCall, DeviceKind, DeviceQuery,
+    DeviceSpec, FieldMatcher, HatMatch, IdleMatch, InputMatch, Latch,
+    LedSetting, Location, MinInterval, ProfileSwitch, RegionMatch,
+    SequenceMatch, TapMatch, ZoneMatch, parse_config,
+    config::{
+        Bounded, HAT_DIRECTIONS, MAX_IDLE_SECS, format_capability, idle_after,
+        is_env_name, json_string, parse_bucket_condition, parse_capability,
+        parse_matcher,
+    },
+};
+
+/// A parsed JSON value.
+#[derive(Clone,Debug,PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// The fields of an object, in the order they were given.
+    Object(Vec<(String, Json)>),
+}
+
+/// Parses a JSON value from the start of `text`, and returns it along with
+/// whatever came after it.
+fn parse_value(text: &str) -> Result<(Json, &str), String> {
+    let text = text.trim_start();
+    let c = match text.chars().next() {
+        Some(x) => x,
+        None => return Err("the line ended too soon".to_owned()),
+    };
+    match c {
+        '{' | '[' => {
+            let close = if c == '{' { '}' } else { ']' };
+            let mut fields = Vec::new();
+            let mut elements = Vec::new();
+            let mut rest = text[1..].trim_start();
+            if let Some(rest) = rest.strip_prefix(close) {
+                return Ok((if c == '{' { Json::Object(fields) }
+                           else { Json::Array(elements) }, rest))
+            }
+            loop {
+                let (value, after) = parse_value(rest)?;
+                if c == '{' {
+                    let key = match value {
+                        Json::String(x) => x,
+                        _ => return Err("object keys must be strings"
+                                        .to_owned()),
+                    };
+                    let after = match after.trim_start().strip_prefix(':') {
+                        Some(x) => x,
+                        None => return Err(format!("wanted a ':' after {:?}",
+                                                   key)),
+                    };
+                    let (value, after) = parse_value(after)?;
+                    fields.push((key, value));
+                    rest = after;
+                }
+                else {
+                    elements.push(value);
+                    rest = after;
+                }
+                rest = rest.trim_start();
+                if let Some(after) = rest.strip_prefix(',') {
+                    rest = after;
+                }
+                else if let Some(after) = rest.strip_prefix(close) {
+                    return Ok((if c == '{' { Json::Object(fields) }
+                               else { Json::Array(elements) }, after))
+                }
+                else {
+                    return Err(format!("wanted a ',' or a '{}'", close))
+                }
+            }
+        },
+        '"' => {
+            let (string, rest) = parse_string(&text[1..])?;
+            Ok((Json::String(string), rest))
+        },
+        _ => {
+            for (word, value) in [("true", Json::Bool(true)),
+                                  ("false", Json::Bool(false)),
+                                  ("null", Json::Null)].iter() {
+                if let Some(rest) = text.strip_prefix(word) {
+                    return Ok((value.clone(), rest))
+                }
+            }
+            let end = text.find(|c: char| !"+-0123456789.eE".contains(c))
+                .unwrap_or(text.len());
+            match text[..end].parse() {
+                Ok(x) if end > 0 => Ok((Json::Number(x), &text[end..])),
+                _ => Err(format!("unexpected {:?}", c)),
+            }
+        },
+    }
+}
+
+/// Parses the rest of a JSON string, whose opening quote has already been
+/// seen, and returns it along with whatever came after the closing quote.
+fn parse_string(text: &str) -> Result<(String, &str), String> {
+    let mut ret = String::new();
+    let mut chars = text.char_indices();
+    // the first half of a surrogate pair, if we've just seen one
+    let mut high = None;
+    while let Some((i, c)) = chars.next() {
+        let c = match c {
+            '"' if high.is_none() => return Ok((ret, &text[i+1..])),
+            '\\' => match chars.next().map(|x| x.1) {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('/') => '/',
+                Some('b') => '\u{8}',
+                Some('f') => '\u{c}',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).map(|x| x.1)
+                        .collect();
+                    let unit = match u32::from_str_radix(&hex, 16) {
+                        Ok(x) if hex.len() == 4 => x,
+                        _ => return Err("bad \"\\u\" escape".to_owned()),
+                    };
+                    if (0xd800..0xdc00).contains(&unit) {
+                        high = Some(unit);
+                        continue
+                    }
+                    let unit = match high.take() {
+                        Some(high) if (0xdc00..0xe000).contains(&unit) => {
+                            0x10000 + ((high - 0xd800) << 10)
+                                + (unit - 0xdc00)
+                        },
+                        _ => unit,
+                    };
+                    match char::from_u32(unit) {
+                        Some(x) => x,
+                        None => return Err("bad \"\\u\" escape".to_owned()),
+                    }
+                },
+                _ => return Err("bad escape in a string".to_owned()),
+            },
+            c => c,
+        };
+        if high.take().is_some() {
+            return Err("bad \"\\u\" escape".to_owned())
+        }
+        ret.push(c);
+    }
+    Err("unterminated string".to_owned())
+}
+
+/// Parses a line that should hold exactly one JSON value.
+fn parse(text: &str) -> Result<Json, String> {
+    let (value, rest) = parse_value(text)?;
+    if !rest.trim().is_empty() {
+        return Err("wanted only one JSON value on the line".to_owned())
+    }
+    Ok(value)
+}
+
+/// The fields of one JSON object, for picking apart.
+struct Fields<'a>(&'a [(String, Json)]);
+
+impl Fields<'_> {
+    /// Fails if the object has any field that isn't in `allowed`.
+    fn only(&self, allowed: &[&str]) -> Result<(), String> {
+        match self.0.iter().find(|x| !allowed.contains(&x.0.as_str())) {
+            Some((key, _)) => Err(format!("unknown field {:?}", key)),
+            None => Ok(()),
+        }
+    }
+    /// Returns the field with the given name, if the object has it and it's
+    /// not `null`.
+    fn get(&self, key: &str) -> Option<&Json> {
+        self.0.iter().find(|x| x.0 == key).map(|x| &x.1)
+            .filter(|x| **x != Json::Null)
+    }
+    /// Returns a string field, checking that it can be written into a
+    /// configuration line. If `word` is set, it also can't contain double
+    /// quotes, since it has to fit inside them.
+    fn string(&self, key: &str, word: bool) -> Result<Option<String>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Json::String(x)) => check_text(key, x, word).map(Some),
+            Some(_) => Err(format!("{:?} wants a string", key)),
+        }
+    }
+    /// Returns a field that should be a whole number that fits in a `T`.
+    fn number<T: TryFrom<i64>>(&self, key: &str)
+                               -> Result<Option<T>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Json::Number(x)) if x.fract() == 0.0 => {
+                T::try_from(*x as i64).map(Some)
+                    .map_err(|_| format!("{:?} is out of range", key))
+            },
+            Some(_) => Err(format!("{:?} wants a whole number", key)),
+        }
+    }
+    /// Returns a field that should be a condition on one of the numbers in an
+    /// event: either a whole number, or a string as it would appear after
+    /// `key=` (like `"30-39"`).
+    fn matcher<T>(&self, key: &str) -> Result<FieldMatcher<T>, String>
+    where T: TryFrom<i64> + Bounded + Ord + Copy {
+        match self.get(key) {
+            Some(Json::String(x)) => {
+                parse_matcher(key, &check_text(key, x, true)?)
+            },
+            _ => self.number(key).map(FieldMatcher::from),
+        }
+    }
+    /// Returns a field that should be an array of whole numbers that each fit
+    /// in a `T`. `what` says what the numbers are, for the error message.
+    fn numbers<T: TryFrom<i64>>(&self, key: &str, what: &str)
+                                -> Result<Option<Vec<T>>, String> {
+        let numbers = match self.get(key) {
+            None => return Ok(None),
+            Some(Json::Array(x)) => x.iter().map(|x| match x {
+                Json::Number(x) if x.fract() == 0.0 => {
+                    T::try_from(*x as i64).ok()
+                },
+                _ => None,
+            }).collect::<Option<_>>(),
+            Some(_) => None,
+        };
+        numbers.map(Some)
+            .ok_or_else(|| format!("{:?} wants an array of {}", key, what))
+    }
+    /// Returns a field that should be an array of two whole numbers, like the
+    /// `[0, 100]` for `to=0-100`.
+    fn pair<T: TryFrom<i64> + Copy>(&self, key: &str)
+                                    -> Result<Option<(T, T)>, String> {
+        match self.numbers(key, "two numbers")?.as_deref() {
+            None => Ok(None),
+            Some(&[lo, hi]) => Ok(Some((lo, hi))),
+            Some(_) => Err(format!("{:?} wants an array of two numbers",
+                                   key)),
+        }
+    }
+    /// Returns a field that should be a shell command, which, being at the
+    /// end of a line, can contain a `#`.
+    fn command(&self, key: &str) -> Result<Option<String>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Json::String(x)) => {
+                check_action_text(key, x, false).map(Some)
+            },
+            Some(_) => Err(format!("{:?} wants a string", key)),
+        }
+    }
+    /// Returns the `"action"` field, which every directive with an action
+    /// needs.
+    fn action(&self) -> Result<Action, String> {
+        match self.get("action") {
+            Some(Json::Object(x)) => to_action(&Fields(x)),
+            _ => Err("missing \"action\"".to_owned()),
+        }
+    }
+    /// Returns a field that should be `true` or `false`.
+    fn boolean(&self, key: &str) -> Result<Option<bool>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Json::Bool(x)) => Ok(Some(*x)),
+            Some(_) => Err(format!("{:?} wants true or false", key)),
+        }
+    }
+}
+
+/// Checks that `text`, from the field `key`, can be written into a
+/// configuration line, and returns a copy of it. `word` is as for
+/// [`Fields::string`].
+fn check_text(key: &str, text: &str, word: bool) -> Result<String, String> {
+    if text.contains('#') {
+        Err(format!("{:?} can't contain a '#'", key))
+    }
+    else {
+        check_action_text(key, text, word)
+    }
+}
+
+/// Like [`check_text`], but for the text of an action, which goes after its
+/// keyword's colon, where a `#` doesn't start a comment.
+fn check_action_text(key: &str, text: &str, word: bool)
+                     -> Result<String, String> {
+    if text.contains(['\n', '\r']) {
+        Err(format!("{:?} can't contain a line break", key))
+    }
+    else if word && text.contains('"') {
+        Err(format!("{:?} can't contain a '\"'", key))
+    }
+    else {
+        Ok(text.to_owned())
+    }
+}
+
+/// Turns one object from a JSON Lines configuration into a line of the usual
+/// form.
+fn to_line(json: &Json) -> Result<String, String> {
+    let fields = match json {
+        Json::Object(x) => Fields(x),
+        _ => return Err("each line must be a JSON object".to_owned()),
+    };
+    let directive = match fields.string("directive", true)? {
+        Some(x) => x,
+        None => return Err("missing \"directive\"".to_owned()),
+    };
+    match directive.as_str() {
+        "dev" => {
+            fields.only(&["directive", "label", "path", "type", "has",
+                          "virtual", "grab", "queue", "policy",
+                          "maxrate"])?;
+            let virtual_policy = match fields.string("virtual", true)? {
+                None => None,
+                Some(x) => match x.parse() {
+                    Ok(x) => Some(x),
+                    Err(()) => return Err("\"virtual\" wants \"include\", \
+                                           \"exclude\", or \"only\""
+                                          .to_owned()),
+                },
+            };
+            let queue_policy = match fields.string("policy", true)? {
+                None => None,
+                Some(x) => Some(x.parse().map_err(|()| {
+                    "\"policy\" wants \"drop-newest\", \"drop-oldest\", \
+                     or \"block\"".to_owned()
+                })?),
+            };
+            let queue = fields.number("queue")?;
+            if queue_policy.is_some() && queue.is_none() {
+                return Err("\"policy\" needs a \"queue\"".to_owned())
+            }
+            let kind = match fields.string("type", true)? {
+                None => None,
+                Some(x) => Some(x.parse().map_err(|()| {
+                    "\"type\" wants \"keyboard\", \"mouse\", \
+                     \"gamepad\", \"joystick\", or \"any\"".to_owned()
+                })?),
+            };
+            let has = match fields.get("has") {
+                None => None,
+                Some(Json::Array(x)) => Some(x.iter().map(|x| match x {
+                    Json::String(x) => parse_capability(x),
+                    _ => None,
+                }).collect::<Option<_>>()
+                    .ok_or("\"has\" wants an array of codes, like \
+                            \"KEY_A\" or \"1/30\"")?),
+                Some(_) => return Err("\"has\" wants an array of codes, \
+                                       like \"KEY_A\" or \"1/30\""
+                                      .to_owned()),
+            };
+            let query = if kind.is_some() || has.is_some() {
+                Some(DeviceQuery {
+                    kind: kind.unwrap_or(DeviceKind::Any),
+                    has: has.unwrap_or_default(),
+                })
+            }
+            else { None };
+            let path = fields.string("path", false)?;
+            let path = match (path, query.is_some()) {
+                (Some(_), true) => {
+                    return Err("\"path\" can't go with \"type\" or \
+                                \"has\"".to_owned())
+                },
+                (Some(x), false) => x,
+                (None, true) => String::new(),
+                (None, false) => return Err("missing \"path\"".to_owned()),
+            };
+            let device = DeviceSpec {
+                label: fields.string("label", true)?,
+                path, query, virtual_policy,
+                grab: fields.boolean("grab")?.unwrap_or(false),
+                queue, queue_policy: queue_policy.unwrap_or_default(),
+                max_rate: match fields.number("maxrate")? {
+                    Some(0) => return Err("\"maxrate\" wants a number of \
+                                           times per second, more than \
+                                           zero".to_owned()),
+                    x => x,
+                },
+                location: nowhere(),
+            };
+            Ok(device.to_string())
+        },
+        "if" | "unless" => {
+            fields.only(&with_options(&["directive", "dev", "type", "code",
+                                        "value", "slot", "above", "below",
+                                        "with_held", "with_latch", "profile",
+                                        "max", "held", "delay",
+                                        "cancel_on_release", "priority",
+                                        "when", "between", "observe",
+                                        "action"]))?;
+            let with_latch = match fields.get("with_latch") {
+                None => Vec::new(),
+                Some(Json::Array(x)) => x.iter().map(|x| match x {
+                    Json::String(x) if !x.is_empty() => Some(x.clone()),
+                    _ => None,
+                }).collect::<Option<_>>()
+                    .ok_or("\"with_latch\" wants an array of latch names")?,
+                Some(_) => return Err("\"with_latch\" wants an array of \
+                                       latch names".to_owned()),
+            };
+            let held = match fields.number::<u64>("held")? {
+                Some(0) => return Err("\"held\" wants a positive number of \
+                                       milliseconds".to_owned()),
+                x => x.map(Duration::from_millis),
+            };
+            let rule = InputMatch {
+                negated: directive == "unless",
+                wants_device: fields.string("dev", true)?,
+                wants_type: fields.matcher("type")?,
+                wants_code: fields.matcher("code")?,
+                wants_value: fields.matcher("value")?,
+                wants_slot: fields.number("slot")?,
+                hysteresis: match (fields.number("above")?,
+                                   fields.number("below")?) {
+                    (None, None) => None,
+                    (Some(above), Some(below)) if below < above => {
+                        Some((above, below))
+                    },
+                    _ => return Err("\"above\" and \"below\" must be given \
+                                     together, with \"below\" less than \
+                                     \"above\"".to_owned()),
+                },
+                with_held: fields.numbers("with_held", "key codes")?
+                    .unwrap_or_default(),
+                with_latch,
+                profile: fields.string("profile", true)?,
+                max: fields.number("max")?,
+                held,
+                delay: fields.number::<u64>("delay")?
+                    .map(Duration::from_millis),
+                cancel_on_release: fields.boolean("cancel_on_release")?
+                    .unwrap_or(false),
+                priority: fields.number("priority")?.unwrap_or(0),
+                options: to_options(&fields)?,
+                when: fields.string("when", true)?,
+                between: match fields.string("between", false)? {
+                    None => None,
+                    Some(x) => Some(x.parse().map_err(|()| {
+                        "\"between\" wants two different times, like \
+                         \"21:00-06:00\"".to_owned()
+                    })?),
+                },
+                observe: fields.boolean("observe")?.unwrap_or(false),
+                action: fields.action()?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "map" => {
+            fields.only(&with_options(&["directive", "dev", "code", "to",
+                                        "action"]))?;
+            let rule = AxisMap {
+                wants_device: fields.string("dev", true)?,
+                wants_code: required(fields.number("code")?, "code")?,
+                to: required(fields.pair("to")?, "to")?,
+                options: to_options(&fields)?,
+                action: fields.action()?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "axis" => {
+            fields.only(&with_options(&["directive", "dev", "code", "center",
+                                        "threshold", "then_pos",
+                                        "then_neg"]))?;
+            let rule = AxisMatch {
+                wants_device: fields.string("dev", true)?,
+                wants_code: required(fields.number("code")?, "code")?,
+                center: fields.number("center")?.unwrap_or(0),
+                threshold: required(fields.number("threshold")?,
+                                    "threshold")?,
+                options: to_options(&fields)?,
+                positive: fields.command("then_pos")?,
+                negative: fields.command("then_neg")?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "buckets" => {
+            fields.only(&with_options(&["directive", "dev", "code",
+                                        "hysteresis", "buckets", "else"]))?;
+            const WANTED: &str = "\"buckets\" wants an array of objects, \
+                                  each with a \"value\" and a \"then\"";
+            let buckets = match fields.get("buckets") {
+                None => Vec::new(),
+                Some(Json::Array(x)) => x.iter().map(|x| match x {
+                    Json::Object(x) => {
+                        let bucket = Fields(x);
+                        bucket.only(&["value", "then"])?;
+                        let value = match bucket.get("value") {
+                            Some(Json::String(x)) => {
+                                parse_bucket_condition(x).ok().flatten()
+                            },
+                            _ => bucket.number("value").ok().flatten()
+                                .map(FieldMatcher::Is),
+                        };
+                        match (value, bucket.command("then")?) {
+                            (Some(value), Some(then)) => Ok((value, then)),
+                            _ => Err(WANTED.to_owned()),
+                        }
+                    },
+                    _ => Err(WANTED.to_owned()),
+                }).collect::<Result<_, String>>()?,
+                Some(_) => return Err(WANTED.to_owned()),
+            };
+            let rule = BucketMatch {
+                wants_device: fields.string("dev", true)?,
+                wants_code: required(fields.number("code")?, "code")?,
+                hysteresis: fields.number("hysteresis")?.unwrap_or(0),
+                options: to_options(&fields)?,
+                buckets,
+                otherwise: fields.command("else")?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "sequence" => {
+            fields.only(&with_options(&["directive", "dev", "codes",
+                                        "within", "action"]))?;
+            let rule = SequenceMatch {
+                wants_device: fields.string("dev", true)?,
+                codes: fields.numbers("codes", "key codes")?
+                    .unwrap_or_default(),
+                within: Duration::from_millis(
+                    required(fields.number("within")?, "within")?),
+                options: to_options(&fields)?,
+                action: fields.action()?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "combo" => {
+            fields.only(&with_options(&["directive", "keys", "action"]))?;
+            const WANTED: &str = "\"keys\" wants an array of objects, each \
+                                  with a \"code\" and maybe a \"dev\"";
+            let keys = match fields.get("keys") {
+                Some(Json::Array(x)) => x.iter().map(|x| match x {
+                    Json::Object(x) => {
+                        let key = Fields(x);
+                        key.only(&["dev", "code"])?;
+                        match key.number("code")? {
+                            Some(code) => Ok((key.string("dev", true)?, code)),
+                            None => Err(WANTED.to_owned()),
+                        }
+                    },
+                    _ => Err(WANTED.to_owned()),
+                }).collect::<Result<_, String>>()?,
+                _ => return Err(WANTED.to_owned()),
+            };
+            let rule = ComboMatch {
+                keys,
+                options: to_options(&fields)?,
+                action: fields.action()?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "while-held" => {
+            fields.only(&with_options(&["directive", "dev", "held_code",
+                                        "tap_code", "count", "action"]))?;
+            let rule = TapMatch {
+                wants_device: fields.string("dev", true)?,
+                held_code: required(fields.number("held_code")?,
+                                    "held_code")?,
+                tap_code: required(fields.number("tap_code")?, "tap_code")?,
+                count: required(fields.number("count")?, "count")?,
+                options: to_options(&fields)?,
+                action: fields.action()?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "hat" => {
+            fields.only(&with_options(&["directive", "dev", "code_x",
+                                        "code_y", "direction", "action"]))?;
+            let direction = required(fields.string("direction", true)?,
+                                     "direction")?;
+            let direction = match HAT_DIRECTIONS.iter()
+            .find(|x| x.0 == direction) {
+                Some(x) => x.1,
+                None => return Err(format!("{:?} is not a direction",
+                                           direction)),
+            };
+            let rule = HatMatch {
+                wants_device: fields.string("dev", true)?,
+                code_x: required(fields.number("code_x")?, "code_x")?,
+                code_y: required(fields.number("code_y")?, "code_y")?,
+                direction,
+                options: to_options(&fields)?,
+                action: fields.action()?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "zone" => {
+            fields.only(&with_options(&["directive", "dev", "code_x",
+                                        "code_y", "x", "y", "action"]))?;
+            let rule = ZoneMatch {
+                wants_device: fields.string("dev", true)?,
+                code_x: required(fields.number("code_x")?, "code_x")?,
+                code_y: required(fields.number("code_y")?, "code_y")?,
+                x: required(fields.pair("x")?, "x")?,
+                y: required(fields.pair("y")?, "y")?,
+                options: to_options(&fields)?,
+                action: fields.action()?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "region" => {
+            fields.only(&with_options(&["directive", "dev", "code_x",
+                                        "code_y", "x_min", "x_max", "y_min",
+                                        "y_max", "action"]))?;
+            let rule = RegionMatch {
+                wants_device: fields.string("dev", true)?,
+                code_x: required(fields.number("code_x")?, "code_x")?,
+                code_y: required(fields.number("code_y")?, "code_y")?,
+                x: (fields.number("x_min")?, fields.number("x_max")?),
+                y: (fields.number("y_min")?, fields.number("y_max")?),
+                options: to_options(&fields)?,
+                action: fields.action()?,
+                location: nowhere(),
+            };
+            Ok(rule.to_string())
+        },
+        "calibrate" => {
+            fields.only(&["directive", "dev", "code", "center", "min",
+                          "max"])?;
+            let calibration = Calibration {
+                wants_device: fields.string("dev", true)?,
+                wants_code: required(fields.number("code")?, "code")?,
+                center: required(fields.number("center")?, "center")?,
+                min: required(fields.number("min")?, "min")?,
+                max: required(fields.number("max")?, "max")?,
+                location: nowhere(),
+            };
+            Ok(calibration.to_string())
+        },
+        "latch" | "switch-profile" => {
+            fields.only(&["directive", "name", "dev", "code"])?;
+            let name = required(fields.string("name", true)?, "name")?;
+            let wants_device = fields.string("dev", true)?;
+            let wants_code = required(fields.number("code")?, "code")?;
+            let location = nowhere();
+            Ok(if directive == "latch" {
+                Latch { name, wants_device, wants_code, location }.to_string()
+            }
+            else {
+                ProfileSwitch { name, wants_device, wants_code, location }
+                    .to_string()
+            })
+        },
+        "min-interval" => {
+            fields.only(&["directive", "interval", "drop"])?;
+            let min_interval = MinInterval {
+                interval: Duration::from_millis(
+                    required(fields.number("interval")?, "interval")?),
+                drop: fields.boolean("drop")?.unwrap_or(false),
+            };
+            Ok(min_interval.to_string())
+        },
+        "idle" => {
+            fields.only(&["directive", "after", "action"])?;
+            let after = match fields.get("after") {
+                Some(Json::Number(x)) => idle_after(*x),
+                _ => None,
+            };
+            let after = match after {
+                Some(x) => x,
+                None => return Err(format!("\"after\" wants a number of \
+                                            seconds, from 0 to {}",
+                                           MAX_IDLE_SECS)),
+            };
+            let idle = IdleMatch {
+                after, action: fields.action()?, location: nowhere(),
+            };
+            Ok(idle.to_string())
+        },
+        "on-activity" | "on-start" | "on-stop" => {
+            fields.only(&["directive", "action"])?;
+            Ok(format!("{} {}", directive, fields.action()?))
+        },
+        x => Err(format!("unknown directive {:?}", x)),
+    }
+}
+
+/// The fields that hold a directive's `CommandOptions`.
+const OPTION_FIELDS: &[&str] = &["nice", "cwd", "shell", "expect_exit", "env"];
+
+/// Returns `fields` with the `OPTION_FIELDS` added, for
+/// [`Fields::only`].
+fn with_options<'a>(fields: &[&'a str]) -> Vec<&'a str> {
+    let mut ret = fields.to_vec();
+    ret.extend_from_slice(OPTION_FIELDS);
+    ret
+}
+
+/// The location given to directives that are only being turned into a line,
+/// which the usual parser then gives their real location.
+fn nowhere() -> Location {
+    Location { file: String::new(), line: 0 }
+}
+
+/// Returns the value of a field that has to be given.
+fn required<T>(value: Option<T>, key: &str) -> Result<T, String> {
+    value.ok_or_else(|| format!("missing {:?}", key))
+}
+
+/// Turns the `OPTION_FIELDS` of an object into `CommandOptions`.
+fn to_options(fields: &Fields) -> Result<CommandOptions, String> {
+    Ok(CommandOptions {
+        nice: fields.number("nice")?,
+        cwd: fields.string("cwd", true)?,
+        shell: fields.string("shell", true)?,
+        expect_exit: fields.numbers("expect_exit", "exit statuses")?,
+        env: match fields.get("env") {
+            None => Vec::new(),
+            Some(Json::Object(x)) => x.iter().map(|(name, x)| match x {
+                Json::String(_) if !is_env_name(name) => {
+                    Err(format!("{:?} can't be the name of an environment \
+                                 variable", name))
+                },
+                Json::String(x) => {
+                    Ok((name.clone(), check_text("env", x, true)?))
+                },
+                _ => Err("\"env\" wants an object whose values are strings"
+                         .to_owned()),
+            }).collect::<Result<_, String>>()?,
+            Some(_) => return Err("\"env\" wants an object whose values \
+                                   are strings".to_owned()),
+        },
+    })
+}
+
+/// Turns the `"action"` of an object into an `Action`.
+fn to_action(fields: &Fields) -> Result<Action, String> {
+    fields.only(&["then", "then-file", "exec", "then-dbus", "then-led"])?;
+    if fields.0.len() != 1 {
+        return Err("\"action\" wants exactly one of \"then\", \
+                    \"then-file\", \"exec\", \"then-dbus\", or \
+                    \"then-led\"".to_owned())
+    }
+    for key in ["then", "then-file"].iter() {
+        let text = match fields.get(key) {
+            None => continue,
+            Some(Json::String(x)) => check_action_text(key, x, false)?,
+            Some(_) => return Err(format!("{:?} wants a string", key)),
+        };
+        return Ok(if *key == "then" { Action::Shell(text) }
+                  else { Action::File(text) })
+    }
+    let key = ["exec", "then-dbus", "then-led"].iter()
+        .find(|x| fields.get(x).is_some()).copied().unwrap_or("then-led");
+    let words = match fields.get(key) {
+        Some(Json::Array(x)) if !x.is_empty() => {
+            x.iter().map(|x| match x {
+                Json::String(x) => check_action_text(key, x, true),
+                _ => Err(format!("{:?} wants an array of strings", key)),
+            }).collect::<Result<Vec<_>, _>>()?
+        },
+        _ => return Err(format!("{:?} wants an array of strings", key)),
+    };
+    match key {
+        "exec" => Ok(Action::Exec(words)),
+        "then-dbus" => DBusCall::from_words(&words).map(Action::DBus),
+        _ => LedSetting::from_words(&words).map(Action::Led),
+    }
+}
+
+/// Parses configuration in the JSON Lines form that
+/// [`Config::to_json_lines`](struct.Config.html#method.to_json_lines)
+/// writes. Each object is turned back into a line of the usual form, and
+/// the result is parsed as usual, so that errors point at the line they're
+/// on. Blank lines are skipped. `path` is only used in error messages.
+pub fn parse_json_lines(path: &str, text: &str)
+                        -> Result<Config, ConfigError> {
+    let mut lines = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            lines.push(String::new());
+            continue
+        }
+        match parse(line).and_then(|x| to_line(&x)) {
+            Ok(x) => lines.push(x),
+            Err(message) => return Err(ConfigError {
+                file: path.to_owned(), line: Some(line_number + 1), message,
+            }),
+        }
+    }
+    parse_config(path, &lines.join("\n"))
+}
+
+/// Writes the `"dev"` field of a directive, with a comma in front, or
+/// nothing if it doesn't have one.
+fn dev_json(device: &Option<String>) -> String {
+    device.as_ref().map(|x| format!(",\"dev\":{}", json_string(x)))
+        .unwrap_or_default()
+}
+
+/// Writes the `OPTION_FIELDS` that `options` has, each with a comma in
+/// front.
+fn options_json(options: &CommandOptions) -> String {
+    let mut ret = String::new();
+    if let Some(x) = options.nice {
+        ret.push_str(&format!(",\"nice\":{}", x));
+    }
+    let strings = [("cwd", &options.cwd), ("shell", &options.shell)];
+    for (key, value) in strings.iter() {
+        if let Some(x) = value {
+            ret.push_str(&format!(",\"{}\":{}", key, json_string(x)));
+        }
+    }
+    if let Some(x) = options.expect_exit.as_ref() {
+        let statuses: Vec<String> = x.iter().map(i32::to_string).collect();
+        ret.push_str(&format!(",\"expect_exit\":[{}]", statuses.join(",")));
+    }
+    if !options.env.is_empty() {
+        let vars: Vec<String> = options.env.iter().map(|(name, value)| {
+            format!("{}:{}", json_string(name), json_string(value))
+        }).collect();
+        ret.push_str(&format!(",\"env\":{{{}}}", vars.join(",")));
+    }
+    ret
+}
+
+/// Writes an action as the object that [`to_action`] reads.
+fn action_json(action: &Action) -> String {
+    let words = |key: &str, words: &[String]| {
+        let words: Vec<String> = words.iter().map(|x| json_string(x))
+            .collect();
+        format!("{{\"{}\":[{}]}}", key, words.join(","))
+    };
+    match action {
+        Action::Shell(x) => format!("{{\"then\":{}}}", json_string(x)),
+        Action::File(x) => format!("{{\"then-file\":{}}}", json_string(x)),
+        Action::Exec(x) => words("exec", x),
+        Action::DBus(x) => words("then-dbus", &x.words()),
+        Action::Led(x) => words("then-led", &x.words()),
+    }
+}
+
+/// Writes a condition on one of the numbers in an event as JSON: a number if
+/// it's just one number, or otherwise a string. Returns `None` for
+/// `FieldMatcher::Any`, which is left out.
+fn matcher_json<T>(matcher: &FieldMatcher<T>) -> Option<String>
+where T: std::fmt::Display + PartialEq {
+    match matcher {
+        FieldMatcher::Any => None,
+        FieldMatcher::Is(x) => Some(x.to_string()),
+        x => Some(json_string(&x.to_string())),
+    }
+}
+
+impl Config {
+    /// Formats the configuration as JSON Lines: one JSON object per line,
+    /// each with a `"directive"` saying what it is, and a field for each of
+    /// its options. Defaults have already been applied to every line they
+    /// apply to, so there are no `defaults` directives.
+    pub fn to_json_lines(&self) -> String {
+        let mut ret = String::new();
+        for device in self.devices.iter() {
+            ret.push_str("{\"directive\":\"dev\"");
+            if let Some(x) = device.label.as_ref() {
+                ret.push_str(&format!(",\"label\":{}", json_string(x)));
+            }
+            match device.query.as_ref() {
+                Some(query) => {
+                    ret.push_str(&format!(",\"type\":\"{}\"", query.kind));
+                    if !query.has.is_empty() {
+                        let has: Vec<String> = query.has.iter()
+                            .map(|&(type_, code)| {
+                                json_string(&format_capability(type_, code))
+                            }).collect();
+                        ret.push_str(&format!(",\"has\":[{}]",
+                                              has.join(",")));
+                    }
+                },
+                None => ret.push_str(&format!(",\"path\":{}",
+                                              json_string(&device.path))),
+            }
+            if let Some(x) = device.virtual_policy {
+                ret.push_str(&format!(",\"virtual\":\"{}\"", x));
+            }
+            if let Some(x) = device.queue {
+                ret.push_str(&format!(",\"queue\":{},\"policy\":\"{}\"",
+                                      x, device.queue_policy));
+            }
+            if let Some(x) = device.max_rate {
+                ret.push_str(&format!(",\"maxrate\":{}", x));
+            }
+            ret.push_str(&format!(",\"grab\":{}}}\n", device.grab));
+        }
+        for rule in self.matches.iter() {
+            ret.push_str(&format!("{{\"directive\":\"{}\"",
+                                  if rule.negated { "unless" } else { "if" }));
+            let between = rule.between.map(|x| x.to_string());
+            let strings = [("dev", &rule.wants_device),
+                           ("profile", &rule.profile),
+                           ("when", &rule.when),
+                           ("between", &between)];
+            let fields = [("type", matcher_json(&rule.wants_type)),
+                          ("code", matcher_json(&rule.wants_code)),
+                          ("value", matcher_json(&rule.wants_value))];
+            for (key, value) in fields.iter() {
+                if let Some(x) = value {
+                    ret.push_str(&format!(",\"{}\":{}", key, x));
+                }
+            }
+            let numbers = [("slot", rule.wants_slot),
+                           ("above", rule.hysteresis.map(|x| x.0)),
+                           ("below", rule.hysteresis.map(|x| x.1)),
+                           ("max", rule.max.map(|x| x as i32)),
+                           ("priority", (rule.priority != 0)
+                            .then_some(rule.priority))];
+            for (key, value) in strings.iter() {
+                if let Some(x) = value {
+                    ret.push_str(&format!(",\"{}\":{}", key, json_string(x)));
+                }
+            }
+            for (key, value) in numbers.iter() {
+                if let Some(x) = value {
+                    ret.push_str(&format!(",\"{}\":{}", key, x));
+                }
+            }
+            if let Some(x) = rule.held {
+                ret.push_str(&format!(",\"held\":{}", x.as_millis()));
+            }
+            if let Some(x) = rule.delay {
+                ret.push_str(&format!(",\"delay\":{}", x.as_millis()));
+            }
+            if rule.cancel_on_release {
+                ret.push_str(",\"cancel_on_release\":true");
+            }
+            if !rule.with_held.is_empty() {
+                let codes: Vec<String> = rule.with_held.iter()
+                    .map(u16::to_string).collect();
+                ret.push_str(&format!(",\"with_held\":[{}]",
+                                      codes.join(",")));
+            }
+            if !rule.with_latch.is_empty() {
+                let names: Vec<String> = rule.with_latch.iter()
+                    .map(|x| json_string(x)).collect();
+                ret.push_str(&format!(",\"with_latch\":[{}]",
+                                      names.join(",")));
+            }
+            ret.push_str(&format!("{},\"observe\":{},\"action\":{}}}\n",
+                                  options_json(&rule.options), rule.observe,
+                                  action_json(&rule.action)));
+        }
+        for x in self.maps.iter() {
+            ret.push_str(&format!("{{\"directive\":\"map\"{},\"code\":{},\
+                                   \"to\":[{},{}]{},\"action\":{}}}\n",
+                                  dev_json(&x.wants_device), x.wants_code,
+                                  x.to.0, x.to.1, options_json(&x.options),
+                                  action_json(&x.action)));
+        }
+        for x in self.axes.iter() {
+            ret.push_str(&format!("{{\"directive\":\"axis\"{},\"code\":{},\
+                                   \"center\":{},\"threshold\":{}{}",
+                                  dev_json(&x.wants_device), x.wants_code,
+                                  x.center, x.threshold,
+                                  options_json(&x.options)));
+            let commands = [("then_pos", &x.positive),
+                            ("then_neg", &x.negative)];
+            for (key, value) in commands.iter() {
+                if let Some(x) = value {
+                    ret.push_str(&format!(",\"{}\":{}", key, json_string(x)));
+                }
+            }
+            ret.push_str("}\n");
+        }
+        for x in self.buckets.iter() {
+            let buckets: Vec<String> = x.buckets.iter()
+                .map(|(value, command)| {
+                    format!("{{\"value\":{},\"then\":{}}}",
+                            matcher_json(value)
+                                .unwrap_or_else(|| json_string("*")),
+                            json_string(command))
+                }).collect();
+            ret.push_str(&format!("{{\"directive\":\"buckets\"{},\"code\":{},\
+                                   \"hysteresis\":{}{},\"buckets\":[{}]",
+                                  dev_json(&x.wants_device), x.wants_code,
+                                  x.hysteresis, options_json(&x.options),
+                                  buckets.join(",")));
+            if let Some(x) = x.otherwise.as_ref() {
+                ret.push_str(&format!(",\"else\":{}", json_string(x)));
+            }
+            ret.push_str("}\n");
+        }
+        for x in self.sequences.iter() {
+            let codes: Vec<String> = x.codes.iter().map(u16::to_string)
+                .collect();
+            ret.push_str(&format!("{{\"directive\":\"sequence\"{},\
+                                   \"codes\":[{}],\"within\":{}{},\
+                                   \"action\":{}}}\n",
+                                  dev_json(&x.wants_device), codes.join(","),
+                                  x.within.as_millis(),
+                                  options_json(&x.options),
+                                  action_json(&x.action)));
+        }
+        for x in self.combos.iter() {
+            let keys: Vec<String> = x.keys.iter().map(|(device, code)| {
+                format!("{{{}\"code\":{}}}",
+                        device.as_ref().map(|x| {
+                            format!("\"dev\":{},", json_string(x))
+                        }).unwrap_or_default(), code)
+            }).collect();
+            ret.push_str(&format!("{{\"directive\":\"combo\",\"keys\":[{}]{},\
+                                   \"action\":{}}}\n",
+                                  keys.join(","), options_json(&x.options),
+                                  action_json(&x.action)));
+        }
+        for x in self.taps.iter() {
+            ret.push_str(&format!("{{\"directive\":\"while-held\"{},\
+                                   \"held_code\":{},\"tap_code\":{},\
+                                   \"count\":{}{},\"action\":{}}}\n",
+                                  dev_json(&x.wants_device), x.held_code,
+                                  x.tap_code, x.count,
+                                  options_json(&x.options),
+                                  action_json(&x.action)));
+        }
+        for x in self.hats.iter() {
+            ret.push_str(&format!("{{\"directive\":\"hat\"{},\"code_x\":{},\
+                                   \"code_y\":{},\"direction\":\"{}\"{},\
+                                   \"action\":{}}}\n",
+                                  dev_json(&x.wants_device), x.code_x,
+                                  x.code_y, x.direction_name(),
+                                  options_json(&x.options),
+                                  action_json(&x.action)));
+        }
+        for x in self.zones.iter() {
+            ret.push_str(&format!("{{\"directive\":\"zone\"{},\"code_x\":{},\
+                                   \"code_y\":{},\"x\":[{},{}],\
+                                   \"y\":[{},{}]{},\"action\":{}}}\n",
+                                  dev_json(&x.wants_device), x.code_x,
+                                  x.code_y, x.x.0, x.x.1, x.y.0, x.y.1,
+                                  options_json(&x.options),
+                                  action_json(&x.action)));
+        }
+        for x in self.regions.iter() {
+            ret.push_str(&format!("{{\"directive\":\"region\"{},\
+                                   \"code_x\":{},\"code_y\":{}",
+                                  dev_json(&x.wants_device), x.code_x,
+                                  x.code_y));
+            let limits = [("x_min", x.x.0), ("x_max", x.x.1),
+                          ("y_min", x.y.0), ("y_max", x.y.1)];
+            for (key, value) in limits.iter() {
+                if let Some(x) = value {
+                    ret.push_str(&format!(",\"{}\":{}", key, x));
+                }
+            }
+            ret.push_str(&format!("{},\"action\":{}}}\n",
+                                  options_json(&x.options),
+                                  action_json(&x.action)));
+        }
+        for x in self.calibrations.iter() {
+            ret.push_str(&format!("{{\"directive\":\"calibrate\"{},\
+                                   \"code\":{},\"center\":{},\"min\":{},\
+                                   \"max\":{}}}\n",
+                                  dev_json(&x.wants_device), x.wants_code,
+                                  x.center, x.min, x.max));
+        }
+        let switches = self.latches.iter()
+            .map(|x| ("latch", &x.name, &x.wants_device, x.wants_code))
+            .chain(self.profile_switches.iter().map(|x| {
+                ("switch-profile", &x.name, &x.wants_device, x.wants_code)
+            }));
+        for (directive, name, device, code) in switches {
+            ret.push_str(&format!("{{\"directive\":\"{}\",\"name\":{}{},\
+                                   \"code\":{}}}\n",
+                                  directive, json_string(name),
+                                  dev_json(device), code));
+        }
+        if let Some(x) = self.min_interval.as_ref() {
+            ret.push_str(&format!("{{\"directive\":\"min-interval\",\
+                                   \"interval\":{},\"drop\":{}}}\n",
+                                  x.interval.as_millis(), x.drop));
+        }
+        for x in self.idle.iter() {
+            ret.push_str(&format!("{{\"directive\":\"idle\",\"after\":{},\
+                                   \"action\":{}}}\n",
+                                  x.after.as_secs_f64(),
+                                  action_json(&x.action)));
+        }
+        let hooks = [("on-activity", &self.on_activity),
+                     ("on-start", &self.on_start),
+                     ("on-stop", &self.on_stop)];
+        for (directive, actions) in hooks.iter() {
+            for x in actions.iter() {
+                ret.push_str(&format!("{{\"directive\":\"{}\",\
+                                       \"action\":{}}}\n",
+                                      directive, action_json(x)));
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueuePolicy;
+
+    /// The directives as they'd be written, without where they came from,
+    /// which differs between the two forms.
+    fn lines<T: std::fmt::Display>(directives: &[T]) -> Vec<String> {
+        directives.iter().map(T::to_string).collect()
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(parse(r#" {"a": [1, -2.5e1, true, null], "b": {}} "#),
+                   Ok(Json::Object(vec![
+                       ("a".to_owned(), Json::Array(vec![
+                           Json::Number(1.0), Json::Number(-25.0),
+                           Json::Bool(true), Json::Null,
+                       ])),
+                       ("b".to_owned(), Json::Object(vec![])),
+                   ])));
+        assert_eq!(parse(r#""tab\there \"\u00e9\ud83d\ude00""#),
+                   Ok(Json::String("tab\there \"\u{e9}\u{1f600}".to_owned())));
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("[] []").is_err());
+        assert!(parse("\"\\ud83d\"").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = parse_config("test.conf", "\
+            dev \"Left Pad\" grab=yes: /dev/input/event3\n\
+            dev /dev/input/event4\n\
+            if dev=\"Left Pad\" type=1 code=30 value=!0 with-held=42 \
+               when=\"pgrep mpd\" observe then: mpc \"toggle\"\n\
+            unless type=3 slot=1 nice=5 exec: notify-send \"a b\"\n\
+            if type=2 value=negative then: scroll down\n\
+            if code=30 delay=500 cancel-on-release then: later\n\
+            latch shift code=42\n\
+            if code=32 between=21:00-06:00 then: late\n\
+            if code=33 expect-exit=0,3 then: maybe\n\
+            if type=3 code=2 above=200 below=50 then: pulled\n\
+            if code=34 env=ACTION=jump env=\"WHO=a b\" then: run.sh\n\
+            if code=31 with-latch=shift then: shifted\n\
+            if code=35 profile=racing then: boost\n\
+            if code=37 then-led: LED_CAPSL toggle\n\
+            switch-profile racing code=36\n\
+            map code=1 to=0-100 then: echo $I2C_SCALED\n\
+            axis type=3 code=1 center=5 threshold=8000 then-neg: up\n\
+            region codeX=0 codeY=1 x>=200 y<=-200 then: corner\n\
+            buckets code=5 [<0: low] [>0: high] [else: mid]\n\
+            buckets code=6 [*: any]\n\
+            while-held code=314 taps code=304 count=2 then: pair\n\
+            sequence code=103 code=108 within=500 then: seq\n\
+            combo dev=\"Left Pad\" code=29 dev=* code=56 then: both\n\
+            hat codeX=16 codeY=17 up-left nice=2 then: hat\n\
+            zone codeX=53 codeY=54 x=0-20 y=80-100 then: corner\n\
+            calibrate code=1 center=120 min=3 max=250\n\
+            idle 0.5 then: sleepy\n\
+            on-activity exec: wake\n\
+            min-interval 10 drop\n\
+            on-stop then: echo bye\n").unwrap();
+        let json = config.to_json_lines();
+        assert_eq!(json.lines().next(),
+                   Some("{\"directive\":\"dev\",\"label\":\"Left Pad\",\
+                         \"path\":\"/dev/input/event3\",\"grab\":true}"));
+        let again = parse_json_lines("test.jsonl", &json).unwrap();
+        assert_eq!(lines(&again.matches), lines(&config.matches));
+        assert_eq!(lines(&again.maps), lines(&config.maps));
+        assert_eq!(lines(&again.axes), lines(&config.axes));
+        assert_eq!(lines(&again.regions), lines(&config.regions));
+        assert_eq!(lines(&again.buckets), lines(&config.buckets));
+        assert_eq!(lines(&again.taps), lines(&config.taps));
+        assert_eq!(lines(&again.sequences), lines(&config.sequences));
+        assert_eq!(lines(&again.combos), lines(&config.combos));
+        assert_eq!(lines(&again.hats), lines(&config.hats));
+        assert_eq!(lines(&again.zones), lines(&config.zones));
+        assert_eq!(lines(&again.calibrations), lines(&config.calibrations));
+        assert_eq!(lines(&again.latches), lines(&config.latches));
+        assert_eq!(lines(&again.profile_switches),
+                   lines(&config.profile_switches));
+        assert_eq!(again.min_interval, config.min_interval);
+        assert_eq!(lines(&again.idle), lines(&config.idle));
+        assert_eq!(again.on_activity, config.on_activity);
+        assert_eq!(again.on_stop, config.on_stop);
+        assert!(json.contains("\n{\"directive\":\"map\",\"code\":1,\
+                               \"to\":[0,100],\
+                               \"action\":{\"then\":\"echo $I2C_SCALED\"}}\n"),
+                "{}", json);
+        assert!(json.contains("\n{\"directive\":\"buckets\",\"code\":5,\
+                               \"hysteresis\":0,\"buckets\":[\
+                               {\"value\":\"<=-1\",\"then\":\"low\"},\
+                               {\"value\":\">=1\",\"then\":\"high\"}],\
+                               \"else\":\"mid\"}\n"), "{}", json);
+        assert_eq!(again.to_json_lines(), json);
+    }
+
+    #[test]
+    fn hashes_in_actions() {
+        let config = parse_config("test.conf", "\
+            if code=30 then: echo hi # x\n\
+            if code=31 exec: echo \"#2\"\n").unwrap();
+        let json = config.to_json_lines();
+        assert!(json.contains("{\"then\":\"echo hi # x\"}"), "{}", json);
+        let again = parse_json_lines("test.jsonl", &json).unwrap();
+        assert_eq!(lines(&again.matches), lines(&config.matches));
+        assert_eq!(again.matches[0].action,
+                   Action::Shell("echo hi # x".to_owned()));
+    }
+
+    #[test]
+    fn dev_queries() {
+        let config = parse_config("test.conf", "\
+            dev pad type=gamepad has=ABS_X,5/0\n\
+            dev type=keyboard queue=16 policy=block maxrate=100\n").unwrap();
+        let json = config.to_json_lines();
+        assert_eq!(json.lines().next(),
+                   Some("{\"directive\":\"dev\",\"label\":\"pad\",\
+                         \"type\":\"gamepad\",\"has\":[\"ABS_X\",\"5/0\"],\
+                         \"grab\":false}"));
+        let again = parse_json_lines("test.jsonl", &json).unwrap();
+        assert_eq!(json.lines().nth(1),
+                   Some("{\"directive\":\"dev\",\"type\":\"keyboard\",\
+                         \"queue\":16,\"policy\":\"block\",\
+                         \"maxrate\":100,\"grab\":false}"));
+        assert_eq!(again.devices[0].query, config.devices[0].query);
+        assert_eq!(again.devices[1].queue_policy, QueuePolicy::Block);
+        assert_eq!(again.to_json_lines(), json);
+        assert!(parse_json_lines("test.jsonl", "\
+            {\"directive\":\"dev\",\"path\":\"/a\",\"type\":\"mouse\"}")
+                .is_err());
+        assert!(parse_json_lines("test.jsonl", "\
+            {\"directive\":\"dev\",\"path\":\"/a\",\"policy\":\"block\"}")
+                .is_err());
+    }
+
+    #[test]
+    fn matchers() {
+        let config = parse_config("test.conf", "\
+            if type=1 code=2-11,30 value=>=1 then: a\n").unwrap();
+        let json = config.to_json_lines();
+        assert!(json.contains("\"type\":1,\"code\":\"2-11,30\",\
+                               \"value\":\">=1\""), "{}", json);
+        let again = parse_json_lines("test.jsonl", &json).unwrap();
+        assert_eq!(lines(&again.matches), lines(&config.matches));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| {
+            let err = parse_json_lines("test.jsonl", text).unwrap_err();
+            (err.line, err.message)
+        };
+        assert_eq!(error("\n[1]"),
+                   (Some(2), "each line must be a JSON object".to_owned()));
+        assert_eq!(error("{\"directive\":\"if\",\"kode\":1}").1,
+                   "unknown field \"kode\"");
+        assert_eq!(error("{\"directive\":\"if\",\"code\":70000,\
+                          \"action\":{\"then\":\"a\"}}").1,
+                   "\"code\" is out of range");
+        assert_eq!(error("{\"directive\":\"if\",\"cwd\":\"/a#b\",\
+                          \"action\":{\"then\":\"a\"}}").1,
+                   "\"cwd\" can't contain a '#'");
+        assert_eq!(error("{\"directive\":\"map\",\"text\":\"map\"}").1,
+                   "unknown field \"text\"");
+        assert_eq!(error("{\"directive\":\"map\",\"code\":1,\
+                          \"action\":{\"then\":\"a\"}}").1,
+                   "missing \"to\"");
+        assert_eq!(error("{\"directive\":\"zone\",\"code_x\":0,\
+                          \"code_y\":1,\"x\":[0],\"y\":[0,5],\
+                          \"action\":{\"then\":\"a\"}}").1,
+                   "\"x\" wants an array of two numbers");
+        assert_eq!(error("{\"directive\":\"hat\",\"code_x\":16,\
+                          \"code_y\":17,\"direction\":\"north\",\
+                          \"action\":{\"then\":\"a\"}}").1,
+                   "\"north\" is not a direction");
+        assert_eq!(error("{\"directive\":\"idle\",\"after\":1e30,\
+                          \"action\":{\"then\":\"x\"}}").1,
+                   "\"after\" wants a number of seconds, from 0 to 31536000");
+        assert_eq!(error("{\"directive\":\"defaults\"}").1,
+                   "unknown directive \"defaults\"");
+        // errors from the usual parser point at the right line too
+        assert_eq!(error("\n\n{\"directive\":\"axis\",\"code\":1,\
+                          \"threshold\":0,\"then_pos\":\"a\"}"),
+                   (Some(3), "\"threshold=\" wants a positive number"
+                    .to_owned()));
+    }
+}
@@ -0,0 +1,166 @@
+//! Turning the LEDs of input devices on and off, for `then-led:`.
+
+use std::{
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Write},
+    os::unix::io::AsRawFd,
+};
+
+use crate::{
+    InputEvent,
+    codes::*,
+    ioctl::{event_bits, led_state},
+};
+
+/// What to do to an LED.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum LedState {
+    /// Light it.
+    On,
+    /// Put it out.
+    Off,
+    /// Light it if it's out, and put it out if it's lit.
+    Toggle,
+}
+
+impl std::fmt::Display for LedState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            LedState::On => "on",
+            LedState::Off => "off",
+            LedState::Toggle => "toggle",
+        })
+    }
+}
+
+/// An LED to change when a match fires, on the device whose event fired it.
+/// (`then-led:`)
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct LedSetting {
+    /// The LED's code, like `LED_CAPSL`'s 1.
+    pub code: u16,
+    /// What to do to it.
+    pub state: LedState,
+}
+
+impl LedSetting {
+    /// Makes a setting out of the words after `then-led:`: the LED's name
+    /// (like `LED_CAPSL`) or number, then `on`, `off`, or `toggle`.
+    pub fn from_words(words: &[String]) -> Result<LedSetting, String> {
+        if words.len() != 2 {
+            return Err("then-led: needs an LED (like LED_CAPSL), then \
+                        \"on\", \"off\", or \"toggle\"".to_owned())
+        }
+        let code = match code_by_name(&words[0]) {
+            Some((EV_LED, code)) => Some(code),
+            Some(_) => None,
+            None => words[0].parse().ok().filter(|x| *x < LED_CNT),
+        };
+        let code = match code {
+            Some(x) => x,
+            None => return Err(format!("then-led: {:?} is not an LED",
+                                       words[0])),
+        };
+        let state = match words[1].as_str() {
+            "on" => LedState::On,
+            "off" => LedState::Off,
+            "toggle" => LedState::Toggle,
+            x => return Err(format!("then-led: wanted \"on\", \"off\", or \
+                                     \"toggle\", saw {:?}", x)),
+        };
+        Ok(LedSetting { code, state })
+    }
+    /// The words [`from_words`](#method.from_words) would make this setting
+    /// out of.
+    pub fn words(&self) -> Vec<String> {
+        vec![self.led_name(), self.state.to_string()]
+    }
+    /// The name of the LED (like `LED_CAPSL`), or its number if it has none.
+    pub fn led_name(&self) -> String {
+        code_name(EV_LED, self.code).map(str::to_owned)
+            .unwrap_or_else(|| self.code.to_string())
+    }
+    /// Changes the LED on the event device at `path`, which is opened just
+    /// for this, for writing, by writing an `EV_LED` event (and a
+    /// `SYN_REPORT`) to it. Fails with `ErrorKind::Unsupported` if the device
+    /// doesn't have the LED.
+    pub fn apply(&self, path: &str) -> Result<(), Error> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let fd = file.as_raw_fd();
+        let mut types = [0u8; (EV_CNT as usize).div_ceil(8)];
+        event_bits(fd, 0, &mut types)?;
+        let mut leds = [0u8; (LED_CNT as usize).div_ceil(8)];
+        let code = self.code as usize;
+        let bit = 1 << (code % 8);
+        let has_led = types[EV_LED as usize / 8] & (1 << (EV_LED % 8)) != 0
+            && event_bits(fd, EV_LED, &mut leds)? > code / 8
+            && leds[code / 8] & bit != 0;
+        if !has_led {
+            return Err(Error::new(ErrorKind::Unsupported,
+                                  format!("the device has no {}",
+                                          self.led_name())))
+        }
+        let on = match self.state {
+            LedState::On => true,
+            LedState::Off => false,
+            LedState::Toggle => {
+                let mut lit = [0u8; (LED_CNT as usize).div_ceil(8)];
+                led_state(fd, &mut lit)?;
+                lit[code / 8] & bit == 0
+            },
+        };
+        let event = |type_, code, value| InputEvent {
+            time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_, code, value,
+        };
+        let mut bytes = Vec::new();
+        for event in [event(EV_LED, self.code, on as i32),
+                      event(EV_SYN, SYN_REPORT, 0)].iter() {
+            // the kernel takes events laid out just as `InputEvent` is
+            bytes.extend_from_slice(unsafe {
+                std::slice::from_raw_parts(
+                    event as *const InputEvent as *const u8,
+                    std::mem::size_of::<InputEvent>())
+            });
+        }
+        file.write_all(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings() {
+        let parse = |text: &str| {
+            let words: Vec<String> = text.split_whitespace()
+                .map(str::to_owned).collect();
+            LedSetting::from_words(&words)
+        };
+        assert_eq!(parse("LED_CAPSL on"),
+                   Ok(LedSetting { code: 1, state: LedState::On }));
+        assert_eq!(parse("8 toggle"),
+                   Ok(LedSetting { code: 8, state: LedState::Toggle }));
+        assert_eq!(parse("8 toggle").unwrap().words(), ["LED_MISC", "toggle"]);
+        assert_eq!(parse("15 off").unwrap().words(), ["15", "off"]);
+        assert!(parse("LED_CAPSL").is_err());
+        assert!(parse("LED_CAPSL on now").is_err());
+        assert!(parse("KEY_A on").is_err());
+        assert!(parse("16 on").is_err());
+        assert!(parse("LED_CAPSL lit").is_err());
+    }
+
+    #[test]
+    fn missing_leds() {
+        // a file that isn't a device can't have any LEDs
+        let path = std::env::temp_dir()
+            .join(format!("input2cmds-led-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let setting = LedSetting { code: 1, state: LedState::On };
+        assert!(setting.apply(path.to_str().unwrap()).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+        std::fs::remove_file(&path).unwrap();
+        assert!(setting.apply(path.to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! This is the engine behind `input2cmds`, a simple utility that maps Linux
+//! `/dev/input` events to shell commands. It parses configuration files,
+//! reads events from devices, and decides which rule an event triggers.
+//! Actually running the commands is left to the caller. See [the README][1]
+//! for the configuration syntax.
+//!
+//! [1]: https://github.com/SolraBizna/input2cmds/blob/master/README.md
+
+pub use libc::input_event as InputEvent;
+
+pub mod codes;
+mod config;
+mod dbus;
+mod dedup;
+mod device;
+#[cfg(feature = "config-url")]
+mod http;
+mod ioctl;
+mod json;
+mod led;
+mod matching;
+mod queue;
+mod sequence;
+mod signals;
+mod starter;
+mod template;
+#[cfg(test)]
+mod test_util;
+
+pub use config::{
+    Action, AxisMap, AxisMatch, BucketMatch, Calibration, ComboMatch,
+    CommandOptions, Config, ConfigError, DeviceKind, DeviceQuery, DeviceSpec,
+    FieldMatcher, HatMatch, IdleMatch, InputMatch, Latch, Location,
+    MinInterval, ProfileSwitch, QueuePolicy, RegionMatch, SequenceMatch,
+    TapMatch, TimeWindow, VirtualPolicy, ZoneMatch, check_nice, json_string,
+    load_config, parse_config, read_config,
+};
+pub use dbus::{Bus, DBusCall, DBusConnections};
+pub use dedup::Deduplicator;
+pub use device::{
+    DeviceEvent, DeviceHandle, DeviceOptions, EventFormat, devices_at,
+    devices_by_id, find_devices, open_devices, present_devices, watch_devices,
+};
+#[cfg(feature = "config-url")]
+pub use http::fetch_url;
+pub use json::parse_json_lines;
+pub use led::{LedSetting, LedState};
+pub use matching::{
+    AxisState, BucketState, DelayedMatches, HatState, InputState,
+    RegionState, TapState, TriggerState, ZoneState, all_matches, match_event,
+    scale_axis,
+};
+pub use queue::{EventReceiver, EventSender, Message, event_queue};
+pub use sequence::SequenceState;
+pub use signals::forward_signals;
+pub use starter::starter_config;
+pub use template::{expand_template, shell_quote};
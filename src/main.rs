@@ -4,178 +4,1718 @@
 //! [1]: https://github.com/SolraBizna/input2cmds/blob/master/README.md
 
 use std::{
-    io::{Read, BufRead, BufReader},
-    process::{exit, Command},
-    sync::mpsc::{channel, Sender},
-    thread::spawn,
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::Write,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            fs::{FileTypeExt, OpenOptionsExt},
+            net::{SocketAddr, UnixDatagram},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::{exit, Child, Command, Stdio},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc},
+    thread::{sleep, spawn, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use input2cmds::{
+    Action, AxisState, BucketState, CommandOptions, Config, ConfigError,
+    DBusCall, DBusConnections, Deduplicator, DelayedMatches, DeviceEvent,
+    DeviceHandle, DeviceKind, DeviceOptions, DeviceQuery, DeviceSpec,
+    EventSender, HatState, InputEvent, InputState, LedSetting, Location,
+    Message, MinInterval, QueuePolicy, RegionState, SequenceState, TapState,
+    TriggerState, ZoneState, all_matches, check_nice, devices_at,
+    devices_by_id, event_queue, expand_template, find_devices,
+    forward_signals, json_string, open_devices, parse_config,
+    parse_json_lines, present_devices, read_config, shell_quote,
+    starter_config, watch_devices,
+    codes::{EV_ABS, EV_KEY, EV_SYN, SYN_REPORT, code_name, type_by_name,
+            type_name},
 };
-use libc::input_event as InputEvent;
-
-use anyhow::{anyhow, Context};
-
-/// Contains a parsed "if ... then ..." line, describing a command to execute
-/// if a certain event is seen.
-#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
-struct InputMatch {
-    /// If not `None`, run this command only if the event type matches this
-    /// value.
-    wants_type: Option<u16>,
-    /// If not `None`, run this command only if the event code matches this
-    /// value.
-    wants_code: Option<u16>,
-    /// If not `None`, run this command only if the event value matches this
-    /// value.
-    wants_value: Option<i32>,
-    /// If all of the above fields matched (or were `None`), run this command
-    /// via `/bin/sh -c command_to_run`.
-    command_to_run: String,
-}
-
-/// Reads a configuration file. For every "dev" directive, opens the given
-/// device and spawns a reader thread that sends events via `event_sender`. For
-/// every "if" directive, adds a match to the `matches` vector.
-fn load_config(path: &str, event_sender: &Sender<InputEvent>,
-               matches: &mut Vec<InputMatch>) -> anyhow::Result<()> {
-    let f = std::fs::File::open(path)
-        .with_context(|| format!("opening {:?}",path))?;
-    let reader = BufReader::new(f);
-    let mut line_number: usize = 0;
-    for line in reader.lines() {
-        line_number = line_number + 1;
-        let line = line.context("reading from the file")?;
-        let line = line.split('#').next().unwrap_or("");
-        let (line, colon) = if let Some(colon_pos) = line.find(':') {
-            let mut colon = &line[colon_pos+1..];
-            while !colon.is_empty() && colon.chars().next().unwrap()
-                .is_whitespace() {
-                colon = &colon[1..];
-            }
-            (&line[..colon_pos], Some(colon))
-        }
-        else {
-            (line, None)
+#[cfg(test)]
+use input2cmds::codes;
+
+// the library's test helpers, for the tests of reloading, which only some of
+// them are needed for
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "test_util.rs"]
+mod test_util;
+
+/// How many messages have to be waiting in the queue before we warn about
+/// it, unless `--backlog-warning` says otherwise.
+const DEFAULT_BACKLOG_WARNING: usize = 500;
+
+/// How long the result of a `when=` command is reused for, before running it
+/// again for the same event.
+const GUARD_CACHE_TIME: Duration = Duration::from_millis(250);
+
+/// How many commands `--history` remembers, if it isn't given.
+const DEFAULT_HISTORY: usize = 50;
+
+/// The most events `--queue` lets wait at once. A limited queue has room for
+/// all of them set aside when it's made, so it can't be arbitrarily large.
+const MAX_QUEUE: usize = 1_000_000;
+
+/// The exit status when we stopped because we were asked to (by a signal), or
+/// because every device reached its end.
+const EXIT_OK: i32 = 0;
+/// The exit status when something was wrong with the command line or the
+/// configuration, or a device couldn't be opened (or, with `--watch-all`,
+/// watched for).
+const EXIT_CONFIG_ERROR: i32 = 1;
+/// The exit status when `--strict` was given and a command couldn't be run.
+const EXIT_COMMAND_FAILED: i32 = 2;
+/// The exit status when we stopped because every device had stopped, and at
+/// least one of them because of an error (rather than reaching its end).
+const EXIT_DEVICE_ERROR: i32 = 3;
+
+/// Whether to color what we print to stdout, as decided by `--color`.
+static COLOR_STDOUT: AtomicBool = AtomicBool::new(false);
+/// Whether to color what we print to stderr, as decided by `--color`.
+static COLOR_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Whether to quote placeholder values in `then:` commands, as asked by
+/// `--shell-escape`.
+static SHELL_ESCAPE: AtomicBool = AtomicBool::new(false);
+
+/// Whether to run commands in their own sessions, as asked by
+/// `--detach-children`.
+static DETACH_CHILDREN: AtomicBool = AtomicBool::new(false);
+
+/// Whether to log every command we spawn, as asked by `--audit`.
+static AUDIT: AtomicBool = AtomicBool::new(false);
+
+/// The color of the rules that events matched, and the commands they ran.
+const GREEN: &str = "\x1b[32m";
+/// The color of events that didn't match anything.
+const DIM: &str = "\x1b[2m";
+/// The color of errors.
+const RED: &str = "\x1b[31m";
+/// Goes back to the normal color.
+const RESET: &str = "\x1b[0m";
+
+/// Something to print, in a color if colors are turned on for where it's
+/// going.
+struct Painted<T> {
+    /// The escape sequence for the color.
+    color: &'static str,
+    /// Whether colors are turned on.
+    on: bool,
+    /// What to print.
+    text: T,
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.on { write!(f, "{}{}{}", self.color, self.text, RESET) }
+        else { write!(f, "{}", self.text) }
+    }
+}
+
+/// Colors something to be printed to stdout.
+fn paint_out<T>(color: &'static str, text: T) -> Painted<T> {
+    Painted { color, on: COLOR_STDOUT.load(Ordering::Relaxed), text }
+}
+
+/// Colors something to be printed to stderr.
+fn paint_err<T>(color: &'static str, text: T) -> Painted<T> {
+    Painted { color, on: COLOR_STDERR.load(Ordering::Relaxed), text }
+}
+
+/// Decides whether to color what we print to the given file descriptor,
+/// given the argument to `--color`. Returns `None` if the argument isn't one
+/// we know.
+fn use_color(when: &str, fd: libc::c_int) -> Option<bool> {
+    match when {
+        "always" => Some(true),
+        "never" => Some(false),
+        "auto" => {
+            let no_color = std::env::var_os("NO_COLOR")
+                .map(|x| !x.is_empty()).unwrap_or(false);
+            Some(!no_color && unsafe { libc::isatty(fd) } == 1)
+        },
+        _ => None,
+    }
+}
+
+/// Formats one word of a command line for the `--audit` log, quoting it if
+/// it has anything unusual in it.
+fn audit_word(word: &std::ffi::OsStr) -> String {
+    match word.to_str() {
+        Some(x) if !x.is_empty() && x.chars().all(|c| {
+            c.is_ascii_alphanumeric() || "/-_.=:,+@%".contains(c)
+        }) => x.to_owned(),
+        _ => format!("{:?}", word),
+    }
+}
+
+/// Formats a command's program and arguments the way a shell would take
+/// them, for logs.
+fn command_line(command: &Command) -> String {
+    let mut ret = audit_word(command.get_program());
+    for arg in command.get_args() {
+        ret.push(' ');
+        ret.push_str(&audit_word(arg));
+    }
+    ret
+}
+
+/// Spawns a command, logging it (with its PID) to stderr if `--audit` was
+/// given.
+fn spawn_audited(command: &mut Command) -> std::io::Result<Child> {
+    let child = command.spawn()?;
+    if AUDIT.load(Ordering::Relaxed) {
+        eprintln!("spawn pid={} {}", child.id(), command_line(command));
+    }
+    Ok(child)
+}
+
+/// Gives a command the details of the event that triggered it, in the
+/// `I2C_TYPE`, `I2C_CODE`, `I2C_VALUE`, `I2C_DEVICE`, and `I2C_DEVICE_PATH`
+/// environment variables.
+fn set_event_env(command: &mut Command, event: &DeviceEvent) {
+    command.env("I2C_TYPE", event.event.type_.to_string())
+        .env("I2C_CODE", event.event.code.to_string())
+        .env("I2C_VALUE", event.event.value.to_string())
+        .env("I2C_DEVICE", event.device.name())
+        .env("I2C_DEVICE_PATH", &event.device.path);
+}
+
+/// What a `when=` result depends on: the command, the event's type, code, and
+/// value, and the name of the device it came from.
+type GuardKey = (String, u16, u16, i32, String);
+
+/// Runs `when=` commands, remembering their results for a little while so
+/// that a flood of events doesn't mean a flood of shells.
+#[derive(Default)]
+struct Guards {
+    /// The command and event details that each result was for, and when we
+    /// got it.
+    results: HashMap<GuardKey, (Instant, bool)>,
+}
+
+impl Guards {
+    /// Returns `true` if the `when=` command succeeds for this event. Its
+    /// output is thrown away, but its errors aren't.
+    fn check(&mut self, guard: &str, event: &DeviceEvent) -> bool {
+        let now = Instant::now();
+        self.results.retain(|_, (at, _)| now - *at < GUARD_CACHE_TIME);
+        let key = (guard.to_owned(), event.event.type_, event.event.code,
+                   event.event.value, event.device.name().to_owned());
+        if let Some((_, result)) = self.results.get(&key) {
+            return *result
+        }
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(guard)
+            .stdin(Stdio::null()).stdout(Stdio::null());
+        set_event_env(&mut command, event);
+        let result = spawn_audited(&mut command).and_then(|mut x| x.wait());
+        let result = match result {
+            Ok(x) => x.success(),
+            Err(x) => {
+                eprintln!("{}", paint_err(RED, format_args!(
+                    "Couldn't execute /bin/sh for \"when={}\": {}", guard,
+                    x)));
+                false
+            },
+        };
+        self.results.insert(key, (now, result));
+        result
+    }
+}
+
+/// One command that was run (or that couldn't be), as remembered for
+/// `--history`.
+struct HistoryEntry {
+    /// When the command was started.
+    time: SystemTime,
+    /// The event that triggered it, if any, formatted as for `-v`.
+    event: Option<String>,
+    /// The rule that wanted the command.
+    rule: String,
+    /// How it went: "OK", an exit status, "not run", "skipped", or "muted".
+    outcome: String,
+}
+
+/// A file that always describes the last command that was run, as given by
+/// `--status-file`.
+struct StatusFile {
+    /// Where the file is.
+    path: String,
+    /// Whether the last attempt to update it failed.
+    failing: bool,
+}
+
+impl StatusFile {
+    /// Replaces the file with a line of JSON describing a command. The new
+    /// file is written next to the old one and renamed over it, so anybody
+    /// reading it sees either all of the old one or all of the new one.
+    /// Complains on stderr when updating it starts failing, and again when
+    /// it works again.
+    fn update(&mut self, time: SystemTime, event: Option<&DeviceEvent>,
+              rule: &str, outcome: &str) {
+        let time = time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!("{{\"time\":{}.{:03},\"event\":{},\"rule\":{},\
+                            \"outcome\":{}}}\n", time.as_secs(),
+                           time.subsec_millis(),
+                           event.map(|x| x.to_json())
+                               .unwrap_or_else(|| "null".to_owned()),
+                           json_string(rule), json_string(outcome));
+        let temp = format!("{}.tmp", self.path);
+        let result = std::fs::write(&temp, line)
+            .and_then(|()| std::fs::rename(&temp, &self.path));
+        match result {
+            Err(x) if !self.failing => {
+                eprintln!("Couldn't update {:?}: {}", self.path, x);
+                self.failing = true;
+            },
+            Ok(()) if self.failing => {
+                eprintln!("Updating {:?} again.", self.path);
+                self.failing = false;
+            },
+            _ => (),
+        }
+    }
+}
+
+/// The last few commands that were run, so that they can be printed when
+/// asked for (with `SIGUSR1`), for working out what happened when.
+struct History {
+    /// The commands, oldest first.
+    entries: VecDeque<HistoryEntry>,
+    /// The most entries to keep. Zero means keeping nothing.
+    capacity: usize,
+    /// Where to write the latest command as it's recorded, if anywhere.
+    status: Option<StatusFile>,
+    /// How many commands have been run (or D-Bus methods called), ever.
+    run: u64,
+    /// How many of those failed.
+    failed: u64,
+    /// How many commands were skipped, because of a rate limit or
+    /// `min-interval`, or because they were muted.
+    skipped: u64,
+    /// How many commands couldn't be run (or methods called) at all.
+    not_run: u64,
+}
+
+impl History {
+    /// Makes an empty history that keeps at most `capacity` entries.
+    fn new(capacity: usize) -> History {
+        History {
+            // grows as it's needed, since `capacity` can be anything
+            entries: VecDeque::new(), capacity, status: None,
+            run: 0, failed: 0, skipped: 0, not_run: 0,
+        }
+    }
+    /// Remembers a command, forgetting the oldest one if there's no room, and
+    /// updates the status file, if there is one.
+    fn record(&mut self, time: SystemTime, event: Option<&DeviceEvent>,
+              rule: &dyn std::fmt::Display, outcome: String) {
+        let rule = rule.to_string();
+        match outcome.as_str() {
+            "skipped" | "muted" => self.skipped += 1,
+            "not run" | "not called" | "not set" => self.not_run += 1,
+            "OK" => self.run += 1,
+            _ => { self.run += 1; self.failed += 1 },
+        }
+        if let Some(status) = self.status.as_mut() {
+            status.update(time, event, &rule, &outcome);
+        }
+        if self.capacity == 0 { return }
+        if self.entries.len() == self.capacity { self.entries.pop_front(); }
+        self.entries.push_back(HistoryEntry {
+            time, event: event.map(|x| x.to_string()), rule, outcome,
+        });
+    }
+    /// Prints every entry to stderr, oldest first, one per line: the time in
+    /// seconds since the epoch, the event, the rule, and how it went.
+    fn print(&self) {
+        eprintln!("# the last {} command{}, oldest first:", self.entries.len(),
+                  if self.entries.len() == 1 { "" } else { "s" });
+        for entry in self.entries.iter() {
+            let time = entry.time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            eprintln!("{}.{:03} {}: {} # {}", time.as_secs(),
+                      time.subsec_millis(),
+                      entry.event.as_deref().unwrap_or("(no event)"),
+                      entry.rule, entry.outcome);
+        }
+    }
+}
+
+/// What we keep track of for the summary printed at exit, as asked for by
+/// `--summary` (or `-v`), apart from what's kept elsewhere anyway.
+struct Summary {
+    /// When we started.
+    started: Instant,
+    /// How many events we've received.
+    events: u64,
+    /// How many times the configuration has been reloaded.
+    reloads: u32,
+}
+
+impl Summary {
+    /// Prints the summary to stderr: how long we ran, how many events there
+    /// were and how many were dropped, the longest the queue got, how the
+    /// commands went, how many times device readers were restarted, and how
+    /// many times each "if" line fired (since the last reload, if any).
+    fn print(&self, config: &Config, fire_counts: &[u32], history: &History,
+             dropped: usize, peak: usize, restarts: u32) {
+        let plural = |n: u64| if n == 1 { "" } else { "s" };
+        eprintln!("# summary: ran for {:.1} seconds",
+                  self.started.elapsed().as_secs_f64());
+        eprintln!("# {} event{}, {} dropped because the queue was full",
+                  self.events, plural(self.events), dropped);
+        eprintln!("# at most {} message{} waiting in the queue at once", peak,
+                  plural(peak as u64));
+        eprintln!("# {} command{} run ({} failed), {} skipped, {} couldn't \
+                   be run", history.run, plural(history.run), history.failed,
+                  history.skipped, history.not_run);
+        eprintln!("# {} device reader restart{}", restarts,
+                  plural(restarts as u64));
+        if config.matches.is_empty() { return }
+        eprintln!("# times each \"if\" line fired{}:",
+                  if self.reloads == 0 { "" }
+                  else { " since the last reload" });
+        for (rule, count) in config.matches.iter().zip(fire_counts.iter()) {
+            eprintln!("# {:>6} {}", count, rule);
+        }
+    }
+}
+
+/// What the commands we run get as their standard input, as given by
+/// `--command-stdin`.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum CommandStdin {
+    /// Whatever our own standard input is.
+    Inherit,
+    /// Nothing: `/dev/null`.
+    Null,
+    /// The event that triggered the command, as a line of JSON. (Nothing, if
+    /// there's no event.)
+    Event,
+}
+
+impl CommandStdin {
+    /// Returns `None` if the argument to `--command-stdin` isn't one we know.
+    fn from_name(name: &str) -> Option<CommandStdin> {
+        match name {
+            "inherit" => Some(CommandStdin::Inherit),
+            "null" => Some(CommandStdin::Null),
+            "event" => Some(CommandStdin::Event),
+            _ => None,
+        }
+    }
+}
+
+/// A limit on how many commands are run per second, across every rule, as
+/// given by `--global-rate`. It's a token bucket: it holds at most `rate`
+/// tokens, gains `rate` of them every second, and each command spends one.
+struct RateLimit {
+    /// How many commands may be run per second.
+    rate: f64,
+    /// How many commands may be run right now.
+    tokens: f64,
+    /// When `tokens` was last brought up to date.
+    updated: Instant,
+    /// How many commands have been skipped since the last one that ran.
+    skipped: u64,
+}
+
+impl RateLimit {
+    /// Makes a full bucket that allows `rate` commands per second.
+    fn new(rate: u32) -> RateLimit {
+        RateLimit {
+            rate: rate as f64, tokens: rate as f64, updated: Instant::now(),
+            skipped: 0,
+        }
+    }
+    /// Spends a token, if there is one. Returns `false` if there isn't, in
+    /// which case the command should be skipped. Complains on stderr when
+    /// commands start being skipped, and again when they stop.
+    fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        let gained = (now - self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + gained).min(self.rate);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            if self.skipped == 0 {
+                eprintln!("{}", paint_err(RED, format_args!(
+                    "More than {} commands per second! Skipping commands \
+                     until things calm down.", self.rate)));
+            }
+            self.skipped += 1;
+            return false
+        }
+        self.tokens -= 1.0;
+        if self.skipped != 0 {
+            eprintln!("Running commands again, after skipping {}.",
+                      self.skipped);
+            self.skipped = 0;
+        }
+        true
+    }
+}
+
+/// A named pipe that the events that fire `if` lines are written to, one JSON
+/// object per line, as given by `--event-fifo`. Writing never waits: if
+/// nobody is reading from the pipe, or whoever is can't keep up, lines are
+/// thrown away.
+struct EventFifo {
+    /// Where the pipe is.
+    path: String,
+    /// The pipe, if somebody was reading from it last we checked.
+    file: Option<File>,
+    /// How many lines have been thrown away since the last one got through.
+    dropped: u64,
+}
+
+impl EventFifo {
+    /// Makes a pipe at `path`, unless there already is one.
+    fn new(path: String) -> std::io::Result<EventFifo> {
+        match std::fs::metadata(&path) {
+            Ok(x) if x.file_type().is_fifo() => (),
+            Ok(_) => return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "something that isn't a named pipe is in the way")),
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => {
+                let c_path = std::ffi::CString::new(path.clone())?;
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+                    return Err(std::io::Error::last_os_error())
+                }
+            },
+            Err(x) => return Err(x),
+        }
+        Ok(EventFifo { path, file: None, dropped: 0 })
+    }
+    /// Writes an event to the pipe, if anybody's there to read it. Complains
+    /// on stderr when lines start being thrown away, and again when they
+    /// stop.
+    fn send(&mut self, event: &DeviceEvent) {
+        if self.file.is_none() {
+            // with O_NONBLOCK, this fails (with ENXIO) if nobody is reading
+            self.file = OpenOptions::new().write(true)
+                .custom_flags(libc::O_NONBLOCK).open(&self.path).ok();
+        }
+        let line = format!("{}\n", event.to_json());
+        // a line this short is written all at once, or not at all
+        let sent = match self.file.as_mut().map(|x| x.write(line.as_bytes())) {
+            Some(Ok(_)) => true,
+            Some(Err(x)) => {
+                // EPIPE means the reader went away; we'll reopen the pipe
+                // next time, in case another one came along
+                if x.kind() != std::io::ErrorKind::WouldBlock {
+                    self.file = None;
+                }
+                false
+            },
+            None => false,
+        };
+        if !sent {
+            if self.dropped == 0 {
+                eprintln!("Nobody is reading from {:?}! Throwing away events \
+                           until somebody does.", self.path);
+            }
+            self.dropped += 1;
+        }
+        else if self.dropped != 0 {
+            eprintln!("Writing events to {:?} again, after throwing away {}.",
+                      self.path, self.dropped);
+            self.dropped = 0;
+        }
+    }
+}
+
+/// What all the commands we run have in common.
+struct Spawner {
+    /// The commands that were run. (Shared, so that it can still be printed
+    /// while a worker thread is running commands.)
+    history: Arc<Mutex<History>>,
+    /// The limit from `--global-rate`, if any.
+    rate: Option<RateLimit>,
+    /// The configuration's "min-interval", if any.
+    min_interval: Option<MinInterval>,
+    /// When the last command finished (or failed to start).
+    last_finished: Option<Instant>,
+    /// Our connections to D-Bus, for `then-dbus:` actions.
+    dbus: DBusConnections,
+    /// The devices (by path) and LEDs that a `then-led:` wanted, but that
+    /// the device didn't have, which we've already warned about.
+    missing_leds: HashSet<(String, u16)>,
+    /// What the commands get as their standard input.
+    stdin: CommandStdin,
+    /// Whether commands are run on a worker thread, in which case how each
+    /// one went is printed on a line of its own, along with its rule.
+    worker: bool,
+    /// If true, commands aren't run at all, only recorded as muted. (Toggled
+    /// by `SIGURG`.)
+    muted: bool,
+}
+
+impl Spawner {
+    /// Adds a command to the history.
+    fn record(&self, time: SystemTime, event: Option<&DeviceEvent>,
+              rule: &dyn std::fmt::Display, outcome: String) {
+        self.history.lock().unwrap().record(time, event, rule, outcome);
+    }
+    /// Prints how a command went, finishing the line that describes it (or,
+    /// on a worker thread, on a line of its own).
+    fn report(&self, rule: &dyn std::fmt::Display, outcome: &str) {
+        if self.worker { println!("{} # {}", rule, outcome) }
+        else { println!(" # {}", outcome) }
+    }
+}
+
+/// Looks up a placeholder for an action. `event` and `extra_env` are as for
+/// [`build_command`](fn.build_command.html).
+fn placeholder(name: &str, event: Option<&DeviceEvent>,
+               extra_env: &[(&str, String)]) -> Option<String> {
+    // the environment's placeholders are there even without an event
+    match (name, event) {
+        ("type", Some(event)) => Some(event.event.type_.to_string()),
+        ("code", Some(event)) => Some(event.event.code.to_string()),
+        ("value", Some(event)) => Some(event.event.value.to_string()),
+        ("device", Some(event)) => Some(event.device.name().to_owned()),
+        ("device_path", Some(event)) => Some(event.device.path.clone()),
+        _ => extra_env.iter()
+            .find(|(k, _)| k.strip_prefix("I2C_")
+                  .map(|k| k.eq_ignore_ascii_case(name))
+                  .unwrap_or(false))
+            .map(|(_, v)| v.clone()),
+    }
+}
+
+/// Fills in the placeholders in the arguments of a D-Bus call. `event` and
+/// `extra_env` are as for [`build_command`](fn.build_command.html).
+fn dbus_args(call: &DBusCall, event: Option<&DeviceEvent>,
+             extra_env: &[(&str, String)]) -> Vec<String> {
+    call.args.iter().map(|x| expand_template(x, |name| {
+        placeholder(name, event, extra_env)
+    })).collect()
+}
+
+/// Sets up the command for an action, without running it. `event` is the
+/// event that triggered it, if any, which is made available to the command
+/// via arguments, environment variables, and placeholders. `extra_env` holds
+/// any other environment variables the command should get, which are also
+/// available as placeholders (`I2C_FOO` as `{foo}`).
+/// `nice` is the niceness adjustment to use if `options` doesn't give one.
+/// Returns `None` for a D-Bus call or an LED change, which don't involve a
+/// command.
+fn build_command(action: &Action, event: Option<&DeviceEvent>,
+                 extra_env: &[(&str, String)], options: &CommandOptions,
+                 nice: Option<i32>) -> Option<Command> {
+    let lookup = |name: &str| placeholder(name, event, extra_env);
+    let shell = options.shell.as_deref().unwrap_or("/bin/sh");
+    let mut command = match action {
+        Action::Shell(x) => {
+            let escape = SHELL_ESCAPE.load(Ordering::Relaxed);
+            let mut command = Command::new(shell);
+            command.arg("-c").arg(expand_template(x, |name| {
+                lookup(name).map(|x| if escape { shell_quote(&x) } else { x })
+            }));
+            command
+        },
+        Action::File(x) => {
+            let mut command = Command::new(shell);
+            command.arg(x);
+            if let Some(event) = event.map(|x| &x.event) {
+                command.arg(event.type_.to_string())
+                    .arg(event.code.to_string())
+                    .arg(event.value.to_string());
+            }
+            command
+        },
+        Action::Exec(x) => {
+            let mut command = Command::new(expand_template(&x[0], lookup));
+            command.args(x[1..].iter().map(|x| expand_template(x, lookup)));
+            command
+        },
+        Action::DBus(_) | Action::Led(_) => return None,
+    };
+    // set first, so that an "env=" can't change what the event's variables
+    // say
+    command.envs(options.env.iter().map(|(k, v)| (k, v)));
+    if let Some(event) = event { set_event_env(&mut command, event) }
+    command.envs(extra_env.iter().map(|(k, v)| (k, v)));
+    if let Some(cwd) = options.cwd.as_ref() {
+        command.current_dir(cwd);
+    }
+    if let Some(nice) = options.nice.or(nice) {
+        unsafe {
+            command.pre_exec(move || {
+                // -1 is a valid return value of `nice`, so only errno can
+                // tell us whether it failed
+                *libc::__errno_location() = 0;
+                if libc::nice(nice) == -1 && *libc::__errno_location() != 0 {
+                    return Err(std::io::Error::last_os_error())
+                }
+                Ok(())
+            });
+        }
+    }
+    if DETACH_CHILDREN.load(Ordering::Relaxed) {
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(std::io::Error::last_os_error())
+                }
+                Ok(())
+            });
+        }
+    }
+    Some(command)
+}
+
+/// Does an action, waits for any command it ran to finish, and prints how it
+/// went. `rule` is the rule that wanted the action, used to describe it if it
+/// can't be done. `event`, `extra_env`, `options`, and `nice` are as for
+/// [`build_command`](fn.build_command.html).
+/// The command, and how it went, is added to the `spawner`'s history. If the
+/// `spawner`'s rate limit has been reached, or the command comes too soon
+/// after the last one, the command is skipped (or, for a `min-interval` that
+/// says `delay`, we wait). Returns `false` if the action couldn't be done
+/// (but not if it was skipped).
+fn run_command(rule: &dyn std::fmt::Display, action: &Action,
+               event: Option<&DeviceEvent>, extra_env: &[(&str, String)],
+               options: &CommandOptions, nice: Option<i32>,
+               spawner: &mut Spawner) -> bool {
+    if spawner.muted {
+        spawner.report(rule, "muted");
+        spawner.record(SystemTime::now(), event, rule, "muted".to_owned());
+        return true
+    }
+    if let (Some(min), Some(last)) = (spawner.min_interval,
+                                      spawner.last_finished) {
+        let now = Instant::now();
+        if now < last + min.interval {
+            if min.drop {
+                spawner.report(rule, "skipped (too soon after the last \
+                                      command)");
+                spawner.record(SystemTime::now(), event, rule,
+                               "skipped".to_owned());
+                return true
+            }
+            sleep(last + min.interval - now);
+        }
+    }
+    let started = SystemTime::now();
+    if !spawner.rate.as_mut().map(RateLimit::allow).unwrap_or(true) {
+        spawner.report(rule, "skipped");
+        spawner.record(started, event, rule, "skipped".to_owned());
+        return true
+    }
+    let mut command = match build_command(action, event, extra_env, options,
+                                          nice) {
+        Some(x) => x,
+        None => return match action {
+            Action::DBus(x) => {
+                call_dbus(rule, x, event, extra_env, spawner, started)
+            },
+            Action::Led(x) => set_led(rule, x, event, spawner, started),
+            _ => unreachable!(),
+        },
+    };
+    command.stdin(match spawner.stdin {
+        CommandStdin::Inherit => Stdio::inherit(),
+        CommandStdin::Null => Stdio::null(),
+        CommandStdin::Event => Stdio::piped(),
+    });
+    let mut child = match spawn_audited(&mut command) {
+        Ok(x) => x,
+        Err(x) => {
+            spawner.report(rule, "not run");
+            eprintln!("{}", paint_err(RED, format_args!(
+                "Couldn't execute {:?} for rule \"{}\": {}",
+                command.get_program(), rule, x)));
+            spawner.record(started, event, rule, "not run".to_owned());
+            spawner.last_finished = Some(Instant::now());
+            return false
+        },
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Some(event) = event {
+            // a line this short fits in the pipe, so this won't wait on the
+            // command; it's fine if the command doesn't read it
+            let _ = writeln!(stdin, "{}", event.to_json());
+        }
+    }
+    let exit_status = match child.wait() {
+        Ok(x) => x,
+        Err(x) => {
+            spawner.report(rule, "not run");
+            eprintln!("{}", paint_err(RED, format_args!(
+                "Couldn't wait on {:?} for rule \"{}\": {}",
+                command.get_program(), rule, x)));
+            spawner.record(started, event, rule, "not run".to_owned());
+            spawner.last_finished = Some(Instant::now());
+            return false
+        },
+    };
+    let worked = match options.expect_exit.as_ref() {
+        None => exit_status.success(),
+        Some(expected) => {
+            exit_status.code().is_some_and(|x| expected.contains(&x))
+        },
+    };
+    let outcome = if worked { "OK".to_owned() }
+    else { exit_status.to_string() };
+    spawner.report(rule, &outcome);
+    spawner.record(started, event, rule, outcome);
+    spawner.last_finished = Some(Instant::now());
+    true
+}
+
+/// Makes a D-Bus call for [`run_command`](fn.run_command.html), which has
+/// already decided that it should be made now, and waits for the reply.
+fn call_dbus(rule: &dyn std::fmt::Display, call: &DBusCall,
+             event: Option<&DeviceEvent>, extra_env: &[(&str, String)],
+             spawner: &mut Spawner, started: SystemTime) -> bool {
+    let args = dbus_args(call, event, extra_env);
+    if AUDIT.load(Ordering::Relaxed) {
+        eprintln!("dbus {}", dbus_line(call, &args));
+    }
+    let ret = match spawner.dbus.call(call, &args) {
+        Ok(outcome) => {
+            let outcome = outcome.err().unwrap_or_else(|| "OK".to_owned());
+            spawner.report(rule, &outcome);
+            spawner.record(started, event, rule, outcome);
+            true
+        },
+        Err(x) => {
+            spawner.report(rule, "not called");
+            eprintln!("{}", paint_err(RED, format_args!(
+                "Couldn't call {}.{} for rule \"{}\": {}",
+                call.interface, call.member, rule, x)));
+            spawner.record(started, event, rule, "not called".to_owned());
+            false
+        },
+    };
+    spawner.last_finished = Some(Instant::now());
+    ret
+}
+
+/// Changes an LED for [`run_command`](fn.run_command.html), which has already
+/// decided that it should be changed now. The LED is on the device that
+/// `event` came from. If that device doesn't have the LED, that's only
+/// warned about (once for each device and LED), since a rule can fire for
+/// several devices, only some of which have it.
+fn set_led(rule: &dyn std::fmt::Display, setting: &LedSetting,
+           event: Option<&DeviceEvent>, spawner: &mut Spawner,
+           started: SystemTime) -> bool {
+    let result = match event {
+        Some(event) => {
+            if AUDIT.load(Ordering::Relaxed) {
+                eprintln!("led {} {}", shell_quote(&event.device.path),
+                          setting.words().join(" "));
+            }
+            setting.apply(&event.device.path)
+        },
+        None => Err(std::io::Error::other("there's no event, so there's no \
+                                           device to set it on")),
+    };
+    let ret = match result {
+        Ok(()) => {
+            spawner.report(rule, "OK");
+            spawner.record(started, event, rule, "OK".to_owned());
+            true
+        },
+        Err(x) if x.kind() == std::io::ErrorKind::Unsupported => {
+            spawner.report(rule, "no such LED");
+            // `Unsupported` is only for a device we opened
+            let path = event.map(|x| x.device.path.clone())
+                .unwrap_or_default();
+            if spawner.missing_leds.insert((path.clone(), setting.code)) {
+                eprintln!("Warning: {:?} has no {}, so rule \"{}\" can't \
+                           change it. (Only warning about this once.)",
+                          path, setting.led_name(), rule);
+            }
+            spawner.record(started, event, rule, "no such LED".to_owned());
+            true
+        },
+        Err(x) => {
+            spawner.report(rule, "not set");
+            eprintln!("{}", paint_err(RED, format_args!(
+                "Couldn't set {} for rule \"{}\": {}",
+                setting.led_name(), rule, x)));
+            spawner.record(started, event, rule, "not set".to_owned());
+            false
+        },
+    };
+    spawner.last_finished = Some(Instant::now());
+    ret
+}
+
+/// Describes a D-Bus call, with its arguments filled in, the way it would be
+/// written after `then-dbus:`.
+fn dbus_line(call: &DBusCall, args: &[String]) -> String {
+    let call = DBusCall { args: args.to_vec(), ..call.clone() };
+    let line = Action::DBus(call).to_string();
+    line.strip_prefix("then-dbus: ").map(str::to_owned).unwrap_or(line)
+}
+
+/// A command for the worker thread to run, with everything
+/// [`run_command`](fn.run_command.html) needs to know about it.
+struct Job {
+    /// The rule that wants the command, described as it would be in a
+    /// configuration file.
+    rule: String,
+    /// What the rule does.
+    action: Action,
+    /// The event that triggered it, if any.
+    event: Option<DeviceEvent>,
+    /// Any other environment variables the command should get.
+    extra_env: Vec<(&'static str, String)>,
+    /// How the command should be run.
+    options: CommandOptions,
+    /// The niceness adjustment to use if `options` doesn't give one.
+    nice: Option<i32>,
+}
+
+/// Something for the worker thread to do.
+enum Work {
+    /// Run a command.
+    Run(Box<Job>),
+    /// Use this "min-interval" from now on, since the configuration was
+    /// reloaded.
+    MinInterval(Option<MinInterval>),
+    /// Mute or unmute commands from now on.
+    Muted(bool),
+}
+
+/// Runs the commands that rules want run. Normally that happens right away,
+/// and nothing else happens until each command is finished. With
+/// `--keep-matching`, they're handed to a worker thread, which runs them one
+/// at a time, in order, while we carry on reading and matching events.
+enum Runner {
+    /// Run commands right here.
+    Here(Spawner),
+    /// Queue commands for a worker thread, which has the `Spawner`.
+    Worker {
+        /// Where the worker gets its jobs.
+        jobs: mpsc::Sender<Work>,
+        /// The worker, which hands the `Spawner` back when it's done.
+        worker: JoinHandle<Spawner>,
+        /// Whether to print " # queued" after the line describing each
+        /// command we hand over.
+        verbose: bool,
+    },
+}
+
+impl Runner {
+    /// Starts a worker thread that owns `spawner` until
+    /// [`finish`](#method.finish) is called. If `strict` is set, the worker
+    /// exits with `EXIT_COMMAND_FAILED` as soon as a command can't be run, as
+    /// we would have if we were running it ourselves.
+    fn worker(mut spawner: Spawner, strict: bool, verbose: bool) -> Runner {
+        spawner.worker = true;
+        let (jobs, receiver) = mpsc::channel();
+        let worker = spawn(move || {
+            for work in receiver.iter() {
+                match work {
+                    Work::Run(job) => {
+                        if !run_command(&job.rule, &job.action,
+                                        job.event.as_ref(), &job.extra_env,
+                                        &job.options, job.nice, &mut spawner)
+                        && strict {
+                            exit(EXIT_COMMAND_FAILED)
+                        }
+                    },
+                    Work::MinInterval(x) => spawner.min_interval = x,
+                    Work::Muted(x) => spawner.muted = x,
+                }
+            }
+            spawner
+        });
+        Runner::Worker { jobs, worker, verbose }
+    }
+    /// Does what [`run_command`](fn.run_command.html) does, or hands it to
+    /// the worker thread. Returns `false` if we ran the command ourselves,
+    /// and it couldn't be run.
+    fn run(&mut self, rule: &dyn std::fmt::Display, action: &Action,
+           event: Option<&DeviceEvent>, extra_env: &[(&'static str, String)],
+           options: &CommandOptions, nice: Option<i32>) -> bool {
+        match self {
+            Runner::Here(spawner) => {
+                run_command(rule, action, event, extra_env, options, nice,
+                            spawner)
+            },
+            Runner::Worker { jobs, verbose, .. } => {
+                if *verbose { println!(" # queued") }
+                // the worker only stops once we're done with it
+                let _ = jobs.send(Work::Run(Box::new(Job {
+                    rule: rule.to_string(), action: action.clone(),
+                    event: event.cloned(), extra_env: extra_env.to_vec(),
+                    options: options.clone(), nice,
+                })));
+                true
+            },
+        }
+    }
+    /// Changes the "min-interval" that applies to commands run from now on.
+    fn set_min_interval(&mut self, min_interval: Option<MinInterval>) {
+        match self {
+            Runner::Here(spawner) => spawner.min_interval = min_interval,
+            Runner::Worker { jobs, .. } => {
+                let _ = jobs.send(Work::MinInterval(min_interval));
+            },
+        }
+    }
+    /// Mutes or unmutes the commands run from now on. Events are still
+    /// matched, and their rules are still recorded, but nothing is run.
+    fn set_muted(&mut self, muted: bool) {
+        match self {
+            Runner::Here(spawner) => spawner.muted = muted,
+            Runner::Worker { jobs, .. } => {
+                let _ = jobs.send(Work::Muted(muted));
+            },
+        }
+    }
+    /// Waits for every command that was handed to the worker thread to be
+    /// run, if there is one, and returns the `Spawner`, so that commands
+    /// can be run right here again.
+    fn finish(self) -> Spawner {
+        match self {
+            Runner::Here(spawner) => spawner,
+            Runner::Worker { jobs, worker, .. } => {
+                drop(jobs);
+                let mut spawner = worker.join()
+                    .expect("The command worker crashed (?!!)");
+                spawner.worker = false;
+                spawner
+            },
+        }
+    }
+}
+
+/// Runs `true` `count` times with `then:`, and again with `exec:`, and (if
+/// there's an `event_fifo`) writes as many events to the pipe, then prints
+/// how fast each of those was, for `--bench`. Commands are built and started
+/// the way a rule's would be, for a made-up event, so setting up their
+/// environment counts too. Returns `false` if a command couldn't be run.
+fn bench(count: usize, stdin: CommandStdin, nice: Option<i32>,
+         mut event_fifo: Option<EventFifo>) -> bool {
+    let event = DeviceEvent {
+        device: Arc::new(DeviceSpec {
+            label: None, path: "--bench".to_owned(), query: None,
+            virtual_policy: None, grab: false, queue: None,
+            queue_policy: QueuePolicy::default(), max_rate: None,
+            location: Location { file: "--bench".to_owned(), line: 1 },
+        }),
+        event: InputEvent {
+            time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_: EV_KEY, code: 304, value: 1,
+        },
+        range: None,
+    };
+    let actions = [Action::Shell("true".to_owned()),
+                   Action::Exec(vec!["true".to_owned()])];
+    let mut results = Vec::new();
+    for action in actions.iter() {
+        let mut times = Vec::with_capacity(count);
+        for _ in 0 .. count {
+            let started = Instant::now();
+            let mut command = build_command(action, Some(&event), &[],
+                                            &CommandOptions::default(), nice)
+                .expect("only D-Bus calls aren't commands");
+            command.stdin(match stdin {
+                CommandStdin::Inherit => Stdio::inherit(),
+                CommandStdin::Null | CommandStdin::Event => Stdio::null(),
+            });
+            match spawn_audited(&mut command).and_then(|mut x| x.wait()) {
+                Ok(_) => times.push(started.elapsed()),
+                Err(x) => {
+                    eprintln!("Couldn't execute {:?} for --bench: {}",
+                              command.get_program(), x);
+                    return false
+                },
+            }
+        }
+        results.push((action.to_string(), times));
+    }
+    if let Some(fifo) = event_fifo.as_mut() {
+        let times = (0 .. count).map(|_| {
+            let started = Instant::now();
+            fifo.send(&event);
+            started.elapsed()
+        }).collect();
+        results.push((format!("--event-fifo {:?}", fifo.path), times));
+    }
+    for (what, mut times) in results {
+        let total: Duration = times.iter().sum();
+        times.sort_unstable();
+        let at = |percent: usize| times[(times.len() - 1) * percent / 100];
+        println!("{}: {} in {:.3} s, {:.0} per second; latency {:.3} ms \
+                  (median), {:.3} ms (90%), {:.3} ms (99%), {:.3} ms (max)",
+                 what, count, total.as_secs_f64(),
+                 count as f64 / total.as_secs_f64(),
+                 at(50).as_secs_f64() * 1000.0, at(90).as_secs_f64() * 1000.0,
+                 at(99).as_secs_f64() * 1000.0,
+                 at(100).as_secs_f64() * 1000.0);
+    }
+    true
+}
+
+/// Tells systemd how we're doing, if it's listening (for a `Type=notify`
+/// service, it sets `NOTIFY_SOCKET`).
+struct Notifier {
+    /// Our socket, and systemd's address, if it's listening.
+    socket: Option<(UnixDatagram, SocketAddr)>,
+}
+
+impl Notifier {
+    /// Finds out whether systemd is listening, and removes `NOTIFY_SOCKET`
+    /// from our environment, so that the commands we run don't think they're
+    /// the ones it's waiting for. Must be called before any threads are
+    /// started.
+    fn from_env() -> Notifier {
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(x) => x,
+            None => return Notifier { socket: None },
         };
-        let mut splat: Vec<&str> = line.split(char::is_whitespace).collect();
-        if let Some(colon) = colon { splat.push(colon) }
-        if splat.is_empty() || splat[0].is_empty() { continue }
-        match splat[0] {
-            "dev" => {
-                if splat.len() != 2 {
-                    return Err(anyhow!("{}:{}: dev wants only one parameter",
-                                       path, line_number));
+        std::env::remove_var("NOTIFY_SOCKET");
+        let bytes = path.as_bytes();
+        // a leading '@' means an address in the abstract namespace
+        let address = match bytes.strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&path),
+        };
+        let socket = address.and_then(|address| {
+            Ok((UnixDatagram::unbound()?, address))
+        });
+        match socket {
+            Ok(x) => Notifier { socket: Some(x) },
+            Err(x) => {
+                eprintln!("Couldn't use NOTIFY_SOCKET {:?}: {}", path, x);
+                Notifier { socket: None }
+            },
+        }
+    }
+    /// Sends systemd a status line, like `READY=1`. Does nothing if it isn't
+    /// listening.
+    fn notify(&self, state: &str) {
+        if let Some((socket, address)) = self.socket.as_ref() {
+            if let Err(x) = socket.send_to_addr(state.as_bytes(), address) {
+                eprintln!("Couldn't notify systemd: {}", x);
+            }
+        }
+    }
+}
+
+/// The event described by `--test-event`, before we know which device it's
+/// from.
+struct TestEvent {
+    /// The device given by `dev=`, if any.
+    device: Option<String>,
+    /// The type, code, and value.
+    event: InputEvent,
+}
+
+impl TestEvent {
+    /// Parses the argument to `--test-event`, like `type=1 code=304 value=1`.
+    fn parse(text: &str) -> Option<TestEvent> {
+        let mut device = None;
+        let (mut type_, mut code, mut value) = (None, None, None);
+        for word in text.split_whitespace() {
+            let (key, rest) = word.split_at(word.find('=')? + 1);
+            match key {
+                "dev=" => device = Some(rest.to_owned()),
+                "type=" => type_ = Some(rest.parse().ok()?),
+                "code=" => code = Some(rest.parse().ok()?),
+                "value=" => value = Some(rest.parse().ok()?),
+                _ => return None,
+            }
+        }
+        Some(TestEvent {
+            device,
+            event: InputEvent {
+                time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+                type_: type_?, code: code?, value: value?,
+            },
+        })
+    }
+    /// Prints which `if` lines the event would fire, and the commands they
+    /// would run, without running anything. The event comes from the device
+    /// given by `dev=`, or else from the first device in the configuration,
+    /// while `profile` is the active profile.
+    fn run(&self, config: &Config, profile: Option<String>) {
+        let device = match self.device.as_ref() {
+            Some(name) => config.devices.iter().find(|x| x.name() == name)
+                .cloned().unwrap_or_else(|| DeviceSpec {
+                    label: Some(name.clone()), path: String::new(),
+                    query: None,
+                    virtual_policy: None, grab: false, queue: None,
+                    queue_policy: QueuePolicy::default(), max_rate: None,
+                    location: Location {
+                        file: "--test-event".to_owned(), line: 1,
+                    },
+                }),
+            None => match config.devices.first() {
+                Some(x) => x.clone(),
+                None => {
+                    println!("# the configuration doesn't name any devices");
+                    return
+                },
+            },
+        };
+        let event = DeviceEvent {
+            device: Arc::new(device), event: self.event, range: None,
+        };
+        let mut state = InputState { profile, ..InputState::default() };
+        state.update(&event);
+        let mut fired = false;
+        for rule in all_matches(&event, &state, &config.matches) {
+            // as if this were the first event about the axis
+            if !rule.crossed(&event, &mut TriggerState::default()) {
+                continue
+            }
+            println!("{}", rule);
+            match (&rule.action, build_command(&rule.action, Some(&event),
+                                               &[], &rule.options, None)) {
+                (_, Some(command)) => {
+                    println!("# would run: {}", command_line(&command))
+                },
+                (Action::DBus(call), None) => {
+                    let args = dbus_args(call, Some(&event), &[]);
+                    println!("# would call: {}", dbus_line(call, &args))
+                },
+                (Action::Led(setting), None) => {
+                    println!("# would set: {} on {:?}",
+                             setting.words().join(" "), event.device.path)
+                },
+                _ => unreachable!(),
+            }
+            fired = true;
+            if let Some(delay) = rule.delay {
+                println!("# ...{} ms later{}", delay.as_millis(),
+                         if rule.cancel_on_release {
+                             ", unless it's released first"
+                         } else { "" });
+            }
+            if let Some(held) = rule.held {
+                println!("# ...but only if the key is still down after {} \
+                          ms. Either way:", held.as_millis());
+            }
+            else if let Some((_, below)) = rule.hysteresis {
+                println!("# ...but only if the value hadn't already risen \
+                          that far since it was last at {} or below. If it \
+                          had:", below);
+            }
+            else if rule.when.is_some() {
+                println!("# ...but only if its \"when=\" command succeeds. \
+                          If it doesn't:");
+            }
+            else if let Some(window) = rule.between {
+                println!("# ...but only at {}, by the local clock. At other \
+                          times:", window);
+            }
+            else if rule.observe {
+                println!("# ...and then keep looking:");
+            }
+            else {
+                return
+            }
+        }
+        println!("# {}nothing fires for \"{}\"",
+                 if fired { "then " } else { "" }, event);
+    }
+}
+
+/// Returns how many minutes it's been since midnight, by the local clock.
+fn local_minutes() -> u32 {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() { return 0 }
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+/// Names an event type, and codes of that type, for a `--print-config`
+/// comment, like `EV_KEY KEY_A KEY_B`. Returns `None` if none of them have
+/// names.
+fn name_codes(type_: Option<u16>, codes: &[u16]) -> Option<String> {
+    let type_ = type_?;
+    let names: Vec<&str> = type_name(type_).into_iter()
+        .chain(codes.iter().filter_map(|x| code_name(type_, *x)))
+        .collect();
+    if names.is_empty() { None } else { Some(names.join(" ")) }
+}
+
+/// Prints every directive in the configuration as it was understood, after
+/// defaults were applied and the files were combined, in a form that could be
+/// pasted back into a configuration file. A comment before each device says
+/// where it was named, and one before each rule says where it was given, and
+/// names the event type and codes it's about.
+fn print_config(config: &Config) {
+    let comment = |location: &Location, names: Option<String>| {
+        match names {
+            Some(names) => println!("# {}: {}", location, names),
+            None => println!("# {}", location),
+        }
+    };
+    for device in config.devices.iter() {
+        comment(&device.location, None);
+        println!("{}", device);
+    }
+    for rule in config.matches.iter() {
+        let types = rule.wants_type.numbers();
+        let type_ = if types.len() == 1 { Some(types[0]) } else { None };
+        comment(&rule.location,
+                name_codes(type_, &rule.wants_code.numbers()));
+        println!("{}", rule);
+    }
+    for map in config.maps.iter() {
+        comment(&map.location,
+                name_codes(Some(EV_ABS), &[map.wants_code]));
+        println!("{}", map);
+    }
+    for axis in config.axes.iter() {
+        comment(&axis.location,
+                name_codes(Some(EV_ABS), &[axis.wants_code]));
+        println!("{}", axis);
+    }
+    for buckets in config.buckets.iter() {
+        comment(&buckets.location,
+                name_codes(Some(EV_ABS), &[buckets.wants_code]));
+        println!("{}", buckets);
+    }
+    for sequence in config.sequences.iter() {
+        comment(&sequence.location,
+                name_codes(Some(EV_KEY), &sequence.codes));
+        println!("{}", sequence);
+    }
+    for combo in config.combos.iter() {
+        let codes: Vec<u16> = combo.keys.iter().map(|x| x.1).collect();
+        comment(&combo.location,
+                name_codes(Some(EV_KEY), &codes));
+        println!("{}", combo);
+    }
+    for taps in config.taps.iter() {
+        comment(&taps.location,
+                name_codes(Some(EV_KEY), &[taps.held_code, taps.tap_code]));
+        println!("{}", taps);
+    }
+    for hat in config.hats.iter() {
+        comment(&hat.location,
+                name_codes(Some(EV_ABS), &[hat.code_x, hat.code_y]));
+        println!("{}", hat);
+    }
+    for zone in config.zones.iter() {
+        comment(&zone.location,
+                name_codes(Some(EV_ABS), &[zone.code_x, zone.code_y]));
+        println!("{}", zone);
+    }
+    for region in config.regions.iter() {
+        comment(&region.location,
+                name_codes(Some(EV_ABS), &[region.code_x, region.code_y]));
+        println!("{}", region);
+    }
+    for calibration in config.calibrations.iter() {
+        comment(&calibration.location,
+                name_codes(Some(EV_ABS), &[calibration.wants_code]));
+        println!("{}", calibration);
+    }
+    for latch in config.latches.iter() {
+        comment(&latch.location,
+                name_codes(Some(EV_KEY), &[latch.wants_code]));
+        println!("{}", latch);
+    }
+    for switch in config.profile_switches.iter() {
+        comment(&switch.location,
+                name_codes(Some(EV_KEY), &[switch.wants_code]));
+        println!("{}", switch);
+    }
+    if let Some(x) = config.min_interval { println!("{}", x) }
+    for idle in config.idle.iter() {
+        comment(&idle.location, None);
+        println!("{}", idle)
+    }
+    for action in config.on_activity.iter() {
+        println!("on-activity {}", action)
+    }
+    for action in config.on_start.iter() { println!("on-start {}", action) }
+    for action in config.on_stop.iter() { println!("on-stop {}", action) }
+}
+
+/// A configuration file, and what was in it when we last read it.
+struct ConfigFile {
+    /// The path to the file, as given on the command line. For configuration
+    /// given by `--config-text` or `--config-env`, this is what errors call
+    /// it instead.
+    path: String,
+    /// When the file had last been modified, as of when we read it (if we
+    /// could tell).
+    modified: Option<SystemTime>,
+    /// True if the configuration came from the command line or the
+    /// environment, rather than a file, so it can never change.
+    inline: bool,
+    /// If the configuration was fetched from a URL (which is then `path`),
+    /// what we fetched, and where to cache it.
+    fetched: Option<Fetched>,
+    /// What was in the file.
+    config: Config,
+}
+
+/// Configuration that was fetched with `--config-url`.
+#[derive(Clone)]
+struct Fetched {
+    /// The text we fetched (or read from the cache), so that rereading can
+    /// tell whether it changed.
+    text: String,
+    /// Where to keep a copy, to fall back on when it can't be fetched, if
+    /// `--config-cache` was given.
+    cache: Option<PathBuf>,
+    /// If true, it may be fetched from another machine.
+    /// (`--config-url-insecure`)
+    remote: bool,
+}
+
+/// Fetches configuration from `url` (from another machine only if `remote`
+/// is true). If that works, and there's a cache, saves a copy there; if it
+/// doesn't, falls back on the copy from last time.
+#[cfg(feature = "config-url")]
+fn fetch_text(url: &str, cache: Option<&Path>, remote: bool)
+              -> Result<String, ConfigError> {
+    let error = match input2cmds::fetch_url(url, remote) {
+        Ok(text) => {
+            if let Some(cache) = cache {
+                // renamed into place, so that a copy is never half-written
+                let mut temp = cache.as_os_str().to_owned();
+                temp.push(".tmp");
+                let result = std::fs::write(&temp, &text)
+                    .and_then(|()| std::fs::rename(&temp, cache));
+                if let Err(x) = result {
+                    eprintln!("Warning: couldn't save a copy of {} to {:?}: \
+                               {}", url, cache, x);
+                }
+            }
+            return Ok(text)
+        },
+        // not something the cache is for
+        Err(x) if x.kind() == std::io::ErrorKind::PermissionDenied => {
+            return Err(ConfigError {
+                file: url.to_owned(), line: None,
+                message: format!("fetching the configuration: {} (give \
+                                  --config-url-insecure to fetch it \
+                                  anyway)", x),
+            })
+        },
+        Err(x) => x,
+    };
+    match cache.map(|x| (x, std::fs::read_to_string(x))) {
+        Some((cache, Ok(text))) => {
+            eprintln!("Warning: couldn't fetch {} ({}), so using the copy \
+                       from {:?}", url, error, cache);
+            Ok(text)
+        },
+        _ => Err(ConfigError {
+            file: url.to_owned(), line: None,
+            message: format!("fetching the configuration: {}", error),
+        }),
+    }
+}
+
+/// Without the `config-url` feature, there's no way to fetch anything.
+#[cfg(not(feature = "config-url"))]
+fn fetch_text(url: &str, _: Option<&Path>, _: bool)
+              -> Result<String, ConfigError> {
+    Err(ConfigError {
+        file: url.to_owned(), line: None,
+        message: "this input2cmds was built without the \"config-url\" \
+                  feature, so it can't fetch configuration".to_owned(),
+    })
+}
+
+/// Returns where to cache the configuration from `url`, in the directory
+/// `dir`: a file named after the URL, with anything that isn't a letter,
+/// digit, `.`, or `-` replaced with `_`.
+fn cache_path(dir: &str, url: &str) -> PathBuf {
+    let name: String = url.chars().map(|x| {
+        if x.is_ascii_alphanumeric() || x == '.' || x == '-' { x }
+        else { '_' }
+    }).collect();
+    Path::new(dir).join(name)
+}
+
+/// Returns when the file at `path` was last modified, if we can tell.
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+impl ConfigFile {
+    /// Reads a configuration file.
+    fn read(path: &str) -> Result<ConfigFile, ConfigError> {
+        // check the time first, so that if the file changes while we're
+        // reading it, we'll reread it next time
+        let modified = modified(path);
+        Ok(ConfigFile { path: path.to_owned(), modified, inline: false,
+                        fetched: None, config: read_config(path)? })
+    }
+    /// Fetches configuration from a URL (from another machine only if
+    /// `remote` is true), falling back on the copy in `cache` (if there is
+    /// one) when it can't be fetched.
+    fn fetch(url: &str, cache: Option<PathBuf>, remote: bool)
+             -> Result<ConfigFile, ConfigError> {
+        let text = fetch_text(url, cache.as_deref(), remote)?;
+        let config = if url.ends_with(".jsonl") {
+            parse_json_lines(url, &text)?
+        }
+        else { parse_config(url, &text)? };
+        Ok(ConfigFile { path: url.to_owned(), modified: None, inline: false,
+                        fetched: Some(Fetched { text, cache, remote }),
+                        config })
+    }
+    /// Parses configuration that was given as text, rather than in a file.
+    /// `name` is what errors call it.
+    fn from_text(name: String, text: &str)
+                 -> Result<ConfigFile, ConfigError> {
+        let config = parse_config(&name, text)?;
+        Ok(ConfigFile { path: name, modified: None, inline: true,
+                        fetched: None, config })
+    }
+    /// Rereads the file, if it has been modified since we last read it.
+    /// Returns `None` if it hasn't. Configuration from a URL is always
+    /// fetched again, but only counts as modified if the text changed.
+    fn reread(&self) -> Result<Option<ConfigFile>, ConfigError> {
+        if self.inline { return Ok(None) }
+        if let Some(fetched) = self.fetched.as_ref() {
+            let new = ConfigFile::fetch(&self.path, fetched.cache.clone(),
+                                        fetched.remote)?;
+            let changed = new.fetched.as_ref()
+                .map(|x| x.text != fetched.text).unwrap_or(true);
+            return Ok(if changed { Some(new) } else { None })
+        }
+        if self.modified.is_some() && modified(&self.path) == self.modified {
+            return Ok(None)
+        }
+        ConfigFile::read(&self.path).map(Some)
+    }
+}
+
+/// Combines the contents of all the configuration files, in order.
+fn merge(files: &[ConfigFile]) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    for file in files.iter() {
+        config.append(file.config.clone())?;
+    }
+    Ok(config)
+}
+
+/// Warns about each line whose `dev=` doesn't select any of the devices in
+/// the configuration, since it will never fire.
+fn warn_unknown_devices(config: &Config) {
+    for (line, name) in config.unknown_devices() {
+        eprintln!("Warning: no device is named {:?}, so this line will never \
+                   fire: {}", name, line);
+    }
+}
+
+/// Warns about each "switch-profile" line that names a profile no "if" line
+/// is in, since it probably has a typo in it.
+fn warn_unknown_profiles(config: &Config) {
+    for switch in config.profile_switches.iter() {
+        if !config.profiles.contains(&switch.name) {
+            eprintln!("Warning: no profile is named {:?}, so this line only \
+                       turns every profile off: {}", switch.name, switch);
+        }
+    }
+}
+
+/// Returns the profile to start in: the one given by `--profile`, or else
+/// the first one in the configuration. Exits if `--profile` names a profile
+/// that isn't in the configuration.
+fn starting_profile(config: &Config, wanted: Option<&str>) -> Option<String> {
+    match wanted {
+        Some(x) if config.profiles.iter().any(|y| y == x) => {
+            Some(x.to_owned())
+        },
+        Some(x) => {
+            eprintln!("Error parsing command line: no profile is named {:?}",
+                      x);
+            exit(EXIT_CONFIG_ERROR)
+        },
+        None => config.profiles.first().cloned(),
+    }
+}
+
+/// Prints an error from a configuration file.
+fn report_error(error: &ConfigError, json: bool) {
+    if json { eprintln!("{}", error.to_json()) }
+    else { eprintln!("{}", paint_err(RED, error)) }
+}
+
+/// How long a reload waits for the reader of a device whose "dev" lines
+/// changed to let go of it, before opening it again.
+const READER_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Rereads any configuration files that have changed, and starts and stops
+/// reading from devices to match. Devices whose "dev" lines didn't change are
+/// left alone. If there's anything wrong with the new configuration,
+/// including a device it adds or changes that can't be opened, reports it and
+/// carries on with the old one, reopening any devices it had to close.
+/// Returns `true` if the configuration changed.
+fn reload(files: &mut Vec<ConfigFile>, config: &mut Config,
+          handles: &mut Vec<DeviceHandle>, event_tx: &EventSender,
+          device_options: &DeviceOptions, errors_json: bool) -> bool {
+    let mut changed = 0;
+    let mut new_files = Vec::with_capacity(files.len());
+    for file in files.iter() {
+        match file.reread() {
+            Ok(Some(x)) => { new_files.push(x); changed += 1 },
+            Ok(None) => new_files.push(ConfigFile {
+                path: file.path.clone(), modified: file.modified,
+                inline: file.inline, fetched: file.fetched.clone(),
+                config: file.config.clone(),
+            }),
+            Err(x) => {
+                report_error(&x, errors_json);
+                eprintln!("{}", paint_err(RED, "Not reloading, because of \
+                                                the above error."));
+                return false
+            },
+        }
+    }
+    if changed == 0 {
+        eprintln!("Not reloading, because no configuration files changed.");
+        return false
+    }
+    let mut new_config = match merge(&new_files) {
+        Ok(x) => x,
+        Err(x) => {
+            report_error(&x, errors_json);
+            eprintln!("{}", paint_err(RED, "Not reloading, because of the \
+                                            above error."));
+            return false
+        },
+    };
+    new_config.devices = find_devices(&new_config.devices);
+    // A path is opened only once, however many "dev" lines give it, so if
+    // any of those lines changed, the path has to be opened again.
+    let unchanged = |path: &str| {
+        let old: Vec<_> = config.devices.iter()
+            .filter(|x| x.path == path).collect();
+        let new: Vec<_> = new_config.devices.iter()
+            .filter(|x| x.path == path).collect();
+        old.len() == new.len()
+            && old.iter().all(|x| new.iter().any(|y| y.same_device(x)))
+    };
+    // Paths we weren't reading from at all are opened before anything else
+    // is touched, so that if one of them can't be, we can still back out.
+    // Paths whose lines changed have to wait until the old reader is gone
+    // (it might have grabbed the device).
+    let was_open = |path: &str| config.devices.iter().any(|x| x.path == path);
+    let mut opened: Vec<&str> = Vec::new();
+    let mut new_handles: Vec<DeviceHandle> = Vec::new();
+    // the paths whose old readers are gone, to open again if we back out
+    let mut closed: Vec<String> = Vec::new();
+    for fresh in [true, false] {
+        if !fresh {
+            let (keep, stopped): (Vec<_>, Vec<_>) = handles.drain(..)
+                .partition(|handle| unchanged(&handle.devices()[0].path));
+            *handles = keep;
+            for handle in stopped.iter() { handle.stop() }
+            for handle in stopped.iter() {
+                let path = &handle.devices()[0].path;
+                if !handle.wait(READER_STOP_TIMEOUT) {
+                    eprintln!("Warning: the old reader for {:?} hasn't \
+                               stopped yet, so it may still have the \
+                               device grabbed.", path);
                 }
-                let event_sender = event_sender.clone();
-                let dev_path = splat[1].to_owned();
-                let dev_file = std::fs::File::open(&dev_path)
-                    .with_context(|| format!("opening device {:?}",dev_path))?;
-                spawn(move || {
-                    let error = format!("Error reading from {:?}", dev_path);
-                    let mut dev_file = BufReader::new(dev_file);
-                    const EVENT_SIZE: usize
-                        = std::mem::size_of::<InputEvent>();
-                    let mut buf = [0u8; EVENT_SIZE];
-                    loop {
-                        dev_file.read_exact(&mut buf[..]).expect(&error);
-                        let event: &InputEvent = unsafe {
-                            std::mem::transmute(&buf)
-                        };
-                        match event.type_ {
-                            0 /* EV_SYN */ | 4 /* EV_MSC */ => continue,
-                            _ => (),
-                        }
-                        if !event_sender.send(*event).is_ok() {
-                            // quietly end the loop, our parent thread is no
-                            // longer listening :(
-                            break
-                        }
-                    }
-                });
-            },
-            "if" => {
-                let mut rest = &splat[1..];
-                let mut wants_type = None;
-                let mut wants_code = None;
-                let mut wants_value = None;
-                while !rest.is_empty() && rest[0] != "then" {
-                    let el = rest[0];
-                    rest = &rest[1..];
-                    if el.starts_with("type=") {
-                        if wants_type.is_some() {
-                            return Err(anyhow!("{}:{}: multiple \"type=\"s",
-                                               path, line_number));
-                        }
-                        let parsed = &el[5..].parse();
-                        match parsed {
-                            Err(_) => {
-                                return Err(anyhow!("{}:{}: invalid \"type=\"",
-                                                   path, line_number));
-                            },
-                            Ok(x) => {
-                                wants_type = Some(*x);
-                            }
-                        }
-                    }
-                    else if el.starts_with("code=") {
-                        if wants_code.is_some() {
-                            return Err(anyhow!("{}:{}: multiple \"code=\"",
-                                               path, line_number));
-                        }
-                        let parsed = &el[5..].parse();
-                        match parsed {
-                            Err(_) => {
-                                return Err(anyhow!("{}:{}: invalid \"code=\"s",
-                                                   path, line_number));
-                            },
-                            Ok(x) => {
-                                wants_code = Some(*x);
-                            }
-                        }
+                closed.push(path.clone());
+            }
+        }
+        for device in new_config.devices.iter() {
+            if unchanged(&device.path) || was_open(&device.path) == fresh
+            || opened.contains(&device.path.as_str()) {
+                continue
+            }
+            opened.push(&device.path);
+            let devices: Vec<_> = new_config.devices.iter()
+                .filter(|x| x.path == device.path).cloned().collect();
+            match open_devices(&devices, event_tx, device_options) {
+                Ok(mut x) => new_handles.append(&mut x),
+                Err(x) => {
+                    report_error(&x, errors_json);
+                    for handle in new_handles.iter() { handle.stop() }
+                    for handle in new_handles.iter() {
+                        handle.wait(READER_STOP_TIMEOUT);
                     }
-                    else if el.starts_with("value=") {
-                        if wants_value.is_some() {
-                            return Err(anyhow!("{}:{}: multiple \"value=\"s",
-                                               path, line_number));
-                        }
-                        let parsed = &el[6..].parse();
-                        match parsed {
-                            Err(_) => {
-                                return Err(anyhow!("{}:{}: invalid \"value=\"",
-                                                   path, line_number));
-                            },
-                            Ok(x) => {
-                                wants_value = Some(*x);
-                            }
+                    for path in closed.iter() {
+                        let devices: Vec<_> = config.devices.iter()
+                            .filter(|x| &x.path == path).cloned().collect();
+                        match open_devices(&devices, event_tx,
+                                           device_options) {
+                            Ok(mut x) => handles.append(&mut x),
+                            Err(x) => report_error(&x, errors_json),
                         }
                     }
-                    else {
-                        return Err(anyhow!("{}:{}: wanted \"type=\", \
-                                            \"code=\", \"value\"=, or \
-                                            \"then\" after \"if\", saw {:?}",
-                                           path, line_number, el));
-                    }
-                }
-                rest = &rest[1..]; // skip "then"
-                if rest.is_empty() {
-                    return Err(anyhow!("{}:{}: \"if\" needs a \"then\"",
-                                       path, line_number));
-                }
-                else if rest.len() >= 2 {
-                    return Err(anyhow!("{}:{}: put a colon after \"then\"",
-                                       path, line_number));
-                }
-                matches.push(InputMatch {
-                    wants_type, wants_code, wants_value,
-                    command_to_run: rest[0].to_owned()
-                })
-            },
-            x => {
-                return Err(anyhow!("{}:{}: Unknown config directive {:?}",
-                                   path, line_number, x));
-            },
+                    eprintln!("{}", paint_err(RED, "Not reloading, because \
+                                                    of the above error."));
+                    return false
+                },
+            }
+        }
+    }
+    handles.append(&mut new_handles);
+    eprintln!("Reloaded {} changed configuration file{}.", changed,
+              if changed == 1 { "" } else { "s" });
+    *files = new_files;
+    *config = new_config;
+    true
+}
+
+/// Starts reading from the event device at `path`, for `--watch-all`, if
+/// we aren't already and any "dev" lines want it. Lines that pick devices by
+/// what they can do are checked against it, and so are lines that name it by
+/// path (in case it was unplugged and plugged back in). If the configuration
+/// has no lines of the first kind, every device is wanted. Returns `true` if
+/// the device was opened.
+fn attach(path: &str, files: &[ConfigFile], config: &mut Config,
+          handles: &mut Vec<DeviceHandle>, event_tx: &EventSender,
+          device_options: &DeviceOptions, errors_json: bool) -> bool {
+    if handles.iter().any(|x| x.devices()[0].path == path) { return false }
+    let mut lines = merge(files).map(|x| x.devices).unwrap_or_default();
+    if lines.iter().all(|x| x.query.is_none()) {
+        lines.push(DeviceSpec {
+            label: None, path: String::new(),
+            query: Some(DeviceQuery {
+                kind: DeviceKind::Any, has: Vec::new(),
+            }),
+            virtual_policy: None, grab: false, queue: None,
+            queue_policy: QueuePolicy::default(), max_rate: None,
+            location: Location { file: "--watch-all".to_owned(), line: 1 },
+        });
+    }
+    let devices = devices_at(path, &lines);
+    if devices.is_empty() { return false }
+    match open_devices(&devices, event_tx, device_options) {
+        Ok(mut x) => handles.append(&mut x),
+        Err(x) => {
+            report_error(&x, errors_json);
+            return false
+        },
+    }
+    for device in devices {
+        if !config.devices.iter().any(|x| x.same_device(&device)) {
+            config.devices.push(device);
         }
     }
-    // All done!
-    Ok(())
+    true
+}
+
+/// Prints an event the way `evtest` would, in the given color.
+fn print_evtest(event: &InputEvent, color: &'static str) {
+    println!("{}", paint_out(color, format_args!(
+        "Event: time {}.{:06}, type {} ({}), code {} ({}), value {}",
+        event.time.tv_sec, event.time.tv_usec, event.type_,
+        type_name(event.type_).unwrap_or("?"), event.code,
+        code_name(event.type_, event.code).unwrap_or("?"), event.value)));
 }
 
 /// Prints a usage string.
@@ -186,9 +1726,10 @@ fn print_usage(program_name: &str, opts: getopts::Options) {
 }
 
 /// The main function of the program. Parses the command line, calls
-/// [`load_config`](fn.load_config.html) as needed, and then loops reading
-/// events and attempting to match them.
+/// [`load_config`](../input2cmds/fn.load_config.html) as needed, and then
+/// loops reading events and attempting to match them.
 fn main() {
+    let notifier = Notifier::from_env();
     let args: Vec<String> = std::env::args().collect();
     let program_name = args[0].clone();
     let mut opts = getopts::Options::new();
@@ -197,21 +1738,474 @@ fn main() {
     opts.optflag("v", "verbose", "Print out all received events, and the \
                                   commands that they execute (great for if \
                                   you're still editing your configuration)");
+    opts.optflag("", "evtest-format", "With -v, print events the way evtest \
+                                       does, instead of as \"if\" lines.");
+    opts.optopt("", "color", "Whether to color the output of -v, and errors: \
+                              \"auto\" (the default, which colors only what \
+                              goes to a terminal, and nothing if NO_COLOR is \
+                              set), \"always\", or \"never\"", "WHEN");
+    opts.optflag("", "audit", "Log each command that is run to stderr, with \
+                               its process ID and its full command line.");
+    opts.optflag("", "shell-escape", "Quote the values of placeholders in \
+                                      \"then:\" commands, so that the shell \
+                                      takes each one as a single word, \
+                                      whatever it contains.");
+    opts.optflag("", "detach-children", "Run each command in a session of \
+                                         its own, so that anything it \
+                                         starts in the background isn't \
+                                         killed by a Ctrl-C or by closing \
+                                         the terminal.");
+    opts.optmulti("", "config-text", "Use TEXT as a configuration file, \
+                                      after any files that were named.",
+                  "TEXT");
+    opts.optmulti("", "config-env", "Use the contents of the environment \
+                                     variable VAR as a configuration file, \
+                                     after any files and --config-texts.",
+                  "VAR");
+    opts.optmulti("", "config-url", "Fetch a configuration file from URL \
+                                     (http:// only), after any files, \
+                                     --config-texts, and --config-envs. \
+                                     It's fetched again on every reload.",
+                  "URL");
+    opts.optopt("", "config-cache", "Keep a copy of each --config-url in \
+                                     DIR, to use when it can't be \
+                                     fetched.", "DIR");
+    opts.optflag("", "config-url-insecure", "Let --config-url fetch from \
+                                             other machines, over plain \
+                                             http://, which anybody in \
+                                             between could tamper with.");
+    opts.optopt("", "test-event", "Print which \"if\" line the given event \
+                                   (like \"type=1 code=304 value=1\", \
+                                   optionally with a \"dev=\") would fire, \
+                                   and what it would run, and then exit \
+                                   without opening any devices.", "EVENT");
+    opts.optflag("", "export-json", "Print the configuration as understood, \
+                                     as JSON Lines (which can be read back \
+                                     from a file whose name ends in \
+                                     \".jsonl\"), and then exit without \
+                                     opening any devices.");
+    opts.optflag("", "print-config", "Print the configuration as it was \
+                                      understood, after combining every \
+                                      file and applying defaults, and then \
+                                      exit without opening any devices.");
+    opts.optflagopt("", "init", "Write a commented configuration file to \
+                                 start from, with the devices that are \
+                                 plugged in now, to PATH (which mustn't \
+                                 exist yet) or else to stdout, and then \
+                                 exit.", "PATH");
+    opts.optflag("", "strict", "Exit if a command can't be executed, instead \
+                                of reporting the error and carrying on.");
+    opts.optflag("", "errors-json", "Report errors in configuration files as \
+                                     JSON objects with \"file\", \"line\", \
+                                     and \"message\" keys.");
+    opts.optopt("", "queue", "Hold at most N events while waiting for \
+                              commands to finish. Events that arrive while \
+                              the queue is full are dropped. N can be at \
+                              most 1000000. (Default: no limit)", "N");
+    opts.optopt("", "backlog-warning", "Warn when N messages (mostly \
+                                        events) are waiting in the queue, \
+                                        which means commands are running \
+                                        late, and say so again once the \
+                                        backlog has cleared. 0 turns the \
+                                        warning off. (Default: 500)", "N");
+    opts.optflag("", "report-drops", "Print a line whenever events have been \
+                                      dropped because the queue was full. \
+                                      (Implied by -v)");
+    opts.optopt("", "virtual", "Whether to read from virtual devices, such \
+                                as ones made with uinput, unless a device's \
+                                configuration says otherwise: \"include\" \
+                                (the default), \"exclude\", or \"only\"",
+                "POLICY");
+    opts.optopt("", "max-restarts", "If reading from a device crashes, \
+                                     restart it at most N times in a row \
+                                     before giving up on that device. \
+                                     (Default: 5)", "N");
+    opts.optflag("", "foreground-check", "Make sure each device is an event \
+                                          device that can be read from, and \
+                                          exit with an error if not.");
+    opts.optflag("", "keep-matching", "Keep reading and matching events \
+                                       while a command runs. Commands are \
+                                       still run one at a time, in order, \
+                                       by a worker thread.");
+    opts.optflag("", "exit-on-eof", "Exit as soon as any device reaches its \
+                                     end (as a file or pipe would), instead \
+                                     of once they all have.");
+    opts.optopt("", "replay-loop", "Read each device that's a regular file \
+                                     N times over, starting again from the \
+                                     beginning each time it ends, or \
+                                     \"forever\". Useful for testing a \
+                                     configuration under load.", "N");
+    opts.optopt("", "event-format", "How events are laid out in files and \
+                                      pipes: \"64\" (as 64-bit machines \
+                                      write them), \"32\" (as 32-bit \
+                                      machines do), \"native\", or \
+                                      \"auto\" (the default), which works \
+                                      it out for each regular file. Real \
+                                      devices are always native.",
+                "FORMAT");
+    opts.optmulti("", "ignore-type", "Throw away every event of this type \
+                                      (like \"EV_REL\", or a number) as \
+                                      soon as it's read. May be given more \
+                                      than once.", "TYPE");
+    opts.optflag("", "drain-on-start", "Ignore any events that are already \
+                                        waiting when a device is opened, so \
+                                        that only new input counts.");
+    opts.optflag("", "watch-all", "Keep watching /dev/input, and start \
+                                   reading from each device that appears \
+                                   and any \"dev\" line wants (or from \
+                                   every device, if no \"dev\" line picks \
+                                   them by what they can do). Don't exit \
+                                   when there are no devices left.");
+    opts.optflagopt("", "realtime", "Read from devices in threads with \
+                                     realtime (SCHED_FIFO) scheduling, at \
+                                     PRIORITY (1 to 99, default 10), so \
+                                     that a busy machine doesn't delay \
+                                     them. Warns, and reads as usual, if \
+                                     that isn't allowed.", "PRIORITY");
+    opts.optflag("", "probe-initial-state", "When starting, ask each device \
+                                             which keys are already down and \
+                                             where its axes already are, so \
+                                             that with-held=, combos, hats, \
+                                             and maps know from the start.");
+    opts.optopt("", "grab-check", "Whenever a device has been quiet for \
+                                   SECS seconds, check whether another \
+                                   program has grabbed it, and warn if so. \
+                                   (A device with \"grab=yes\" that \
+                                   couldn't be grabbed is grabbed then, if \
+                                   it's free.)", "SECS");
+    opts.optopt("", "max-runtime", "Exit after running for SECS seconds, the \
+                                    same way as for SIGTERM: running the \
+                                    \"on-stop\" commands, and printing the \
+                                    summary if asked.", "SECS");
+    opts.optopt("", "profile", "Start with NAME as the active profile, \
+                                instead of the first one in the \
+                                configuration.", "NAME");
+    opts.optopt("", "history", "Remember the last N commands that were run, \
+                                and print them to stderr when SIGUSR1 is \
+                                received. (Default: 50)", "N");
+    opts.optflag("", "summary", "When exiting, print a summary to stderr: how \
+                                 long we ran, how many events and commands \
+                                 there were, and how many times each \"if\" \
+                                 line fired. (-v implies this.)");
+    opts.optopt("", "status-file", "After each command, replace this file \
+                                    with a line of JSON saying what the \
+                                    event, the rule, and the outcome were, \
+                                    and when.", "PATH");
+    opts.optopt("", "nice", "Adjust the niceness of executed commands by this \
+                             amount. Only root can use a negative value.",
+                "N");
+    opts.optopt("", "dedup", "Ignore an event if a different device reported \
+                              the same type, code, and value less than MS \
+                              milliseconds earlier. (For hardware that shows \
+                              up as several devices.)", "MS");
+    opts.optopt("", "global-rate", "Run at most N commands per second, \
+                                    across every rule, skipping any more \
+                                    than that. (A safety valve for when a \
+                                    device goes haywire.)", "N");
+    opts.optopt("", "command-stdin", "What commands get as their standard \
+                                      input: \"null\" (the default, \
+                                      nothing), \"inherit\" (whatever \
+                                      input2cmds has), or \"event\" (the \
+                                      event, as a line of JSON)", "WHAT");
+    opts.optopt("", "event-fifo", "Write each event that fires an \"if\" \
+                                   line to the named pipe at PATH (making \
+                                   it if it isn't there), as a line of \
+                                   JSON.", "PATH");
+    opts.optflag("", "fifo-only", "With --event-fifo, don't run the \
+                                   commands of \"if\" lines. Their events \
+                                   are only written to the pipe.");
+    opts.optopt("", "bench", "Run \"true\" N times with \"then:\", and N \
+                              times with \"exec:\" (and, with \
+                              --event-fifo, write N events to the pipe), \
+                              print how fast each was, and exit without \
+                              reading any configuration or opening any \
+                              devices.", "N");
     let matches = match opts.parse(&args[1..]) {
         Ok(x) => x,
         Err(x) => {
             eprintln!("Error parsing command line: {}", x);
             print_usage(&program_name, opts);
-            exit(1)
+            exit(EXIT_CONFIG_ERROR)
         },
     };
     if matches.opt_present("?") || matches.opt_present("h") {
         print_usage(&program_name, opts);
         exit(0);
     }
+    let color = matches.opt_str("color");
+    let color = color.as_deref().unwrap_or("auto");
+    match (use_color(color, 1), use_color(color, 2)) {
+        (Some(stdout), Some(stderr)) => {
+            COLOR_STDOUT.store(stdout, Ordering::Relaxed);
+            COLOR_STDERR.store(stderr, Ordering::Relaxed);
+        },
+        _ => {
+            eprintln!("Error parsing command line: invalid --color");
+            exit(EXIT_CONFIG_ERROR)
+        },
+    }
+    AUDIT.store(matches.opt_present("audit"), Ordering::Relaxed);
+    SHELL_ESCAPE.store(matches.opt_present("shell-escape"), Ordering::Relaxed);
+    DETACH_CHILDREN.store(matches.opt_present("detach-children"),
+                          Ordering::Relaxed);
     let verbose = matches.opt_present("v");
+    let evtest_format = matches.opt_present("evtest-format");
+    let strict = matches.opt_present("strict");
+    let exit_on_eof = matches.opt_present("exit-on-eof");
+    let watch_all = matches.opt_present("watch-all");
+    let keep_matching = matches.opt_present("keep-matching");
+    let errors_json = matches.opt_present("errors-json");
+    let mut device_options = DeviceOptions::default();
+    if let Some(x) = matches.opt_str("max-restarts") {
+        match x.parse() {
+            Ok(x) => device_options.max_restarts = x,
+            Err(_) => {
+                eprintln!("Error parsing command line: invalid \
+                           --max-restarts");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        }
+    }
+    if let Some(x) = matches.opt_str("virtual") {
+        match x.parse() {
+            Ok(x) => device_options.virtual_policy = x,
+            Err(()) => {
+                eprintln!("Error parsing command line: invalid --virtual");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        }
+    }
+    device_options.drain_on_start = matches.opt_present("drain-on-start");
+    device_options.probe_initial_state
+        = matches.opt_present("probe-initial-state");
+    device_options.check_readable = matches.opt_present("foreground-check");
+    if matches.opt_present("realtime") {
+        let max = unsafe { libc::sched_get_priority_max(libc::SCHED_FIFO) };
+        match matches.opt_str("realtime").map(|x| x.parse()) {
+            None => device_options.realtime = Some(10.min(max)),
+            Some(Ok(x)) if 1 <= x && x <= max => {
+                device_options.realtime = Some(x)
+            },
+            _ => {
+                eprintln!("Error parsing command line: invalid --realtime \
+                           (wanted a priority from 1 to {})", max);
+                exit(EXIT_CONFIG_ERROR)
+            },
+        }
+    }
+    if let Some(x) = matches.opt_str("grab-check") {
+        match x.parse() {
+            Ok(x) if x > 0 => {
+                device_options.grab_check = Some(Duration::from_secs(x))
+            },
+            _ => {
+                eprintln!("Error parsing command line: invalid --grab-check");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        }
+    }
+    let max_runtime = match matches.opt_str("max-runtime") {
+        None => None,
+        Some(x) => match x.parse() {
+            Ok(x) if x > 0 => Some(Duration::from_secs(x)),
+            _ => {
+                eprintln!("Error parsing command line: invalid --max-runtime");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        },
+    };
+    if let Some(x) = matches.opt_str("replay-loop") {
+        match x.parse() {
+            Ok(x) if x > 0 => device_options.replay_passes = Some(x),
+            _ if x == "forever" => device_options.replay_passes = None,
+            _ => {
+                eprintln!("Error parsing command line: invalid --replay-loop");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        }
+    }
+    match matches.opt_str("event-format").as_deref() {
+        None | Some("auto") => (),
+        Some(x) => match x.parse() {
+            Ok(x) => device_options.event_format = Some(x),
+            Err(()) => {
+                eprintln!("Error parsing command line: invalid \
+                           --event-format");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        },
+    }
+    for x in matches.opt_strs("ignore-type") {
+        match type_by_name(&x).or_else(|| x.parse().ok()) {
+            Some(type_) if type_ != EV_SYN => {
+                device_options.ignored_types.push(type_)
+            },
+            _ => {
+                eprintln!("Error parsing command line: invalid --ignore-type");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        }
+    }
+    let report_drops = verbose || matches.opt_present("report-drops");
+    let backlog_warning = match matches.opt_str("backlog-warning")
+        .map(|x| x.parse()) {
+            None => Some(DEFAULT_BACKLOG_WARNING),
+            Some(Ok(0)) => None,
+            Some(Ok(x)) => Some(x),
+            Some(Err(_)) => {
+                eprintln!("Error parsing command line: invalid \
+                           --backlog-warning");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        };
+    let queue_size = match matches.opt_str("queue").map(|x| x.parse()) {
+        None => None,
+        Some(Ok(x)) if x > 0 && x <= MAX_QUEUE => Some(x),
+        Some(_) => {
+            eprintln!("Error parsing command line: invalid --queue");
+            exit(EXIT_CONFIG_ERROR)
+        },
+    };
+    if device_options.replay_passes.is_none() && queue_size.is_none() {
+        eprintln!("Warning: with --replay-loop forever and no --queue, events \
+                   can pile up faster than commands run, without limit (and \
+                   input2cmds won't notice being asked to stop until it \
+                   catches up)");
+    }
+    let nice = match matches.opt_str("nice").map(|x| x.parse()) {
+        None => None,
+        Some(Ok(x)) => {
+            if let Err(why) = check_nice(x) {
+                eprintln!("Error parsing command line: {}", why);
+                exit(EXIT_CONFIG_ERROR)
+            }
+            Some(x)
+        },
+        Some(Err(_)) => {
+            eprintln!("Error parsing command line: invalid --nice");
+            exit(EXIT_CONFIG_ERROR)
+        },
+    };
+    let mut dedup = match matches.opt_str("dedup").map(|x| x.parse()) {
+        None => None,
+        Some(Ok(x)) => Some(Deduplicator::new(Duration::from_millis(x))),
+        Some(Err(_)) => {
+            eprintln!("Error parsing command line: invalid --dedup");
+            exit(EXIT_CONFIG_ERROR)
+        },
+    };
+    let mut history = match matches.opt_str("history").map(|x| x.parse()) {
+        None => History::new(DEFAULT_HISTORY),
+        Some(Ok(x)) => History::new(x),
+        Some(Err(_)) => {
+            eprintln!("Error parsing command line: invalid --history");
+            exit(EXIT_CONFIG_ERROR)
+        },
+    };
+    history.status = matches.opt_str("status-file").map(|path| {
+        StatusFile { path, failing: false }
+    });
+    let rate = match matches.opt_str("global-rate").map(|x| x.parse()) {
+        None => None,
+        Some(Ok(x)) if x > 0 => Some(RateLimit::new(x)),
+        Some(_) => {
+            eprintln!("Error parsing command line: invalid --global-rate");
+            exit(EXIT_CONFIG_ERROR)
+        },
+    };
+    let stdin = matches.opt_str("command-stdin");
+    let stdin = match CommandStdin::from_name(stdin.as_deref()
+                                              .unwrap_or("null")) {
+        Some(x) => x,
+        None => {
+            eprintln!("Error parsing command line: invalid --command-stdin");
+            exit(EXIT_CONFIG_ERROR)
+        },
+    };
+    let event_fifo = matches.opt_str("event-fifo");
+    let fifo_only = matches.opt_present("fifo-only");
+    let summary = verbose || matches.opt_present("summary");
+    let mut spawner = Spawner {
+        history: Arc::new(Mutex::new(history)), rate, min_interval: None,
+        last_finished: None, dbus: DBusConnections::default(),
+        missing_leds: HashSet::new(), stdin, worker: false, muted: false,
+    };
+    let test_event = match matches.opt_str("test-event") {
+        None => None,
+        Some(x) => match TestEvent::parse(&x) {
+            Some(x) => Some(x),
+            None => {
+                eprintln!("Error parsing command line: invalid --test-event");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        },
+    };
+    let start_profile = matches.opt_str("profile");
+    let print = matches.opt_present("print-config");
+    let export = matches.opt_present("export-json");
+    let mut inline = Vec::new();
+    for (i, text) in matches.opt_strs("config-text").into_iter().enumerate() {
+        inline.push((format!("--config-text #{}", i + 1), text));
+    }
+    for var in matches.opt_strs("config-env").into_iter() {
+        match std::env::var(&var) {
+            Ok(text) => inline.push((format!("${}", var), text)),
+            Err(x) => {
+                eprintln!("Error parsing command line: invalid --config-env \
+                           ({}: {})", var, x);
+                exit(EXIT_CONFIG_ERROR)
+            },
+        }
+    }
+    let cache_dir = matches.opt_str("config-cache");
+    let remote = matches.opt_present("config-url-insecure");
+    let urls: Vec<(String, Option<PathBuf>)> = matches.opt_strs("config-url")
+        .into_iter().map(|url| {
+            let cache = cache_dir.as_ref().map(|dir| cache_path(dir, &url));
+            (url, cache)
+        }).collect();
+    if matches.opt_present("init") {
+        let mut devices = devices_by_id();
+        if devices.is_empty() { devices = present_devices() }
+        let text = starter_config(&devices);
+        let result = match matches.opt_str("init") {
+            None => std::io::stdout().write_all(text.as_bytes()),
+            Some(path) => std::fs::OpenOptions::new().write(true)
+                .create_new(true).open(&path)
+                .and_then(|mut x| x.write_all(text.as_bytes()))
+                .map_err(|x| {
+                    std::io::Error::new(x.kind(), format!("{:?}: {}", path, x))
+                }),
+        };
+        if let Err(x) = result {
+            eprintln!("Couldn't write the configuration: {}", x);
+            exit(EXIT_CONFIG_ERROR)
+        }
+        exit(EXIT_OK)
+    }
+    if let Some(x) = matches.opt_str("bench") {
+        let count = match x.parse() {
+            Ok(x) if x > 0 => x,
+            _ => {
+                eprintln!("Error parsing command line: invalid --bench");
+                exit(EXIT_CONFIG_ERROR)
+            },
+        };
+        let event_fifo = match event_fifo.map(EventFifo::new) {
+            None => None,
+            Some(Ok(x)) => Some(x),
+            Some(Err(x)) => {
+                eprintln!("Couldn't use --event-fifo: {}", x);
+                exit(EXIT_CONFIG_ERROR)
+            },
+        };
+        if !bench(count, spawner.stdin, nice, event_fifo) {
+            exit(EXIT_COMMAND_FAILED)
+        }
+        exit(EXIT_OK)
+    }
     let free = matches.free;
-    if free.is_empty() {
+    if free.is_empty() && inline.is_empty() && urls.is_empty() {
         print!(r#"
 To get started with input2cmds, create a configuration file. The file can be
 named anything you want. Put one or more "dev" directives inside the file,
@@ -220,9 +2214,10 @@ like so:
 dev /dev/input/by-id/usb-Gamepad_Name_Goes_Here_USB-event-joystick
 
 Make sure you specify an "event-joystick" device and not a "joystick" device
-here. Also, be aware that input2cmds doesn't distinguish between input devices
-(so you can't map the same button on different gamepads to different things,
-for example).
+here. If you want to map the same button on different devices to different
+things, give each device a label, like so:
+
+dev "Left Pad": /dev/input/by-id/usb-Gamepad_Name_Goes_Here_USB-event-joystick
 
 Once that's done, run input2cmds with the -v option and pass it the path to
 your configuration file. It will produce output like:
@@ -234,60 +2229,784 @@ to map, then you can paste that line into the configuration file, and replace
 ... with the command you want to execute. input2cmds will wait until the
 command has fully executed before executing any further commands (unless you
 put a & on the end).
+
+Or run input2cmds --init=my.conf to write a commented file to start from, with
+the devices that are plugged in right now already filled in.
 "#);
         exit(0)
     }
-    let (event_tx, event_rx) = channel();
-    let mut matches = Vec::new();
-    for conf in free.into_iter() {
-        if let Err(x) = load_config(&conf, &event_tx, &mut matches) {
-            eprintln!("{:?}", x);
-            exit(1);
+    if print || export || test_event.is_some() {
+        let result = free.iter().map(|x| ConfigFile::read(x))
+            .chain(inline.into_iter()
+                   .map(|(name, text)| ConfigFile::from_text(name, &text)))
+            .chain(urls.iter()
+                   .map(|(url, cache)| {
+                       ConfigFile::fetch(url, cache.clone(), remote)
+                   }))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|files| merge(&files));
+        let config = match result {
+            Ok(x) => x,
+            Err(x) => {
+                report_error(&x, errors_json);
+                exit(EXIT_CONFIG_ERROR)
+            },
+        };
+        warn_unknown_devices(&config);
+        warn_unknown_profiles(&config);
+        let profile = starting_profile(&config, start_profile.as_deref());
+        if print { print_config(&config) }
+        if export { print!("{}", config.to_json_lines()) }
+        if let Some(test_event) = test_event {
+            test_event.run(&config, profile)
+        }
+        exit(EXIT_OK)
+    }
+    let (event_tx, event_rx) = event_queue(queue_size);
+    if let Err(x) = forward_signals(&[libc::SIGHUP, libc::SIGINT,
+                                      libc::SIGTERM, libc::SIGUSR1,
+                                      libc::SIGUSR2, libc::SIGURG],
+                                      event_tx.clone()) {
+        eprintln!("{}", paint_err(RED, format_args!(
+            "Couldn't set up signal handling: {}", x)));
+        exit(EXIT_CONFIG_ERROR)
+    }
+    let result = free.iter().map(|x| ConfigFile::read(x))
+        .chain(inline.into_iter()
+               .map(|(name, text)| ConfigFile::from_text(name, &text)))
+        .chain(urls.iter()
+               .map(|(url, cache)| {
+                   ConfigFile::fetch(url, cache.clone(), remote)
+               }))
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|files| {
+            let mut config = merge(&files)?;
+            config.devices = find_devices(&config.devices);
+            let handles = open_devices(&config.devices, &event_tx,
+                                       &device_options)?;
+            Ok((files, config, handles))
+        });
+    let (mut files, mut config, mut handles) = match result {
+        Ok(x) => x,
+        Err(x) => {
+            report_error(&x, errors_json);
+            exit(EXIT_CONFIG_ERROR)
+        },
+    };
+    // with --watch-all, the devices they want might turn up later
+    if !watch_all { warn_unknown_devices(&config) }
+    warn_unknown_profiles(&config);
+    if watch_all {
+        if let Err(x) = watch_devices(event_tx.clone()) {
+            eprintln!("{}", paint_err(RED, format_args!(
+                "Couldn't watch for new devices: {}", x)));
+            exit(EXIT_CONFIG_ERROR)
+        }
+        for path in present_devices() {
+            attach(&path, &files, &mut config, &mut handles, &event_tx,
+                   &device_options, errors_json);
+        }
+    }
+    spawner.min_interval = config.min_interval;
+    let history = spawner.history.clone();
+    let mut runner = if keep_matching {
+        Runner::worker(spawner, strict, verbose)
+    }
+    else { Runner::Here(spawner) };
+    let mut event_fifo = match event_fifo {
+        None if fifo_only => {
+            eprintln!("Error parsing command line: --fifo-only needs \
+                       --event-fifo");
+            exit(EXIT_CONFIG_ERROR)
+        },
+        None => None,
+        Some(path) => match EventFifo::new(path.clone()) {
+            Ok(x) => Some(x),
+            Err(x) => {
+                eprintln!("Couldn't use {:?} for --event-fifo: {}", path, x);
+                exit(EXIT_CONFIG_ERROR)
+            },
+        },
+    };
+    let mut last_event = Instant::now();
+    let mut counted = Summary { started: last_event, events: 0, reloads: 0 };
+    // which "idle" commands have run since the last event
+    let mut idle_done = vec![false; config.idle.len()];
+    let mut state = InputState {
+        profile: starting_profile(&config, start_profile.as_deref()),
+        ..InputState::default()
+    };
+    let mut reported_drops = 0;
+    // whether we've warned that the queue is backing up, and haven't said
+    // that it's cleared since
+    let mut backlogged = false;
+    // whether SIGURG has muted commands
+    let mut muted = false;
+    let mut guards = Guards::default();
+    // how many times each "if" line has fired
+    let mut fire_counts = vec![0; config.matches.len()];
+    // which axes each "if" line with "above=" is waiting to see fall again
+    let mut trigger_states = vec![TriggerState::default();
+                                  config.matches.len()];
+    // the value each "map" last ran its command for
+    let mut map_values = vec![None; config.maps.len()];
+    // which way each "axis" is pushed
+    let mut axis_states = vec![AxisState::default(); config.axes.len()];
+    // which bucket each "buckets" line's axis is in
+    let mut bucket_states = vec![BucketState::default();
+                                 config.buckets.len()];
+    // how far along each "sequence" is
+    let mut sequence_states = vec![SequenceState::new();
+                                   config.sequences.len()];
+    // how many taps each "while-held" line has seen during the current hold
+    let mut tap_states = vec![TapState::default(); config.taps.len()];
+    // where each "hat" is pointing
+    let mut hat_states = vec![HatState::default(); config.hats.len()];
+    // the touches each "zone" knows about
+    let mut zone_states = vec![ZoneState::default(); config.zones.len()];
+    // where each "region"'s axes are
+    let mut region_states = vec![RegionState::default();
+                                 config.regions.len()];
+    // the "held=" and "delay=" lines that are waiting to fire
+    let mut delayed = DelayedMatches::default();
+    // whether every device we've finished reading from reached its end
+    let mut all_eof = true;
+    // the codes of the keys that --probe-initial-state found already down
+    let mut initially_held = Vec::new();
+    for event in handles.iter().flat_map(|x| x.initial_state()) {
+        let mut event = event.clone();
+        config.calibrations.iter().any(|x| x.apply(&mut event));
+        if verbose { println!("# initially {}", event) }
+        state.update(&event);
+        if event.event.type_ == EV_KEY
+        && !initially_held.contains(&event.event.code) {
+            initially_held.push(event.event.code);
+        }
+        // the hat is already pointing wherever it points, so that doesn't
+        // fire anything
+        for (hat, hat_state) in config.hats.iter().zip(hat_states.iter_mut()) {
+            hat.update(&event, hat_state);
+            hat.settle(&event.device, hat_state);
+        }
+        for (region, region_state) in config.regions.iter()
+        .zip(region_states.iter_mut()) {
+            region.update(&event, region_state);
+            region.settle(&event.device, region_state);
+        }
+        for (map, last) in config.maps.iter().zip(map_values.iter_mut()) {
+            if let Some(x) = map.scaled_value(&event) { *last = Some(x) }
+        }
+        for (axis, axis_state) in config.axes.iter()
+        .zip(axis_states.iter_mut()) {
+            axis.update(&event, axis_state);
+        }
+        for (buckets, bucket_state) in config.buckets.iter()
+        .zip(bucket_states.iter_mut()) {
+            buckets.update(&event, bucket_state);
+        }
+    }
+    initially_held.sort_unstable();
+    let initially_held: Vec<String> = initially_held.iter()
+        .map(u16::to_string).collect();
+    let held_env = [("I2C_HELD", initially_held.join(" "))];
+    for action in config.on_start.iter() {
+        let rule = format!("on-start {}", action);
+        if verbose { print!("{}", paint_out(GREEN, &rule)) }
+        if !runner.run(&rule, action, None, &held_env,
+                        &CommandOptions::default(), nice) && strict {
+            exit(EXIT_COMMAND_FAILED)
         }
     }
-    std::mem::drop(event_tx); // we've cloned this poor thing enough
-    while let Ok(event) = event_rx.recv() {
-        let mut command = None;
-        for possibility in matches.iter() {
-            match possibility.wants_type {
-                Some(x) if event.type_ != x => continue,
-                _ => (),
+    notifier.notify("READY=1");
+    let status = loop {
+        if handles.is_empty() && !watch_all {
+            break if all_eof { EXIT_OK } else { EXIT_DEVICE_ERROR }
+        }
+        // Anything that needs to happen at a certain time, rather than in
+        // response to an event, puts its deadline here. We wake up at the
+        // earliest one, do whatever is due, and go back to waiting.
+        let next_idle = config.idle.iter().zip(idle_done.iter())
+            .filter(|(_, done)| !**done)
+            // a deadline too far off to represent is never reached
+            .filter_map(|(idle, _)| last_event.checked_add(idle.after))
+            .min();
+        // a limit too far off to represent is no limit at all
+        let stop_at = max_runtime.and_then(|x| counted.started.checked_add(x));
+        let deadline = next_idle.into_iter().chain(delayed.next_due())
+            .chain(stop_at).min();
+        let message = match event_rx.recv_until(deadline) {
+            Ok(x) => x,
+            // can't happen, since we're holding a sender ourselves
+            Err(_) => break EXIT_OK,
+        };
+        let now = Instant::now();
+        if stop_at.is_some_and(|x| now >= x) {
+            if verbose {
+                let secs = max_runtime.unwrap().as_secs();
+                println!("# exiting after running for {} second{}", secs,
+                         if secs == 1 { "" } else { "s" });
+            }
+            break EXIT_OK
+        }
+        // timed work
+        let idle_for = now - last_event;
+        let got_event = matches!(message, Some(Message::Event(_)));
+        for (idle, done) in config.idle.iter().zip(idle_done.iter_mut()) {
+            if *done || idle_for < idle.after || got_event { continue }
+            *done = true;
+            if verbose { print!("{}", paint_out(GREEN, idle)) }
+            if !runner.run(idle, &idle.action, None, &[],
+                            &CommandOptions::default(), nice) && strict {
+                exit(EXIT_COMMAND_FAILED)
+            }
+        }
+        for (index, event) in delayed.take_due(now) {
+            let rule = &config.matches[index];
+            let count = &mut fire_counts[index];
+            if rule.max.map(|max| *count >= max).unwrap_or(false) { continue }
+            // a "delay=" line already checked this when the event came
+            if let (Some(guard), Some(_)) = (rule.when.as_ref(), rule.held) {
+                if !guards.check(guard, &event) { continue }
+            }
+            if verbose {
+                print!("{}", paint_out(GREEN, format_args!(
+                    "if {} {}", event, rule.action)));
             }
-            match possibility.wants_code {
-                Some(x) if event.code != x => continue,
-                _ => (),
+            if let Some(fifo) = event_fifo.as_mut() { fifo.send(&event) }
+            *count += 1;
+            if fifo_only {
+                if verbose { println!(" # sent") }
             }
-            match possibility.wants_value {
-                Some(x) if event.value != x => continue,
-                _ => (),
+            else if !runner.run(rule, &rule.action, Some(&event), &[],
+                                 &rule.options, nice)
+            && strict {
+                exit(EXIT_COMMAND_FAILED)
             }
-            command = Some(possibility.command_to_run.as_str());
-            break
         }
-        match command {
-            Some(command) => {
+        let event = match message {
+            Some(Message::Event(x)) => x,
+            Some(Message::Signal(libc::SIGHUP)) => {
+                if reload(&mut files, &mut config, &mut handles, &event_tx,
+                          &device_options, errors_json) {
+                    counted.reloads += 1;
+                    if !watch_all { warn_unknown_devices(&config) }
+                    warn_unknown_profiles(&config);
+                    runner.set_min_interval(config.min_interval);
+                    idle_done = vec![false; config.idle.len()];
+                    fire_counts = vec![0; config.matches.len()];
+                    trigger_states = vec![TriggerState::default();
+                                          config.matches.len()];
+                    map_values = vec![None; config.maps.len()];
+                    axis_states = vec![AxisState::default();
+                                       config.axes.len()];
+                    bucket_states = vec![BucketState::default();
+                                         config.buckets.len()];
+                    sequence_states = vec![SequenceState::new();
+                                           config.sequences.len()];
+                    tap_states = vec![TapState::default();
+                                      config.taps.len()];
+                    hat_states = vec![HatState::default(); config.hats.len()];
+                    zone_states = vec![ZoneState::default();
+                                       config.zones.len()];
+                    region_states = vec![RegionState::default();
+                                         config.regions.len()];
+                    delayed = DelayedMatches::default();
+                    state.latched.clear();
+                    // stay in the same profile, if it's still there
+                    if !state.profile.as_ref()
+                    .is_some_and(|x| config.profiles.contains(x)) {
+                        state.profile = config.profiles.first().cloned();
+                    }
+                    // the new configuration might want devices it didn't
+                    // before, or not want ones it did
+                    if watch_all {
+                        for path in present_devices() {
+                            attach(&path, &files, &mut config, &mut handles,
+                                   &event_tx, &device_options, errors_json);
+                        }
+                    }
+                }
+                continue
+            },
+            Some(Message::DeviceAppeared(path)) => {
+                if attach(&path, &files, &mut config, &mut handles,
+                          &event_tx, &device_options, errors_json)
+                && verbose {
+                    println!("# started reading from {:?}", path);
+                }
+                continue
+            },
+            Some(Message::Signal(signal @ (libc::SIGINT | libc::SIGTERM))) => {
                 if verbose {
-                    print!("if type={} code={} value={} then: {}",
-                           event.type_, event.code, event.value, command);
+                    println!("# exiting because of signal {}", signal);
+                }
+                break EXIT_OK
+            },
+            Some(Message::Signal(libc::SIGUSR1)) => {
+                history.lock().unwrap().print();
+                let (waiting, peak) = event_rx.depth();
+                eprintln!("# {} message{} waiting in the queue (at most {} \
+                           at once)", waiting,
+                          if waiting == 1 { "" } else { "s" }, peak);
+                continue
+            },
+            Some(Message::Signal(libc::SIGURG)) => {
+                // everything else goes on as usual, so that what's held
+                // down is still known when we're unmuted
+                muted = !muted;
+                runner.set_muted(muted);
+                eprintln!("{}", if muted {
+                    "Muted: no commands will be run until the next SIGURG"
+                } else { "Unmuted" });
+                continue
+            },
+            Some(Message::Signal(libc::SIGUSR2)) => {
+                // on to the next profile, or back to the first after the
+                // last
+                let next = config.profiles.iter()
+                    .position(|x| Some(x) == state.profile.as_ref())
+                    .map_or(0, |x| x + 1);
+                state.profile = config.profiles.get(next)
+                    .or_else(|| config.profiles.first()).cloned();
+                if let (true, Some(x)) = (verbose, state.profile.as_ref()) {
+                    println!("# switched to profile {}", x);
+                }
+                continue
+            },
+            Some(Message::Signal(_)) | None => continue,
+            Some(Message::Sync(device)) => {
+                // what hats, zones, and regions fire on, so that their
+                // commands know which device it was
+                let report = DeviceEvent {
+                    device: device.clone(),
+                    event: InputEvent {
+                        time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+                        type_: EV_SYN, code: SYN_REPORT, value: 0,
+                    },
+                    range: None,
+                };
+                for (hat, hat_state) in config.hats.iter()
+                .zip(hat_states.iter_mut()) {
+                    if !hat.settle(&device, hat_state) { continue }
+                    if verbose { print!("{}", paint_out(GREEN, hat)) }
+                    let direction = hat.direction_name().to_owned();
+                    if !runner.run(hat, &hat.action, Some(&report),
+                                    &[("I2C_DIRECTION", direction)],
+                                    &hat.options, nice)
+                    && strict {
+                        exit(EXIT_COMMAND_FAILED)
+                    }
+                }
+                for (zone, zone_state) in config.zones.iter()
+                .zip(zone_states.iter_mut()) {
+                    for (x, y) in zone.settle(&device, zone_state) {
+                        if verbose {
+                            print!("{} # I2C_X={} I2C_Y={}",
+                                   paint_out(GREEN, zone), x, y)
+                        }
+                        if !runner.run(zone, &zone.action, Some(&report),
+                                        &[("I2C_X", x.to_string()),
+                                          ("I2C_Y", y.to_string())],
+                                        &zone.options, nice)
+                        && strict {
+                            exit(EXIT_COMMAND_FAILED)
+                        }
+                    }
                 }
-                let mut child = Command::new("/bin/sh").arg("-c").arg(command)
-                    .spawn().expect("Couldn't execute /bin/sh");
-                let exit_status = child.wait()
-                    .expect("Couldn't wait on child process (?!!)");
-                if exit_status.success() {
-                    println!(" # OK");
+                for (region, region_state) in config.regions.iter()
+                .zip(region_states.iter_mut()) {
+                    let (x, y) = match region.settle(&device, region_state) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    if verbose {
+                        print!("{} # I2C_X={} I2C_Y={}",
+                               paint_out(GREEN, region), x, y)
+                    }
+                    if !runner.run(region, &region.action, Some(&report),
+                                    &[("I2C_X", x.to_string()),
+                                      ("I2C_Y", y.to_string())],
+                                    &region.options, nice)
+                    && strict {
+                        exit(EXIT_COMMAND_FAILED)
+                    }
                 }
-                else {
-                    println!(" # {}", exit_status);
+                continue
+            },
+            Some(Message::Finished { devices, eof }) => {
+                let count = handles.len();
+                handles.retain(|x| !Arc::ptr_eq(&x.devices()[0], &devices[0]));
+                // a device we stopped reading from on purpose doesn't count
+                if handles.len() != count {
+                    if eof && verbose {
+                        println!("# reached the end of {:?}",
+                                 devices[0].path);
+                    }
+                    all_eof = all_eof && eof;
+                    if eof && exit_on_eof { break EXIT_OK }
                 }
+                continue
             },
-            None => {
+        };
+        last_event = now;
+        counted.events += 1;
+        if report_drops {
+            let total_dropped = event_rx.total_dropped();
+            if total_dropped != reported_drops {
+                println!("# dropped {} events because the queue was full \
+                          ({} in total)", total_dropped - reported_drops,
+                         total_dropped);
+                reported_drops = total_dropped;
+            }
+        }
+        if let Some(threshold) = backlog_warning {
+            let (waiting, _) = event_rx.depth();
+            if !backlogged && waiting >= threshold {
+                backlogged = true;
+                eprintln!("Warning: {} messages (mostly events) are waiting \
+                           in the queue, so commands are running late. (Is \
+                           a slow command holding things up? Put a & after \
+                           it to run it in the background.)", waiting);
+            }
+            else if backlogged && waiting <= threshold / 2 {
+                backlogged = false;
+                eprintln!("The backlog has cleared: {} messages are waiting.",
+                          waiting);
+            }
+        }
+        if idle_done.iter().any(|x| *x) {
+            for done in idle_done.iter_mut() { *done = false }
+            for action in config.on_activity.iter() {
+                let rule = format!("on-activity {}", action);
+                if verbose { print!("{}", paint_out(GREEN, &rule)) }
+                if !runner.run(&rule, action, None, &[],
+                                &CommandOptions::default(), nice) && strict {
+                    exit(EXIT_COMMAND_FAILED)
+                }
+            }
+        }
+        if let Some(dedup) = dedup.as_mut() {
+            if dedup.is_duplicate(&event) {
                 if verbose {
-                    println!("if type={} code={} value={} then: ...",
-                             event.type_, event.code, event.value);
+                    println!("# ignored duplicate: {}", event);
                 }
+                continue
+            }
+        }
+        // only the first calibration for the axis applies
+        let mut event = event;
+        config.calibrations.iter().any(|x| x.apply(&mut event));
+        state.update(&event);
+        delayed.update(&event);
+        for latch in config.latches.iter() {
+            if !latch.pressed_by(&event) { continue }
+            let on = state.toggle_latch(&latch.name);
+            if verbose {
+                println!("# latch {} is {}", latch.name,
+                         if on { "on" } else { "off" });
+            }
+        }
+        for switch in config.profile_switches.iter() {
+            if !switch.pressed_by(&event) { continue }
+            state.profile = Some(switch.name.clone());
+            if verbose { println!("# switched to profile {}", switch.name) }
+        }
+        for (hat, hat_state) in config.hats.iter().zip(hat_states.iter_mut()) {
+            hat.update(&event, hat_state);
+        }
+        for (zone, zone_state) in config.zones.iter()
+        .zip(zone_states.iter_mut()) {
+            zone.update(&event, zone_state);
+        }
+        for (region, region_state) in config.regions.iter()
+        .zip(region_states.iter_mut()) {
+            region.update(&event, region_state);
+        }
+        for (map, last) in config.maps.iter().zip(map_values.iter_mut()) {
+            let scaled = match map.scaled_value(&event) {
+                Some(x) if *last != Some(x) => x,
+                _ => continue,
+            };
+            *last = Some(scaled);
+            if verbose {
+                print!("{} # I2C_SCALED={}", paint_out(GREEN, map), scaled)
+            }
+            if !runner.run(map, &map.action, Some(&event),
+                            &[("I2C_SCALED", scaled.to_string())],
+                            &map.options, nice) && strict {
+                exit(EXIT_COMMAND_FAILED)
+            }
+        }
+        for (axis, axis_state) in config.axes.iter()
+        .zip(axis_states.iter_mut()) {
+            let (command, direction) = match axis.update(&event,
+                                                         axis_state) {
+                Some(x) => x,
+                None => continue,
+            };
+            if verbose {
+                print!("{} # I2C_DIRECTION={}", paint_out(GREEN, axis),
+                       direction)
+            }
+            if !runner.run(axis, &Action::Shell(command.to_owned()),
+                            Some(&event),
+                            &[("I2C_DIRECTION", direction.to_owned())],
+                            &axis.options, nice) && strict {
+                exit(EXIT_COMMAND_FAILED)
+            }
+        }
+        for (buckets, bucket_state) in config.buckets.iter()
+        .zip(bucket_states.iter_mut()) {
+            let (command, bucket) = match buckets.update(&event,
+                                                         bucket_state) {
+                Some(x) => x,
+                None => continue,
+            };
+            if verbose {
+                print!("{} # I2C_BUCKET={}", paint_out(GREEN, buckets), bucket)
+            }
+            if !runner.run(buckets, &Action::Shell(command.to_owned()),
+                            Some(&event),
+                            &[("I2C_BUCKET", bucket)], &buckets.options,
+                            nice) && strict {
+                exit(EXIT_COMMAND_FAILED)
+            }
+        }
+        for (sequence, seq_state) in config.sequences.iter()
+        .zip(sequence_states.iter_mut()) {
+            if !seq_state.advance(sequence, &event) { continue }
+            if verbose { print!("{}", paint_out(GREEN, sequence)) }
+            if !runner.run(sequence, &sequence.action, Some(&event), &[],
+                            &sequence.options, nice) && strict {
+                exit(EXIT_COMMAND_FAILED)
+            }
+        }
+        for combo in config.combos.iter() {
+            if !combo.completed_by(&event, &state) { continue }
+            if verbose { print!("{}", paint_out(GREEN, combo)) }
+            if !runner.run(combo, &combo.action, Some(&event), &[],
+                            &combo.options, nice) && strict {
+                exit(EXIT_COMMAND_FAILED)
+            }
+        }
+        for (taps, tap_state) in config.taps.iter()
+        .zip(tap_states.iter_mut()) {
+            if !taps.update(&event, &state, tap_state) { continue }
+            if verbose { print!("{}", paint_out(GREEN, taps)) }
+            if !runner.run(taps, &taps.action, Some(&event), &[],
+                            &taps.options, nice) && strict {
+                exit(EXIT_COMMAND_FAILED)
+            }
+        }
+        // every "above=" line sees every event about its axis, whether or not
+        // an earlier line fires
+        let crossed: Vec<bool> = config.matches.iter()
+            .zip(trigger_states.iter_mut())
+            .map(|(rule, x)| rule.crossed(&event, x)).collect();
+        // whether any rule fired for this event
+        let mut fired = false;
+        // whether the event has been written to the event FIFO
+        let mut sent = false;
+        for (index, (rule, count)) in config.matches.iter()
+        .zip(fire_counts.iter_mut()).enumerate()
+        .filter(|(_, (x, _))| x.matches(&event, &state)) {
+            if rule.max.map(|max| *count >= max).unwrap_or(false) { continue }
+            if !crossed[index] { continue }
+            if let Some(window) = rule.between {
+                if !window.contains(local_minutes()) { continue }
+            }
+            // it fires later, if at all, so the press goes on to later lines
+            if let Some(held) = rule.held {
+                delayed.start(index, &event, now + held, true);
+                continue
             }
+            if let Some(guard) = rule.when.as_ref() {
+                if !guards.check(guard, &event) { continue }
+            }
+            if verbose && evtest_format {
+                if !fired { print_evtest(&event.event, GREEN) }
+                print!("{}", paint_out(GREEN, rule));
+            }
+            else if verbose {
+                print!("{}", paint_out(GREEN, format_args!(
+                    "if {} {}", event, rule.action)));
+            }
+            fired = true;
+            if let Some(delay) = rule.delay {
+                if verbose { println!(" # in {} ms", delay.as_millis()) }
+                delayed.start(index, &event, now + delay,
+                              rule.cancel_on_release);
+                if !rule.observe { break }
+                continue
+            }
+            if !sent {
+                if let Some(fifo) = event_fifo.as_mut() { fifo.send(&event) }
+                sent = true;
+            }
+            *count += 1;
+            if fifo_only {
+                if verbose { println!(" # sent") }
+            }
+            else if !runner.run(rule, &rule.action, Some(&event), &[],
+                                 &rule.options, nice)
+            && strict {
+                exit(EXIT_COMMAND_FAILED)
+            }
+            if !rule.observe { break }
+        }
+        if !fired {
+            if verbose && evtest_format {
+                print_evtest(&event.event, DIM);
+            }
+            else if verbose {
+                println!("{}", paint_out(DIM, format_args!(
+                    "if {} then: ...", event)));
+            }
+        }
+    };
+    notifier.notify("STOPPING=1");
+    let mut status = status;
+    // on-stop commands come after everything that's still queued, and run
+    // even while muted, so that they can clean up
+    let mut spawner = runner.finish();
+    spawner.muted = false;
+    let mut runner = Runner::Here(spawner);
+    for action in config.on_stop.iter() {
+        let rule = format!("on-stop {}", action);
+        if verbose { print!("{}", paint_out(GREEN, &rule)) }
+        if !runner.run(&rule, action, None, &[], &CommandOptions::default(),
+                        nice) && strict {
+            status = EXIT_COMMAND_FAILED
         }
     }
-    std::process::exit(1)
+    if summary {
+        counted.print(&config, &fire_counts, &history.lock().unwrap(),
+                      event_rx.total_dropped(), event_rx.depth().1,
+                      device_options.restart_count.load(Ordering::Relaxed));
+    }
+    exit(status)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+    use super::*;
+
+    /// Returns a path in the temporary directory for a test's file.
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("input2cmds-{}-{}", name,
+                                          std::process::id()))
+            .to_str().unwrap().to_owned()
+    }
+
+    /// Reads the configuration file at `path`, and opens its devices.
+    fn start(path: &str, event_tx: &EventSender)
+             -> (Vec<ConfigFile>, Config, Vec<DeviceHandle>) {
+        let files = vec![ConfigFile::read(path).unwrap()];
+        let config = merge(&files).unwrap();
+        let handles = open_devices(&config.devices, event_tx,
+                                   &DeviceOptions::default()).unwrap();
+        (files, config, handles)
+    }
+
+    /// Replaces the configuration file at `path`, and makes sure the next
+    /// reload rereads it, however coarse the file system's clock is.
+    fn rewrite(files: &mut [ConfigFile], path: &str, text: &str) {
+        std::fs::write(path, text).unwrap();
+        files[0].modified = None;
+    }
+
+    /// The rules in a configuration, as they'd be printed.
+    fn rules(config: &Config) -> Vec<String> {
+        config.matches.iter().map(ToString::to_string).collect()
+    }
+
+    /// The labels of the devices we're reading from.
+    fn labels(handles: &[DeviceHandle]) -> Vec<String> {
+        handles.iter().map(|x| x.devices()[0].name().to_owned()).collect()
+    }
+
+    #[test]
+    fn broken_reload_leaves_previous_mappings_intact() {
+        let (path, one, two) = (temp_path("broken.conf"),
+                                temp_path("broken-1"), temp_path("broken-2"));
+        std::fs::write(&one, b"").unwrap();
+        std::fs::write(&two, b"").unwrap();
+        std::fs::write(&path, format!(
+            "dev one: {}\n\
+             dev two: {}\n\
+             if dev=one type=1 code=30 value=1 then: a\n", one, two))
+            .unwrap();
+        let (event_tx, _event_rx) = event_queue(None);
+        let (mut files, mut config, mut handles) = start(&path, &event_tx);
+        let before = rules(&config);
+        let mut reload = |files: &mut Vec<ConfigFile>,
+                          handles: &mut Vec<DeviceHandle>, text: &str| {
+            rewrite(files, &path, text);
+            reload(files, &mut config, handles, &event_tx,
+                   &DeviceOptions::default(), false)
+        };
+        // a mistake
+        assert!(!reload(&mut files, &mut handles,
+                        "if type=1 cod=30 then: b\n"));
+        // a new device that can't be opened
+        assert!(!reload(&mut files, &mut handles, &format!(
+            "dev one: {}\n\
+             dev two: {}\n\
+             dev three: {}\n\
+             if dev=one type=1 code=30 value=1 then: b\n",
+            one, two, temp_path("broken-3"))));
+        assert_eq!(labels(&handles), ["one", "two"]);
+        // a changed one that can't be opened again, after the one before it
+        // was
+        std::fs::remove_file(&two).unwrap();
+        assert!(!reload(&mut files, &mut handles, &format!(
+            "dev uno: {}\n\
+             dev dos: {}\n\
+             if dev=uno type=1 code=30 value=1 then: b\n", one, two)));
+        assert_eq!(rules(&config), before);
+        assert_eq!(rules(&merge(&files).unwrap()), before);
+        assert_eq!(config.devices.iter().map(|x| x.name())
+                       .collect::<Vec<_>>(), ["one", "two"]);
+        // the first one is being read for its old line again, and the second
+        // one is gone
+        assert_eq!(labels(&handles), ["one"]);
+        std::fs::remove_file(&one).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reload_reopens_changed_devices() {
+        let keyboard = test_util::VirtualDevice::keyboard(&[codes::KEY_A]);
+        let keyboard = match keyboard {
+            Some(x) => x,
+            // there's no uinput here, so nothing to test against
+            None => return,
+        };
+        let path = temp_path("changed.conf");
+        std::fs::write(&path, format!(
+            "dev kbd grab=yes: {}\n\
+             if dev=kbd type=1 code=30 value=1 then: a\n", keyboard.path))
+            .unwrap();
+        let (event_tx, _event_rx) = event_queue(None);
+        let (mut files, mut config, mut handles) = start(&path, &event_tx);
+        rewrite(&mut files, &path, &format!(
+            "dev keys grab=yes: {}\n\
+             if dev=keys type=1 code=30 value=1 then: b\n", keyboard.path));
+        assert!(reload(&mut files, &mut config, &mut handles, &event_tx,
+                       &DeviceOptions::default(), false));
+        assert_eq!(labels(&handles), ["keys"]);
+        assert_eq!(rules(&config), ["if dev=keys type=1 code=30 value=1 \
+                                     then: b"]);
+        // the new reader has it grabbed, so nobody else can grab it (this is
+        // EVIOCGRAB)
+        let file = File::open(&keyboard.path).unwrap();
+        assert_eq!(unsafe { libc::ioctl(file.as_raw_fd(), 0x40044590, 1) },
+                   -1);
+        assert_eq!(std::io::Error::last_os_error().raw_os_error(),
+                   Some(libc::EBUSY));
+        for handle in handles.iter() { handle.stop() }
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,1145 @@
+//! Matching events against the rules from the configuration.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Instant,
+};
+
+use crate::{
+    AxisMap, AxisMatch, BucketMatch, Calibration, ComboMatch, DeviceEvent,
+    DeviceSpec, FieldMatcher, HatMatch, InputMatch, Latch, ProfileSwitch,
+    RegionMatch, TapMatch, ZoneMatch,
+    codes::{EV_ABS, EV_KEY, ABS_MT_SLOT, ABS_MT_TRACKING_ID, BTN_TOUCH},
+};
+
+/// Returns true if `device` is the one that `wanted` names, or if `wanted` is
+/// `None`.
+fn is_wanted(wanted: &Option<String>, device: &DeviceSpec) -> bool {
+    wanted.as_ref().map(|x| device.name() == x).unwrap_or(true)
+}
+
+/// What we know about the input devices, beyond the event currently being
+/// matched. Feed every event to [`update`](#method.update) before matching
+/// it.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct InputState {
+    /// The most recently selected multitouch slot.
+    pub current_slot: i32,
+    /// The keys that are down, each with the name of the device it's down
+    /// on.
+    pub pressed: HashSet<(String, u16)>,
+    /// The names of the latches that are on. (See
+    /// [`toggle_latch`](#method.toggle_latch).)
+    pub latched: HashSet<String>,
+    /// The name of the active profile, if there is one. "if" lines in any
+    /// other profile don't match.
+    pub profile: Option<String>,
+}
+
+impl InputState {
+    /// Updates the state to reflect an event that was just received.
+    pub fn update(&mut self, event: &DeviceEvent) {
+        let device = event.device.name();
+        let event = &event.event;
+        if event.type_ == EV_ABS && event.code == ABS_MT_SLOT {
+            self.current_slot = event.value;
+        }
+        if event.type_ == EV_KEY {
+            let key = (device.to_owned(), event.code);
+            // a repeat means the key is down, even if we missed the press
+            // (say, because it was pressed before we started)
+            if event.value == 0 { self.pressed.remove(&key); }
+            else { self.pressed.insert(key); }
+        }
+    }
+    /// Returns true if the given key is down on the device with the given
+    /// name, or, if `device` is `None`, on any device.
+    pub fn is_pressed(&self, device: Option<&str>, code: u16) -> bool {
+        match device {
+            Some(device) => self.pressed.contains(&(device.to_owned(), code)),
+            None => self.pressed.iter().any(|x| x.1 == code),
+        }
+    }
+    /// Turns the latch with the given name on if it's off, or off if it's
+    /// on. Returns true if it's on now.
+    pub fn toggle_latch(&mut self, name: &str) -> bool {
+        if self.latched.remove(name) { return false }
+        self.latched.insert(name.to_owned());
+        true
+    }
+}
+
+impl<T: Ord + Copy> FieldMatcher<T> {
+    /// Returns true if `n` meets this condition.
+    pub fn matches(&self, n: T) -> bool {
+        match self {
+            FieldMatcher::Any => true,
+            FieldMatcher::Is(x) => n == *x,
+            FieldMatcher::Not(x) => n != *x,
+            FieldMatcher::Range(lo, hi) => *lo <= n && n <= *hi,
+            FieldMatcher::List(x) => {
+                x.iter().any(|(lo, hi)| *lo <= n && n <= *hi)
+            },
+            FieldMatcher::AtLeast(x) => n >= *x,
+            FieldMatcher::AtMost(x) => n <= *x,
+        }
+    }
+}
+
+impl InputMatch {
+    /// Returns true if this match wants to fire for the given event, in the
+    /// given state.
+    pub fn matches(&self, event: &DeviceEvent, state: &InputState) -> bool {
+        // an "unless" line in another profile doesn't fire either
+        if self.profile.is_some() && self.profile != state.profile {
+            return false
+        }
+        self.conditions_match(event, state) != self.negated
+    }
+    /// Returns true if every condition of this match holds for the given
+    /// event, in the given state, ignoring whether it's an "unless".
+    fn conditions_match(&self, event: &DeviceEvent, state: &InputState)
+                        -> bool {
+        match self.wants_device.as_ref() {
+            Some(x) if event.device.name() != x => return false,
+            _ => (),
+        }
+        let device = event.device.name();
+        if !self.with_held.iter().all(|x| state.is_pressed(Some(device), *x)) {
+            return false
+        }
+        if !self.with_latch.iter().all(|x| state.latched.contains(x)) {
+            return false
+        }
+        let event = &event.event;
+        // a "held=" line starts its timer on the press, and only then
+        if self.held.is_some()
+        && (event.type_ != EV_KEY || event.value != 1) {
+            return false
+        }
+        if !self.wants_type.matches(event.type_)
+        || !self.wants_code.matches(event.code)
+        || !self.wants_value.matches(event.value) {
+            return false
+        }
+        match self.wants_slot {
+            Some(x) if state.current_slot != x => return false,
+            _ => (),
+        }
+        true
+    }
+}
+
+/// Which axes have risen to an "if" line's `above=`, and haven't yet fallen
+/// to its `below=`, as far as that line knows.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct TriggerState {
+    /// The names of the devices, and the codes of their axes.
+    high: HashSet<(String, u16)>,
+}
+
+impl InputMatch {
+    /// For a line with `above=` and `below=`, takes note of where the event's
+    /// axis is, if it's one the line is about, and returns true if the axis
+    /// has just risen to `above=` (so that the line can fire, if everything
+    /// else about it matches). It can't happen again until the axis has
+    /// fallen to `below=`. Lines without them always return true.
+    pub fn crossed(&self, event: &DeviceEvent, state: &mut TriggerState)
+                   -> bool {
+        let (above, below) = match self.hysteresis {
+            Some(x) => x,
+            None => return true,
+        };
+        if !is_wanted(&self.wants_device, &event.device)
+        || !self.wants_type.matches(event.event.type_)
+        || !self.wants_code.matches(event.event.code) {
+            return false
+        }
+        let axis = (event.device.name().to_owned(), event.event.code);
+        if state.high.contains(&axis) {
+            if event.event.value <= below { state.high.remove(&axis); }
+            false
+        }
+        else if event.event.value >= above {
+            state.high.insert(axis);
+            true
+        }
+        else { false }
+    }
+}
+
+impl Latch {
+    /// Returns true if the given event is a press of this latch's key, which
+    /// turns the latch on or off.
+    pub fn pressed_by(&self, event: &DeviceEvent) -> bool {
+        is_wanted(&self.wants_device, &event.device)
+            && event.event.type_ == EV_KEY
+            && event.event.code == self.wants_code
+            && event.event.value == 1
+    }
+}
+
+impl ProfileSwitch {
+    /// Returns true if the given event is a press of this switch's key,
+    /// which makes its profile the active one.
+    pub fn pressed_by(&self, event: &DeviceEvent) -> bool {
+        is_wanted(&self.wants_device, &event.device)
+            && event.event.type_ == EV_KEY
+            && event.event.code == self.wants_code
+            && event.event.value == 1
+    }
+}
+
+impl AxisMap {
+    /// If this map follows the axis that the given event is about, returns the
+    /// event's value scaled into the map's range. Returns `None` if it's a
+    /// different axis, or if the device didn't tell us the axis's range.
+    pub fn scaled_value(&self, event: &DeviceEvent) -> Option<i32> {
+        match self.wants_device.as_ref() {
+            Some(x) if event.device.name() != x => return None,
+            _ => (),
+        }
+        if event.event.type_ != EV_ABS || event.event.code != self.wants_code {
+            return None
+        }
+        scale_axis(event.event.value, event.range?, self.to)
+    }
+}
+
+impl Calibration {
+    /// If this calibration is for the axis that the given event is about,
+    /// corrects the event's value, replaces its range with the one the axis
+    /// really has, and returns true. Each side of `center` is stretched
+    /// separately, so that the axis reads as centered when it's at rest, and
+    /// still reaches `min` and `max` when pushed all the way.
+    pub fn apply(&self, event: &mut DeviceEvent) -> bool {
+        if !is_wanted(&self.wants_device, &event.device)
+        || event.event.type_ != EV_ABS || event.event.code != self.wants_code {
+            return false
+        }
+        let middle = ((self.min as i64 + self.max as i64) / 2) as i32;
+        let value = event.event.value;
+        let corrected = if value <= self.center {
+            scale_axis(value, (self.min, self.center), (self.min, middle))
+        }
+        else {
+            scale_axis(value, (self.center, self.max), (middle, self.max))
+        };
+        event.event.value = corrected.unwrap_or(value);
+        event.range = Some((self.min, self.max));
+        true
+    }
+}
+
+impl ComboMatch {
+    /// Returns true if the given event pressed one of this combo's keys, and
+    /// now all of them are down. `state` must already have been updated with
+    /// the event.
+    pub fn completed_by(&self, event: &DeviceEvent, state: &InputState)
+                        -> bool {
+        if event.event.type_ != EV_KEY || event.event.value != 1 {
+            return false
+        }
+        let name = event.device.name();
+        self.keys.iter().any(|(device, code)| {
+            *code == event.event.code
+                && device.as_deref().map(|x| x == name).unwrap_or(true)
+        })
+            && self.keys.iter().all(|(device, code)| {
+                state.is_pressed(device.as_deref(), *code)
+            })
+    }
+}
+
+/// How many times one "while-held" line's key has been tapped during the
+/// current hold.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct TapState {
+    /// The taps so far, since the held key went down (or since the line last
+    /// fired).
+    taps: u32,
+}
+
+impl TapMatch {
+    /// Takes note of an event, and returns true if it was the tap that
+    /// brought the count up to `count`. Only presses of the key to tap count,
+    /// and only while the key to hold is down; letting go of that key starts
+    /// the count over, and so does firing. `input` must already have been
+    /// updated with the event.
+    pub fn update(&self, event: &DeviceEvent, input: &InputState,
+                  state: &mut TapState) -> bool {
+        if event.event.type_ != EV_KEY
+        || !is_wanted(&self.wants_device, &event.device) {
+            return false
+        }
+        if !input.is_pressed(self.wants_device.as_deref(), self.held_code) {
+            state.taps = 0;
+            return false
+        }
+        if event.event.code != self.tap_code || event.event.value != 1 {
+            return false
+        }
+        state.taps += 1;
+        if state.taps < self.count { return false }
+        state.taps = 0;
+        true
+    }
+}
+
+/// Where a hat is pointing, as far as one "hat" line knows.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct HatState {
+    /// The X and Y values from the latest events, each -1, 0, or 1.
+    position: (i32, i32),
+    /// The position as of the last `SYN_REPORT`.
+    settled: (i32, i32),
+}
+
+impl HatMatch {
+    /// If the event is about one of this hat's axes, takes note of its value.
+    /// Nothing fires until the device's next `SYN_REPORT`, since moving the
+    /// hat diagonally moves both axes at once.
+    pub fn update(&self, event: &DeviceEvent, state: &mut HatState) {
+        if event.event.type_ != EV_ABS
+        || !is_wanted(&self.wants_device, &event.device) {
+            return
+        }
+        let value = event.event.value.signum();
+        if event.event.code == self.code_x { state.position.0 = value }
+        if event.event.code == self.code_y { state.position.1 = value }
+    }
+    /// Called when `device` sends a `SYN_REPORT`. Returns true if, since the
+    /// last one, the hat started pointing in this line's direction.
+    pub fn settle(&self, device: &DeviceSpec, state: &mut HatState) -> bool {
+        if !is_wanted(&self.wants_device, device)
+        || state.position == state.settled {
+            return false
+        }
+        state.settled = state.position;
+        state.settled == self.direction
+    }
+}
+
+/// Which way an axis is pushed, as far as one "axis" line knows.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct AxisState {
+    /// The direction of the axis on each device it's been seen on, by
+    /// device name: 1 past the threshold above the center, -1 past it below,
+    /// or 0 back near the center.
+    directions: BTreeMap<String, i32>,
+}
+
+impl AxisMatch {
+    /// If the event moves this line's axis past the threshold in a direction
+    /// it wasn't already pushed in, returns the command for that direction
+    /// (if there is one) and the direction's name. The axis has to come back
+    /// to within half the threshold of the center before the same direction
+    /// can fire again, so that a stick wobbling at the threshold doesn't fire
+    /// over and over.
+    pub fn update(&self, event: &DeviceEvent, state: &mut AxisState)
+                  -> Option<(&str, &'static str)> {
+        if event.event.type_ != EV_ABS || event.event.code != self.wants_code
+        || !is_wanted(&self.wants_device, &event.device) {
+            return None
+        }
+        let offset = i64::from(event.event.value) - i64::from(self.center);
+        let threshold = i64::from(self.threshold);
+        let direction = state.directions
+            .entry(event.device.name().to_owned()).or_insert(0);
+        let new_direction = if offset >= threshold { 1 }
+        else if offset <= -threshold { -1 }
+        else if offset.abs() <= threshold / 2 { 0 }
+        else { *direction };
+        if new_direction == *direction { return None }
+        *direction = new_direction;
+        match new_direction {
+            1 => self.positive.as_deref().map(|x| (x, "positive")),
+            -1 => self.negative.as_deref().map(|x| (x, "negative")),
+            _ => None,
+        }
+    }
+}
+
+/// Which bucket an axis is in, as far as one "buckets" line knows.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct BucketState {
+    /// The bucket the axis is in on each device it's been seen on, by device
+    /// name, as an index into the line's buckets (or one past the end, for
+    /// none of them).
+    current: BTreeMap<String, usize>,
+}
+
+impl BucketMatch {
+    /// Returns the index of the first bucket `value` is in, or one past the
+    /// last bucket if it isn't in any of them.
+    fn bucket(&self, value: i32) -> usize {
+        self.buckets.iter().position(|(x, _)| x.matches(value))
+            .unwrap_or(self.buckets.len())
+    }
+    /// If the event moves this line's axis into a different bucket, returns
+    /// the command for that bucket (if there is one) and the bucket's
+    /// number, counting from 1, or `else`. The first value seen on each
+    /// device counts as moving into its bucket. With `hysteresis=N`, the
+    /// value has to be at least `N` into the new bucket before it counts.
+    pub fn update(&self, event: &DeviceEvent, state: &mut BucketState)
+                  -> Option<(&str, String)> {
+        if event.event.type_ != EV_ABS || event.event.code != self.wants_code
+        || !is_wanted(&self.wants_device, &event.device) {
+            return None
+        }
+        let value = event.event.value;
+        let new = self.bucket(value);
+        let name = event.device.name();
+        if let Some(&old) = state.current.get(name) {
+            if old == new
+            || self.bucket(value.saturating_sub(self.hysteresis)) != new
+            || self.bucket(value.saturating_add(self.hysteresis)) != new {
+                return None
+            }
+        }
+        state.current.insert(name.to_owned(), new);
+        match self.buckets.get(new) {
+            Some((_, command)) => Some((command, (new + 1).to_string())),
+            None => self.otherwise.as_deref().map(|x| (x, "else".to_owned())),
+        }
+    }
+}
+
+/// What one "zone" line knows about the touches on its device.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct ZoneState {
+    /// The multitouch slot that `ABS_MT_*` events currently apply to.
+    slot: i32,
+    /// Each slot that has been touched, by number.
+    touches: BTreeMap<i32, Touch>,
+}
+
+/// What a "zone" line knows about one multitouch slot.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+struct Touch {
+    /// The latest X position, in percent of the axis's range, if known.
+    x: Option<i32>,
+    /// The latest Y position, in percent of the axis's range, if known.
+    y: Option<i32>,
+    /// True if a touch landed in this slot since the last `SYN_REPORT`.
+    landed: bool,
+}
+
+impl ZoneMatch {
+    /// Takes note of an event that might be about a touch, on any of the
+    /// device's multitouch slots. A touch lands when its slot gets a new
+    /// tracking ID, or (for single-touch devices) when `BTN_TOUCH` is
+    /// pressed. Positions are scaled using the range the device reports for
+    /// each axis, so they mean the same thing on any device.
+    pub fn update(&self, event: &DeviceEvent, state: &mut ZoneState) {
+        if !is_wanted(&self.wants_device, &event.device) { return }
+        let (type_, code, value) = (event.event.type_, event.event.code,
+                                    event.event.value);
+        if type_ == EV_ABS && code == ABS_MT_SLOT {
+            state.slot = value;
+            return
+        }
+        let touch = state.touches.entry(state.slot).or_default();
+        if (type_ == EV_ABS && code == ABS_MT_TRACKING_ID && value >= 0)
+        || (type_ == EV_KEY && code == BTN_TOUCH && value == 1) {
+            touch.landed = true;
+        }
+        if type_ == EV_ABS {
+            let scaled = event.range
+                .and_then(|range| scale_axis(value, range, (0, 100)));
+            if code == self.code_x { touch.x = scaled }
+            if code == self.code_y { touch.y = scaled }
+        }
+    }
+    /// Called when `device` sends a `SYN_REPORT`. Returns the position, in
+    /// percent, of each touch that landed in this zone since the last one.
+    pub fn settle(&self, device: &DeviceSpec, state: &mut ZoneState)
+                  -> Vec<(i32, i32)> {
+        if !is_wanted(&self.wants_device, device) { return Vec::new() }
+        let mut ret = Vec::new();
+        for touch in state.touches.values_mut() {
+            if !touch.landed { continue }
+            touch.landed = false;
+            if let (Some(x), Some(y)) = (touch.x, touch.y) {
+                if self.x.0 <= x && x <= self.x.1
+                && self.y.0 <= y && y <= self.y.1 {
+                    ret.push((x, y));
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// Where one "region" line's axes are, on each device it's seen them on.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct RegionState {
+    /// What's known about the axes on each device, by device name.
+    devices: BTreeMap<String, Stick>,
+}
+
+/// What a "region" line knows about one device's axes.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+struct Stick {
+    /// The latest X value, if known.
+    x: Option<i32>,
+    /// The latest Y value, if known.
+    y: Option<i32>,
+    /// True if the axes were in the region as of the last `SYN_REPORT`.
+    inside: bool,
+}
+
+/// Returns true if `value` is between the bounds that are given.
+fn within(value: i32, (lo, hi): (Option<i32>, Option<i32>)) -> bool {
+    lo.map(|lo| value >= lo).unwrap_or(true)
+        && hi.map(|hi| value <= hi).unwrap_or(true)
+}
+
+impl RegionMatch {
+    /// If the event is about one of this region's axes, takes note of its
+    /// value. Nothing fires until the device's next `SYN_REPORT`, since a
+    /// stick moving diagonally moves both axes at once.
+    pub fn update(&self, event: &DeviceEvent, state: &mut RegionState) {
+        if event.event.type_ != EV_ABS
+        || !is_wanted(&self.wants_device, &event.device)
+        || (event.event.code != self.code_x
+            && event.event.code != self.code_y) {
+            return
+        }
+        let stick = state.devices
+            .entry(event.device.name().to_owned()).or_default();
+        let value = Some(event.event.value);
+        if event.event.code == self.code_x { stick.x = value }
+        if event.event.code == self.code_y { stick.y = value }
+    }
+    /// Called when `device` sends a `SYN_REPORT`. If, since the last one,
+    /// the axes moved into the region, returns where they are. Leaving the
+    /// region lets it fire again the next time they move into it. Until
+    /// both axes have been seen, they aren't anywhere.
+    pub fn settle(&self, device: &DeviceSpec, state: &mut RegionState)
+                  -> Option<(i32, i32)> {
+        if !is_wanted(&self.wants_device, device) { return None }
+        let stick = state.devices.get_mut(device.name())?;
+        let (x, y) = match (stick.x, stick.y) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return None,
+        };
+        let was_inside = stick.inside;
+        stick.inside = within(x, self.x) && within(y, self.y);
+        if stick.inside && !was_inside { Some((x, y)) } else { None }
+    }
+}
+
+/// The "if" lines that are waiting to fire: "held=" lines waiting to see
+/// whether a key stays down, and "delay=" lines waiting out their delay.
+/// Feed every event to [`update`](#method.update), so that releasing a key
+/// calls off the lines that were waiting on it.
+#[derive(Clone,Default)]
+pub struct DelayedMatches {
+    /// The index of each waiting line, when it's due, the event that started
+    /// it, and whether releasing that event's key calls it off.
+    pending: Vec<(usize, Instant, DeviceEvent, bool)>,
+}
+
+impl DelayedMatches {
+    /// Starts waiting on behalf of the line with the given index, which will
+    /// fire at `due` for `event`. If `cancel_on_release` is true, and the key
+    /// `event` is about is released before then (or, for anything but a key,
+    /// its type and code go back to 0), on the same device, it won't.
+    pub fn start(&mut self, index: usize, event: &DeviceEvent, due: Instant,
+                 cancel_on_release: bool) {
+        self.pending.push((index, due, event.clone(), cancel_on_release));
+    }
+    /// Calls off every line that the given event releases.
+    pub fn update(&mut self, event: &DeviceEvent) {
+        if event.event.value != 0 { return }
+        self.pending.retain(|(_, _, started, cancel_on_release)| {
+            !cancel_on_release
+                || started.event.type_ != event.event.type_
+                || started.event.code != event.event.code
+                || started.device.name() != event.device.name()
+        });
+    }
+    /// Returns when the next line is due, if any are waiting.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.iter().map(|x| x.1).min()
+    }
+    /// Removes and returns every line that's due as of `now`, in the order
+    /// they were started, along with the event that started each one.
+    pub fn take_due(&mut self, now: Instant) -> Vec<(usize, DeviceEvent)> {
+        let mut ret = Vec::new();
+        self.pending.retain(|(index, due, event, _)| {
+            if *due > now { return true }
+            ret.push((*index, event.clone()));
+            false
+        });
+        ret
+    }
+}
+
+/// Scales `value` from the range `from` into the range `to`, rounding to the
+/// nearest whole number. Values outside `from` are clamped. Returns `None` if
+/// `from` is empty.
+pub fn scale_axis(value: i32, from: (i32, i32), to: (i32, i32))
+                  -> Option<i32> {
+    if from.1 <= from.0 { return None }
+    let value = value.max(from.0).min(from.1);
+    let fraction = (value as f64 - from.0 as f64)
+        / (from.1 as f64 - from.0 as f64);
+    Some((to.0 as f64 + fraction * (to.1 as f64 - to.0 as f64)).round()
+         as i32)
+}
+
+/// Returns the first match that wants to fire for the given event, if any.
+/// This doesn't run `when=` commands; if you want to honor them, use
+/// [`all_matches`](fn.all_matches.html) instead.
+pub fn match_event<'a>(event: &DeviceEvent, state: &InputState,
+                       matches: &'a [InputMatch]) -> Option<&'a InputMatch> {
+    all_matches(event, state, matches).next()
+}
+
+/// Returns every match that wants to fire for the given event, in order. The
+/// first one whose `when=` command (if any) succeeds is the one that should
+/// actually fire.
+pub fn all_matches<'a, 'b>(event: &'b DeviceEvent, state: &'b InputState,
+                           matches: &'a [InputMatch])
+                           -> impl Iterator<Item=&'a InputMatch> + 'b
+where 'a: 'b {
+    matches.iter().filter(move |x| x.matches(event, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_config, test_util::{device, event}};
+    use std::sync::Arc;
+
+    #[test]
+    fn hysteresis() {
+        let config = parse_config("test.conf", "\
+            if type=3 code=2 above=200 below=50 then: a\n").unwrap();
+        let rule = &config.matches[0];
+        let (dev, other) = (device(None), device(Some("other")));
+        let mut state = TriggerState::default();
+        let fired: Vec<bool> = [10, 150, 210, 255, 100, 220, 50, 199, 200]
+            .iter().map(|x| rule.crossed(&event(&dev, 3, 2, *x), &mut state))
+            .collect();
+        assert_eq!(fired, [false, false, true, false, false, false, false,
+                           false, true]);
+        // each device's axis is tracked on its own
+        assert!(rule.crossed(&event(&other, 3, 2, 255), &mut state));
+        // and other axes are left alone
+        assert!(!rule.crossed(&event(&dev, 3, 1, 255), &mut state));
+        assert!(!rule.crossed(&event(&dev, 3, 2, 0), &mut state));
+        assert!(rule.crossed(&event(&dev, 3, 2, 255), &mut state));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let config = parse_config("test.conf", "\
+            if type=1 code=30 value=1 then: a\n\
+            if type=1 code=30 value=!2 then: b\n\
+            if type=3 slot=1 then: c\n").unwrap();
+        let dev = device(None);
+        let mut state = InputState::default();
+        let fired = |state: &InputState, event: DeviceEvent| {
+            match_event(&event, state, &config.matches)
+                .map(|x| x.action.to_string())
+        };
+        assert_eq!(fired(&state, event(&dev, 1, 30, 1)).as_deref(),
+                   Some("then: a"));
+        assert_eq!(fired(&state, event(&dev, 1, 30, 0)).as_deref(),
+                   Some("then: b"));
+        assert_eq!(fired(&state, event(&dev, 1, 30, 2)), None);
+        assert_eq!(fired(&state, event(&dev, 1, 31, 1)), None);
+        assert_eq!(fired(&state, event(&dev, 3, 53, 100)), None);
+        state.update(&event(&dev, EV_ABS, ABS_MT_SLOT, 1));
+        assert_eq!(fired(&state, event(&dev, 3, 53, 100)).as_deref(),
+                   Some("then: c"));
+    }
+
+    #[test]
+    fn signs() {
+        let config = parse_config("test.conf", "\
+            if type=2 code=8 value=positive then: up\n\
+            if type=2 code=8 value=negative then: down\n").unwrap();
+        let dev = device(None);
+        let state = InputState::default();
+        let fired = |value| {
+            match_event(&event(&dev, 2, 8, value), &state, &config.matches)
+                .map(|x| x.action.to_string())
+        };
+        assert_eq!(fired(3).as_deref(), Some("then: up"));
+        assert_eq!(fired(-1).as_deref(), Some("then: down"));
+        assert_eq!(fired(0), None);
+    }
+
+    #[test]
+    fn ranges_and_lists() {
+        let config = parse_config("test.conf", "\
+            if type=1 code=2-11 value=1 then: digit\n\
+            if type=1 code=59,60,87-88 value=1 then: fkey\n\
+            if type=3 code=0 value=<=99 then: left\n\
+            if type=3 code=0 value=>=900 then: right\n").unwrap();
+        let dev = device(None);
+        let state = InputState::default();
+        let fired = |kind, code, value| {
+            match_event(&event(&dev, kind, code, value), &state,
+                        &config.matches)
+                .map(|x| x.action.to_string())
+        };
+        assert_eq!(fired(1, 2, 1).as_deref(), Some("then: digit"));
+        assert_eq!(fired(1, 11, 1).as_deref(), Some("then: digit"));
+        assert_eq!(fired(1, 12, 1), None);
+        assert_eq!(fired(1, 60, 1).as_deref(), Some("then: fkey"));
+        assert_eq!(fired(1, 88, 1).as_deref(), Some("then: fkey"));
+        assert_eq!(fired(1, 61, 1), None);
+        assert_eq!(fired(3, 0, -5).as_deref(), Some("then: left"));
+        assert_eq!(fired(3, 0, 900).as_deref(), Some("then: right"));
+        assert_eq!(fired(3, 0, 500), None);
+    }
+
+    #[test]
+    fn device_selectors() {
+        let config = parse_config("test.conf", "\
+            if dev=\"Left Pad\" then: left\n\
+            if dev=/dev/input/event0 then: unlabeled\n").unwrap();
+        let state = InputState::default();
+        let fired = |event: DeviceEvent| {
+            match_event(&event, &state, &config.matches)
+                .map(|x| x.action.to_string())
+        };
+        let left = device(Some("Left Pad"));
+        let right = device(Some("Right Pad"));
+        let unlabeled = device(None);
+        assert_eq!(fired(event(&left, 1, 2, 3)).as_deref(),
+                   Some("then: left"));
+        // a labeled device is only known by its label
+        assert_eq!(fired(event(&right, 1, 2, 3)), None);
+        assert_eq!(fired(event(&unlabeled, 1, 2, 3)).as_deref(),
+                   Some("then: unlabeled"));
+        assert_eq!(event(&left, 1, 2, 3).to_string(),
+                   "dev=\"Left Pad\" type=1 code=2 value=3");
+        assert_eq!(event(&unlabeled, 1, 2, 3).to_string(),
+                   "type=1 code=2 value=3");
+    }
+
+    #[test]
+    fn all_matches_in_order() {
+        let config = parse_config("test.conf", "\
+            if type=1 when=false then: a\n\
+            if type=2 then: b\n\
+            if type=1 then: c\n").unwrap();
+        let state = InputState::default();
+        let dev = device(None);
+        let event = event(&dev, 1, 2, 3);
+        let fired: Vec<String> = all_matches(&event, &state, &config.matches)
+            .map(|x| x.action.to_string()).collect();
+        assert_eq!(fired, ["then: a", "then: c"]);
+    }
+
+    #[test]
+    fn scaling() {
+        assert_eq!(scale_axis(0, (0, 255), (0, 100)), Some(0));
+        assert_eq!(scale_axis(128, (0, 255), (0, 100)), Some(50));
+        assert_eq!(scale_axis(255, (0, 255), (0, 100)), Some(100));
+        assert_eq!(scale_axis(300, (0, 255), (0, 100)), Some(100));
+        assert_eq!(scale_axis(-32768, (-32768, 32767), (100, 0)), Some(100));
+        assert_eq!(scale_axis(5, (3, 3), (0, 100)), None);
+        let config = parse_config("test.conf",
+                                  "map code=1 to=0-10 then: a").unwrap();
+        let dev = device(None);
+        let mut ev = event(&dev, EV_ABS, 1, 50);
+        assert_eq!(config.maps[0].scaled_value(&ev), None);
+        ev.range = Some((0, 100));
+        assert_eq!(config.maps[0].scaled_value(&ev), Some(5));
+        ev.event.code = 2;
+        assert_eq!(config.maps[0].scaled_value(&ev), None);
+    }
+
+    #[test]
+    fn calibration() {
+        let config = parse_config("test.conf",
+                                  "calibrate code=0 center=140 min=10 \
+                                   max=250").unwrap();
+        let calibration = &config.calibrations[0];
+        let dev = device(None);
+        let corrected = |type_, code, value| {
+            let mut ev = event(&dev, type_, code, value);
+            ev.range = Some((0, 255));
+            calibration.apply(&mut ev).then_some((ev.event.value, ev.range))
+        };
+        let range = Some((10, 250));
+        assert_eq!(corrected(EV_ABS, 0, 140), Some((130, range)));
+        assert_eq!(corrected(EV_ABS, 0, 10), Some((10, range)));
+        assert_eq!(corrected(EV_ABS, 0, 250), Some((250, range)));
+        assert_eq!(corrected(EV_ABS, 0, 75), Some((70, range)));
+        assert_eq!(corrected(EV_ABS, 0, 0), Some((10, range)));
+        assert_eq!(corrected(EV_ABS, 1, 140), None);
+        assert_eq!(corrected(EV_KEY, 0, 140), None);
+    }
+
+    #[test]
+    fn with_held() {
+        let config = parse_config("test.conf",
+                                  "if code=30 value=1 with-held=42 then: a")
+            .unwrap();
+        let rule = &config.matches[0];
+        let left = device(Some("left"));
+        let right = device(Some("right"));
+        let mut state = InputState::default();
+        let mut press = |event: DeviceEvent| {
+            state.update(&event);
+            rule.matches(&event, &state)
+        };
+        assert!(!press(event(&left, EV_KEY, 30, 1)));
+        assert!(!press(event(&left, EV_KEY, 42, 1)));
+        assert!(press(event(&left, EV_KEY, 30, 1)));
+        // held on a different device
+        assert!(!press(event(&right, EV_KEY, 30, 1)));
+        assert!(!press(event(&left, EV_KEY, 42, 0)));
+        assert!(!press(event(&left, EV_KEY, 30, 1)));
+        // a key we only saw repeating counts as held
+        assert!(!press(event(&left, EV_KEY, 42, 2)));
+        assert!(press(event(&left, EV_KEY, 30, 1)));
+    }
+
+    #[test]
+    fn latches() {
+        let config = parse_config("test.conf", "\
+            latch shift code=42\n\
+            latch shift dev=right code=54\n\
+            if code=30 value=1 with-latch=shift then: a").unwrap();
+        let rule = &config.matches[0];
+        let left = device(Some("left"));
+        let right = device(Some("right"));
+        let mut state = InputState::default();
+        let mut press = |event: DeviceEvent| {
+            state.update(&event);
+            for latch in config.latches.iter() {
+                if latch.pressed_by(&event) {
+                    state.toggle_latch(&latch.name);
+                }
+            }
+            rule.matches(&event, &state)
+        };
+        assert!(!press(event(&left, EV_KEY, 30, 1)));
+        // a latch stays on after its key is released
+        assert!(!press(event(&left, EV_KEY, 42, 1)));
+        assert!(!press(event(&left, EV_KEY, 42, 0)));
+        assert!(press(event(&left, EV_KEY, 30, 1)));
+        assert!(press(event(&right, EV_KEY, 30, 1)));
+        // repeats don't count as presses
+        assert!(!press(event(&left, EV_KEY, 42, 2)));
+        assert!(press(event(&left, EV_KEY, 30, 1)));
+        // only on the right device
+        assert!(!press(event(&left, EV_KEY, 54, 1)));
+        assert!(press(event(&left, EV_KEY, 30, 1)));
+        assert!(!press(event(&right, EV_KEY, 54, 1)));
+        assert!(!press(event(&left, EV_KEY, 30, 1)));
+    }
+
+    #[test]
+    fn profiles() {
+        let config = parse_config("test.conf", "\
+            profile racing {\n\
+                if code=30 value=1 then: boost\n\
+            }\n\
+            profile flying {\n\
+                if code=30 value=1 then: climb\n\
+                unless code=30 then: level\n\
+            }\n\
+            if code=30 value=1 then: other\n\
+            switch-profile flying code=59\n").unwrap();
+        let dev = device(None);
+        let mut state = InputState::default();
+        let fired = |state: &InputState, event: DeviceEvent| {
+            all_matches(&event, state, &config.matches)
+                .map(|x| x.action.to_string()).collect::<Vec<_>>()
+        };
+        // with no profile active, only the lines in no profile can fire
+        assert_eq!(fired(&state, event(&dev, EV_KEY, 30, 1)), ["then: other"]);
+        state.profile = Some("racing".to_owned());
+        assert_eq!(fired(&state, event(&dev, EV_KEY, 30, 1)),
+                   ["then: boost", "then: other"]);
+        assert_eq!(fired(&state, event(&dev, EV_KEY, 31, 1)),
+                   Vec::<String>::new());
+        let switch = &config.profile_switches[0];
+        assert!(switch.pressed_by(&event(&dev, EV_KEY, 59, 1)));
+        assert!(!switch.pressed_by(&event(&dev, EV_KEY, 59, 0)));
+        state.profile = Some(switch.name.clone());
+        assert_eq!(fired(&state, event(&dev, EV_KEY, 30, 1)),
+                   ["then: climb", "then: other"]);
+        assert_eq!(fired(&state, event(&dev, EV_KEY, 31, 1)),
+                   ["then: level"]);
+    }
+
+    #[test]
+    fn repeats() {
+        let config = parse_config("test.conf", "\
+            if type=1 code=30 value=repeat then: again\n\
+            if type=1 code=30 value=1 then: press\n").unwrap();
+        let dev = device(None);
+        let state = InputState::default();
+        let fired = |value| {
+            match_event(&event(&dev, EV_KEY, 30, value), &state,
+                        &config.matches)
+                .map(|x| x.action.to_string())
+        };
+        assert_eq!(fired(1).as_deref(), Some("then: press"));
+        assert_eq!(fired(2).as_deref(), Some("then: again"));
+        assert_eq!(fired(0), None);
+    }
+
+    #[test]
+    fn delayed_matches() {
+        let config = parse_config("test.conf",
+                                  "if type=1 code=30 held=500 then: long\n")
+            .unwrap();
+        let rule = &config.matches[0];
+        let left = device(Some("left"));
+        let right = device(Some("right"));
+        let state = InputState::default();
+        assert!(rule.matches(&event(&left, EV_KEY, 30, 1), &state));
+        assert!(!rule.matches(&event(&left, EV_KEY, 30, 2), &state));
+        assert!(!rule.matches(&event(&left, EV_KEY, 30, 0), &state));
+        let start = Instant::now();
+        let due = start + std::time::Duration::from_millis(500);
+        let mut delayed = DelayedMatches::default();
+        assert_eq!(delayed.next_due(), None);
+        delayed.start(0, &event(&left, EV_KEY, 30, 1), due, true);
+        delayed.start(0, &event(&right, EV_KEY, 30, 1), due, true);
+        delayed.start(1, &event(&left, EV_KEY, 30, 1), due, false);
+        delayed.start(2, &event(&left, EV_ABS, 30, 50), due, true);
+        assert_eq!(delayed.next_due(), Some(due));
+        assert!(delayed.take_due(start).is_empty());
+        // releasing on one device doesn't call off the other, and only
+        // the lines that asked are called off
+        delayed.update(&event(&left, EV_KEY, 30, 0));
+        delayed.update(&event(&right, EV_KEY, 31, 0));
+        delayed.update(&event(&right, EV_KEY, 30, 2));
+        let fired = delayed.take_due(due);
+        let fired: Vec<(usize, &str)> = fired.iter()
+            .map(|(index, event)| (*index, event.device.name())).collect();
+        assert_eq!(fired, [(0, "right"), (1, "left"), (2, "left")]);
+        assert_eq!(delayed.next_due(), None);
+    }
+
+    #[test]
+    fn combos_across_devices() {
+        let config = parse_config("test.conf",
+                                  "combo dev=left code=1 dev=right code=1 \
+                                   then: a").unwrap();
+        let combo = &config.combos[0];
+        let left = device(Some("left"));
+        let right = device(Some("right"));
+        let mut state = InputState::default();
+        let mut press = |event: DeviceEvent| {
+            state.update(&event);
+            combo.completed_by(&event, &state)
+        };
+        assert!(!press(event(&left, EV_KEY, 1, 1)));
+        // a key that isn't part of the combo
+        assert!(!press(event(&left, EV_KEY, 2, 1)));
+        assert!(press(event(&right, EV_KEY, 1, 1)));
+        // repeats don't fire again
+        assert!(!press(event(&right, EV_KEY, 1, 2)));
+        assert!(!press(event(&left, EV_KEY, 1, 0)));
+        assert!(!press(event(&right, EV_KEY, 2, 1)));
+        assert!(press(event(&left, EV_KEY, 1, 1)));
+    }
+
+    #[test]
+    fn taps_while_held() {
+        let config = parse_config("test.conf",
+                                  "while-held code=314 taps code=304 count=2 \
+                                   then: a").unwrap();
+        let taps = &config.taps[0];
+        let dev = device(None);
+        let mut input = InputState::default();
+        let mut state = TapState::default();
+        let mut key = |code: u16, value: i32| {
+            let event = event(&dev, EV_KEY, code, value);
+            input.update(&event);
+            taps.update(&event, &input, &mut state)
+        };
+        // taps before the hold don't count
+        assert!(!key(304, 1));
+        assert!(!key(304, 0));
+        assert!(!key(314, 1));
+        assert!(!key(304, 1));
+        // nor do repeats
+        assert!(!key(304, 2));
+        assert!(!key(304, 0));
+        assert!(key(304, 1));
+        assert!(!key(304, 0));
+        // firing starts the count over
+        assert!(!key(304, 1));
+        assert!(!key(304, 0));
+        // and so does letting go
+        assert!(!key(314, 0));
+        assert!(!key(314, 1));
+        assert!(!key(304, 1));
+        assert!(!key(304, 0));
+        assert!(key(304, 1));
+    }
+
+    #[test]
+    fn hats() {
+        let config = parse_config("test.conf", "\
+            hat codeX=16 codeY=17 up-left then: a\n\
+            hat codeX=16 codeY=17 center then: b\n").unwrap();
+        let dev = device(None);
+        let mut states = [HatState::default(); 2];
+        let mut report = |events: &[(u16, i32)]| -> Vec<usize> {
+            for (code, value) in events.iter() {
+                for (hat, state) in config.hats.iter().zip(states.iter_mut()) {
+                    hat.update(&event(&dev, EV_ABS, *code, *value), state);
+                }
+            }
+            config.hats.iter().zip(states.iter_mut()).enumerate()
+                .filter_map(|(i, (hat, state))| {
+                    if hat.settle(&dev, state) { Some(i) } else { None }
+                }).collect()
+        };
+        // straight to the diagonal, without passing through "left"
+        assert_eq!(report(&[(16, -1), (17, -1)]), [0]);
+        // nothing changed
+        assert_eq!(report(&[]), [] as [usize; 0]);
+        assert_eq!(report(&[(17, 0)]), [] as [usize; 0]);
+        assert_eq!(report(&[(16, 0), (5, 1)]), [1]);
+    }
+
+    #[test]
+    fn axes() {
+        let config = parse_config("test.conf",
+                                  "axis type=3 code=1 threshold=8000 \
+                                   then-pos: down then-neg: up").unwrap();
+        let axis = &config.axes[0];
+        let dev = device(None);
+        let mut state = AxisState::default();
+        let mut push = |value| {
+            axis.update(&event(&dev, EV_ABS, 1, value), &mut state)
+                .map(|(command, name)| (command.to_owned(), name))
+        };
+        let down = Some(("down".to_owned(), "positive"));
+        let up = Some(("up".to_owned(), "negative"));
+        assert_eq!(push(7999), None);
+        assert_eq!(push(8000), down);
+        // wobbling around the threshold doesn't fire again
+        assert_eq!(push(5000), None);
+        assert_eq!(push(9000), None);
+        // back through the center, and all the way to the other side
+        assert_eq!(push(-32000), up);
+        assert_eq!(push(-5000), None);
+        assert_eq!(push(-8000), None);
+        assert_eq!(push(0), None);
+        assert_eq!(push(-8000), up);
+        // a different axis
+        assert_eq!(axis.update(&event(&dev, EV_ABS, 0, 32000), &mut state),
+                   None);
+    }
+
+    #[test]
+    fn regions() {
+        let config = parse_config("test.conf",
+                                  "region codeX=0 codeY=1 x>=20000 \
+                                   y>=20000 then: a").unwrap();
+        let region = &config.regions[0];
+        let (left, right) = (device(Some("left")), device(Some("right")));
+        let mut state = RegionState::default();
+        let mut report = |dev: &Arc<DeviceSpec>,
+                          events: &[(u16, i32)]| {
+            for (code, value) in events.iter() {
+                region.update(&event(dev, EV_ABS, *code, *value), &mut state);
+            }
+            region.settle(dev, &mut state)
+        };
+        // only one axis is known so far
+        assert_eq!(report(&left, &[(0, 30000)]), None);
+        assert_eq!(report(&left, &[(1, 25000)]), Some((30000, 25000)));
+        // moving around inside doesn't fire again
+        assert_eq!(report(&left, &[(0, 32000), (1, 32000)]), None);
+        // each device's stick is followed on its own
+        assert_eq!(report(&right, &[(1, 32000)]), None);
+        assert_eq!(report(&left, &[(1, 0), (5, 1)]), None);
+        assert_eq!(report(&left, &[(1, 20000)]), Some((32000, 20000)));
+        assert_eq!(report(&right, &[(0, 20000)]), Some((20000, 32000)));
+    }
+
+    #[test]
+    fn buckets() {
+        let config = parse_config("test.conf",
+                                  "buckets code=5 hysteresis=100 \
+                                   [<-16000: low] [>16000: high] \
+                                   [else: mid]").unwrap();
+        let buckets = &config.buckets[0];
+        let dev = device(None);
+        let mut state = BucketState::default();
+        let mut move_to = |value| {
+            buckets.update(&event(&dev, EV_ABS, 5, value), &mut state)
+                .map(|(command, name)| (command.to_owned(), name))
+        };
+        let bucket = |command: &str, name: &str| {
+            Some((command.to_owned(), name.to_owned()))
+        };
+        assert_eq!(move_to(0), bucket("mid", "else"));
+        assert_eq!(move_to(5000), None);
+        // not far enough past the edge yet
+        assert_eq!(move_to(16050), None);
+        assert_eq!(move_to(16101), bucket("high", "2"));
+        assert_eq!(move_to(15950), None);
+        assert_eq!(move_to(-17000), bucket("low", "1"));
+        assert_eq!(move_to(-32768), None);
+        assert_eq!(move_to(-15000), bucket("mid", "else"));
+    }
+
+    #[test]
+    fn zones() {
+        let config = parse_config("test.conf",
+                                  "zone codeX=53 codeY=54 x=0-20 y=0-20 \
+                                   then: a").unwrap();
+        let zone = &config.zones[0];
+        let dev = device(None);
+        let mut state = ZoneState::default();
+        let mut report = |events: &[(u16, u16, i32)]| {
+            for (type_, code, value) in events.iter() {
+                let mut ev = event(&dev, *type_, *code, *value);
+                ev.range = Some((0, 1000));
+                zone.update(&ev, &mut state);
+            }
+            zone.settle(&dev, &mut state)
+        };
+        assert_eq!(report(&[(EV_ABS, ABS_MT_TRACKING_ID, 7),
+                            (EV_ABS, 53, 100), (EV_ABS, 54, 150)]),
+                   [(10, 15)]);
+        // moving around doesn't count as landing again
+        assert_eq!(report(&[(EV_ABS, 53, 110)]), []);
+        // a second finger in another slot, outside the zone
+        assert_eq!(report(&[(EV_ABS, ABS_MT_SLOT, 1),
+                            (EV_ABS, ABS_MT_TRACKING_ID, 8),
+                            (EV_ABS, 53, 900), (EV_ABS, 54, 900)]), []);
+        // lifting the first and putting it back down
+        assert_eq!(report(&[(EV_ABS, ABS_MT_SLOT, 0),
+                            (EV_ABS, ABS_MT_TRACKING_ID, -1)]), []);
+        assert_eq!(report(&[(EV_ABS, ABS_MT_TRACKING_ID, 9)]), [(11, 15)]);
+    }
+
+    #[test]
+    fn unless_inverts() {
+        let config = parse_config("test.conf", "\
+            unless type=1 code=30 then: not-a\n\
+            if then: a\n").unwrap();
+        let state = InputState::default();
+        let dev = device(None);
+        let fired = |event: DeviceEvent| {
+            match_event(&event, &state, &config.matches)
+                .map(|x| x.action.to_string())
+        };
+        assert_eq!(fired(event(&dev, 1, 30, 1)).as_deref(), Some("then: a"));
+        assert_eq!(fired(event(&dev, 1, 31, 1)).as_deref(),
+                   Some("then: not-a"));
+        assert_eq!(fired(event(&dev, 3, 30, 1)).as_deref(),
+                   Some("then: not-a"));
+    }
+}
@@ -0,0 +1,455 @@
+//! The queue that carries events from the reader threads to whoever is
+//! matching them, along with any other news the main loop has to hear about.
+
+use std::{
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{
+            channel, sync_channel, Receiver, RecvError, RecvTimeoutError,
+            Sender, SyncSender, TrySendError,
+        },
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{DeviceEvent, DeviceSpec, QueuePolicy};
+
+/// Something that came through an event queue.
+#[derive(Clone)]
+pub enum Message {
+    /// An event from a device.
+    Event(DeviceEvent),
+    /// A device finished reporting a group of events that happened at the
+    /// same moment (with a `SYN_REPORT`), so things that depend on several
+    /// of them at once (like both axes of a hat) can be looked at now.
+    Sync(Arc<DeviceSpec>),
+    /// A signal was received. (See
+    /// [`forward_signals`](fn.forward_signals.html).)
+    Signal(i32),
+    /// The reader for a device has stopped for good, and won't send any more
+    /// events.
+    Finished {
+        /// Every "dev" line that named the device, as in
+        /// [`DeviceHandle::devices`](struct.DeviceHandle.html#method.devices).
+        devices: Vec<Arc<DeviceSpec>>,
+        /// True if the reader stopped because it reached the end of the
+        /// device (which is only likely if it's really a file or a pipe).
+        eof: bool,
+    },
+    /// An event device appeared in `/dev/input` (or its permissions changed,
+    /// which might mean we can open it now). (See
+    /// [`watch_devices`](fn.watch_devices.html).)
+    DeviceAppeared(String),
+}
+
+/// A limit on how many of one device's events can be waiting in the queue,
+/// shared by the device's `EventSender` and the `EventReceiver`.
+struct DeviceLimit {
+    capacity: usize,
+    policy: QueuePolicy,
+    /// How many of the device's events are in the queue, and how many of
+    /// those (the oldest ones) are to be thrown away when they come out,
+    /// because `DropOldest` made room for newer ones.
+    state: Mutex<(usize, usize)>,
+    /// Notified whenever one of the device's events comes out of the queue.
+    room: Condvar,
+}
+
+/// How many messages are in the queue, shared by all the `EventSender`s and
+/// the `EventReceiver`.
+#[derive(Debug,Default)]
+struct Depth {
+    /// How many messages are waiting. A message is counted just before it
+    /// goes in, so this is never less than the real number, but can be more
+    /// for a moment.
+    waiting: AtomicUsize,
+    /// The most messages that have ever been waiting at once.
+    peak: AtomicUsize,
+}
+
+impl Depth {
+    /// Counts a message that's about to go into the queue.
+    fn add(&self) {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Called once a message that was counted is in the queue.
+    fn added(&self) {
+        let waiting = self.waiting.load(Ordering::Relaxed);
+        self.peak.fetch_max(waiting, Ordering::Relaxed);
+    }
+    /// Uncounts a message that came out of the queue (or never got in).
+    fn remove(&self) {
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+    /// Called once a message that was counted is in the queue, if `sent`,
+    /// or didn't get in, if not.
+    fn finish(&self, sent: bool) {
+        if sent { self.added() } else { self.remove() }
+    }
+}
+
+/// A message, and the limit it counts against, if any.
+struct Envelope {
+    message: Message,
+    limit: Option<Arc<DeviceLimit>>,
+}
+
+/// The two kinds of channel an event queue can be built on.
+#[derive(Clone)]
+enum Inner {
+    Unbounded(Sender<Envelope>),
+    Bounded(SyncSender<Envelope>),
+}
+
+/// The sending end of an event queue. Each reader thread gets its own clone.
+#[derive(Clone)]
+pub struct EventSender {
+    inner: Inner,
+    /// How many events have been dropped because the queue was full, ever.
+    dropped: Arc<AtomicUsize>,
+    /// How many messages are in the queue.
+    depth: Arc<Depth>,
+    /// If not `None`, the limit on this sender's own events. (See
+    /// [`limited`](#method.limited).)
+    limit: Option<Arc<DeviceLimit>>,
+}
+
+/// The receiving end of an event queue.
+pub struct EventReceiver {
+    inner: Receiver<Envelope>,
+    /// Shared with all the `EventSender`s.
+    dropped: Arc<AtomicUsize>,
+    /// Shared with all the `EventSender`s.
+    depth: Arc<Depth>,
+}
+
+/// Makes a new event queue. If `capacity` is `None`, the queue can grow
+/// without limit. Otherwise, it holds at most that many events, and events
+/// that arrive while it's full are dropped.
+pub fn event_queue(capacity: Option<usize>) -> (EventSender, EventReceiver) {
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let depth = Arc::new(Depth::default());
+    let (inner, receiver) = match capacity {
+        None => {
+            let (tx, rx) = channel();
+            (Inner::Unbounded(tx), rx)
+        },
+        Some(capacity) => {
+            let (tx, rx) = sync_channel(capacity);
+            (Inner::Bounded(tx), rx)
+        },
+    };
+    (EventSender {
+        inner, dropped: dropped.clone(), depth: depth.clone(), limit: None,
+     },
+     EventReceiver { inner: receiver, dropped, depth })
+}
+
+/// How an attempt to put an event into the channel went.
+enum Sent {
+    Yes,
+    Full,
+    Disconnected,
+}
+
+impl EventSender {
+    /// Returns a sender for one device's events, which lets at most
+    /// `capacity` of them wait in the queue at once (on top of any limit on
+    /// the queue as a whole), and handles any more according to `policy`.
+    /// Dropped events are counted along with the rest.
+    pub fn limited(&self, capacity: usize, policy: QueuePolicy)
+                   -> EventSender {
+        EventSender {
+            inner: self.inner.clone(),
+            dropped: self.dropped.clone(),
+            depth: self.depth.clone(),
+            limit: Some(Arc::new(DeviceLimit {
+                capacity, policy,
+                state: Mutex::new((0, 0)),
+                room: Condvar::new(),
+            })),
+        }
+    }
+    /// Puts a message into the channel without waiting.
+    fn try_send(&self, message: Message) -> Sent {
+        let envelope = Envelope { message, limit: self.limit.clone() };
+        self.depth.add();
+        let sent = match &self.inner {
+            Inner::Unbounded(x) => match x.send(envelope) {
+                Ok(()) => Sent::Yes,
+                Err(_) => Sent::Disconnected,
+            },
+            Inner::Bounded(x) => match x.try_send(envelope) {
+                Ok(()) => Sent::Yes,
+                Err(TrySendError::Full(_)) => Sent::Full,
+                Err(TrySendError::Disconnected(_)) => Sent::Disconnected,
+            },
+        };
+        self.depth.finish(matches!(sent, Sent::Yes));
+        sent
+    }
+    /// Puts an event into the queue, or drops it if the queue is full (or,
+    /// for a [`limited`](#method.limited) sender, handles it as the device's
+    /// policy says, if the device's share of the queue is full). Returns
+    /// `false` if nobody is listening anymore.
+    pub fn send(&self, event: DeviceEvent) -> bool {
+        let event = Message::Event(event);
+        let limit = match self.limit.as_ref() {
+            None => match self.try_send(event) {
+                Sent::Yes => return true,
+                Sent::Full => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return true
+                },
+                Sent::Disconnected => return false,
+            },
+            Some(x) => x,
+        };
+        let mut state = limit.state.lock().unwrap();
+        let mut make_room = false;
+        while state.0 - state.1 >= limit.capacity {
+            match limit.policy {
+                QueuePolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return true
+                },
+                QueuePolicy::DropOldest => {
+                    make_room = true;
+                    break
+                },
+                QueuePolicy::Block => {
+                    state = limit.room.wait(state).unwrap();
+                },
+            }
+        }
+        match self.try_send(event) {
+            Sent::Yes => {
+                state.0 += 1;
+                if make_room { state.1 += 1 }
+                true
+            },
+            Sent::Full => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            Sent::Disconnected => false,
+        }
+    }
+    /// Puts a `Message::Sync` into the queue, or drops it if the queue is
+    /// full. Unlike events, dropped syncs aren't counted, since the next one
+    /// will do just as well. Returns `false` if nobody is listening anymore.
+    pub fn send_sync(&self, device: Arc<DeviceSpec>) -> bool {
+        // syncs don't count against a device's limit
+        let envelope = Envelope {
+            message: Message::Sync(device), limit: None,
+        };
+        self.depth.add();
+        let result = match &self.inner {
+            Inner::Unbounded(x) => x.send(envelope).map_err(|_| true),
+            Inner::Bounded(x) => x.try_send(envelope).map_err(|x| {
+                matches!(x, TrySendError::Disconnected(_))
+            }),
+        };
+        self.depth.finish(result.is_ok());
+        // a sync that didn't fit is fine, as long as someone's listening
+        result != Err(true)
+    }
+    /// Puts some other message into the queue, waiting for room if the queue
+    /// is full. (Only events are ever dropped.) Returns `false` if nobody is
+    /// listening anymore.
+    pub fn send_message(&self, message: Message) -> bool {
+        let envelope = Envelope { message, limit: None };
+        self.depth.add();
+        let sent = match &self.inner {
+            Inner::Unbounded(x) => x.send(envelope).is_ok(),
+            Inner::Bounded(x) => x.send(envelope).is_ok(),
+        };
+        self.depth.finish(sent);
+        sent
+    }
+}
+
+impl EventReceiver {
+    /// Takes a message out of its envelope, making room for another event
+    /// from its device if it counted against a limit. Returns `None` if it's
+    /// an event that `DropOldest` has since thrown away.
+    fn open(&self, envelope: Envelope) -> Option<Message> {
+        self.depth.remove();
+        let limit = match envelope.limit {
+            None => return Some(envelope.message),
+            Some(x) => x,
+        };
+        let mut state = limit.state.lock().unwrap();
+        state.0 -= 1;
+        let skip = state.1 > 0;
+        if skip {
+            state.1 -= 1;
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        limit.room.notify_all();
+        if skip { None } else { Some(envelope.message) }
+    }
+    /// Waits for the next message. Fails once all the senders are gone and
+    /// the queue is empty.
+    pub fn recv(&self) -> Result<Message, RecvError> {
+        loop {
+            if let Some(x) = self.open(self.inner.recv()?) { return Ok(x) }
+        }
+    }
+    /// Waits for the next message, but not longer than `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration)
+                        -> Result<Message, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if let Some(x) = self.open(self.inner.recv_timeout(timeout)?) {
+                return Ok(x)
+            }
+        }
+    }
+    /// Waits for the next message, but only until `deadline` (if there is
+    /// one). Returns `Ok(None)` if the deadline passed first. Fails once all
+    /// the senders are gone and the queue is empty.
+    pub fn recv_until(&self, deadline: Option<Instant>)
+                      -> Result<Option<Message>, RecvError> {
+        let deadline = match deadline {
+            None => return self.recv().map(Some),
+            Some(x) => x,
+        };
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.recv_timeout(timeout) {
+            Ok(x) => Ok(Some(x)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+        }
+    }
+    /// Returns how many events have been dropped because the queue was full,
+    /// since the queue was made.
+    pub fn total_dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+    /// Returns about how many messages (events and everything else) are
+    /// waiting in the queue right now, and the most there have ever been at
+    /// once.
+    pub fn depth(&self) -> (usize, usize) {
+        (self.depth.waiting.load(Ordering::Relaxed),
+         self.depth.peak.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn event() -> DeviceEvent {
+        test_util::event(&test_util::device(None), 1, 2, 3)
+    }
+
+    #[test]
+    fn bounded_drops() {
+        let (tx, rx) = event_queue(Some(2));
+        for _ in 0 .. 5 { assert!(tx.send(event())) }
+        assert_eq!(rx.total_dropped(), 3);
+        assert!(rx.recv().is_ok());
+        assert!(tx.send(event()));
+        assert_eq!(rx.total_dropped(), 3);
+        assert!(matches!(rx.recv(), Ok(Message::Event(_))));
+        assert!(tx.send_message(Message::Signal(1)));
+        // full again, but only events get dropped
+        assert!(tx.send(event()));
+        assert_eq!(rx.total_dropped(), 4);
+        assert!(matches!(rx.recv(), Ok(Message::Event(_))));
+        assert!(matches!(rx.recv(), Ok(Message::Signal(1))));
+        drop(tx);
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn device_limits() {
+        let device = test_util::device(None);
+        let (tx, rx) = event_queue(None);
+        let newest = tx.limited(2, QueuePolicy::DropNewest);
+        let oldest = tx.limited(2, QueuePolicy::DropOldest);
+        for value in 0 .. 4 {
+            assert!(newest.send(test_util::event(&device, 1, 1, value)));
+            assert!(oldest.send(test_util::event(&device, 1, 2, value)));
+        }
+        // other messages don't count against a device's share
+        assert!(newest.send_sync(device.clone()));
+        assert_eq!(rx.total_dropped(), 2);
+        let got: Vec<(u16, i32)> = (0 .. 4).map(|_| match rx.recv() {
+            Ok(Message::Event(x)) => (x.event.code, x.event.value),
+            _ => panic!("expected an event"),
+        }).collect();
+        assert_eq!(got, [(1, 0), (1, 1), (2, 2), (2, 3)]);
+        assert_eq!(rx.total_dropped(), 4);
+        assert!(matches!(rx.recv(), Ok(Message::Sync(_))));
+        // with room again, nothing is dropped
+        assert!(oldest.send(test_util::event(&device, 1, 2, 4)));
+        assert!(matches!(rx.recv(), Ok(Message::Event(x))
+                         if x.event.value == 4));
+        // a blocked sender waits until the receiver makes room
+        let block = tx.limited(1, QueuePolicy::Block);
+        let thread_device = device.clone();
+        let sender = std::thread::spawn(move || {
+            for value in 0 .. 3 {
+                assert!(block.send(test_util::event(&thread_device, 1, 3,
+                                                    value)));
+            }
+        });
+        for value in 0 .. 3 {
+            assert!(matches!(rx.recv(), Ok(Message::Event(x))
+                             if x.event.value == value));
+        }
+        sender.join().unwrap();
+        assert_eq!(rx.total_dropped(), 4);
+    }
+
+    #[test]
+    fn depth() {
+        let (tx, rx) = event_queue(Some(3));
+        assert_eq!(rx.depth(), (0, 0));
+        for _ in 0 .. 5 { assert!(tx.send(event())) }
+        assert!(tx.send_sync(test_util::device(None)));
+        assert_eq!(rx.depth(), (3, 3));
+        assert!(rx.recv().is_ok());
+        assert!(rx.recv().is_ok());
+        assert!(tx.send_message(Message::Signal(1)));
+        assert_eq!(rx.depth(), (2, 3));
+        // events that DropOldest throws away come out of the queue too
+        let oldest = tx.limited(1, QueuePolicy::DropOldest);
+        assert!(oldest.send(event()));
+        assert!(matches!(rx.recv(), Ok(Message::Event(_))));
+        assert!(matches!(rx.recv(), Ok(Message::Signal(1))));
+        assert!(oldest.send(event()));
+        assert!(matches!(rx.recv(), Ok(Message::Event(_))));
+        assert_eq!(rx.depth(), (0, 3));
+        drop(rx);
+        assert!(!tx.send(event()));
+    }
+
+    #[test]
+    fn recv_until_deadline() {
+        let (tx, rx) = event_queue(None);
+        let soon = Instant::now() + Duration::from_millis(10);
+        assert!(matches!(rx.recv_until(Some(soon)), Ok(None)));
+        assert!(Instant::now() >= soon);
+        assert!(tx.send(event()));
+        assert!(matches!(rx.recv_until(Some(soon)), Ok(Some(_))));
+        assert!(tx.send(event()));
+        assert!(matches!(rx.recv_until(None), Ok(Some(_))));
+        drop(tx);
+        assert!(rx.recv_until(Some(soon)).is_err());
+    }
+
+    #[test]
+    fn unbounded_never_drops() {
+        let (tx, rx) = event_queue(None);
+        for _ in 0 .. 100 { assert!(tx.send(event())) }
+        assert_eq!(rx.total_dropped(), 0);
+        drop(rx);
+        assert!(!tx.send(event()));
+    }
+}
@@ -0,0 +1,114 @@
+//! Noticing when keys are pressed one after another.
+
+use std::{
+    collections::VecDeque,
+    time::Duration,
+};
+
+use crate::{
+    DeviceEvent, SequenceMatch,
+    codes::EV_KEY,
+    dedup::timestamp,
+};
+
+/// Follows the progress of one "sequence" directive through the key presses
+/// that arrive.
+#[derive(Clone,Debug,Default)]
+pub struct SequenceState {
+    /// The most recent presses, as many as the sequence is long, oldest
+    /// first, each with the time it happened.
+    recent: VecDeque<(u16, Duration)>,
+}
+
+impl SequenceState {
+    /// Makes a new `SequenceState`, with none of the keys pressed.
+    pub fn new() -> SequenceState {
+        SequenceState::default()
+    }
+    /// Takes note of an event, and returns `true` if it finished `sequence`.
+    /// Only presses count; releases and repeats are ignored. Pressing the
+    /// wrong key, or taking too long, starts the sequence over. Uses the
+    /// timestamps the kernel put on the events, so time spent waiting in the
+    /// queue doesn't matter.
+    pub fn advance(&mut self, sequence: &SequenceMatch, event: &DeviceEvent)
+                   -> bool {
+        if event.event.type_ != EV_KEY || event.event.value != 1 {
+            return false
+        }
+        if let Some(wanted) = sequence.wants_device.as_ref() {
+            if event.device.name() != wanted { return false }
+        }
+        let now = timestamp(&event.event);
+        self.recent.push_back((event.event.code, now));
+        while self.recent.len() > sequence.codes.len() {
+            self.recent.pop_front();
+        }
+        let finished = self.recent.len() == sequence.codes.len()
+            && self.recent.iter().map(|x| x.0).eq(sequence.codes.iter()
+                                                   .copied())
+            && now.saturating_sub(self.recent[0].1) <= sequence.within;
+        if finished { self.recent.clear() }
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::{Action, CommandOptions, DeviceSpec, Location};
+    use crate::test_util::{device, event};
+
+    /// Makes an event at the given number of milliseconds.
+    fn event_at(device: &Arc<DeviceSpec>, code: u16, value: i32, millis: i64)
+                -> DeviceEvent {
+        let mut ret = event(device, EV_KEY, code, value);
+        ret.event.time.tv_sec = millis / 1000;
+        ret.event.time.tv_usec = (millis % 1000) * 1000;
+        ret
+    }
+
+    fn sequence(codes: &[u16]) -> SequenceMatch {
+        SequenceMatch {
+            wants_device: None, codes: codes.to_vec(),
+            within: Duration::from_millis(400),
+            options: CommandOptions::default(),
+            action: Action::Shell("a".to_owned()),
+            location: Location { file: "test.conf".to_owned(), line: 1 },
+        }
+    }
+
+    #[test]
+    fn presses_in_order() {
+        let dev = device(None);
+        let seq = sequence(&[30, 48]);
+        let mut state = SequenceState::new();
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 1, 1000)));
+        // releases and repeats don't get in the way
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 2, 1050)));
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 0, 1100)));
+        assert!(state.advance(&seq, &event_at(&dev, 48, 1, 1200)));
+        // starts over afterward
+        assert!(!state.advance(&seq, &event_at(&dev, 48, 1, 1300)));
+    }
+
+    #[test]
+    fn wrong_keys_and_timeouts() {
+        let dev = device(None);
+        let seq = sequence(&[30, 30, 48]);
+        let mut state = SequenceState::new();
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 1, 1000)));
+        assert!(!state.advance(&seq, &event_at(&dev, 31, 1, 1010)));
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 1, 1020)));
+        assert!(!state.advance(&seq, &event_at(&dev, 48, 1, 1030)));
+        // an extra press of the first key doesn't spoil things
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 1, 2000)));
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 1, 2100)));
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 1, 2200)));
+        assert!(state.advance(&seq, &event_at(&dev, 48, 1, 2300)));
+        // too slow
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 1, 3000)));
+        assert!(!state.advance(&seq, &event_at(&dev, 30, 1, 3200)));
+        assert!(!state.advance(&seq, &event_at(&dev, 48, 1, 3500)));
+    }
+}
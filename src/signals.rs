@@ -0,0 +1,39 @@
+//! Turning signals into messages on the event queue, so that the main loop can
+//! deal with them when it's ready.
+
+use std::{io, thread::spawn};
+
+use crate::{EventSender, Message};
+
+/// Blocks the given signals in the calling thread, and spawns a thread that
+/// waits for them and sends each one that arrives via `event_sender`, as a
+/// `Message::Signal`.
+///
+/// Threads inherit the blocked signals of the thread that spawned them, so
+/// call this before spawning any other threads (including by opening
+/// devices), or one of those threads might get the signal instead. Programs
+/// run with `std::process::Command` start with no signals blocked.
+pub fn forward_signals(signals: &[libc::c_int], event_sender: EventSender)
+                       -> io::Result<()> {
+    let set = unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &signal in signals.iter() {
+            if libc::sigaddset(&mut set, signal) != 0 {
+                return Err(io::Error::last_os_error())
+            }
+        }
+        set
+    };
+    let err = unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut())
+    };
+    if err != 0 { return Err(io::Error::from_raw_os_error(err)) }
+    spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 { continue }
+        if !event_sender.send_message(Message::Signal(signal)) { return }
+    });
+    Ok(())
+}
+
@@ -0,0 +1,84 @@
+//! The commented starter configuration that `--init` writes.
+
+/// Returns a configuration file to start from, with comments explaining each
+/// part, a `dev` line for the first of `devices` (and commented-out ones for
+/// the rest), and a few example `if` lines. With no devices, the `dev` line
+/// picks gamepads by kind instead.
+pub fn starter_config(devices: &[String]) -> String {
+    let mut ret = String::from("\
+# A starting point for an input2cmds configuration. Lines beginning with #
+# are comments. Everything that can go in here is explained in the README:
+# https://github.com/SolraBizna/input2cmds/blob/master/README.md
+#
+# To find out what a key or button sends, run \"input2cmds -v THIS_FILE\" and
+# press it. Each event is printed as an \"if\" line that would match it. Use
+# \"input2cmds --print-config THIS_FILE\" to see how this file is understood.
+
+");
+    match devices.split_first() {
+        Some((first, rest)) => {
+            ret.push_str("\
+# The device to read from. These were the devices found when this file was
+# made; uncomment any others you'd like to read from too.
+");
+            ret.push_str(&dev_line(first));
+            for path in rest.iter() {
+                ret.push_str("# ");
+                ret.push_str(&dev_line(path));
+            }
+        },
+        None => ret.push_str("\
+# No devices were found when this file was made, so this reads from every
+# gamepad instead. To read from just one device, give its path instead, like
+# \"dev /dev/input/by-id/usb-Name_Of_The_Device-event-joystick\".
+dev type=gamepad
+"),
+    }
+    ret.push_str("
+# Pressing (value=1) a key or button (type=1) whose code is 304 (BTN_SOUTH,
+# the \"A\" button on most gamepads):
+if type=1 code=304 value=1 then: echo \"A was pressed\"
+# ...and releasing it (value=0):
+if type=1 code=304 value=0 then: echo \"A was released\"
+
+# An absolute axis (type=3), like a stick, moving. ABS_X is code 0, and
+# $I2C_VALUE is where it is now.
+# if type=3 code=0 then: echo \"X is at $I2C_VALUE\"
+");
+    ret
+}
+
+/// Returns a `dev` line for the device at `path`.
+fn dev_line(path: &str) -> String {
+    if path.starts_with('"') || path.contains(char::is_whitespace) {
+        // a colon makes the rest of the line the path, just as it is
+        format!("dev: {}\n", path)
+    }
+    else {
+        format!("dev {}\n", path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_config;
+
+    #[test]
+    fn starter_configs_parse() {
+        let config = parse_config("init.conf", &starter_config(&[])).unwrap();
+        assert!(config.devices[0].query.is_some());
+        assert_eq!(config.matches.len(), 2);
+        let devices = ["/dev/input/by-id/usb-Pad-event-joystick".to_owned(),
+                       "/dev/input/by-id/usb-Odd Name-event-kbd".to_owned()];
+        let config = parse_config("init.conf", &starter_config(&devices))
+            .unwrap();
+        assert_eq!(config.devices.len(), 1);
+        assert_eq!(config.devices[0].path, devices[0]);
+        assert_eq!(dev_line(&devices[1]),
+                   "dev: /dev/input/by-id/usb-Odd Name-event-kbd\n");
+        let config = parse_config("init.conf", &dev_line(&devices[1]))
+            .unwrap();
+        assert_eq!(config.devices[0].path, devices[1]);
+    }
+}
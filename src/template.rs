@@ -0,0 +1,74 @@
+//! Filling in the details of an event in a command.
+
+/// Replaces each `{name}` in `template` with the value `lookup` gives for
+/// `name`. A `{name}` that `lookup` doesn't know is left alone, so that
+/// commands that use braces for their own purposes (like `${HOME}`) still
+/// work. `{{` and `}}` stand for literal `{` and `}`.
+pub fn expand_template<F>(template: &str, lookup: F) -> String
+where F: Fn(&str) -> Option<String> {
+    let mut ret = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        ret.push_str(&rest[..i]);
+        let brace = &rest[i..i+1];
+        rest = &rest[i+1..];
+        if rest.starts_with(brace) {
+            // doubled brace
+            ret.push_str(brace);
+            rest = &rest[1..];
+            continue
+        }
+        if brace == "{" {
+            if let Some(end) = rest.find('}') {
+                if let Some(value) = lookup(&rest[..end]) {
+                    ret.push_str(&value);
+                    rest = &rest[end+1..];
+                    continue
+                }
+            }
+        }
+        ret.push_str(brace);
+    }
+    ret.push_str(rest);
+    ret
+}
+
+/// Quotes `text` for `/bin/sh`, so that it's passed along as a single word
+/// exactly as it is, whatever characters it contains.
+pub fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(template: &str) -> String {
+        expand_template(template, |name| match name {
+            "code" => Some("30".to_owned()),
+            "value" => Some("1".to_owned()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(expand("key {code} is now {value}"), "key 30 is now 1");
+        assert_eq!(expand("{code}{value}"), "301");
+        assert_eq!(expand("no placeholders"), "no placeholders");
+    }
+
+    #[test]
+    fn escapes_and_strangers() {
+        assert_eq!(expand("{{code}} {{{code}}}"), "{code} {30}");
+        assert_eq!(expand("echo ${HOME} {a,b} {"), "echo ${HOME} {a,b} {");
+        assert_eq!(expand("} {value"), "} {value");
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("Left Pad"), "'Left Pad'");
+        assert_eq!(shell_quote("it's $HOME"), "'it'\\''s $HOME'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
@@ -0,0 +1,141 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::io::AsRawFd,
+    sync::Arc,
+    thread::sleep,
+    time::Duration,
+};
+
+use crate::{
+    DeviceEvent, DeviceSpec, InputEvent, Location, QueuePolicy,
+    codes::{BUS_VIRTUAL, EV_KEY, EV_SYN, SYN_REPORT},
+};
+
+/// Makes a device at `/dev/input/event0`, with the given label.
+pub(crate) fn device(label: Option<&str>) -> Arc<DeviceSpec> {
+    Arc::new(DeviceSpec {
+        label: label.map(str::to_owned),
+        path: "/dev/input/event0".to_owned(),
+        query: None,
+        virtual_policy: None,
+        grab: false,
+        queue: None,
+        queue_policy: QueuePolicy::default(),
+        max_rate: None,
+        location: Location { file: "test.conf".to_owned(), line: 1 },
+    })
+}
+
+/// Makes an event from the given device.
+pub(crate) fn event(device: &Arc<DeviceSpec>, type_: u16, code: u16,
+                    value: i32) -> DeviceEvent {
+    DeviceEvent {
+        device: device.clone(),
+        event: InputEvent {
+            time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_, code, value,
+        },
+        range: None,
+    }
+}
+
+/// A device made with `uinput`, for tests that go all the way from opening
+/// a device to matching its events. It goes away when dropped.
+pub(crate) struct VirtualDevice {
+    /// Our handle on `/dev/uinput`.
+    uinput: File,
+    /// The path of the event device that appeared for it.
+    pub path: String,
+}
+
+/// Builds a `uinput` ioctl request number, like the kernel's `_IOC` macro.
+const fn uinput_ioctl(dir: libc::c_ulong, nr: libc::c_ulong, size: usize)
+                      -> libc::c_ulong {
+    const MAGIC: libc::c_ulong = b'U' as libc::c_ulong;
+    (dir << 30) | ((size as libc::c_ulong) << 16) | (MAGIC << 8) | nr
+}
+
+impl VirtualDevice {
+    /// Makes a device that can send the given key codes, and waits for its
+    /// event device to appear. Returns `None` if `/dev/uinput` can't be used
+    /// (as in most sandboxes), so that the test can be skipped.
+    pub(crate) fn keyboard(keys: &[u16]) -> Option<VirtualDevice> {
+        let uinput = OpenOptions::new().write(true).open("/dev/uinput").ok()?;
+        let fd = uinput.as_raw_fd();
+        let int = std::mem::size_of::<libc::c_int>();
+        let set_bit = |nr, bit: u16| unsafe {
+            libc::ioctl(fd, uinput_ioctl(1, nr, int) as _,
+                        bit as libc::c_int) >= 0
+        };
+        // UI_SET_EVBIT and UI_SET_KEYBIT
+        if !set_bit(100, EV_KEY) || !keys.iter().all(|x| set_bit(101, *x)) {
+            return None
+        }
+        // the old way of setting the name and ID, a `uinput_user_dev`, which
+        // every kernel understands: the name, an `input_id`, and room for
+        // information about absolute axes, which we don't have
+        let mut setup = vec![0u8; 80 + 8 + 4 + 4 * 64 * 4];
+        setup[.. 15].copy_from_slice(b"input2cmds test");
+        setup[80 .. 82].copy_from_slice(&BUS_VIRTUAL.to_ne_bytes());
+        (&uinput).write_all(&setup).ok()?;
+        // UI_DEV_CREATE
+        if unsafe { libc::ioctl(fd, uinput_ioctl(0, 1, 0) as _) } < 0 {
+            return None
+        }
+        let mut device = VirtualDevice { uinput, path: String::new() };
+        // UI_GET_SYSNAME, which says where it is in sysfs
+        let mut sysname = [0u8; 64];
+        if unsafe {
+            libc::ioctl(fd, uinput_ioctl(2, 44, sysname.len()) as _,
+                        sysname.as_mut_ptr())
+        } < 0 {
+            return None
+        }
+        let sysname = String::from_utf8_lossy(
+            sysname.split(|x| *x == 0).next().unwrap()).into_owned();
+        let dir = format!("/sys/devices/virtual/input/{}", sysname);
+        // udev needs a moment to make the device node, and to let us at it
+        for _ in 0 .. 100 {
+            let name = std::fs::read_dir(&dir).ok()?.filter_map(|x| {
+                x.ok()?.file_name().into_string().ok()
+            }).find(|x| x.starts_with("event"));
+            if let Some(name) = name {
+                let path = format!("/dev/input/{}", name);
+                if File::open(&path).is_ok() {
+                    device.path = path;
+                    return Some(device)
+                }
+            }
+            sleep(Duration::from_millis(20));
+        }
+        None
+    }
+    /// Sends an event, followed by a `SYN_REPORT`.
+    pub(crate) fn inject(&self, type_: u16, code: u16, value: i32) {
+        for (type_, code, value) in [(type_, code, value),
+                                     (EV_SYN, SYN_REPORT, 0)] {
+            let event = InputEvent {
+                time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+                type_, code, value,
+            };
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    &event as *const InputEvent as *const u8,
+                    std::mem::size_of::<InputEvent>())
+            };
+            (&self.uinput).write_all(bytes).unwrap();
+        }
+    }
+}
+
+impl Drop for VirtualDevice {
+    fn drop(&mut self) {
+        // UI_DEV_DESTROY
+        unsafe {
+            libc::ioctl(self.uinput.as_raw_fd(), uinput_ioctl(0, 2, 0) as _);
+        }
+    }
+}